@@ -0,0 +1,229 @@
+// Copyright 2018-2020 the Deno authors. All rights reserved. MIT license.
+
+//! The time the watcher runs on, so that tests can control it instead of
+//! waiting for real time to pass.
+
+use deno_core::futures::Future;
+use deno_core::futures::FutureExt;
+use std::fmt;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::Context;
+use std::task::Poll;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// A sleep of a `Clock`, which resolves once the clock has reached its
+/// deadline.
+pub trait Sleep: Future<Output = ()> + Send + Unpin {
+  /// Moves the deadline, whether or not the sleep has resolved yet.
+  fn reset(&mut self, deadline: Instant);
+}
+
+impl Sleep for tokio::time::Delay {
+  fn reset(&mut self, deadline: Instant) {
+    tokio::time::Delay::reset(self, deadline)
+  }
+}
+
+/// A source of the current time and of sleeps.
+pub trait Clock: Send + Sync {
+  fn now(&self) -> Instant;
+  /// Resolves once `now` has reached `deadline`.
+  fn sleep_until(&self, deadline: Instant) -> Box<dyn Sleep>;
+  fn sleep(&self, duration: Duration) -> Box<dyn Sleep> {
+    self.sleep_until(self.now() + duration)
+  }
+}
+
+/// The tokio timer, the default.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+  fn now(&self) -> Instant {
+    Instant::now()
+  }
+
+  fn sleep_until(&self, deadline: Instant) -> Box<dyn Sleep> {
+    Box::new(tokio::time::delay_until(deadline))
+  }
+}
+
+/// A `Clock` that can be shared, e.g. between the options and a test.
+#[derive(Clone)]
+pub struct SharedClock(Arc<dyn Clock>);
+
+impl SharedClock {
+  pub fn new<C: Clock + 'static>(clock: C) -> Self {
+    Self(Arc::new(clock))
+  }
+}
+
+impl std::ops::Deref for SharedClock {
+  type Target = dyn Clock;
+
+  fn deref(&self) -> &Self::Target {
+    &*self.0
+  }
+}
+
+impl Default for SharedClock {
+  fn default() -> Self {
+    Self::new(SystemClock)
+  }
+}
+
+impl fmt::Debug for SharedClock {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.write_str("SharedClock")
+  }
+}
+
+/// A sleep until a deadline that can be moved, like tokio's `Delay`, on any
+/// clock. The one sleep is reset rather than recreated, and only when the
+/// deadline actually changes.
+pub struct Timer {
+  clock: SharedClock,
+  deadline: Instant,
+  sleep: Box<dyn Sleep>,
+}
+
+impl Timer {
+  pub fn new(clock: SharedClock, duration: Duration) -> Self {
+    let deadline = clock.now() + duration;
+    let sleep = clock.sleep_until(deadline);
+    Self {
+      clock,
+      deadline,
+      sleep,
+    }
+  }
+
+  pub fn now(&self) -> Instant {
+    self.clock.now()
+  }
+
+  pub fn deadline(&self) -> Instant {
+    self.deadline
+  }
+
+  pub fn reset(&mut self, deadline: Instant) {
+    if deadline != self.deadline {
+      self.deadline = deadline;
+      self.sleep.reset(deadline);
+    }
+  }
+}
+
+impl Future for Timer {
+  type Output = ();
+
+  fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+    self.get_mut().sleep.poll_unpin(cx)
+  }
+}
+
+/// A clock that only moves when told to, for tests. Clones share the time.
+#[cfg(test)]
+#[derive(Clone)]
+pub struct MockClock(Arc<MockState>);
+
+#[cfg(test)]
+struct MockState {
+  start: Instant,
+  elapsed: std::sync::Mutex<Duration>,
+  /// The tasks of pending sleeps, woken whenever time moves on.
+  wakers: std::sync::Mutex<Vec<std::task::Waker>>,
+}
+
+#[cfg(test)]
+impl MockClock {
+  pub fn new() -> Self {
+    Self(Arc::new(MockState {
+      start: Instant::now(),
+      elapsed: Default::default(),
+      wakers: Default::default(),
+    }))
+  }
+
+  /// Moves the time forward, resolving every sleep that is due by then.
+  pub fn advance(&self, duration: Duration) {
+    *self.0.elapsed.lock().unwrap() += duration;
+    for waker in self.0.wakers.lock().unwrap().drain(..) {
+      waker.wake();
+    }
+  }
+}
+
+#[cfg(test)]
+impl Clock for MockClock {
+  fn now(&self) -> Instant {
+    self.0.start + *self.0.elapsed.lock().unwrap()
+  }
+
+  fn sleep_until(&self, deadline: Instant) -> Box<dyn Sleep> {
+    Box::new(MockSleep {
+      clock: self.clone(),
+      deadline,
+    })
+  }
+}
+
+#[cfg(test)]
+struct MockSleep {
+  clock: MockClock,
+  deadline: Instant,
+}
+
+#[cfg(test)]
+impl Future for MockSleep {
+  type Output = ();
+
+  fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+    if self.clock.now() >= self.deadline {
+      Poll::Ready(())
+    } else {
+      self.clock.0.wakers.lock().unwrap().push(cx.waker().clone());
+      Poll::Pending
+    }
+  }
+}
+
+#[cfg(test)]
+impl Sleep for MockSleep {
+  fn reset(&mut self, deadline: Instant) {
+    self.deadline = deadline;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[tokio::test]
+  async fn mock_sleeps_resolve_once_time_is_advanced() {
+    let clock = MockClock::new();
+    let start = clock.now();
+    let mut sleep = clock.sleep(Duration::from_secs(60));
+    assert!((&mut sleep).now_or_never().is_none());
+    clock.advance(Duration::from_secs(59));
+    assert!((&mut sleep).now_or_never().is_none());
+    clock.advance(Duration::from_secs(1));
+    assert!(sleep.now_or_never().is_some());
+    assert_eq!(clock.now() - start, Duration::from_secs(60));
+  }
+
+  #[tokio::test]
+  async fn timers_move_their_sleep() {
+    let clock = MockClock::new();
+    let start = clock.now();
+    let mut timer =
+      Timer::new(SharedClock::new(clock.clone()), Duration::from_secs(10));
+    timer.reset(start + Duration::from_secs(20));
+    clock.advance(Duration::from_secs(10));
+    assert!((&mut timer).now_or_never().is_none());
+    clock.advance(Duration::from_secs(10));
+    assert!((&mut timer).now_or_never().is_some());
+    assert_eq!(timer.deadline(), start + Duration::from_secs(20));
+  }
+}
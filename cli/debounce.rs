@@ -0,0 +1,1076 @@
+// Copyright 2018-2020 the Deno authors. All rights reserved. MIT license.
+
+use crate::clock::SharedClock;
+use crate::clock::Timer;
+use deno_core::error::AnyError;
+use deno_core::futures::stream::Stream;
+use deno_core::futures::task::noop_waker_ref;
+use deno_core::futures::Future;
+use std::path::Path;
+use std::pin::Pin;
+use std::task::Context;
+use std::task::Poll;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+
+/// When `Debounce` yields a burst of items. In every strategy each item
+/// restarts the window, which ends once no item has arrived for the debounce
+/// time.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DebounceStrategy {
+  /// Yield everything collected once the window ends.
+  Trailing,
+  /// Yield the first item right away and drop the rest of the burst.
+  Leading,
+  /// Yield the first item right away, and the rest of the burst, if any,
+  /// once the window ends.
+  Both,
+}
+
+impl Default for DebounceStrategy {
+  fn default() -> Self {
+    DebounceStrategy::Trailing
+  }
+}
+
+/// What the end of a `Debounce` window is measured from.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DebounceAnchor {
+  /// The latest item: every item restarts the window, which ends once the
+  /// items have stopped for the debounce time.
+  FirstEvent,
+  /// The last batch yielded: the window ends the debounce time after it,
+  /// however many items keep arriving, so that a source that never goes
+  /// quiet is yielded at a steady pace. A window opened more than the
+  /// debounce time after the last batch ends the debounce time after its
+  /// first item.
+  LastRestart,
+}
+
+impl Default for DebounceAnchor {
+  fn default() -> Self {
+    DebounceAnchor::FirstEvent
+  }
+}
+
+/// Stream adapter that groups items arriving in quick succession. Every item
+/// restarts the window, and once no item has arrived for the debounce time
+/// the items collected so far are yielded as one batch; see
+/// `DebounceStrategy` for the alternatives. A window can be capped with
+/// `max_debounce`, for sources that never go quiet, and followed by a
+/// `confirm_quiet` period, for slow writers. Items considered the same
+/// as one already in the batch are dropped. Errors are passed through
+/// immediately.
+///
+/// A debounce time of zero means no debouncing: the items that are already
+/// queued when the first one is received are yielded as one batch right
+/// away, without waiting for more.
+///
+/// Both the receiver and the timer register the task's waker, so the stream
+/// sleeps while idle instead of spinning. The timer runs on the clock given
+/// to `clock`, real time by default.
+pub struct Debounce<T> {
+  timer: Timer,
+  state: State<T>,
+}
+
+// Nothing is pinned: the timer is `Unpin`, and the items are only ever
+// moved in and out of the batch.
+impl<T> Unpin for Debounce<T> {}
+
+/// Everything but the timer.
+struct State<T> {
+  receiver: mpsc::Receiver<Result<T, AnyError>>,
+  debounce_time: Duration,
+  is_same: Box<dyn Fn(&T, &T) -> bool + Send + Sync>,
+  strategy: DebounceStrategy,
+  anchor: DebounceAnchor,
+  max_debounce: Option<Duration>,
+  confirm_quiet: Option<Duration>,
+  /// See `Debounce::arrival`.
+  arrival: Option<fn(&T) -> Instant>,
+  batch: Vec<T>,
+  /// When the first item of the open window arrived.
+  window_start: Option<Instant>,
+  /// When the last item arrived, dropped ones included.
+  last_item: Option<Instant>,
+  /// Whether the window ended and the `confirm_quiet` period is running.
+  confirming: bool,
+  /// When the last batch was yielded, for `DebounceAnchor::LastRestart`.
+  last_yield: Option<Instant>,
+  /// How long the window of the last batch had been open.
+  last_window: Option<Duration>,
+  /// When the first and the last item of the last batch's window arrived.
+  last_items: Option<(Instant, Instant)>,
+}
+
+impl<T: PartialEq + 'static> Debounce<T> {
+  pub fn new(
+    receiver: mpsc::Receiver<Result<T, AnyError>>,
+    debounce_time: Duration,
+  ) -> Self {
+    Self::with_predicate(receiver, debounce_time, T::eq)
+  }
+}
+
+impl<T> Debounce<T> {
+  /// Like `new`, but `is_same` decides whether an item duplicates one that
+  /// is already part of the current batch.
+  pub fn with_predicate(
+    receiver: mpsc::Receiver<Result<T, AnyError>>,
+    debounce_time: Duration,
+    is_same: fn(&T, &T) -> bool,
+  ) -> Self
+  where
+    T: 'static,
+  {
+    Self::with_same(receiver, debounce_time, Box::new(is_same))
+  }
+
+  /// Like `new`, but items are the same if `key` returns the same for them,
+  /// e.g. to compare only some of their fields.
+  pub fn with_key_fn<K>(
+    receiver: mpsc::Receiver<Result<T, AnyError>>,
+    debounce_time: Duration,
+    key: fn(&T) -> K,
+  ) -> Self
+  where
+    T: 'static,
+    K: PartialEq + 'static,
+  {
+    let is_same = move |a: &T, b: &T| key(a) == key(b);
+    Self::with_same(receiver, debounce_time, Box::new(is_same))
+  }
+
+  fn with_same(
+    receiver: mpsc::Receiver<Result<T, AnyError>>,
+    debounce_time: Duration,
+    is_same: Box<dyn Fn(&T, &T) -> bool + Send + Sync>,
+  ) -> Self {
+    Self {
+      timer: Timer::new(SharedClock::default(), debounce_time),
+      state: State {
+        receiver,
+        debounce_time,
+        is_same,
+        strategy: DebounceStrategy::default(),
+        anchor: DebounceAnchor::default(),
+        max_debounce: None,
+        confirm_quiet: None,
+        arrival: None,
+        batch: Vec::new(),
+        window_start: None,
+        last_item: None,
+        confirming: false,
+        last_yield: None,
+        last_window: None,
+        last_items: None,
+      },
+    }
+  }
+
+  pub fn strategy(mut self, strategy: DebounceStrategy) -> Self {
+    self.state.strategy = strategy;
+    self
+  }
+
+  pub fn anchor(mut self, anchor: DebounceAnchor) -> Self {
+    self.state.anchor = anchor;
+    self
+  }
+
+  /// Ends a window this long after it opened even if items keep arriving.
+  /// Windows are unbounded when `None`, the default.
+  pub fn max_debounce(mut self, max_debounce: Option<Duration>) -> Self {
+    self.state.max_debounce = max_debounce;
+    self
+  }
+
+  /// Once a window ends, waits this much longer for stragglers before
+  /// yielding. An item arriving meanwhile restarts the window, and the
+  /// confirmation follows it again, but never past `max_debounce`. No
+  /// confirmation when `None`, the default.
+  pub fn confirm_quiet(mut self, confirm_quiet: Option<Duration>) -> Self {
+    self.state.confirm_quiet = confirm_quiet;
+    self
+  }
+
+  /// Runs the windows on `clock` rather than real time, e.g. on a
+  /// `MockClock` in tests.
+  pub fn clock(mut self, clock: SharedClock) -> Self {
+    self.timer = Timer::new(clock, self.state.debounce_time);
+    self
+  }
+
+  /// Takes when each item arrived from the item itself, e.g. stamped by its
+  /// sender on the same clock, rather than from when it is received. Items
+  /// that queue up while the stream isn't polled then keep their spacing.
+  pub fn arrival(mut self, arrival: fn(&T) -> Instant) -> Self {
+    self.state.arrival = Some(arrival);
+    self
+  }
+
+  /// Takes the items of the open window, including those sent but not
+  /// polled yet, and closes the window, e.g. to act on them before the
+  /// stream is dropped. Pending errors are dropped.
+  pub fn take_pending(&mut self) -> Vec<T> {
+    let state = &mut self.state;
+    let mut cx = Context::from_waker(noop_waker_ref());
+    while let Poll::Ready(Some(result)) = state.receiver.poll_recv(&mut cx) {
+      if let Ok(item) = result {
+        let is_same = &state.is_same;
+        if !state.batch.iter().any(|existing| is_same(existing, &item)) {
+          state.batch.push(item);
+        }
+      }
+    }
+    state.window_start = None;
+    state.confirming = false;
+    std::mem::take(&mut state.batch)
+  }
+
+  /// Whether no window is open, so that nothing will be yielded before the
+  /// next item arrives.
+  pub fn is_idle(&self) -> bool {
+    self.state.window_start.is_none()
+  }
+
+  /// How long until the open window ends, as of the last poll, e.g. for a
+  /// countdown. `None` if no window is open.
+  pub fn time_until_yield(&self) -> Option<Duration> {
+    if self.is_idle() {
+      return None;
+    }
+    Some(
+      self
+        .timer
+        .deadline()
+        .saturating_duration_since(self.timer.now()),
+    )
+  }
+
+  /// How long the window of the last batch yielded had been open when it
+  /// was yielded, i.e. how much later than its first item the batch came.
+  /// `None` until a batch is yielded.
+  pub fn last_window(&self) -> Option<Duration> {
+    self.state.last_window
+  }
+
+  /// When the first and the last item of the window of the last batch
+  /// yielded arrived, dropped duplicates included. `None` until a batch is
+  /// yielded.
+  pub fn last_items(&self) -> Option<(Instant, Instant)> {
+    self.state.last_items
+  }
+}
+
+impl<T> State<T> {
+  /// Adds the item to the batch, returning whether it opened a window that
+  /// should be yielded right away. The window is measured from when the
+  /// item arrived, see `Debounce::arrival`.
+  fn push(&mut self, timer: &mut Timer, item: T) -> bool {
+    let arrived = match self.arrival {
+      Some(arrival) => arrival(&item),
+      None => timer.now(),
+    };
+    let opens_window = self.window_start.is_none();
+    let window_start = *self.window_start.get_or_insert(arrived);
+    self.last_item = Some(arrived);
+    self.confirming = false;
+    let mut deadline = match (self.anchor, self.last_yield) {
+      (DebounceAnchor::LastRestart, _) if !opens_window => timer.deadline(),
+      (DebounceAnchor::LastRestart, Some(last_yield))
+        if last_yield + self.debounce_time > arrived =>
+      {
+        last_yield + self.debounce_time
+      }
+      _ => arrived + self.debounce_time,
+    };
+    if let Some(max_debounce) = self.max_debounce {
+      deadline = deadline.min(window_start + max_debounce);
+    }
+    timer.reset(deadline);
+    let is_leading =
+      opens_window && self.strategy != DebounceStrategy::Trailing;
+    if !is_leading && self.strategy == DebounceStrategy::Leading {
+      trace!("Debounce: window reset, item dropped");
+      return false;
+    }
+    let is_same = &self.is_same;
+    if self.batch.iter().any(|existing| is_same(existing, &item)) {
+      trace!("Debounce: duplicate item dropped");
+    } else {
+      self.batch.push(item);
+    }
+    trace!(
+      "Debounce: window reset, {} item(s) pending",
+      self.batch.len()
+    );
+    is_leading
+  }
+
+  fn take_batch(&mut self, now: Instant) -> Option<Vec<T>> {
+    if self.batch.is_empty() {
+      None
+    } else {
+      trace!("Debounce: yielding {} item(s)", self.batch.len());
+      self.last_window = self
+        .window_start
+        .map(|start| now.saturating_duration_since(start));
+      self.last_items = match (self.window_start, self.last_item) {
+        (Some(first), Some(last)) => Some((first, last)),
+        _ => None,
+      };
+      Some(std::mem::take(&mut self.batch))
+    }
+  }
+}
+
+impl<T> Stream for Debounce<T> {
+  type Item = Result<Vec<T>, AnyError>;
+
+  fn poll_next(
+    self: Pin<&mut Self>,
+    cx: &mut Context,
+  ) -> Poll<Option<Self::Item>> {
+    let this = self.get_mut();
+    let timer = &mut this.timer;
+    let inner = &mut this.state;
+
+    // Drain everything that is ready; each item restarts the window.
+    loop {
+      match inner.receiver.poll_recv(cx) {
+        Poll::Ready(Some(Ok(item))) => {
+          if inner.push(timer, item) {
+            inner.last_yield = Some(timer.now());
+            return Poll::Ready(inner.take_batch(timer.now()).map(Ok));
+          }
+        }
+        Poll::Ready(Some(Err(err))) => return Poll::Ready(Some(Err(err))),
+        // The sender is gone, flush whatever is pending and finish.
+        Poll::Ready(None) => {
+          return Poll::Ready(inner.take_batch(timer.now()).map(Ok))
+        }
+        Poll::Pending => break,
+      }
+    }
+
+    if inner.window_start.is_none() {
+      return Poll::Pending;
+    }
+
+    // Nothing to wait for, the window ends once the queue is drained. Left
+    // to the timer, a deadline of now would only be reached on a later tick.
+    if inner.debounce_time == Duration::from_secs(0)
+      && inner.confirm_quiet.is_none()
+    {
+      let batch = inner.take_batch(timer.now());
+      inner.window_start = None;
+      return match batch {
+        Some(batch) => Poll::Ready(Some(Ok(batch))),
+        // The receiver has registered the waker.
+        None => Poll::Pending,
+      };
+    }
+
+    match Pin::new(&mut *timer).poll(cx) {
+      Poll::Ready(()) => {
+        // The confirmation can't run past `max_debounce` either, or a source
+        // that never goes quiet would keep restarting it.
+        let now = timer.now();
+        let cap = match (inner.max_debounce, inner.window_start) {
+          (Some(max_debounce), Some(start)) => Some(start + max_debounce),
+          _ => None,
+        };
+        let is_capped = cap.map_or(false, |cap| cap <= now);
+        if let (Some(confirm_quiet), false, false) =
+          (inner.confirm_quiet, inner.confirming, is_capped)
+        {
+          inner.confirming = true;
+          trace!("Debounce: window ended, confirming quiet");
+          let mut deadline = now + confirm_quiet;
+          if let Some(cap) = cap {
+            deadline = deadline.min(cap);
+          }
+          timer.reset(deadline);
+          // Registers the waker for the end of the confirmation.
+          if Pin::new(&mut *timer).poll(cx).is_pending() {
+            return Poll::Pending;
+          }
+        }
+        inner.confirming = false;
+        let batch = inner.take_batch(timer.now());
+        inner.window_start = None;
+        inner.last_yield = Some(timer.now());
+        match batch {
+          Some(batch) => Poll::Ready(Some(Ok(batch))),
+          // The receiver has registered the waker.
+          None => Poll::Pending,
+        }
+      }
+      Poll::Pending => Poll::Pending,
+    }
+  }
+}
+
+/// Like `Debounce`, but every group of items, as decided by `key` from the
+/// path of an item, has a window of its own: a group is yielded along with
+/// its key once none of its items has arrived for the debounce time, however
+/// busy the other groups are. Items equal to one already in their group are
+/// dropped, and errors are passed through immediately.
+pub struct GroupedDebounce<T, K> {
+  timer: Timer,
+  state: GroupedState<T, K>,
+}
+
+// Nothing is pinned, as in `Debounce`.
+impl<T, K> Unpin for GroupedDebounce<T, K> {}
+
+/// Everything but the timer.
+struct GroupedState<T, K> {
+  receiver: mpsc::Receiver<Result<T, AnyError>>,
+  debounce_time: Duration,
+  key: Box<dyn Fn(&T) -> K + Send + Sync>,
+  is_same: fn(&T, &T) -> bool,
+  /// See `Debounce::arrival`.
+  arrival: Option<fn(&T) -> Instant>,
+  /// The groups with an open window, in the order they were opened.
+  groups: Vec<Group<T, K>>,
+  is_closed: bool,
+  /// How long the window of the last group yielded had been open.
+  last_window: Option<Duration>,
+  /// See `Debounce::last_items`, for the last group yielded.
+  last_items: Option<(Instant, Instant)>,
+}
+
+struct Group<T, K> {
+  key: K,
+  items: Vec<T>,
+  opened: Instant,
+  /// When the last item of the group arrived.
+  last_item: Instant,
+  deadline: Instant,
+}
+
+impl<T, K> GroupedDebounce<T, K>
+where
+  T: AsRef<Path> + PartialEq + 'static,
+  K: PartialEq,
+{
+  pub fn new<F>(
+    receiver: mpsc::Receiver<Result<T, AnyError>>,
+    debounce_time: Duration,
+    key: F,
+  ) -> Self
+  where
+    F: Fn(&Path) -> K + Send + Sync + 'static,
+  {
+    Self::with_key(
+      receiver,
+      debounce_time,
+      move |item: &T| key(item.as_ref()),
+      T::eq,
+    )
+  }
+}
+
+impl<T, K> GroupedDebounce<T, K>
+where
+  K: PartialEq,
+{
+  /// Like `new`, but `key` decides the group from the item itself, and
+  /// `is_same` whether an item duplicates one already in its group.
+  pub fn with_key<F>(
+    receiver: mpsc::Receiver<Result<T, AnyError>>,
+    debounce_time: Duration,
+    key: F,
+    is_same: fn(&T, &T) -> bool,
+  ) -> Self
+  where
+    F: Fn(&T) -> K + Send + Sync + 'static,
+  {
+    Self {
+      timer: Timer::new(SharedClock::default(), debounce_time),
+      state: GroupedState {
+        receiver,
+        debounce_time,
+        key: Box::new(key),
+        is_same,
+        arrival: None,
+        groups: Vec::new(),
+        is_closed: false,
+        last_window: None,
+        last_items: None,
+      },
+    }
+  }
+
+  /// See `Debounce::clock`.
+  pub fn clock(mut self, clock: SharedClock) -> Self {
+    self.timer = Timer::new(clock, self.state.debounce_time);
+    self
+  }
+
+  /// See `Debounce::arrival`.
+  pub fn arrival(mut self, arrival: fn(&T) -> Instant) -> Self {
+    self.state.arrival = Some(arrival);
+    self
+  }
+
+  /// Takes the items of every open group, including those sent but not
+  /// polled yet, see `Debounce::take_pending`.
+  pub fn take_pending(&mut self) -> Vec<T> {
+    let state = &mut self.state;
+    let mut cx = Context::from_waker(noop_waker_ref());
+    while let Poll::Ready(Some(result)) = state.receiver.poll_recv(&mut cx) {
+      if let Ok(item) = result {
+        state.push(self.timer.now(), item);
+      }
+    }
+    std::mem::take(&mut state.groups)
+      .into_iter()
+      .flat_map(|group| group.items)
+      .collect()
+  }
+
+  /// Whether no group has an open window.
+  pub fn is_idle(&self) -> bool {
+    self.state.groups.is_empty()
+  }
+
+  /// How long until the first group's window ends, as of the last poll.
+  /// `None` if no window is open.
+  pub fn time_until_yield(&self) -> Option<Duration> {
+    let deadline = self.state.groups.iter().map(|g| g.deadline).min()?;
+    Some(deadline.saturating_duration_since(self.timer.now()))
+  }
+
+  /// See `Debounce::last_window`, for the last group yielded.
+  pub fn last_window(&self) -> Option<Duration> {
+    self.state.last_window
+  }
+
+  /// See `Debounce::last_items`, for the last group yielded.
+  pub fn last_items(&self) -> Option<(Instant, Instant)> {
+    self.state.last_items
+  }
+}
+
+impl<T, K> GroupedState<T, K>
+where
+  K: PartialEq,
+{
+  /// Adds the item to its group, restarting that group's window only. `now`
+  /// is when it arrived unless the item tells, see `Debounce::arrival`.
+  fn push(&mut self, now: Instant, item: T) {
+    let now = self.arrival.map_or(now, |arrival| arrival(&item));
+    let key = (self.key)(&item);
+    let deadline = now + self.debounce_time;
+    match self.groups.iter_mut().find(|group| group.key == key) {
+      Some(group) => {
+        group.deadline = deadline;
+        group.last_item = now;
+        let is_same = self.is_same;
+        if group.items.iter().any(|existing| is_same(existing, &item)) {
+          trace!("Debounce: duplicate item dropped");
+        } else {
+          group.items.push(item);
+        }
+      }
+      None => self.groups.push(Group {
+        key,
+        items: vec![item],
+        opened: now,
+        last_item: now,
+        deadline,
+      }),
+    }
+  }
+
+  /// Removes the first group whose window has ended, or the first group at
+  /// all once the receiver is closed.
+  fn take_ready(&mut self, now: Instant) -> Option<(K, Vec<T>)> {
+    let is_closed = self.is_closed;
+    let index = self
+      .groups
+      .iter()
+      .position(|group| is_closed || group.deadline <= now)?;
+    let group = self.groups.remove(index);
+    self.last_window = Some(now.saturating_duration_since(group.opened));
+    self.last_items = Some((group.opened, group.last_item));
+    trace!(
+      "Debounce: yielding {} item(s) of a group",
+      group.items.len()
+    );
+    Some((group.key, group.items))
+  }
+}
+
+impl<T, K> Stream for GroupedDebounce<T, K>
+where
+  K: PartialEq,
+{
+  type Item = Result<(K, Vec<T>), AnyError>;
+
+  fn poll_next(
+    self: Pin<&mut Self>,
+    cx: &mut Context,
+  ) -> Poll<Option<Self::Item>> {
+    let this = self.get_mut();
+    let timer = &mut this.timer;
+    let inner = &mut this.state;
+
+    while !inner.is_closed {
+      match inner.receiver.poll_recv(cx) {
+        Poll::Ready(Some(Ok(item))) => inner.push(timer.now(), item),
+        Poll::Ready(Some(Err(err))) => return Poll::Ready(Some(Err(err))),
+        // The sender is gone, flush the groups one by one and finish.
+        Poll::Ready(None) => inner.is_closed = true,
+        Poll::Pending => break,
+      }
+    }
+
+    loop {
+      if let Some(group) = inner.take_ready(timer.now()) {
+        return Poll::Ready(Some(Ok(group)));
+      }
+      if inner.is_closed {
+        return Poll::Ready(None);
+      }
+      let deadline = match inner.groups.iter().map(|g| g.deadline).min() {
+        Some(deadline) => deadline,
+        // The receiver has registered the waker.
+        None => return Poll::Pending,
+      };
+      timer.reset(deadline);
+      if Pin::new(&mut *timer).poll(cx).is_pending() {
+        return Poll::Pending;
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::clock::Clock;
+  use crate::clock::MockClock;
+  use deno_core::error::generic_error;
+  use deno_core::futures::future;
+  use deno_core::futures::FutureExt;
+  use deno_core::futures::StreamExt;
+  use tokio::time::delay_for;
+
+  const WINDOW: Duration = Duration::from_millis(100);
+
+  #[test]
+  fn auto_traits() {
+    fn assert_send<T: Send>() {}
+    fn assert_unpin<T: Unpin>() {}
+    assert_send::<Debounce<u32>>();
+    // Lets `Debounce` be used with `StreamExt::next` directly, whatever the
+    // items.
+    assert_unpin::<Debounce<std::marker::PhantomPinned>>();
+  }
+
+  #[tokio::test]
+  async fn pinned_debounce_yields() {
+    let (mut sender, receiver) = mpsc::channel(16);
+    let debounce = Debounce::new(receiver, WINDOW);
+    tokio::pin!(debounce);
+    sender.send(Ok(1u32)).await.unwrap();
+    let batch = future::poll_fn(|cx| debounce.as_mut().poll_next(cx)).await;
+    assert_eq!(batch.unwrap().unwrap(), vec![1]);
+  }
+
+  #[tokio::test]
+  async fn pending_while_idle() {
+    let (_sender, receiver) = mpsc::channel::<Result<u32, AnyError>>(16);
+    let mut debounce = Debounce::new(receiver, WINDOW);
+    assert!(debounce.next().now_or_never().is_none());
+  }
+
+  #[tokio::test]
+  async fn fresh_debounce_stays_pending_past_its_window() {
+    let (_sender, receiver) = mpsc::channel::<Result<u32, AnyError>>(16);
+    let mut debounce = Debounce::new(receiver, WINDOW);
+    // The timer created along with the stream elapses without any item
+    // having been received.
+    tokio::time::delay_for(WINDOW * 2).await;
+    assert!(debounce.next().now_or_never().is_none());
+    assert!(debounce.is_idle());
+  }
+
+  #[tokio::test]
+  async fn window_ends_exactly_after_the_debounce_time() {
+    let clock = MockClock::new();
+    let (mut sender, receiver) = mpsc::channel(16);
+    let mut debounce =
+      Debounce::new(receiver, WINDOW).clock(SharedClock::new(clock.clone()));
+
+    sender.send(Ok(1u32)).await.unwrap();
+    assert!(debounce.next().now_or_never().is_none());
+    clock.advance(WINDOW - Duration::from_millis(1));
+    assert!(debounce.next().now_or_never().is_none());
+    assert_eq!(debounce.time_until_yield(), Some(Duration::from_millis(1)));
+    clock.advance(Duration::from_millis(1));
+    let batch = debounce.next().now_or_never().unwrap().unwrap().unwrap();
+    assert_eq!(batch, vec![1]);
+    assert!(debounce.is_idle());
+    assert_eq!(debounce.last_window(), Some(WINDOW));
+  }
+
+  #[tokio::test]
+  async fn batches_tell_when_their_first_and_last_items_arrived() {
+    let clock = MockClock::new();
+    let (mut sender, receiver) = mpsc::channel(16);
+    let mut debounce =
+      Debounce::new(receiver, WINDOW).clock(SharedClock::new(clock.clone()));
+    let start = clock.now();
+
+    sender.send(Ok(1u32)).await.unwrap();
+    assert!(debounce.next().now_or_never().is_none());
+    clock.advance(Duration::from_millis(100));
+    sender.send(Ok(2)).await.unwrap();
+    assert!(debounce.next().now_or_never().is_none());
+    clock.advance(WINDOW);
+    let batch = debounce.next().now_or_never().unwrap().unwrap().unwrap();
+    assert_eq!(batch, vec![1, 2]);
+    let (first, last) = debounce.last_items().unwrap();
+    assert_eq!(first, start);
+    assert_eq!(last - first, Duration::from_millis(100));
+    assert_eq!(debounce.last_window(), Some(WINDOW + (last - first)));
+  }
+
+  #[tokio::test]
+  async fn stamped_items_keep_their_spacing_when_queued() {
+    let clock = MockClock::new();
+    let (mut sender, receiver) = mpsc::channel(16);
+    let mut debounce = Debounce::new(receiver, WINDOW)
+      .arrival(|item: &(u32, Instant)| item.1)
+      .clock(SharedClock::new(clock.clone()));
+    let start = clock.now();
+
+    // Not polled until both are queued.
+    sender.send(Ok((1u32, clock.now()))).await.unwrap();
+    clock.advance(Duration::from_millis(60));
+    sender.send(Ok((2, clock.now()))).await.unwrap();
+    assert!(debounce.next().now_or_never().is_none());
+    assert_eq!(debounce.time_until_yield(), Some(WINDOW));
+    clock.advance(WINDOW);
+    let batch = debounce.next().now_or_never().unwrap().unwrap().unwrap();
+    assert_eq!(batch.len(), 2);
+    let (first, last) = debounce.last_items().unwrap();
+    assert_eq!(first, start);
+    assert_eq!(last - first, Duration::from_millis(60));
+  }
+
+  #[tokio::test]
+  async fn zero_debounce_yields_what_is_queued_right_away() {
+    let (mut sender, receiver) = mpsc::channel(16);
+    let mut debounce = Debounce::new(receiver, Duration::from_secs(0));
+    for i in &[1u32, 2, 1, 3] {
+      sender.send(Ok(*i)).await.unwrap();
+    }
+    let batch = debounce.next().now_or_never().unwrap().unwrap().unwrap();
+    assert_eq!(batch, vec![1, 2, 3]);
+    assert!(debounce.is_idle());
+    assert!(debounce.next().now_or_never().is_none());
+
+    sender.send(Ok(4)).await.unwrap();
+    let batch = debounce.next().now_or_never().unwrap().unwrap().unwrap();
+    assert_eq!(batch, vec![4]);
+  }
+
+  #[tokio::test]
+  async fn burst_yields_once() {
+    let window = Duration::from_secs(1);
+    let (mut sender, receiver) = mpsc::channel(16);
+    let mut debounce = Debounce::new(receiver, window);
+
+    let start = std::time::Instant::now();
+    for i in 0..5u32 {
+      sender.send(Ok(i)).await.unwrap();
+      delay_for(Duration::from_millis(50)).await;
+    }
+    let batch = debounce.next().await.unwrap().unwrap();
+    assert_eq!(batch, vec![0, 1, 2, 3, 4]);
+    assert!(start.elapsed() >= window);
+    assert!(debounce.is_idle());
+    assert!(debounce.next().now_or_never().is_none());
+  }
+
+  #[tokio::test]
+  async fn max_debounce_caps_the_window() {
+    let max_debounce = Duration::from_millis(500);
+    let (mut sender, receiver) = mpsc::channel(16);
+    let mut debounce =
+      Debounce::new(receiver, WINDOW).max_debounce(Some(max_debounce));
+
+    let start = std::time::Instant::now();
+    let feed = async {
+      // Never quiet for a whole window.
+      for i in 0..40u32 {
+        sender.send(Ok(i)).await.unwrap();
+        delay_for(WINDOW / 2).await;
+      }
+    };
+    let first = async {
+      let batch = debounce.next().await.unwrap().unwrap();
+      (batch, start.elapsed())
+    };
+    let ((batch, elapsed), _) = tokio::join!(first, feed);
+    assert!(!batch.is_empty());
+    assert!(elapsed >= max_debounce);
+    assert!(elapsed < max_debounce * 2);
+  }
+
+  #[tokio::test]
+  async fn anchor_decides_whether_continuous_items_are_yielded() {
+    for anchor in &[DebounceAnchor::FirstEvent, DebounceAnchor::LastRestart] {
+      let clock = MockClock::new();
+      let (mut sender, receiver) = mpsc::channel(16);
+      let mut debounce = Debounce::new(receiver, WINDOW)
+        .anchor(*anchor)
+        .clock(SharedClock::new(clock.clone()));
+      let mut batches = Vec::new();
+      // An item every half window, for six windows. The item sent as a
+      // window ends is still part of it.
+      for i in 0..12u32 {
+        sender.send(Ok(i)).await.unwrap();
+        if let Some(batch) = debounce.next().now_or_never() {
+          batches.push(batch.unwrap().unwrap());
+        }
+        clock.advance(WINDOW / 2);
+      }
+      match anchor {
+        DebounceAnchor::FirstEvent => assert!(batches.is_empty()),
+        DebounceAnchor::LastRestart => assert_eq!(
+          batches,
+          vec![
+            vec![0, 1, 2],
+            vec![3, 4],
+            vec![5, 6],
+            vec![7, 8],
+            vec![9, 10]
+          ]
+        ),
+      }
+    }
+  }
+
+  #[tokio::test]
+  async fn same_items_are_collapsed() {
+    let (mut sender, receiver) = mpsc::channel(16);
+    let mut debounce = Debounce::new(receiver, WINDOW);
+    for i in &[1u32, 2, 1, 1] {
+      sender.send(Ok(*i)).await.unwrap();
+    }
+    assert_eq!(debounce.next().await.unwrap().unwrap(), vec![1, 2]);
+  }
+
+  #[tokio::test]
+  async fn custom_predicate() {
+    let (mut sender, receiver) = mpsc::channel(16);
+    let mut debounce =
+      Debounce::with_predicate(receiver, WINDOW, |a: &u32, b: &u32| {
+        a % 10 == b % 10
+      });
+    for i in &[1u32, 11, 2, 21] {
+      sender.send(Ok(*i)).await.unwrap();
+    }
+    assert_eq!(debounce.next().await.unwrap().unwrap(), vec![1, 2]);
+  }
+
+  #[tokio::test]
+  async fn custom_key_fn() {
+    let (mut sender, receiver) = mpsc::channel(16);
+    let mut debounce =
+      Debounce::with_key_fn(receiver, WINDOW, |pair: &(u32, u32)| pair.0);
+    for pair in &[(1u32, 1u32), (1, 2), (2, 1)] {
+      sender.send(Ok(*pair)).await.unwrap();
+    }
+    assert_eq!(
+      debounce.next().await.unwrap().unwrap(),
+      vec![(1, 1), (2, 1)]
+    );
+  }
+
+  #[tokio::test]
+  async fn errors_pass_through() {
+    let (mut sender, receiver) = mpsc::channel(16);
+    let mut debounce = Debounce::new(receiver, WINDOW);
+    sender.send(Ok(1u32)).await.unwrap();
+    sender.send(Err(generic_error("boom"))).await.unwrap();
+
+    let err = debounce.next().await.unwrap().unwrap_err();
+    assert_eq!(err.to_string(), "boom");
+    assert_eq!(debounce.next().await.unwrap().unwrap(), vec![1]);
+  }
+
+  #[tokio::test]
+  async fn leading_yields_first_item_and_drops_burst() {
+    let (mut sender, receiver) = mpsc::channel(16);
+    let mut debounce =
+      Debounce::new(receiver, WINDOW).strategy(DebounceStrategy::Leading);
+
+    let start = std::time::Instant::now();
+    sender.send(Ok(1u32)).await.unwrap();
+    sender.send(Ok(2)).await.unwrap();
+    assert_eq!(debounce.next().await.unwrap().unwrap(), vec![1]);
+    assert!(start.elapsed() < WINDOW);
+
+    sender.send(Ok(3)).await.unwrap();
+    let rest = tokio::time::timeout(WINDOW * 3, debounce.next()).await;
+    assert!(rest.is_err());
+
+    // The window has ended, so the next item is yielded right away again.
+    sender.send(Ok(4)).await.unwrap();
+    assert_eq!(debounce.next().await.unwrap().unwrap(), vec![4]);
+  }
+
+  #[tokio::test]
+  async fn both_yields_first_item_and_rest_of_burst() {
+    let (mut sender, receiver) = mpsc::channel(16);
+    let mut debounce =
+      Debounce::new(receiver, WINDOW).strategy(DebounceStrategy::Both);
+
+    let start = std::time::Instant::now();
+    for i in &[1u32, 2, 3] {
+      sender.send(Ok(*i)).await.unwrap();
+    }
+    assert_eq!(debounce.next().await.unwrap().unwrap(), vec![1]);
+    assert!(start.elapsed() < WINDOW);
+    assert_eq!(debounce.next().await.unwrap().unwrap(), vec![2, 3]);
+    assert!(start.elapsed() >= WINDOW);
+    assert!(debounce.next().now_or_never().is_none());
+  }
+
+  #[tokio::test]
+  async fn both_without_burst_yields_once() {
+    let (mut sender, receiver) = mpsc::channel(16);
+    let mut debounce =
+      Debounce::new(receiver, WINDOW).strategy(DebounceStrategy::Both);
+
+    sender.send(Ok(1u32)).await.unwrap();
+    assert_eq!(debounce.next().await.unwrap().unwrap(), vec![1]);
+    let rest = tokio::time::timeout(WINDOW * 3, debounce.next()).await;
+    assert!(rest.is_err());
+  }
+
+  #[tokio::test]
+  async fn max_debounce_caps_the_confirmation() {
+    let clock = MockClock::new();
+    let (mut sender, receiver) = mpsc::channel(16);
+    let mut debounce = Debounce::new(receiver, WINDOW)
+      .max_debounce(Some(WINDOW * 3))
+      .confirm_quiet(Some(WINDOW))
+      .clock(SharedClock::new(clock.clone()));
+
+    // An item every half window, never quiet for a window, let alone for
+    // the confirmation after it.
+    let mut batches = Vec::new();
+    for i in 0..16u32 {
+      sender.send(Ok(i)).await.unwrap();
+      if let Some(batch) = debounce.next().now_or_never() {
+        batches.push(batch.unwrap().unwrap());
+      }
+      clock.advance(WINDOW / 2);
+    }
+    assert_eq!(
+      batches,
+      vec![(0..=6).collect::<Vec<_>>(), (7..=13).collect::<Vec<_>>()]
+    );
+  }
+
+  #[tokio::test]
+  async fn straggler_during_confirmation_delays_batch() {
+    let (mut sender, receiver) = mpsc::channel(16);
+    let mut debounce =
+      Debounce::new(receiver, WINDOW).confirm_quiet(Some(WINDOW));
+
+    let start = std::time::Instant::now();
+    sender.send(Ok(1u32)).await.unwrap();
+    // The window has ended, but the confirmation is still running.
+    let early = tokio::time::timeout(WINDOW * 3 / 2, debounce.next()).await;
+    assert!(early.is_err());
+    sender.send(Ok(2u32)).await.unwrap();
+
+    assert_eq!(debounce.next().await.unwrap().unwrap(), vec![1, 2]);
+    assert!(start.elapsed() >= WINDOW * 7 / 2);
+    let rest = tokio::time::timeout(WINDOW * 3, debounce.next()).await;
+    assert!(rest.is_err());
+  }
+
+  #[tokio::test]
+  async fn pending_items_can_be_taken() {
+    let (mut sender, receiver) = mpsc::channel(16);
+    let mut debounce = Debounce::new(receiver, Duration::from_secs(60));
+    sender.send(Ok(1u32)).await.unwrap();
+    assert!(debounce.next().now_or_never().is_none());
+    sender.send(Ok(2u32)).await.unwrap();
+    sender.send(Ok(1u32)).await.unwrap();
+
+    assert_eq!(debounce.take_pending(), vec![1, 2]);
+    assert!(debounce.is_idle());
+    assert!(debounce.take_pending().is_empty());
+  }
+
+  #[tokio::test]
+  async fn time_until_yield_counts_down() {
+    let (mut sender, receiver) = mpsc::channel(16);
+    let mut debounce = Debounce::new(receiver, WINDOW);
+    assert_eq!(debounce.time_until_yield(), None);
+
+    sender.send(Ok(1u32)).await.unwrap();
+    assert!(debounce.next().now_or_never().is_none());
+    let first = debounce.time_until_yield().unwrap();
+    assert!(first > Duration::from_millis(0) && first <= WINDOW);
+    tokio::time::delay_for(WINDOW / 4).await;
+    let second = debounce.time_until_yield().unwrap();
+    assert!(second < first);
+
+    assert_eq!(debounce.next().await.unwrap().unwrap(), vec![1]);
+    assert_eq!(debounce.time_until_yield(), None);
+  }
+
+  #[tokio::test]
+  async fn closed_channel_flushes_batch() {
+    let (mut sender, receiver) = mpsc::channel(16);
+    let mut debounce = Debounce::new(receiver, Duration::from_secs(60));
+    sender.send(Ok(1u32)).await.unwrap();
+    drop(sender);
+
+    assert_eq!(debounce.next().await.unwrap().unwrap(), vec![1]);
+    assert!(debounce.next().await.is_none());
+  }
+
+  #[tokio::test]
+  async fn grouped_windows_are_independent() {
+    use std::path::PathBuf;
+
+    let (mut sender, receiver) = mpsc::channel(16);
+    let mut debounce = GroupedDebounce::new(receiver, WINDOW, |path: &Path| {
+      path.components().next().map(|c| c.as_os_str().to_owned())
+    });
+    let key = |name: &str| Some(std::ffi::OsString::from(name));
+
+    sender.send(Ok(PathBuf::from("a/1"))).await.unwrap();
+    sender.send(Ok(PathBuf::from("b/1"))).await.unwrap();
+    tokio::time::delay_for(WINDOW / 2).await;
+    sender.send(Ok(PathBuf::from("a/2"))).await.unwrap();
+    sender.send(Ok(PathBuf::from("a/2"))).await.unwrap();
+
+    // `b` went quiet first, `a` only once its own window ended.
+    let (group, items) = debounce.next().await.unwrap().unwrap();
+    assert_eq!(group, key("b"));
+    assert_eq!(items, vec![PathBuf::from("b/1")]);
+    let (group, items) = debounce.next().await.unwrap().unwrap();
+    assert_eq!(group, key("a"));
+    assert_eq!(items, vec![PathBuf::from("a/1"), PathBuf::from("a/2")]);
+
+    drop(sender);
+    assert!(debounce.next().await.is_none());
+  }
+}
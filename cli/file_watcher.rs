@@ -1,94 +1,11938 @@
 // Copyright 2018-2020 the Deno authors. All rights reserved. MIT license.
 
+use crate::checksum;
+use crate::clock::SharedClock;
 use crate::colors;
+use crate::debounce::Debounce;
+use crate::debounce::DebounceAnchor;
+use crate::debounce::DebounceStrategy;
+use crate::debounce::GroupedDebounce;
+use crate::glob::GlobSet;
+use crate::tokio_util;
+use deno_core::error::generic_error;
 use deno_core::error::AnyError;
+use deno_core::futures::future;
+use deno_core::futures::future::BoxFuture;
+use deno_core::futures::stream;
+use deno_core::futures::stream::BoxStream;
+use deno_core::futures::stream::FuturesUnordered;
+use deno_core::futures::stream::Stream;
 use deno_core::futures::stream::StreamExt;
+use deno_core::futures::task::AtomicWaker;
 use deno_core::futures::Future;
+use deno_core::futures::FutureExt;
+use deno_core::serde_json::json;
+use deno_core::serde_json::Value;
+use notify::event::CreateKind;
 use notify::event::Event as NotifyEvent;
 use notify::event::EventKind;
+use notify::event::Flag;
+use notify::event::ModifyKind;
+use notify::event::RemoveKind;
+use notify::event::RenameMode;
 use notify::Config;
 use notify::Error as NotifyError;
+use notify::PollWatcher;
 use notify::RecommendedWatcher;
 use notify::RecursiveMode;
 use notify::Watcher;
+use regex::Regex;
+use serde::Deserialize;
+use serde::Serialize;
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::collections::VecDeque;
+use std::error::Error;
+use std::ffi::OsStr;
+use std::fmt;
+use std::io;
+use std::io::Write;
+use std::panic;
+use std::panic::AssertUnwindSafe;
+use std::path::Path;
 use std::path::PathBuf;
 use std::pin::Pin;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::task::Context;
+use std::task::Poll;
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+use tokio::io::AsyncBufRead;
+use tokio::io::AsyncBufReadExt;
 use tokio::select;
 use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::watch;
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+use tokio::time::delay_for;
+use tokio::time::Delay;
+use walkdir::WalkDir;
+
+const DEBOUNCE_TIME_MS: u64 = 200;
+const STARTUP_GRACE_MS: u64 = 50;
+const DEBOUNCE_ENV_VAR: &str = "DENO_WATCH_DEBOUNCE_MS";
+const EVENT_CHANNEL_CAPACITY: usize = 16;
+/// Events kept for `WatchDiagnostics::recent_events` by default.
+const RECENT_EVENTS_CAPACITY: usize = 256;
+/// The gitignore-style file read from each watched directory, see
+/// `WatchOptions::respect_denoignore`.
+const DENOIGNORE_FILE: &str = ".denoignore";
+/// How long a path that exceeded `WatchOptions::noisy_path_limit` is
+/// ignored before its events are counted again.
+const NOISY_PATH_COOLDOWN: Duration = Duration::from_secs(30);
+pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_millis(500);
+/// Attempts at restarting a watcher that stopped, the first one after
+/// `WATCHER_RESTART_BACKOFF` and each further one after twice as long.
+const WATCHER_RESTART_ATTEMPTS: u32 = 5;
+const WATCHER_RESTART_BACKOFF: Duration = Duration::from_millis(100);
+/// How often watching paths that were all removed is attempted again, for
+/// when even the ancestors standing in for them are gone.
+const LOST_ROOTS_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+/// Paths listed when reporting a restart before the rest are summarized.
+const MAX_REPORTED_PATHS: usize = 10;
+/// Erases the screen and moves the cursor to the top left corner.
+const CLEAR_SCREEN: &str = "\x1B[2J\x1B[1;1H";
+/// Runs in a row that have to end within `WatchOptions::fail_fast` before
+/// restarts are slowed down, and by how much.
+const FAIL_FAST_RUNS: usize = 3;
+const FAIL_FAST_DELAY: Duration = Duration::from_secs(1);
+/// Paths listed as the most changed in the `WatchOptions::summary_on_exit`
+/// summary.
+const SUMMARY_TOP_PATHS: usize = 3;
 
 // TODO(bartlomieju): rename
-type WatchFuture = Pin<Box<dyn Future<Output = Result<(), AnyError>>>>;
+pub type WatchFuture = Pin<Box<dyn Future<Output = Result<(), AnyError>>>>;
 
-async fn error_handler(watch_future: WatchFuture) {
-  let result = watch_future.await;
-  if let Err(err) = result {
-    let msg = format!("{}: {}", colors::red_bold("error"), err.to_string(),);
-    eprintln!("{}", msg);
+/// A portable classification of file system events. Backends differ in how
+/// precisely they describe a change, so events that can't be classified are
+/// conservatively treated as modifications.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum ChangeKind {
+  Created,
+  Modified,
+  Removed,
+  Renamed,
+  /// Only reported with `WatchOptions::watch_access`.
+  Accessed,
+}
+
+impl ChangeKind {
+  /// Returns `None` for access events, which don't change anything.
+  pub fn from_event_kind(kind: &EventKind) -> Option<Self> {
+    match kind {
+      EventKind::Access(_) => None,
+      EventKind::Create(_) => Some(ChangeKind::Created),
+      EventKind::Modify(ModifyKind::Name(_)) => Some(ChangeKind::Renamed),
+      EventKind::Modify(_) => Some(ChangeKind::Modified),
+      EventKind::Remove(_) => Some(ChangeKind::Removed),
+      // `Any` and `Other`, as reported by backends that only know that
+      // something happened.
+      _ => Some(ChangeKind::Modified),
+    }
+  }
+
+  pub fn as_str(self) -> &'static str {
+    match self {
+      ChangeKind::Created => "created",
+      ChangeKind::Modified => "modified",
+      ChangeKind::Removed => "removed",
+      ChangeKind::Renamed => "renamed",
+      ChangeKind::Accessed => "accessed",
+    }
   }
 }
 
-pub async fn watch_func<F>(
-  watch_paths: &[PathBuf],
-  closure: F,
-) -> Result<(), AnyError>
-where
-  F: Fn() -> WatchFuture,
-{
-  loop {
-    let func = error_handler(closure());
-    let mut is_file_changed = false;
-    select! {
-      _ = file_watcher(watch_paths) => {
-          is_file_changed = true;
-          info!(
-            "{} File change detected! Restarting!",
-            colors::intense_blue("Watcher")
-          );
-        },
-      _ = func => { },
+/// A file moved from one watched path to another.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Rename {
+  pub from: PathBuf,
+  pub to: PathBuf,
+}
+
+/// What changed about a watched path that is a symlink.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum LinkChange {
+  /// The symlink itself was pointed at another target.
+  Retargeted,
+  /// The contents of the target changed.
+  Content,
+}
+
+/// What a batch means beyond the paths that changed.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum BatchTag {
+  /// The lockfile of `WatchOptions::watch_lockfile` changed, so the
+  /// dependencies may have to be fetched again before restarting.
+  LockfileChanged,
+}
+
+/// How the contents of a changed file compare with the last version seen,
+/// see `WatchOptions::with_content_delta`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ContentDelta {
+  pub path: PathBuf,
+  /// The length of the last version seen, `None` if the file wasn't seen
+  /// before, e.g. because it was just created.
+  pub old_len: Option<u64>,
+  /// `None` if the file can't be read, e.g. because it was removed.
+  pub new_len: Option<u64>,
+  /// Whether the contents differ, which they can at the same length too.
+  pub changed: bool,
+}
+
+/// The changes of one debounced batch of events.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ChangeBatch {
+  /// Sorted and without duplicates.
+  pub paths: Vec<PathBuf>,
+  /// The kind shared by every change in the batch, `Modified` if they
+  /// differ.
+  pub kind: ChangeKind,
+  /// Every kind of change seen in the batch.
+  pub kinds: BTreeSet<ChangeKind>,
+  /// The net change to each path, as `WatchOptions::net_changes` tells it:
+  /// both ends of a rename count as removed and created. See `kind_of`.
+  pub path_kinds: BTreeMap<PathBuf, ChangeKind>,
+  /// The renames of the batch whose both ends were seen. A file moved out
+  /// of the watched paths counts as removed, and one moved in as created.
+  pub renames: Vec<Rename>,
+  /// The label of the watch root every change is below, see
+  /// `WatchOptions::root_labels`.
+  pub source: Option<String>,
+  /// Set if a watched path that is a symlink changed, `Retargeted` if any
+  /// of them was.
+  pub link_change: Option<LinkChange>,
+  /// See `BatchTag`.
+  pub tag: Option<BatchTag>,
+  /// How long after its first event the batch was reported, because of the
+  /// debouncing. `None` for batches that were not debounced.
+  pub debounced: Option<Duration>,
+  /// When the first and the last event of the batch arrived. `None` for
+  /// batches that were not debounced.
+  pub first_event_at: Option<Instant>,
+  pub last_event_at: Option<Instant>,
+  /// See `WatchOptions::with_content_delta`.
+  pub content_deltas: Vec<ContentDelta>,
+}
+
+impl ChangeBatch {
+  fn new(paths: Vec<PathBuf>, kind: ChangeKind) -> Self {
+    let mut kinds = BTreeSet::new();
+    kinds.insert(kind);
+    Self {
+      paths,
+      kind,
+      kinds,
+      path_kinds: BTreeMap::new(),
+      renames: Vec::new(),
+      source: None,
+      link_change: None,
+      tag: None,
+      debounced: None,
+      first_event_at: None,
+      last_event_at: None,
+      content_deltas: Vec::new(),
+    }
+  }
+
+  fn with_kinds(paths: Vec<PathBuf>, kinds: BTreeSet<ChangeKind>) -> Self {
+    let mut batch = Self::new(paths, ChangeKind::Modified);
+    batch.set_kinds(kinds);
+    batch
+  }
+
+  fn set_kinds(&mut self, kinds: BTreeSet<ChangeKind>) {
+    self.kind = match kinds.iter().next() {
+      Some(kind) if kinds.len() == 1 => *kind,
+      _ => ChangeKind::Modified,
     };
-    if !is_file_changed {
-      info!(
-        "{} Process terminated! Restarting on file change...",
-        colors::intense_blue("Watcher")
+    self.kinds = kinds;
+  }
+
+  /// Whether every change in the batch is of the given kind, e.g. to tell a
+  /// batch of removals from one that needs a restart.
+  pub fn is_only(&self, kind: ChangeKind) -> bool {
+    self.kinds.len() == 1 && self.kinds.contains(&kind)
+  }
+
+  /// The net change to the path, `Modified` if it is not known, e.g. for
+  /// the paths of dropped events.
+  pub fn kind_of(&self, path: &Path) -> ChangeKind {
+    self
+      .path_kinds
+      .get(path)
+      .copied()
+      .unwrap_or(ChangeKind::Modified)
+  }
+
+  /// Adds the changes of a later batch.
+  fn merge(&mut self, other: ChangeBatch) {
+    let mut kinds = std::mem::take(&mut self.kinds);
+    kinds.extend(other.kinds);
+    self.set_kinds(kinds);
+    for (path, kind) in other.path_kinds {
+      let kind = match self.path_kinds.get(&path) {
+        Some(first) => net_change_kind(&path, &[*first, kind]),
+        None => kind,
+      };
+      self.path_kinds.insert(path, kind);
+    }
+    self.paths.extend(other.paths);
+    collapse_paths(&mut self.paths);
+    self.renames.extend(other.renames);
+    if self.source != other.source {
+      self.source = None;
+    }
+    if self.link_change.is_none()
+      || other.link_change == Some(LinkChange::Retargeted)
+    {
+      self.link_change = other.link_change;
+    }
+    if self.tag.is_none() {
+      self.tag = other.tag;
+    }
+    self.debounced = self.debounced.max(other.debounced);
+    self.first_event_at = match (self.first_event_at, other.first_event_at) {
+      (Some(first), Some(other)) => Some(first.min(other)),
+      (first, other) => first.or(other),
+    };
+    self.last_event_at = self.last_event_at.max(other.last_event_at);
+    self.content_deltas.extend(other.content_deltas);
+  }
+}
+
+/// A batch as yielded by `watch_batches`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct WatchBatch {
+  /// Sorted and without duplicates.
+  pub paths: Vec<PathBuf>,
+  pub kinds: HashSet<ChangeKind>,
+  /// See `ChangeBatch::path_kinds`.
+  pub path_kinds: HashMap<PathBuf, ChangeKind>,
+  /// The label of the watch root every change is below, see
+  /// `WatchOptions::root_labels`.
+  pub source: Option<String>,
+  /// Why the batch was forced, if it did not come from the file system.
+  pub reason: Option<String>,
+  /// See `ChangeBatch::link_change`.
+  pub link_change: Option<LinkChange>,
+  /// See `ChangeBatch::tag`.
+  pub tag: Option<BatchTag>,
+  /// See `WatchOptions::with_content_delta`.
+  pub content_deltas: Vec<ContentDelta>,
+  /// When the first and the last event of the batch arrived, so that the
+  /// length of the burst can be told from the latency the debouncing added.
+  /// Both are when the batch was made for batches that were not debounced.
+  pub first_event_at: Instant,
+  pub last_event_at: Instant,
+  /// When the batch was reported.
+  pub time: SystemTime,
+}
+
+impl WatchBatch {
+  /// A batch without changes that restarts the closure like the restart
+  /// signal does, for merging other sources into `WatcherBuilder::watch_merged`.
+  pub fn forced(reason: &str) -> Self {
+    Self {
+      paths: Vec::new(),
+      kinds: HashSet::new(),
+      path_kinds: HashMap::new(),
+      source: None,
+      reason: Some(reason.to_string()),
+      link_change: None,
+      tag: None,
+      content_deltas: Vec::new(),
+      first_event_at: Instant::now(),
+      last_event_at: Instant::now(),
+      time: SystemTime::now(),
+    }
+  }
+}
+
+impl From<ChangeBatch> for WatchBatch {
+  fn from(batch: ChangeBatch) -> Self {
+    let now = Instant::now();
+    Self {
+      paths: batch.paths,
+      kinds: batch.kinds.into_iter().collect(),
+      path_kinds: batch.path_kinds.into_iter().collect(),
+      source: batch.source,
+      reason: None,
+      link_change: batch.link_change,
+      tag: batch.tag,
+      content_deltas: batch.content_deltas,
+      first_event_at: batch.first_event_at.unwrap_or(now),
+      last_event_at: batch.last_event_at.unwrap_or(now),
+      time: SystemTime::now(),
+    }
+  }
+}
+
+impl From<WatchBatch> for ChangeBatch {
+  fn from(batch: WatchBatch) -> Self {
+    if let Some(reason) = &batch.reason {
+      debug!("Restart forced: {}", reason);
+    }
+    let mut change_batch =
+      ChangeBatch::with_kinds(batch.paths, batch.kinds.into_iter().collect());
+    change_batch.path_kinds = batch.path_kinds.into_iter().collect();
+    change_batch.source = batch.source;
+    change_batch.link_change = batch.link_change;
+    change_batch.tag = batch.tag;
+    change_batch.content_deltas = batch.content_deltas;
+    change_batch.first_event_at = Some(batch.first_event_at);
+    change_batch.last_event_at = Some(batch.last_event_at);
+    change_batch
+  }
+}
+
+/// Whether paths differing only in case name the same file, as on the
+/// default file systems of Windows and macOS.
+const CASE_INSENSITIVE_PATHS: bool = cfg!(any(windows, target_os = "macos"));
+
+/// Sorts the paths and removes duplicates, which on case insensitive file
+/// systems includes paths differing only in case.
+fn collapse_paths(paths: &mut Vec<PathBuf>) {
+  paths.sort();
+  if CASE_INSENSITIVE_PATHS {
+    let mut seen = HashSet::new();
+    paths.retain(|path| seen.insert(path.to_string_lossy().to_lowercase()));
+  } else {
+    paths.dedup();
+  }
+}
+
+/// Options controlling how `watch_func` watches files.
+#[derive(Clone, Debug, Default)]
+pub struct WatchOptions {
+  /// Time without new events after which a change is reported. Falls back to
+  /// `DENO_WATCH_DEBOUNCE_MS` and then to `DEBOUNCE_TIME_MS` when unset.
+  pub debounce_time: Option<Duration>,
+  /// Whether a change is reported at the start or the end of a burst of
+  /// events, or both. Defaults to the end.
+  pub debounce_strategy: DebounceStrategy,
+  /// Whether the time without new events is measured from the latest event
+  /// or from the last change reported. Measured from the last change, a
+  /// burst that never ends is still reported once per debounce time.
+  /// Defaults to the latest event.
+  pub debounce_anchor: DebounceAnchor,
+  /// Whether changes in different watched roots share one debounce window
+  /// or each root has its own. Defaults to one for all.
+  pub debounce_scope: DebounceScope,
+  /// Report a change at most this long after the first event of a burst,
+  /// even if events keep arriving, as they may from polling or some
+  /// container file systems. Unbounded when unset.
+  pub max_debounce: Option<Duration>,
+  /// Once a burst seems over, wait this much longer for stragglers before
+  /// reporting it, e.g. for saves that stall on slow disks.
+  pub confirm_quiet: Option<Duration>,
+  /// Gitignore-style globs; events for matching paths are dropped before
+  /// they reach the debouncer. Relative patterns are matched against both
+  /// the absolute path and the path relative to each watched root.
+  pub exclude: Vec<String>,
+  /// Regular expressions matched against the absolute event path, for rules
+  /// that are awkward as globs. Events for matching paths are dropped along
+  /// with the ones matching `exclude`.
+  pub exclude_regex: Vec<Regex>,
+  /// Globs an event path must match at least one of to be forwarded. An
+  /// empty list matches every path.
+  pub include: Vec<String>,
+  /// File extensions, such as `ts` or `.tsx`, an event path must have one
+  /// of to be forwarded, ignoring case. A shorthand for the common `include`
+  /// globs; a path has to match both if both are set. An empty list matches
+  /// every path.
+  pub extensions: Vec<String>,
+  /// Forces the recursive mode for every watched path. When unset,
+  /// directories are watched recursively and files non-recursively.
+  pub recursive_mode: Option<RecursiveMode>,
+  /// The recursive mode of individual watched paths, taking precedence
+  /// over `recursive_mode`, e.g. to watch only the top level of a large
+  /// data directory next to a recursively watched source directory.
+  pub root_modes: HashMap<PathBuf, RecursiveMode>,
+  /// Names for watched paths, e.g. `frontend` and `backend`, used in the
+  /// restart message and as the `source` of batches whose changes are all
+  /// below the same labeled path. A change below several labeled paths
+  /// belongs to the innermost one.
+  pub root_labels: HashMap<PathBuf, String>,
+  /// Watch directories that would be watched recursively only down to this
+  /// many levels of subdirectories, to stay within OS watch limits. Each
+  /// directory within the limit gets a watch of its own, including ones
+  /// created later; changes deeper down are not seen.
+  pub max_depth: Option<usize>,
+  /// Also watch the targets of symlinked directories below recursively
+  /// watched directories, reporting their changes under the symlink's path.
+  /// Only symlinks present when watching starts are followed.
+  pub follow_symlinks: bool,
+  /// Report reads as changes too. By default access events are dropped as
+  /// soon as they arrive, so that tools scanning the watched files don't
+  /// keep the debounce window open.
+  pub watch_access: bool,
+  /// Report changes to metadata alone, e.g. by `touch` or `chmod`, which
+  /// are dropped by default since the contents stayed the same. Backends
+  /// that can't tell what changed report a plain modification, which is
+  /// never dropped.
+  pub watch_metadata: bool,
+  /// Report the net effect of a batch on each path rather than every kind
+  /// of change seen: `Removed` if the path was removed last, `Created` if it
+  /// was created and still exists, and `Modified` otherwise. The two ends of
+  /// a rename count as a removal and a creation; `ChangeBatch::renames`
+  /// still pairs them.
+  pub net_changes: bool,
+  /// Report changes to directories themselves, such as their creation or
+  /// removal. By default only the files in them count, since many backends
+  /// and editors also report the directory of a changed file. New
+  /// directories are watched either way, and watched directories
+  /// themselves always count.
+  pub watch_dir_events: bool,
+  /// Report changes to hidden files, and to anything in hidden directories
+  /// such as `.git`, found below a watched directory. Hidden paths that are
+  /// watched explicitly are always reported.
+  pub watch_hidden: bool,
+  /// Drop events for paths matching the patterns of the `.denoignore` file
+  /// in a watched directory, in addition to `exclude`. The file is read
+  /// again whenever it changes. Defaults to `true`.
+  pub respect_denoignore: Option<bool>,
+  /// Print a line for every event that passes the filters, as it arrives
+  /// and before debouncing, to diagnose what the watcher sees.
+  pub verbose: bool,
+  /// The clock the debouncer, `startup_grace`, `throttle`, `idle_timeout`,
+  /// `restart_delay` and the restart backoff run on, real time by default.
+  /// Tests can pass a `MockClock` to control them.
+  pub clock: SharedClock,
+  /// `watch_func` returns `Ok(())` once `true` is broadcast on this channel.
+  pub shutdown: Option<watch::Receiver<bool>>,
+  /// Let the running closure complete on shutdown instead of dropping it.
+  pub finish_on_shutdown: bool,
+  /// Stop watching and return `WatchError::Closure` when a run of the
+  /// closure fails. By default the error is printed and watching continues.
+  pub exit_on_error: bool,
+  /// Runs of the closure that end sooner than this count as failing fast,
+  /// e.g. a script that throws on startup. After `FAIL_FAST_RUNS` of them in
+  /// a row, a warning is printed and every further restart waits
+  /// `FAIL_FAST_DELAY` until a run lasts longer. Disabled when unset.
+  pub fail_fast: Option<Duration>,
+  /// Report an error that repeats the one of the previous run only as such,
+  /// see `WatchReporter::on_repeated_error`.
+  pub collapse_repeated_errors: bool,
+  /// Stop watching and return `WatchError::Watcher` when the OS watcher
+  /// reports an error, e.g. because the watch limit was reached. By default
+  /// the error is reported and watching continues.
+  pub exit_on_watcher_error: bool,
+  /// Stop watching, ending the file watcher's stream and `watch_func`
+  /// without an error, once every watched path has been removed. By
+  /// default watching goes on until one of them reappears.
+  pub exit_on_roots_lost: bool,
+  /// Clear the terminal before each restart. Defaults to clearing only when
+  /// stderr is a TTY and colors are enabled.
+  pub clear_screen: Option<bool>,
+  /// Thresholds for slowing down self-triggering restart loops. Uses
+  /// `RestartStorm::default()` when unset.
+  pub restart_storm: Option<RestartStorm>,
+  /// Stop watching once the closure has been restarted this many times,
+  /// after its last run completes, e.g. for CI jobs that have to finish.
+  /// `Some(0)` runs the closure once. Unbounded when unset.
+  pub max_restarts: Option<usize>,
+  /// Print a summary of the session, from its `WatchMetrics`, when
+  /// `watch_func` ends without an error, e.g. as a recap in CI logs.
+  pub summary_on_exit: bool,
+  /// Minimum time from the start of one run to the next restart. Changes
+  /// arriving sooner let the current run go on and are collected into a
+  /// single restart once the interval has passed.
+  pub throttle: Option<Duration>,
+  /// Fixed pause between the end of one run and the start of the next, to
+  /// give the previous run time to release ports or file locks.
+  pub restart_delay: Option<Duration>,
+  /// Called when a run is aborted for a restart; see `AbortHook`.
+  pub on_abort: Option<AbortHook>,
+  /// Start the next run only once the cleanup `on_abort` returned for the
+  /// aborted one has finished, so that it can't overlap with its
+  /// replacement. Otherwise the cleanup runs in the background.
+  pub wait_for_exit: bool,
+  /// Don't restart for files the last run of the closure wrote itself, e.g.
+  /// build output inside a watched directory, if nothing else changed since
+  /// the run ended. The files a run wrote are those whose modification time
+  /// changed while it ran, which costs a scan of the watched paths before
+  /// and after every run. A change that comes in while the run is still
+  /// going restarts it as usual, since what it writes is only known once it
+  /// has ended. Set `output_paths` too, or a watched file saved during a run
+  /// shorter than the debounce time is taken for one the run wrote.
+  pub detect_self_writes: bool,
+  /// Where the closure writes, e.g. a `dist` directory. Only files below
+  /// these count as written by a run for `detect_self_writes`, and only
+  /// they are scanned. Relative paths are resolved against the working
+  /// directory.
+  pub output_paths: Vec<PathBuf>,
+  /// Run the closure as soon as watching starts, rather than on the first
+  /// change. Defaults to `true`.
+  pub run_on_start: Option<bool>,
+  /// How long after watching starts events are ignored, since some backends
+  /// and the initial poll scan report a burst of changes that would restart
+  /// the first run right away. Defaults to `STARTUP_GRACE_MS`. Only
+  /// `watch_func` applies it; `watch_batches` reports every change.
+  pub startup_grace: Option<Duration>,
+  /// A JSON file recording the modification times of the watched files
+  /// each time the closure runs. When watching starts again, files that
+  /// changed in between are reported to the first run as changed, and
+  /// trigger it even without `run_on_start`.
+  pub state_file: Option<PathBuf>,
+  /// Stop watching, returning without an error, once no file has changed
+  /// for this long, e.g. so that CI jobs don't leak watch processes.
+  pub idle_timeout: Option<Duration>,
+  /// Only log the batches that would restart the closure, without ever
+  /// running it, e.g. to tune excludes.
+  pub dry_run: bool,
+  /// Compare the contents of changed files with the last version seen, and
+  /// don't report a change if they're all identical. Costs a read of every
+  /// changed file, and of every watched file when watching starts.
+  pub skip_unchanged: bool,
+  /// Report how each changed file compares with the last version seen, in
+  /// `ChangeBatch::content_deltas`, so that consumers can tell e.g. a file
+  /// that grew without reading it. Comes from the reads comparing contents,
+  /// so it needs `skip_unchanged`.
+  pub with_content_delta: bool,
+  /// Ignore changes to files larger than this many bytes, e.g. binary
+  /// build artifacts. Paths that can't be stat'ed, because they were
+  /// removed, are still reported.
+  pub max_file_size: Option<u64>,
+  /// The files the closure depends on, e.g. the module graph of the last
+  /// successful run, kept up to date by the caller. A change none of whose
+  /// paths are in the set doesn't cause a restart. Every path is relevant
+  /// when unset.
+  pub relevant: Option<Arc<Mutex<HashSet<PathBuf>>>>,
+  /// The kinds of change that cause a restart, e.g. to not restart when
+  /// temporary files are cleaned up. A batch none of whose changes is of
+  /// one of these kinds is skipped. Every kind triggers when unset.
+  pub trigger_on: Option<HashSet<ChangeKind>>,
+  /// Has the final say on whether a batch causes a restart; see
+  /// `RestartPredicate`.
+  pub should_restart: Option<RestartPredicate>,
+  /// Also write every restart as a JSON object, for tools wrapping the
+  /// watcher.
+  pub json_events: Option<EventWriter>,
+  pub backend: WatchBackend,
+  /// Builds the notify watcher, taking precedence over `backend`; see
+  /// `WatcherFactory`. Can also stand for a `WatchSource`.
+  pub watcher: Option<WatcherFactory>,
+  /// Settings handed to the native watcher.
+  pub watch_config: WatchConfig,
+  /// Called with the changed paths before the closure runs again; see
+  /// `RestartHook`.
+  pub on_before_restart: Option<RestartHook>,
+  /// A command, program first, started on every restart with the changed
+  /// paths appended as arguments, e.g. to show a desktop notification. It
+  /// runs in the background; failures are logged and don't stop watching.
+  pub on_restart_command: Option<Vec<String>>,
+  /// Called with the changes still being debounced when watching ends;
+  /// see `ShutdownFlush`.
+  pub on_shutdown_flush: Option<ShutdownFlush>,
+  /// A config file listing what to watch, watched in addition to the given
+  /// paths; see `ConfigFile`.
+  pub config: Option<ConfigFile>,
+  /// An entrypoint whose imports are watched in addition to the given
+  /// paths; see `ImportGraph`.
+  pub imports: Option<ImportGraph>,
+  /// A lockfile, e.g. `deno.lock`, watched in addition to the given paths.
+  /// Batches it is part of are tagged `BatchTag::LockfileChanged`, since
+  /// the dependencies may have changed even if no source file did.
+  pub watch_lockfile: Option<PathBuf>,
+  /// Unix signal that makes `watch_func` restart the closure as if a file
+  /// had changed, e.g. to pick up a changed environment. Defaults to
+  /// `SIGUSR1` when unset. Not supported on other platforms.
+  pub restart_signal: Option<i32>,
+  /// Number of events buffered between the OS watcher and the debouncer.
+  /// Defaults to 16. Events arriving while the buffer is full are dropped,
+  /// but their paths are still reported with the next change.
+  pub channel_capacity: Option<usize>,
+  /// Events per second a single path may produce. A path producing more,
+  /// e.g. a log file that is appended to continuously, is ignored for
+  /// `noisy_path_cooldown` so that it can't keep the debouncer from ever
+  /// reporting the other changes. Unlimited when unset.
+  pub noisy_path_limit: Option<u32>,
+  /// How long a noisy path is ignored. Defaults to `NOISY_PATH_COOLDOWN`.
+  pub noisy_path_cooldown: Option<Duration>,
+  /// Updated by `watch_func` on every restart; see `WatchMetrics`.
+  pub metrics: Option<Arc<Mutex<WatchMetrics>>>,
+  /// Receives the messages `watch_func` would otherwise print.
+  pub reporter: Reporter,
+  /// Don't report the startup summary and restarts, or clear the screen,
+  /// e.g. when the output is captured. Errors are still reported.
+  pub quiet: bool,
+  /// Holds back restarts while paused; see `PauseFlag`.
+  pub pause: Option<PauseFlag>,
+  /// Paths whose changes are ignored for a while; see `Suppressions`.
+  pub suppressions: Option<Suppressions>,
+  /// Updated with every reported batch; see `LastBatch`.
+  pub last_batch: Option<LastBatch>,
+  /// Kept up to date with what is being watched; see `Diagnostics`.
+  pub diagnostics: Option<Diagnostics>,
+  /// How many of the most recent events `diagnostics` keeps, with what
+  /// became of them, to tell after the fact why the closure restarted.
+  /// Defaults to `RECENT_EVENTS_CAPACITY`.
+  pub recent_events: Option<usize>,
+}
+
+/// Destination of the messages `watch_func` prints about restarts and
+/// failed runs, for embedders that want them elsewhere or not at all.
+/// Warnings about the watcher itself are still logged.
+pub trait WatchReporter: Send + Sync {
+  /// Watching has started and the closure is about to run for the first
+  /// time. Logs the summary unless overridden.
+  fn on_start(&self, summary: &WatchSummary) {
+    info!("{}", summary);
+  }
+  /// The closure is about to be restarted because `changed_paths` changed.
+  /// No paths means the restart was requested by signal.
+  fn on_restart(&self, changed_paths: &[PathBuf]);
+  /// Like `on_restart`, for changes that are all below the watched path
+  /// labeled `source` in `WatchOptions::root_labels`. Calls `on_restart`
+  /// unless overridden.
+  fn on_labeled_restart(&self, source: &str, changed_paths: &[PathBuf]) {
+    let _ = source;
+    self.on_restart(changed_paths);
+  }
+  /// Like `on_restart`, or `on_labeled_restart` if there is a `source`,
+  /// with how long the restart took to come about. Calls those unless
+  /// overridden.
+  fn on_timed_restart(
+    &self,
+    source: Option<&str>,
+    changed_paths: &[PathBuf],
+    timing: &RestartTiming,
+  ) {
+    let _ = timing;
+    match source {
+      Some(source) => self.on_labeled_restart(source, changed_paths),
+      None => self.on_restart(changed_paths),
+    }
+  }
+  /// The closure completed, and will run again on the next change.
+  fn on_process_terminated(&self);
+  /// A run of the closure failed, and watching goes on.
+  fn on_error(&self, err: &AnyError);
+  /// A run of the closure failed with the same error as the previous run,
+  /// when `collapse_repeated_errors` is set.
+  fn on_repeated_error(&self) {
+    eprintln!("{}", ConsoleReporter::repeated_error_line(use_color()));
+  }
+}
+
+/// How long it took to get to a restart, see `WatchReporter::on_timed_restart`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct RestartTiming {
+  /// How long the run being restarted lasted, up to now if it is still
+  /// going.
+  pub ran: Duration,
+  /// See `ChangeBatch::debounced`.
+  pub debounced: Option<Duration>,
+}
+
+impl fmt::Display for RestartTiming {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "ran {}", human_duration(self.ran))?;
+    if let Some(debounced) = self.debounced {
+      write!(f, ", debounced {}", human_duration(debounced))?;
+    }
+    Ok(())
+  }
+}
+
+/// Milliseconds below a second, seconds with one decimal from there.
+fn human_duration(duration: Duration) -> String {
+  if duration < Duration::from_secs(1) {
+    format!("{}ms", duration.as_millis())
+  } else {
+    format!("{:.1}s", duration.as_secs_f64())
+  }
+}
+
+/// What `watch_func` ended up watching.
+#[derive(Clone, Debug, PartialEq)]
+pub struct WatchSummary {
+  /// Paths watched, not counting paths another path covers.
+  pub watched: usize,
+  /// Paths that could not be watched.
+  pub skipped: usize,
+  pub debounce_time: Duration,
+  /// The backend in use, never `WatchBackend::Auto`.
+  pub backend: WatchBackend,
+}
+
+impl fmt::Display for WatchSummary {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(
+      f,
+      "{} Watching {} path{}",
+      paint(colors::intense_blue("Watcher")),
+      self.watched,
+      if self.watched == 1 { "" } else { "s" }
+    )?;
+    if self.skipped > 0 {
+      let skipped = format!("{} skipped", self.skipped);
+      write!(f, " ({})", paint(colors::yellow(&skipped)))?;
+    }
+    write!(f, ", debounce: {}ms, ", self.debounce_time.as_millis())?;
+    match &self.backend {
+      WatchBackend::Poll { interval } => {
+        write!(f, "polling every {}ms", interval.as_millis())
+      }
+      _ => write!(f, "native notifications"),
+    }
+  }
+}
+
+/// Reports to the terminal, the default.
+pub struct ConsoleReporter;
+
+impl ConsoleReporter {
+  fn restart_line(
+    changed_paths: &[PathBuf],
+    timing: Option<&RestartTiming>,
+    color: bool,
+  ) -> String {
+    let line = if changed_paths.is_empty() {
+      format!(
+        "{} Restart requested. {}",
+        colors::intense_blue("Watcher"),
+        Self::restarting(timing)
+      )
+    } else {
+      format!(
+        "{} File change detected: {}. {}",
+        colors::intense_blue("Watcher"),
+        summarize_paths(changed_paths, MAX_REPORTED_PATHS),
+        Self::restarting(timing)
+      )
+    };
+    paint_if(line, color)
+  }
+
+  fn labeled_restart_line(
+    source: &str,
+    changed_paths: &[PathBuf],
+    timing: Option<&RestartTiming>,
+    color: bool,
+  ) -> String {
+    let line = format!(
+      "{} Change in [{}]: {}. {}",
+      colors::intense_blue("Watcher"),
+      source,
+      summarize_paths(changed_paths, MAX_REPORTED_PATHS),
+      Self::restarting(timing)
+    );
+    paint_if(line, color)
+  }
+
+  fn restarting(timing: Option<&RestartTiming>) -> String {
+    match timing {
+      Some(timing) => format!("Restarting ({})!", timing),
+      None => "Restarting!".to_string(),
+    }
+  }
+
+  fn terminated_line(color: bool) -> String {
+    let line = format!(
+      "{} Process terminated! Restarting on file change...",
+      colors::intense_blue("Watcher")
+    );
+    paint_if(line, color)
+  }
+
+  /// The error followed by each of its causes, one per indented line, so
+  /// that e.g. a failed module load shows why it failed.
+  fn error_line(err: &AnyError, color: bool) -> String {
+    let mut line = format!("{}: {}", colors::red_bold("error"), err);
+    for cause in err.chain().skip(1) {
+      line.push_str(&format!("\n    {} {}", colors::gray("Caused by:"), cause));
+    }
+    paint_if(line, color)
+  }
+
+  fn repeated_error_line(color: bool) -> String {
+    paint_if(colors::gray("(same error as before)").to_string(), color)
+  }
+}
+
+impl WatchReporter for ConsoleReporter {
+  fn on_restart(&self, changed_paths: &[PathBuf]) {
+    info!("{}", Self::restart_line(changed_paths, None, use_color()));
+  }
+
+  fn on_labeled_restart(&self, source: &str, changed_paths: &[PathBuf]) {
+    let line =
+      Self::labeled_restart_line(source, changed_paths, None, use_color());
+    info!("{}", line);
+  }
+
+  fn on_timed_restart(
+    &self,
+    source: Option<&str>,
+    changed_paths: &[PathBuf],
+    timing: &RestartTiming,
+  ) {
+    let line = match source {
+      Some(source) => Self::labeled_restart_line(
+        source,
+        changed_paths,
+        Some(timing),
+        use_color(),
+      ),
+      None => Self::restart_line(changed_paths, Some(timing), use_color()),
+    };
+    info!("{}", line);
+  }
+
+  fn on_process_terminated(&self) {
+    info!("{}", Self::terminated_line(use_color()));
+  }
+
+  fn on_error(&self, err: &AnyError) {
+    eprintln!("{}", Self::error_line(err, use_color()));
+  }
+}
+
+/// Whether the watcher's messages are colored: only if colors are enabled,
+/// see `colors::use_color`, and stderr, where the messages go, is a TTY.
+fn use_color() -> bool {
+  colors::use_color() && atty::is(atty::Stream::Stderr)
+}
+
+/// Removes any escape sequences from `message` unless `color` is set. Every
+/// colored message of the watcher goes through here or `paint`.
+fn paint_if(message: String, color: bool) -> String {
+  if color {
+    message
+  } else {
+    colors::strip_ansi_codes(&message).into_owned()
+  }
+}
+
+/// A colored part of a message, left plain when `use_color` is off.
+fn paint<D: fmt::Display>(colored: D) -> String {
+  paint_if(colored.to_string(), use_color())
+}
+
+/// A shared `WatchReporter`. Defaults to `ConsoleReporter`.
+#[derive(Clone)]
+pub struct Reporter(Arc<dyn WatchReporter>);
+
+impl Reporter {
+  pub fn new<R: WatchReporter + 'static>(reporter: R) -> Self {
+    Self(Arc::new(reporter))
+  }
+}
+
+impl std::ops::Deref for Reporter {
+  type Target = dyn WatchReporter;
+
+  fn deref(&self) -> &Self::Target {
+    &*self.0
+  }
+}
+
+impl Default for Reporter {
+  fn default() -> Self {
+    Self::new(ConsoleReporter)
+  }
+}
+
+impl fmt::Debug for Reporter {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.write_str("Reporter")
+  }
+}
+
+/// Statistics about a `watch_func` session, readable while it runs.
+#[derive(Clone, Debug, Default)]
+pub struct WatchMetrics {
+  /// Number of times the closure was restarted.
+  pub restarts: u64,
+  pub last_restart: Option<Instant>,
+  /// Total time spent running the closure, over all runs that have ended.
+  /// A run ends when it completes or is dropped for a restart.
+  pub run_time: Duration,
+  /// Whether the last run that completed returned `Ok`. Runs dropped for a
+  /// restart don't count.
+  pub last_run_ok: Option<bool>,
+  /// Number of completed runs that returned `Ok`, and that failed.
+  pub successful_runs: u64,
+  pub failed_runs: u64,
+  /// Number of restarts each path changed in.
+  pub path_changes: HashMap<PathBuf, u64>,
+}
+
+/// Updates the caller's `WatchMetrics`, if any.
+struct MetricsRecorder {
+  metrics: Option<Arc<Mutex<WatchMetrics>>>,
+  run_started: Option<Instant>,
+  /// How long the last run that ended lasted.
+  last_run_time: Option<Duration>,
+}
+
+impl MetricsRecorder {
+  fn new(metrics: Option<Arc<Mutex<WatchMetrics>>>) -> Self {
+    Self {
+      metrics,
+      run_started: None,
+      last_run_time: None,
+    }
+  }
+
+  fn start_run(&mut self, now: Instant) {
+    self.run_started = Some(now);
+  }
+
+  /// Adds the current run to the run time, unless it already ended.
+  fn end_run(&mut self) {
+    if let Some(started) = self.run_started.take() {
+      let run_time = started.elapsed();
+      self.last_run_time = Some(run_time);
+      if let Some(metrics) = &self.metrics {
+        metrics.lock().unwrap().run_time += run_time;
+      }
+    }
+  }
+
+  /// How long the current run has lasted so far, or the last one if it
+  /// ended.
+  fn run_time(&self) -> Option<Duration> {
+    match self.run_started {
+      Some(started) => Some(started.elapsed()),
+      None => self.last_run_time,
+    }
+  }
+
+  fn record_restart(&mut self, changed_paths: &[PathBuf]) {
+    self.end_run();
+    if let Some(metrics) = &self.metrics {
+      let mut metrics = metrics.lock().unwrap();
+      metrics.restarts += 1;
+      metrics.last_restart = Some(Instant::now());
+      for path in changed_paths {
+        *metrics.path_changes.entry(path.clone()).or_insert(0) += 1;
+      }
+    }
+  }
+}
+
+/// Callback run on every restart. It is called after the "File change
+/// detected" message is logged and any restart storm backoff has passed,
+/// immediately before the closure is invoked again. A panic in the hook is
+/// caught and logged, and the restart goes ahead.
+#[derive(Clone)]
+pub struct RestartHook(Arc<dyn Fn(&[PathBuf]) + Send + Sync>);
+
+impl RestartHook {
+  pub fn new<F>(hook: F) -> Self
+  where
+    F: Fn(&[PathBuf]) + Send + Sync + 'static,
+  {
+    Self(Arc::new(hook))
+  }
+
+  fn call(&self, changed_paths: &[PathBuf]) {
+    let hook = &self.0;
+    let result = panic::catch_unwind(AssertUnwindSafe(|| hook(changed_paths)));
+    if let Err(payload) = result {
+      eprintln!(
+        "{}: restart hook panicked: {}",
+        paint(colors::red_bold("error")),
+        panic_message(&*payload)
+      );
+    }
+  }
+}
+
+impl fmt::Debug for RestartHook {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.write_str("RestartHook")
+  }
+}
+
+/// Callback run once a run of the closure that was still going has been
+/// dropped for a restart, returning a future that cleans up after it, e.g.
+/// waits for a child process to exit or a port to be released. See
+/// `WatchOptions::wait_for_exit`. A panic in the hook is caught and logged,
+/// and the restart goes ahead.
+#[derive(Clone)]
+pub struct AbortHook(Arc<dyn Fn() -> BoxFuture<'static, ()> + Send + Sync>);
+
+impl AbortHook {
+  pub fn new<F>(hook: F) -> Self
+  where
+    F: Fn() -> BoxFuture<'static, ()> + Send + Sync + 'static,
+  {
+    Self(Arc::new(hook))
+  }
+
+  fn call(&self) -> BoxFuture<'static, ()> {
+    let hook = &self.0;
+    match panic::catch_unwind(AssertUnwindSafe(|| hook())) {
+      Ok(cleanup) => cleanup,
+      Err(payload) => {
+        eprintln!(
+          "{}: abort hook panicked: {}",
+          paint(colors::red_bold("error")),
+          panic_message(&*payload)
+        );
+        future::ready(()).boxed()
+      }
+    }
+  }
+}
+
+impl fmt::Debug for AbortHook {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.write_str("AbortHook")
+  }
+}
+
+/// Callback run when watching ends, e.g. on shutdown, while the debounce
+/// window of some changes is still open, with the paths that would otherwise
+/// be lost. It isn't called if nothing is pending. A panic in the callback
+/// is caught and logged.
+#[derive(Clone)]
+pub struct ShutdownFlush(Arc<dyn Fn(Vec<PathBuf>) + Send + Sync>);
+
+impl ShutdownFlush {
+  pub fn new<F>(flush: F) -> Self
+  where
+    F: Fn(Vec<PathBuf>) + Send + Sync + 'static,
+  {
+    Self(Arc::new(flush))
+  }
+
+  fn call(&self, pending_paths: Vec<PathBuf>) {
+    let flush = &self.0;
+    let result = panic::catch_unwind(AssertUnwindSafe(|| flush(pending_paths)));
+    if let Err(payload) = result {
+      eprintln!(
+        "{}: shutdown flush panicked: {}",
+        paint(colors::red_bold("error")),
+        panic_message(&*payload)
+      );
+    }
+  }
+}
+
+impl fmt::Debug for ShutdownFlush {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.write_str("ShutdownFlush")
+  }
+}
+
+/// Decides whether a batch of changes that passed every other filter causes
+/// a restart, given its paths and kinds of change. Returning `false` skips
+/// the batch and watching goes on. A panic in the predicate is caught and
+/// logged, and the restart goes ahead.
+#[derive(Clone)]
+pub struct RestartPredicate(
+  Arc<dyn Fn(&[PathBuf], &BTreeSet<ChangeKind>) -> bool + Send + Sync>,
+);
+
+impl RestartPredicate {
+  pub fn new<F>(predicate: F) -> Self
+  where
+    F: Fn(&[PathBuf], &BTreeSet<ChangeKind>) -> bool + Send + Sync + 'static,
+  {
+    Self(Arc::new(predicate))
+  }
+
+  fn call(&self, batch: &ChangeBatch) -> bool {
+    let predicate = &self.0;
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+      predicate(&batch.paths, &batch.kinds)
+    }));
+    result.unwrap_or_else(|payload| {
+      eprintln!(
+        "{}: restart predicate panicked: {}",
+        paint(colors::red_bold("error")),
+        panic_message(&*payload)
       );
-      file_watcher(watch_paths).await?;
+      true
+    })
+  }
+}
+
+impl fmt::Debug for RestartPredicate {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.write_str("RestartPredicate")
+  }
+}
+
+/// A config file that determines the paths to watch. Whenever it changes,
+/// `resolve` is called with its path, and the paths it returns replace the
+/// watched paths without recreating the watcher. The config file itself
+/// stays watched. The paths given when watching starts are used until the
+/// first change.
+#[derive(Clone)]
+pub struct ConfigFile {
+  path: PathBuf,
+  resolve: Arc<dyn Fn(&Path) -> Vec<PathBuf> + Send + Sync>,
+}
+
+impl ConfigFile {
+  pub fn new<P, F>(path: P, resolve: F) -> Self
+  where
+    P: Into<PathBuf>,
+    F: Fn(&Path) -> Vec<PathBuf> + Send + Sync + 'static,
+  {
+    Self {
+      path: path.into(),
+      resolve: Arc::new(resolve),
+    }
+  }
+
+  /// The paths to watch according to the config file, including itself.
+  fn paths(&self) -> Vec<PathBuf> {
+    let mut paths = (self.resolve)(&self.path);
+    paths.push(self.path.clone());
+    paths
+  }
+}
+
+impl fmt::Debug for ConfigFile {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.debug_struct("ConfigFile")
+      .field("path", &self.path)
+      .finish()
+  }
+}
+
+/// An entrypoint and its transitive imports, as returned by `resolve`, so
+/// that only the files the program actually loads are watched. `resolve` is
+/// called again after every batch of changes, on tokio's blocking threads,
+/// and the imports it no longer returns stop being watched while new ones
+/// start to be. Paths that are also watched for their own sake stay so.
+#[derive(Clone)]
+pub struct ImportGraph {
+  entrypoint: PathBuf,
+  resolve: Arc<dyn Fn(&Path) -> Vec<PathBuf> + Send + Sync>,
+}
+
+impl ImportGraph {
+  pub fn new<P, F>(entrypoint: P, resolve: F) -> Self
+  where
+    P: Into<PathBuf>,
+    F: Fn(&Path) -> Vec<PathBuf> + Send + Sync + 'static,
+  {
+    Self {
+      entrypoint: entrypoint.into(),
+      resolve: Arc::new(resolve),
+    }
+  }
+
+  /// The entrypoint and its imports, sorted.
+  fn paths(&self) -> Vec<PathBuf> {
+    let mut paths = (self.resolve)(&self.entrypoint);
+    paths.push(self.entrypoint.clone());
+    paths.sort();
+    paths.dedup();
+    paths
+  }
+}
+
+impl fmt::Debug for ImportGraph {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.debug_struct("ImportGraph")
+      .field("entrypoint", &self.entrypoint)
+      .finish()
+  }
+}
+
+/// The modification times of the watched files, in milliseconds since the
+/// epoch, as saved to `WatchOptions::state_file`.
+#[derive(Debug, Default, Deserialize, PartialEq, Serialize)]
+struct WatchState {
+  mtimes: BTreeMap<PathBuf, u64>,
+}
+
+impl WatchState {
+  /// Every file below `paths`, leaving out the state file itself, if any.
+  /// Paths that aren't valid UTF-8 are left out too, since JSON can't hold
+  /// them.
+  fn scan(paths: &[PathBuf], state_file: Option<&Path>) -> Self {
+    let mut mtimes = BTreeMap::new();
+    for path in paths {
+      for entry in WalkDir::new(path).into_iter().filter_map(Result::ok) {
+        if !entry.file_type().is_file() || Some(entry.path()) == state_file {
+          continue;
+        }
+        if entry.path().to_str().is_none() {
+          trace!("Not saving the state of {}", entry.path().display());
+          continue;
+        }
+        if let Some(mtime) = entry.metadata().ok().and_then(mtime_millis) {
+          mtimes.insert(entry.into_path(), mtime);
+        }
+      }
+    }
+    Self { mtimes }
+  }
+
+  /// Returns `None` if there is no state file yet, or it can't be parsed.
+  fn load(state_file: &Path) -> Option<Self> {
+    let contents = std::fs::read_to_string(state_file).ok()?;
+    deno_core::serde_json::from_str(&contents).ok()
+  }
+
+  fn save(&self, state_file: &Path) {
+    let result = deno_core::serde_json::to_string(self)
+      .map_err(AnyError::from)
+      .and_then(|contents| Ok(std::fs::write(state_file, contents)?));
+    if let Err(err) = result {
       info!(
-        "{} File change detected! Restarting!",
-        colors::intense_blue("Watcher")
+        "{} Failed to save the watch state to {}: {}",
+        paint(colors::yellow("Warning")),
+        state_file.display(),
+        err
       );
     }
   }
+
+  /// The files that were added, modified or removed since `previous`.
+  fn changed_since(&self, previous: &Self) -> Vec<PathBuf> {
+    let mut changed: Vec<PathBuf> = self
+      .mtimes
+      .iter()
+      .filter(|(path, mtime)| previous.mtimes.get(*path) != Some(*mtime))
+      .map(|(path, _)| path.clone())
+      .collect();
+    changed.extend(
+      previous
+        .mtimes
+        .keys()
+        .filter(|path| !self.mtimes.contains_key(*path))
+        .cloned(),
+    );
+    changed.sort();
+    changed
+  }
 }
 
-pub async fn file_watcher(paths: &[PathBuf]) -> Result<(), AnyError> {
-  let (sender, mut receiver) =
-    mpsc::channel::<Result<NotifyEvent, AnyError>>(16);
-  let sender = std::sync::Mutex::new(sender);
+/// The modification time in milliseconds since the epoch.
+fn mtime_millis(metadata: std::fs::Metadata) -> Option<u64> {
+  let modified = metadata.modified().ok()?;
+  let mtime = modified.duration_since(UNIX_EPOCH).ok()?;
+  Some(mtime.as_millis() as u64)
+}
 
-  let mut watcher: RecommendedWatcher =
-    Watcher::new_immediate(move |res: Result<NotifyEvent, NotifyError>| {
-      let res2 = res.map_err(AnyError::from);
-      let mut sender = sender.lock().unwrap();
-      // Ignore result, if send failed it means that watcher was already closed,
-      // but not all messages have been flushed.
-      let _ = sender.try_send(res2);
-    })?;
+/// The files the last run of the closure wrote, for
+/// `WatchOptions::detect_self_writes`: those whose modification time changed
+/// between its start and its end.
+struct SelfWrites {
+  /// The watched paths, with how many levels below each files are seen.
+  paths: Vec<(PathBuf, usize)>,
+  /// Built from the same options as the watcher's, so that the files it
+  /// ignores aren't scanned.
+  filter: EventFilter,
+  /// `WatchOptions::output_paths`, made absolute.
+  output_paths: Vec<PathBuf>,
+  cwd: PathBuf,
+  /// The files as of when the current run started.
+  before: Option<WatchState>,
+  /// The modification times the last run left the files it wrote with.
+  written: BTreeMap<PathBuf, u64>,
+}
 
-  watcher.configure(Config::PreciseEvents(true)).unwrap();
+impl SelfWrites {
+  fn new(paths: &[PathBuf], options: &WatchOptions) -> Result<Self, AnyError> {
+    let cwd = std::env::current_dir()?;
+    let registrations: Vec<Registration> = paths
+      .iter()
+      .map(|path| Registration::new(path, options))
+      .collect();
+    Ok(Self {
+      paths: paths
+        .iter()
+        .map(|path| {
+          let depth = match options.requested_recursive_mode(path) {
+            RecursiveMode::Recursive => options
+              .max_depth
+              .map_or(std::usize::MAX, |levels| levels.saturating_add(1)),
+            RecursiveMode::NonRecursive => 1,
+          };
+          (path.clone(), depth)
+        })
+        .collect(),
+      filter: EventFilter::new(&registrations, options)?,
+      output_paths: options
+        .output_paths
+        .iter()
+        .map(|path| cwd.join(path))
+        .collect(),
+      cwd,
+      before: None,
+      written: BTreeMap::new(),
+    })
+  }
 
-  for path in paths {
-    watcher.watch(path, RecursiveMode::NonRecursive)?;
+  /// Whether the path is below one of `output_paths`, or leads to one if
+  /// `is_dir`. Every path is if there are none.
+  fn is_output(&self, path: &Path, is_dir: bool) -> bool {
+    let path = self.cwd.join(path);
+    self.output_paths.is_empty()
+      || self.output_paths.iter().any(|output| {
+        path.starts_with(output) || (is_dir && output.starts_with(&path))
+      })
   }
 
-  while let Some(result) = receiver.next().await {
-    let event = result?;
-    match event.kind {
-      EventKind::Create(_) => break,
-      EventKind::Modify(_) => break,
-      EventKind::Remove(_) => break,
-      _ => continue,
+  /// The files the watcher reports changes of that a run may have written,
+  /// down to the depth they are watched to.
+  fn scan(&self) -> WatchState {
+    let mut mtimes = BTreeMap::new();
+    for (path, depth) in &self.paths {
+      let entries = WalkDir::new(path)
+        .max_depth(*depth)
+        .into_iter()
+        .filter_entry(|entry| {
+          entry.depth() == 0
+            || !entry.file_type().is_dir()
+            || (self.is_output(entry.path(), true)
+              && !self.filter.prunes(entry.path()))
+        })
+        .filter_map(Result::ok)
+        .filter(|entry| {
+          entry.file_type().is_file()
+            && self.is_output(entry.path(), false)
+            && self.filter.rejection(entry.path()).is_none()
+        });
+      for entry in entries {
+        if let Some(mtime) = entry.metadata().ok().and_then(mtime_millis) {
+          mtimes.insert(entry.into_path(), mtime);
+        }
+      }
     }
+    WatchState { mtimes }
+  }
+
+  fn run_started(&mut self) {
+    self.before = Some(self.scan());
+  }
+
+  fn run_ended(&mut self) {
+    let before = match self.before.take() {
+      Some(before) => before,
+      None => return,
+    };
+    let after = self.scan();
+    let written = after.changed_since(&before);
+    self.written = after
+      .mtimes
+      .into_iter()
+      .filter(|(path, _)| written.contains(path))
+      .collect();
+    if !self.written.is_empty() {
+      debug!("The run wrote: {}", format_paths(&written));
+    }
+  }
+
+  /// Whether all of `paths` are files the last run wrote and that haven't
+  /// changed since.
+  fn wrote_only(&self, paths: &[PathBuf]) -> bool {
+    !paths.is_empty()
+      && paths.iter().all(|path| match self.written.get(path) {
+        Some(mtime) => {
+          std::fs::metadata(path).ok().and_then(mtime_millis) == Some(*mtime)
+        }
+        None => false,
+      })
+  }
+}
+
+/// Destination of the JSON events written on each restart, one object per
+/// line, e.g. `{"event":"restart","kind":"modified","paths":["src/a.ts"]}`.
+/// Defaults to stderr.
+#[derive(Clone)]
+pub struct EventWriter(Arc<Mutex<dyn Write + Send>>);
+
+impl EventWriter {
+  pub fn new<W: Write + Send + 'static>(writer: W) -> Self {
+    Self(Arc::new(Mutex::new(writer)))
+  }
+
+  fn write_event(&self, event: &Value) {
+    let mut writer = self.0.lock().unwrap();
+    // Tooling output must never take the watcher down.
+    let _ = writeln!(writer, "{}", event);
+    let _ = writer.flush();
+  }
+}
+
+impl Default for EventWriter {
+  fn default() -> Self {
+    Self::new(io::stderr())
+  }
+}
+
+impl fmt::Debug for EventWriter {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.write_str("EventWriter")
+  }
+}
+
+/// Hands the watcher's events to the debouncer.
+pub type EventCallback = Box<dyn Fn(Result<NotifyEvent, NotifyError>) + Send>;
+
+/// Builds a notify watcher that delivers its events to the given callback,
+/// for watchers configured in ways `WatchBackend` doesn't cover, or fakes in
+/// tests. Called again if the watcher has to be rebuilt, which happens once
+/// the callback is dropped, as notify does when its backend goes away.
+///
+/// ```ignore
+/// WatcherFactory::new(|events| {
+///   let mut watcher: RecommendedWatcher = Watcher::new_immediate(events)?;
+///   watcher.configure(Config::OngoingEvents(None))?;
+///   Ok(watcher)
+/// })
+/// ```
+#[derive(Clone)]
+pub struct WatcherFactory(
+  Arc<
+    dyn Fn(EventCallback) -> Result<Box<dyn DynWatcher>, NotifyError>
+      + Send
+      + Sync,
+  >,
+);
+
+impl WatcherFactory {
+  pub fn new<W, F>(build: F) -> Self
+  where
+    W: Watcher + Send + 'static,
+    F: Fn(EventCallback) -> Result<W, NotifyError> + Send + Sync + 'static,
+  {
+    Self(Arc::new(move |events| {
+      let watcher: Box<dyn DynWatcher> = Box::new(build(events)?);
+      Ok(watcher)
+    }))
+  }
+}
+
+impl fmt::Debug for WatcherFactory {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.write_str("WatcherFactory")
+  }
+}
+
+/// Where the events come from when they don't come from a notify watcher,
+/// e.g. a remote file system or an in-memory one in tests. Its events go
+/// through the same filtering, debouncing and restarts as those of the OS
+/// watcher; see `WatcherFactory::from_source`.
+///
+/// A source is adapted into a `WatcherFactory` rather than the notify
+/// watcher being made one more source: the watcher is told what to watch
+/// path by path, rebuilt when it stops, and swapped for a polling one at the
+/// OS watch limit, none of which a stream of events can express. Adapting
+/// the source leaves all of that, and the default path, as it is.
+pub trait WatchSource: Send + Sync {
+  /// The events of every change the source sees. Like an OS watcher, the
+  /// watcher only passes on those concerning the paths it watches. Called
+  /// again for a fresh stream whenever the watcher is rebuilt, including
+  /// when the stream ends.
+  fn events(&self) -> BoxStream<'static, Result<NotifyEvent, NotifyError>>;
+}
+
+impl WatcherFactory {
+  /// Takes the events from `source` instead of a notify watcher. They are
+  /// forwarded by a task on the current runtime for as long as the watcher
+  /// lives, so the `FileWatcher` has to be created within a tokio runtime,
+  /// as `watch_func` always is; building it elsewhere panics.
+  pub fn from_source<S: WatchSource + 'static>(source: S) -> Self {
+    let source = Arc::new(source);
+    Self(Arc::new(move |events| {
+      let watched: Arc<Mutex<Vec<(PathBuf, RecursiveMode)>>> =
+        Default::default();
+      let forward_watched = watched.clone();
+      let forward = source.events().for_each(move |result| {
+        let is_watched = match &result {
+          Ok(event) if !event.paths.is_empty() => {
+            let watched = forward_watched.lock().unwrap();
+            event.paths.iter().any(|path| is_watched_by(&watched, path))
+          }
+          _ => true,
+        };
+        if is_watched {
+          events(result);
+        }
+        future::ready(())
+      });
+      let (forward, abort) = future::abortable(forward);
+      tokio::spawn(forward);
+      let watcher: Box<dyn DynWatcher> =
+        Box::new(SourceWatcher { watched, abort });
+      Ok(watcher)
+    }))
+  }
+}
+
+/// The watcher of a `WatchSource`, which only keeps track of what is
+/// watched. Dropping it stops forwarding the source's events.
+struct SourceWatcher {
+  watched: Arc<Mutex<Vec<(PathBuf, RecursiveMode)>>>,
+  abort: future::AbortHandle,
+}
+
+impl DynWatcher for SourceWatcher {
+  fn watch_path(
+    &mut self,
+    path: &Path,
+    recursive_mode: RecursiveMode,
+  ) -> Result<(), NotifyError> {
+    let mut watched = self.watched.lock().unwrap();
+    watched.retain(|(watched, _)| watched.as_path() != path);
+    watched.push((path.to_path_buf(), recursive_mode));
+    Ok(())
+  }
+
+  fn unwatch_path(&mut self, path: &Path) -> Result<(), NotifyError> {
+    let mut watched = self.watched.lock().unwrap();
+    watched.retain(|(watched, _)| watched.as_path() != path);
+    Ok(())
+  }
+}
+
+impl Drop for SourceWatcher {
+  fn drop(&mut self) {
+    self.abort.abort();
+  }
+}
+
+/// Whether a watch of one of the paths would see a change to `path`, as
+/// the OS watchers decide.
+fn is_watched_by(watched: &[(PathBuf, RecursiveMode)], path: &Path) -> bool {
+  watched.iter().any(|(watched, mode)| match mode {
+    RecursiveMode::Recursive => path.starts_with(watched),
+    RecursiveMode::NonRecursive => {
+      path == watched.as_path() || path.parent() == Some(watched.as_path())
+    }
+  })
+}
+
+/// The part of `notify::Watcher` that is used once the watcher is built,
+/// which unlike the whole trait can be boxed.
+trait DynWatcher: Send {
+  fn watch_path(
+    &mut self,
+    path: &Path,
+    recursive_mode: RecursiveMode,
+  ) -> Result<(), NotifyError>;
+
+  fn unwatch_path(&mut self, path: &Path) -> Result<(), NotifyError>;
+}
+
+impl<W: Watcher + Send> DynWatcher for W {
+  fn watch_path(
+    &mut self,
+    path: &Path,
+    recursive_mode: RecursiveMode,
+  ) -> Result<(), NotifyError> {
+    self.watch(path, recursive_mode)
+  }
+
+  fn unwatch_path(&mut self, path: &Path) -> Result<(), NotifyError> {
+    self.unwatch(path)
+  }
+}
+
+/// Settings of the native notify watcher.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct WatchConfig {
+  /// Ask the backend for events describing exactly what changed, rather
+  /// than a generic event per path. Defaults to `true`.
+  pub precise_events: bool,
+  /// Only report changes that alter a file's contents. This version of
+  /// notify can't compare contents itself, so the watcher does by hashing
+  /// changed files, like `WatchOptions::skip_unchanged`.
+  pub compare_contents: bool,
+}
+
+impl Default for WatchConfig {
+  fn default() -> Self {
+    Self {
+      precise_events: true,
+      compare_contents: false,
+    }
+  }
+}
+
+/// The declarative part of `WatchOptions`, e.g. the watch section of a
+/// config file, with camelCase keys and durations in milliseconds. Unset
+/// fields leave the options as they are, and globs and extensions are added
+/// to the ones already set; see `WatcherBuilder::settings`.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase", default, deny_unknown_fields)]
+pub struct WatchSettings {
+  pub debounce_ms: Option<u64>,
+  /// Whether directories are watched recursively, for `recursive_mode`.
+  pub recursive: Option<bool>,
+  pub exclude: Vec<String>,
+  pub include: Vec<String>,
+  pub extensions: Vec<String>,
+  pub backend: Option<BackendSetting>,
+  /// For the poll backend, `DEFAULT_POLL_INTERVAL` when unset.
+  pub poll_interval_ms: Option<u64>,
+  pub clear_screen: Option<bool>,
+  pub throttle_ms: Option<u64>,
+  pub restart_delay_ms: Option<u64>,
+  pub idle_timeout_ms: Option<u64>,
+  pub run_on_start: Option<bool>,
+  pub max_restarts: Option<usize>,
+  pub exit_on_error: Option<bool>,
+  pub watch_hidden: Option<bool>,
+  pub respect_denoignore: Option<bool>,
+  pub quiet: Option<bool>,
+}
+
+/// `WatchBackend` as named in `WatchSettings`.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum BackendSetting {
+  Native,
+  Poll,
+  Auto,
+}
+
+impl WatchSettings {
+  /// Reads settings from a JSON file.
+  pub fn load(path: &Path) -> Result<Self, AnyError> {
+    let contents = std::fs::read_to_string(path)?;
+    Ok(deno_core::serde_json::from_str(&contents)?)
+  }
+
+  fn apply(&self, options: &mut WatchOptions) {
+    let millis = |ms: Option<u64>| ms.map(Duration::from_millis);
+    if let Some(debounce_time) = millis(self.debounce_ms) {
+      options.debounce_time = Some(debounce_time);
+    }
+    if let Some(recursive) = self.recursive {
+      options.recursive_mode = Some(if recursive {
+        RecursiveMode::Recursive
+      } else {
+        RecursiveMode::NonRecursive
+      });
+    }
+    options.exclude.extend(self.exclude.iter().cloned());
+    options.include.extend(self.include.iter().cloned());
+    options.extensions.extend(self.extensions.iter().cloned());
+    if let Some(backend) = self.backend {
+      options.backend = match backend {
+        BackendSetting::Native => WatchBackend::Native,
+        BackendSetting::Poll => WatchBackend::Poll {
+          interval: millis(self.poll_interval_ms)
+            .unwrap_or(DEFAULT_POLL_INTERVAL),
+        },
+        BackendSetting::Auto => WatchBackend::Auto,
+      };
+    }
+    if let Some(clear_screen) = self.clear_screen {
+      options.clear_screen = Some(clear_screen);
+    }
+    if let Some(throttle) = millis(self.throttle_ms) {
+      options.throttle = Some(throttle);
+    }
+    if let Some(restart_delay) = millis(self.restart_delay_ms) {
+      options.restart_delay = Some(restart_delay);
+    }
+    if let Some(idle_timeout) = millis(self.idle_timeout_ms) {
+      options.idle_timeout = Some(idle_timeout);
+    }
+    if let Some(run_on_start) = self.run_on_start {
+      options.run_on_start = Some(run_on_start);
+    }
+    if let Some(max_restarts) = self.max_restarts {
+      options.max_restarts = Some(max_restarts);
+    }
+    if let Some(exit_on_error) = self.exit_on_error {
+      options.exit_on_error = exit_on_error;
+    }
+    if let Some(watch_hidden) = self.watch_hidden {
+      options.watch_hidden = watch_hidden;
+    }
+    if let Some(respect_denoignore) = self.respect_denoignore {
+      options.respect_denoignore = Some(respect_denoignore);
+    }
+    if let Some(quiet) = self.quiet {
+      options.quiet = quiet;
+    }
+  }
+}
+
+/// How the changes in different watched roots are debounced.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DebounceScope {
+  /// One window for every root: changes in several roots at about the same
+  /// time are reported together, once all of them have settled.
+  Global,
+  /// A window per root: the changes in each root are reported once that
+  /// root has settled, however busy the others are. `debounce_strategy`,
+  /// `debounce_anchor`, `max_debounce` and `confirm_quiet` only apply to
+  /// `Global`.
+  PerRoot,
+}
+
+impl Default for DebounceScope {
+  fn default() -> Self {
+    DebounceScope::Global
+  }
+}
+
+/// Which notify watcher delivers file system events.
+#[derive(Clone, Debug, PartialEq)]
+pub enum WatchBackend {
+  /// The platform's native notification API (inotify, FSEvents, ...).
+  Native,
+  /// Periodically scan the watched paths. Works on network and virtual file
+  /// systems that never emit native notifications.
+  Poll { interval: Duration },
+  /// Use the native API, falling back to polling if it is unavailable.
+  Auto,
+}
+
+impl Default for WatchBackend {
+  fn default() -> Self {
+    WatchBackend::Auto
+  }
+}
+
+/// More than `max_restarts` restarts within `window` is considered a restart
+/// storm. While a storm lasts, each restart is delayed by a backoff that
+/// starts at `initial_backoff` and doubles up to `max_backoff`.
+#[derive(Clone, Debug)]
+pub struct RestartStorm {
+  pub max_restarts: usize,
+  pub window: Duration,
+  pub initial_backoff: Duration,
+  pub max_backoff: Duration,
+}
+
+impl Default for RestartStorm {
+  fn default() -> Self {
+    Self {
+      max_restarts: 5,
+      window: Duration::from_secs(3),
+      initial_backoff: Duration::from_millis(250),
+      max_backoff: Duration::from_secs(8),
+    }
+  }
+}
+
+/// Tracks recent restarts to detect restart storms.
+struct RestartTracker {
+  storm: RestartStorm,
+  restarts: VecDeque<Instant>,
+  backoff: Option<Duration>,
+}
+
+impl RestartTracker {
+  fn new(storm: RestartStorm) -> Self {
+    Self {
+      storm,
+      restarts: VecDeque::new(),
+      backoff: None,
+    }
+  }
+
+  /// Records a restart, returning how long to wait before it if the restart
+  /// is part of a storm. Warns once when a storm begins.
+  fn record(&mut self, now: Instant) -> Option<Duration> {
+    self.restarts.push_back(now);
+    while let Some(first) = self.restarts.front() {
+      if now.duration_since(*first) > self.storm.window {
+        self.restarts.pop_front();
+      } else {
+        break;
+      }
+    }
+
+    if self.restarts.len() <= self.storm.max_restarts {
+      self.backoff = None;
+      return None;
+    }
+
+    let backoff = match self.backoff {
+      Some(backoff) => std::cmp::min(backoff * 2, self.storm.max_backoff),
+      None => {
+        info!(
+          "{} More than {} restarts in {:?}; a watched file is probably \
+          being written by the program itself. Slowing down restarts.",
+          paint(colors::yellow("Warning")),
+          self.storm.max_restarts,
+          self.storm.window
+        );
+        self.storm.initial_backoff
+      }
+    };
+    self.backoff = Some(backoff);
+    Some(backoff)
+  }
+}
+
+impl WatchOptions {
+  /// Whether the contents of changed files are compared with the last
+  /// version seen.
+  fn compares_contents(&self) -> bool {
+    self.skip_unchanged || self.watch_config.compare_contents
+  }
+
+  /// The mode handed to the OS watcher for `path` itself.
+  fn recursive_mode(&self, path: &Path) -> RecursiveMode {
+    if self.depth_limit(path).is_some() {
+      RecursiveMode::NonRecursive
+    } else {
+      self.requested_recursive_mode(path)
+    }
+  }
+
+  fn requested_recursive_mode(&self, path: &Path) -> RecursiveMode {
+    if let Some(recursive_mode) = self.root_modes.get(path) {
+      return *recursive_mode;
+    }
+    self
+      .recursive_mode
+      .unwrap_or_else(|| match std::fs::metadata(path) {
+        Ok(metadata) if metadata.is_dir() => RecursiveMode::Recursive,
+        _ => RecursiveMode::NonRecursive,
+      })
+  }
+
+  /// Levels of subdirectories below `path` to watch one by one, if it
+  /// would be watched recursively but `max_depth` is set.
+  fn depth_limit(&self, path: &Path) -> Option<usize> {
+    match self.requested_recursive_mode(path) {
+      RecursiveMode::Recursive => self.max_depth,
+      RecursiveMode::NonRecursive => None,
+    }
+  }
+
+  fn run_on_start(&self) -> bool {
+    self.run_on_start.unwrap_or(true)
+  }
+
+  fn respect_denoignore(&self) -> bool {
+    self.respect_denoignore.unwrap_or(true)
+  }
+
+  fn clear_screen(&self) -> bool {
+    self.clear_screen.unwrap_or_else(use_color)
+  }
+
+  fn startup_grace(&self) -> Duration {
+    self
+      .startup_grace
+      .unwrap_or_else(|| Duration::from_millis(STARTUP_GRACE_MS))
+  }
+
+  fn debounce_time(&self) -> Duration {
+    self
+      .debounce_time
+      .or_else(|| {
+        std::env::var(DEBOUNCE_ENV_VAR)
+          .ok()
+          .and_then(|value| parse_debounce_ms(&value))
+      })
+      .unwrap_or_else(|| Duration::from_millis(DEBOUNCE_TIME_MS))
+  }
+}
+
+fn parse_debounce_ms(value: &str) -> Option<Duration> {
+  match value.trim().parse::<u64>() {
+    Ok(ms) => Some(Duration::from_millis(ms)),
+    Err(_) => {
+      info!(
+        "{} Ignoring invalid {} value: {:?}",
+        paint(colors::yellow("Warning")),
+        DEBOUNCE_ENV_VAR,
+        value
+      );
+      None
+    }
+  }
+}
+
+/// The `exclude` list of a tsconfig or deno config file.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ConfigExclude {
+  /// The directory of the config file, which the patterns are relative to.
+  pub base: PathBuf,
+  pub patterns: Vec<String>,
+}
+
+impl ConfigExclude {
+  /// Reads the `exclude` array of a parsed config, e.g. from
+  /// `tsc_config::parse_raw_config`. Entries that aren't strings are
+  /// skipped.
+  pub fn from_config(base: &Path, config: &Value) -> Self {
+    let patterns = config
+      .get("exclude")
+      .and_then(Value::as_array)
+      .map(|exclude| {
+        exclude
+          .iter()
+          .filter_map(Value::as_str)
+          .map(String::from)
+          .collect()
+      })
+      .unwrap_or_default();
+    Self {
+      base: base.to_path_buf(),
+      patterns,
+    }
+  }
+}
+
+/// Turns a config's `exclude` list into globs for `WatchOptions::exclude`,
+/// so that the watcher ignores what type checking does.
+///
+/// Entries are resolved against the config's directory, as the compiler
+/// does: `node_modules` excludes only the `node_modules` next to the config,
+/// not every directory of that name like a bare exclude glob would.
+pub fn exclude_globs_from_config(exclude: &ConfigExclude) -> Vec<String> {
+  exclude
+    .patterns
+    .iter()
+    .map(|pattern| {
+      let pattern = pattern.trim_start_matches("./");
+      let path = Path::new(pattern);
+      if path.is_absolute() {
+        pattern.to_string()
+      } else {
+        exclude.base.join(path).to_string_lossy().into_owned()
+      }
+    })
+    .collect()
+}
+
+/// Turns glob patterns given in place of paths, e.g. `src/**/*.ts`, into
+/// the directories to watch and the globs events have to match, for
+/// `WatchOptions::include`. Each pattern is watched from the longest leading
+/// part of it without wildcards, so that files created later are seen too.
+/// A pattern without wildcards is watched as is. Relative patterns are
+/// resolved against the working directory, as the changed paths they are
+/// matched against are absolute.
+pub fn expand_watch_globs(
+  patterns: &[String],
+) -> Result<(Vec<PathBuf>, GlobSet), AnyError> {
+  expand_watch_globs_in(patterns, &std::env::current_dir()?)
+}
+
+fn expand_watch_globs_in(
+  patterns: &[String],
+  cwd: &Path,
+) -> Result<(Vec<PathBuf>, GlobSet), AnyError> {
+  let patterns: Vec<String> = patterns
+    .iter()
+    .map(|pattern| match pattern.strip_prefix('!') {
+      Some(negated) => format!("!{}", absolute_pattern(negated, cwd)),
+      None => absolute_pattern(pattern, cwd),
+    })
+    .collect();
+  let mut roots: Vec<PathBuf> = patterns
+    .iter()
+    .map(|pattern| {
+      Path::new(pattern.trim_start_matches('!'))
+        .components()
+        .take_while(|component| {
+          let component = component.as_os_str().to_string_lossy();
+          !component.contains(|c| matches!(c, '*' | '?' | '[' | '{'))
+        })
+        .collect()
+    })
+    .collect();
+  roots.sort();
+  roots.dedup();
+  Ok((roots, GlobSet::new(&patterns)?))
+}
+
+/// The pattern rooted at `cwd` unless it is absolute already.
+fn absolute_pattern(pattern: &str, cwd: &Path) -> String {
+  let pattern = pattern.trim_start_matches("./");
+  let path = Path::new(pattern);
+  if path.is_absolute() {
+    pattern.to_string()
+  } else {
+    cwd.join(path).to_string_lossy().into_owned()
+  }
+}
+
+/// Reads the paths to watch from a list file, as given with `@list.txt` for
+/// watch sets too large for the command line. Each line holds a path or a
+/// glob pattern, see `expand_watch_globs`; blank lines and `#` comments are
+/// skipped. Relative entries are resolved against the list's directory, and
+/// globs are expanded to the files matching them. An entry that can't be
+/// used, e.g. an invalid glob, is warned about and skipped.
+pub fn read_watch_list(path: &Path) -> Result<Vec<PathBuf>, AnyError> {
+  let contents = std::fs::read_to_string(path)?;
+  let dir = std::env::current_dir()?
+    .join(path.parent().unwrap_or_else(|| Path::new("")));
+  let mut paths = Vec::new();
+  for (index, line) in contents.lines().enumerate() {
+    let entry = line.trim();
+    if entry.is_empty() || entry.starts_with('#') {
+      continue;
+    }
+    match watch_list_entry(entry, &dir) {
+      Ok(entry_paths) => paths.extend(entry_paths),
+      Err(err) => info!(
+        "{} Skipping line {} of {}: {}",
+        paint(colors::yellow("Warning")),
+        index + 1,
+        path.display(),
+        err
+      ),
+    }
+  }
+  paths.sort();
+  paths.dedup();
+  Ok(paths)
+}
+
+/// The paths to watch for arguments like `--watch-path`, where `@list.txt`
+/// stands for the paths of a watch list, see `read_watch_list`, and any
+/// other argument for itself.
+pub fn expand_watch_args(args: &[String]) -> Result<Vec<PathBuf>, AnyError> {
+  let mut paths = Vec::new();
+  for arg in args {
+    match arg.strip_prefix('@') {
+      Some(list) => paths.extend(read_watch_list(Path::new(list))?),
+      None => paths.push(PathBuf::from(arg)),
+    }
+  }
+  Ok(paths)
+}
+
+/// The paths one line of a watch list stands for.
+fn watch_list_entry(entry: &str, dir: &Path) -> Result<Vec<PathBuf>, AnyError> {
+  if entry.contains('\0') {
+    return Err(generic_error("paths can't contain NUL bytes"));
+  }
+  let path = dir.join(entry);
+  if !entry.contains(|c| matches!(c, '*' | '?' | '[' | '{')) {
+    return Ok(vec![path]);
+  }
+  let (roots, globs) =
+    expand_watch_globs(&[path.to_string_lossy().into_owned()])?;
+  Ok(
+    roots
+      .iter()
+      .flat_map(|root| WalkDir::new(root).into_iter().filter_map(Result::ok))
+      .filter(|entry| {
+        entry.file_type().is_file() && globs.is_match(entry.path())
+      })
+      .map(|entry| entry.into_path())
+      .collect(),
+  )
+}
+
+/// The rules deciding which paths below the watched roots count, all in one
+/// place. A path counts if it matches `include`, or `include` is empty, and
+/// has one of the `extensions`, if any are given, unless it is excluded. A
+/// path is excluded if it matches `exclude`, one of the `exclude_regex`
+/// expressions or the `.denoignore` file of its root, or if it is hidden
+/// while `watch_hidden` is off. Exclusion always wins over inclusion. Within
+/// `include` and `exclude`, a later `!` pattern overrides an earlier one, see
+/// `GlobSet`.
+///
+/// Paths are checked against the watched roots they are below, given as
+/// pairs of a requested path and the directory its relative globs are
+/// matched against.
+pub struct PathFilter {
+  include: GlobSet,
+  exclude: GlobSet,
+  exclude_regex: Vec<Regex>,
+  /// Lowercase and without the leading dot.
+  extensions: Vec<String>,
+  watch_hidden: bool,
+}
+
+impl PathFilter {
+  pub fn new(options: &WatchOptions) -> Result<Self, AnyError> {
+    Ok(Self {
+      include: GlobSet::new(&options.include)?,
+      exclude: GlobSet::new(&options.exclude)?,
+      exclude_regex: options.exclude_regex.clone(),
+      extensions: options
+        .extensions
+        .iter()
+        .map(|extension| extension.trim_start_matches('.').to_lowercase())
+        .collect(),
+      watch_hidden: options.watch_hidden,
+    })
+  }
+
+  /// Why the path doesn't count, or `None` if it does. `denoignore` holds
+  /// the patterns of the `.denoignore` file of each root directory that has
+  /// one.
+  pub fn rejection(
+    &self,
+    path: &Path,
+    roots: &[(PathBuf, PathBuf)],
+    denoignore: &[(PathBuf, GlobSet)],
+  ) -> Option<&'static str> {
+    if !self.watch_hidden && is_hidden(path, roots) {
+      Some("hidden")
+    } else if self.is_excluded(path, roots, denoignore)
+      || !self.is_included(path, roots)
+    {
+      Some("ignored by glob rules")
+    } else {
+      None
+    }
+  }
+
+  pub fn is_match(
+    &self,
+    path: &Path,
+    roots: &[(PathBuf, PathBuf)],
+    denoignore: &[(PathBuf, GlobSet)],
+  ) -> bool {
+    self.rejection(path, roots, denoignore).is_none()
+  }
+
+  /// Whether nothing below the directory can match: it is hidden, or glob
+  /// rules exclude it with no `!` pattern that could bring back something
+  /// below it. Exclusion by regular expression is left to the files, since
+  /// an expression matching a directory needn't match what it contains.
+  fn prunes(
+    &self,
+    dir: &Path,
+    roots: &[(PathBuf, PathBuf)],
+    denoignore: &[(PathBuf, GlobSet)],
+  ) -> bool {
+    if !self.watch_hidden && is_hidden(dir, roots) {
+      return true;
+    }
+    let negates =
+      |globs: &GlobSet| globs.patterns().iter().any(|p| p.starts_with('!'));
+    if negates(&self.exclude)
+      || denoignore.iter().any(|(_, globs)| negates(globs))
+    {
+      return false;
+    }
+    matches_globs(&self.exclude, dir, roots)
+      || denoignore.iter().any(|(root, globs)| {
+        dir
+          .strip_prefix(root)
+          .map_or(false, |relative| globs.is_match(relative))
+      })
+  }
+
+  fn is_included(&self, path: &Path, roots: &[(PathBuf, PathBuf)]) -> bool {
+    (self.include.is_empty() || matches_globs(&self.include, path, roots))
+      && (self.extensions.is_empty() || self.has_extension(path))
+  }
+
+  fn is_excluded(
+    &self,
+    path: &Path,
+    roots: &[(PathBuf, PathBuf)],
+    denoignore: &[(PathBuf, GlobSet)],
+  ) -> bool {
+    if !self.exclude.is_empty() && matches_globs(&self.exclude, path, roots) {
+      return true;
+    }
+    // A `.denoignore` file's patterns are relative to its directory.
+    let is_denoignored = |(root, globs): &(PathBuf, GlobSet)| {
+      path
+        .strip_prefix(root)
+        .map_or(false, |relative| globs.is_match(relative))
+    };
+    if denoignore.iter().any(is_denoignored) {
+      return true;
+    }
+    let path = path.to_string_lossy();
+    self.exclude_regex.iter().any(|regex| regex.is_match(&path))
+  }
+
+  fn has_extension(&self, path: &Path) -> bool {
+    match path.extension() {
+      Some(extension) => {
+        let extension = extension.to_string_lossy().to_lowercase();
+        self.extensions.contains(&extension)
+      }
+      None => false,
+    }
+  }
+}
+
+/// Matches the path in its absolute form as well as relative to each root.
+fn matches_globs(
+  globs: &GlobSet,
+  path: &Path,
+  roots: &[(PathBuf, PathBuf)],
+) -> bool {
+  globs.is_match(path)
+    || roots
+      .iter()
+      .filter_map(|(_, root)| path.strip_prefix(root).ok())
+      .any(|relative| globs.is_match(relative))
+}
+
+/// Whether the path has a component starting with `.` below every
+/// requested path that contains it.
+fn is_hidden(path: &Path, roots: &[(PathBuf, PathBuf)]) -> bool {
+  let mut relative_paths = roots
+    .iter()
+    .filter_map(|(requested, _)| path.strip_prefix(requested).ok())
+    .peekable();
+  relative_paths.peek().is_some()
+    && relative_paths.all(|relative| {
+      relative.components().any(|component| {
+        component.as_os_str().to_string_lossy().starts_with('.')
+      })
+    })
+}
+
+/// Decides which event paths are forwarded from the OS watcher.
+struct EventFilter {
+  /// Each requested path, with the directory relative globs are matched
+  /// against.
+  roots: Mutex<Vec<(PathBuf, PathBuf)>>,
+  /// Ancestors watched in place of paths that don't exist. Updated as paths
+  /// appear and disappear.
+  ancestors: Mutex<Vec<PathBuf>>,
+  /// Canonical forms of the watched paths that differ from the paths as
+  /// given, e.g. behind a symlink. Some backends report events with the
+  /// canonical path, which is translated back before matching.
+  aliases: Mutex<Vec<(PathBuf, PathBuf)>>,
+  /// Targets of followed symlinks, mapped to the symlink's path.
+  symlinks: Mutex<Vec<(PathBuf, PathBuf)>>,
+  /// Targets of watched paths that are symlinks to files, mapped to the
+  /// symlink's path.
+  link_targets: Mutex<Vec<(PathBuf, PathBuf)>>,
+  /// Directories of watched paths that are symlinks to directories, which
+  /// are watched like ancestors to see the symlinks retargeted.
+  link_dirs: Mutex<Vec<PathBuf>>,
+  path_filter: PathFilter,
+  /// The patterns of the `.denoignore` file of each root that has one.
+  /// Unused unless `respect_denoignore` is set.
+  denoignore: Mutex<Vec<(PathBuf, GlobSet)>>,
+  respect_denoignore: bool,
+  /// `WatchOptions::state_file`, resolved like the watched paths. Saving
+  /// the state must not cause a restart.
+  state_file: Option<PathBuf>,
+}
+
+impl EventFilter {
+  fn new(
+    registrations: &[Registration],
+    options: &WatchOptions,
+  ) -> Result<Self, AnyError> {
+    let filter = Self {
+      roots: Mutex::new(Vec::new()),
+      ancestors: Mutex::new(Vec::new()),
+      aliases: Mutex::new(Vec::new()),
+      symlinks: Mutex::new(Vec::new()),
+      link_targets: Mutex::new(Vec::new()),
+      link_dirs: Mutex::new(Vec::new()),
+      path_filter: PathFilter::new(options)?,
+      denoignore: Mutex::new(Vec::new()),
+      respect_denoignore: options.respect_denoignore(),
+      state_file: options.state_file.clone(),
+    };
+    filter.set_paths(registrations);
+    Ok(filter)
+  }
+
+  /// Changes the requested paths. Paths that were already requested keep
+  /// their root, even if they've been removed since.
+  fn set_paths(&self, registrations: &[Registration]) {
+    let mut roots = self.roots.lock().unwrap();
+    *roots = registrations
+      .iter()
+      .map(|r| {
+        let known = roots.iter().find(|(path, _)| *path == r.path);
+        let root = match known {
+          Some((_, root)) => root.clone(),
+          None => match r.path.parent() {
+            Some(parent) if r.path.is_file() => parent.to_path_buf(),
+            _ => r.path.clone(),
+          },
+        };
+        (r.path.clone(), root)
+      })
+      .collect();
+    drop(roots);
+    self.update(registrations);
+    self.load_denoignore();
+  }
+
+  /// Reads the `.denoignore` file of each root directory. A file that
+  /// can't be parsed is skipped with a warning.
+  fn load_denoignore(&self) {
+    if !self.respect_denoignore {
+      return;
+    }
+    let mut dirs: Vec<PathBuf> = self
+      .roots
+      .lock()
+      .unwrap()
+      .iter()
+      .map(|(_, root)| root.clone())
+      .filter(|root| root.is_dir())
+      .collect();
+    dirs.sort();
+    dirs.dedup();
+    let mut denoignore = Vec::new();
+    for dir in dirs {
+      let path = dir.join(DENOIGNORE_FILE);
+      let contents = match std::fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(_) => continue,
+      };
+      match GlobSet::from_ignore_file(&contents) {
+        Ok(globs) => {
+          debug!("Ignoring the patterns of {}", path.display());
+          denoignore.push((dir, globs));
+        }
+        Err(err) => info!(
+          "{} Failed to read {}: {}",
+          paint(colors::yellow("Warning")),
+          path.display(),
+          err
+        ),
+      }
+    }
+    *self.denoignore.lock().unwrap() = denoignore;
+  }
+
+  fn is_denoignore_file(&self, path: &Path) -> bool {
+    self.respect_denoignore
+      && path.file_name() == Some(OsStr::new(DENOIGNORE_FILE))
+      && self
+        .roots
+        .lock()
+        .unwrap()
+        .iter()
+        .any(|(_, root)| path.parent() == Some(root.as_path()))
+  }
+
+  /// Refreshes what depends on the watched paths, after they changed.
+  fn update(&self, registrations: &[Registration]) {
+    *self.ancestors.lock().unwrap() = registrations
+      .iter()
+      .filter(|r| r.watches_ancestor())
+      .map(|r| r.watched.clone())
+      .collect();
+    // Paths that can't be canonicalized are reported as they are.
+    *self.aliases.lock().unwrap() = registrations
+      .iter()
+      .filter_map(|r| match r.watched.canonicalize() {
+        Ok(canonical) if canonical != r.watched => {
+          Some((canonical, r.watched.clone()))
+        }
+        _ => None,
+      })
+      .collect();
+  }
+
+  fn set_symlinks(&self, symlinks: Vec<(PathBuf, PathBuf)>) {
+    *self.symlinks.lock().unwrap() = symlinks;
+  }
+
+  fn set_links(&self, links: &[LinkRoot]) {
+    *self.link_targets.lock().unwrap() = links
+      .iter()
+      .filter(|link| !link.to_dir)
+      .map(|link| (link.target.clone(), link.link.clone()))
+      .collect();
+    *self.link_dirs.lock().unwrap() = links
+      .iter()
+      .filter(|link| link.to_dir)
+      .map(|link| link.watched.clone())
+      .collect();
+  }
+
+  /// Translates a canonical event path back to the form the watched path
+  /// was given in.
+  fn user_path(&self, path: PathBuf) -> PathBuf {
+    let symlinks = self.symlinks.lock().unwrap();
+    let aliases = self.aliases.lock().unwrap();
+    let link_targets = self.link_targets.lock().unwrap();
+    for (canonical, given) in symlinks
+      .iter()
+      .chain(aliases.iter())
+      .chain(link_targets.iter())
+    {
+      if let Ok(relative) = path.strip_prefix(canonical) {
+        if relative.as_os_str().is_empty() {
+          return given.clone();
+        }
+        return given.join(relative);
+      }
+    }
+    path
+  }
+
+  /// Events seen only because an ancestor of a missing path, or the
+  /// directory of a symlink, is watched are out of scope unless they
+  /// concern one of the requested paths.
+  fn is_out_of_scope(&self, path: &Path) -> bool {
+    let ancestors = self.ancestors.lock().unwrap();
+    let link_dirs = self.link_dirs.lock().unwrap();
+    ancestors
+      .iter()
+      .chain(link_dirs.iter())
+      .any(|ancestor| path.starts_with(ancestor))
+      && !self
+        .roots
+        .lock()
+        .unwrap()
+        .iter()
+        .any(|(requested, _)| path.starts_with(requested))
+  }
+
+  /// The innermost requested path the path is below, if any.
+  fn root_of(&self, path: &Path) -> Option<PathBuf> {
+    self
+      .roots
+      .lock()
+      .unwrap()
+      .iter()
+      .map(|(requested, _)| requested)
+      .filter(|requested| path.starts_with(requested))
+      .max_by_key(|requested| requested.components().count())
+      .cloned()
+  }
+
+  /// Why the path is filtered out, if it is; see `PathFilter`.
+  fn rejection(&self, path: &Path) -> Option<&'static str> {
+    let roots = self.roots.lock().unwrap();
+    let denoignore = self.denoignore.lock().unwrap();
+    self.path_filter.rejection(path, &roots, &denoignore)
+  }
+
+  /// See `PathFilter::prunes`.
+  fn prunes(&self, dir: &Path) -> bool {
+    let roots = self.roots.lock().unwrap();
+    let denoignore = self.denoignore.lock().unwrap();
+    self.path_filter.prunes(dir, &roots, &denoignore)
+  }
+
+  /// Removes ignored paths from the event, returning `None` if every path
+  /// it carried was ignored.
+  #[cfg(test)]
+  fn filter(&self, event: NotifyEvent) -> Option<NotifyEvent> {
+    self.filter_with_reason(event).ok()
+  }
+
+  /// Removes ignored paths from the event, or tells why the last path was
+  /// ignored if none are left.
+  fn filter_with_reason(
+    &self,
+    mut event: NotifyEvent,
+  ) -> Result<NotifyEvent, &'static str> {
+    if event.paths.is_empty() {
+      return Ok(event);
+    }
+    event.paths = event
+      .paths
+      .into_iter()
+      .map(|path| self.user_path(path))
+      .collect();
+    // Whatever the event is, the file has to be read again to know.
+    if event.paths.iter().any(|path| self.is_denoignore_file(path)) {
+      self.load_denoignore();
+    }
+    let mut last_reason = "";
+    event.paths.retain(|path| {
+      let reason = if self.is_out_of_scope(path) {
+        Some("outside the watched paths")
+      } else if self.state_file.as_ref() == Some(path) {
+        Some("the watch state file")
+      } else {
+        self.rejection(path)
+      };
+      match reason {
+        Some(reason) => {
+          trace!("Dropping {}: {}", path.display(), reason);
+          last_reason = reason;
+          false
+        }
+        None => true,
+      }
+    });
+    if event.paths.is_empty() {
+      Err(last_reason)
+    } else {
+      Ok(event)
+    }
+  }
+}
+
+/// Why `watch_func` stopped before being shut down.
+#[derive(Debug)]
+pub enum WatchError {
+  /// The file watcher failed, e.g. none of the paths could be watched.
+  Watcher(AnyError),
+  /// A run of the closure failed while `WatchOptions::exit_on_error` was set.
+  Closure(AnyError),
+}
+
+impl fmt::Display for WatchError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      WatchError::Watcher(err) => write!(f, "{}", err),
+      WatchError::Closure(err) => write!(f, "{}", err),
+    }
+  }
+}
+
+impl Error for WatchError {}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+  payload
+    .downcast_ref::<&str>()
+    .map(|message| message.to_string())
+    .or_else(|| payload.downcast_ref::<String>().cloned())
+    .unwrap_or_else(|| "unknown panic".to_string())
+}
+
+fn closure_panicked(payload: Box<dyn Any + Send>) -> AnyError {
+  generic_error(format!(
+    "Watched closure panicked: {}",
+    panic_message(&*payload)
+  ))
+}
+
+/// Calls the closure, turning a panic into a run that fails right away.
+fn start_run<F>(closure: &mut F, changed_paths: &[PathBuf]) -> WatchFuture
+where
+  F: FnMut(&[PathBuf]) -> WatchFuture,
+{
+  match panic::catch_unwind(AssertUnwindSafe(|| closure(changed_paths))) {
+    Ok(future) => future,
+    Err(payload) => {
+      let err = closure_panicked(payload);
+      async move { Err(err) }.boxed_local()
+    }
+  }
+}
+
+/// Reports the error of a failed run, or hands it back if closure errors are
+/// fatal. A panicking run counts as a failed one.
+async fn error_handler(
+  watch_future: WatchFuture,
+  exit_on_error: bool,
+  reporter: Reporter,
+  metrics: Option<Arc<Mutex<WatchMetrics>>>,
+  last_error: Option<LastError>,
+) -> Result<(), AnyError> {
+  let result = match AssertUnwindSafe(watch_future).catch_unwind().await {
+    Ok(result) => result,
+    Err(payload) => Err(closure_panicked(payload)),
+  };
+  if let Some(metrics) = metrics {
+    let mut metrics = metrics.lock().unwrap();
+    metrics.last_run_ok = Some(result.is_ok());
+    if result.is_ok() {
+      metrics.successful_runs += 1;
+    } else {
+      metrics.failed_runs += 1;
+    }
+  }
+  let is_repeated = match &last_error {
+    Some(last_error) => last_error.record(result.as_ref().err()),
+    None => false,
+  };
+  if let Err(err) = result {
+    if exit_on_error {
+      return Err(err);
+    }
+    if is_repeated {
+      reporter.on_repeated_error();
+    } else {
+      reporter.on_error(&err);
+    }
+  }
+  Ok(())
+}
+
+/// The error of the last run that ended, for `collapse_repeated_errors`.
+#[derive(Clone, Default)]
+struct LastError(Arc<Mutex<Option<String>>>);
+
+impl LastError {
+  /// Records how a run ended, returning whether it failed with the same
+  /// error, causes included, as the previous run.
+  fn record(&self, err: Option<&AnyError>) -> bool {
+    let message = err.map(|err| format!("{:#}", err));
+    let mut last = self.0.lock().unwrap();
+    let is_repeated = message.is_some() && *last == message;
+    *last = message;
+    is_repeated
+  }
+}
+
+/// Watches `paths` and yields the sorted paths of every debounced change,
+/// without running anything in response. The OS watcher lives as long as
+/// the stream, so dropping the stream stops watching.
+pub fn watch_paths(
+  paths: &[PathBuf],
+  options: WatchOptions,
+) -> Result<impl Stream<Item = Result<Vec<PathBuf>, AnyError>> + Unpin, AnyError>
+{
+  let watcher = FileWatcher::new(paths, options)?;
+  Ok(watcher.map(|result| result.map(|batch| batch.paths)))
+}
+
+/// Watches `paths` and yields every batch that makes it through the
+/// configured filters, as `watch_func` would restart on. Watcher errors go
+/// to the reporter, and a fatal one ends the stream. The OS watcher lives
+/// as long as the stream, so dropping the stream stops watching.
+pub fn watch_batches(
+  paths: &[PathBuf],
+  options: WatchOptions,
+) -> Result<impl Stream<Item = WatchBatch> + Unpin, AnyError> {
+  let watcher = FileWatcher::new(paths, options)?;
+  Ok(into_batches(watcher))
+}
+
+fn into_batches(
+  watcher: FileWatcher,
+) -> impl Stream<Item = WatchBatch> + Unpin {
+  let reporter = watcher.options.reporter.clone();
+  let options = watcher.options.clone();
+  report_watcher_errors(watcher, &options)
+    .take_while(move |result| {
+      if let Err(err) = result {
+        reporter.on_error(err);
+      }
+      future::ready(result.is_ok())
+    })
+    .filter_map(|result| future::ready(result.ok().map(WatchBatch::from)))
+}
+
+/// Waits for the first debounced change to `paths` and returns the changed
+/// paths, e.g. to block a shell loop until something is edited. The OS
+/// watcher is stopped before this returns. Returns no paths if shut down
+/// first.
+pub async fn watch_until_change(
+  paths: &[PathBuf],
+  options: WatchOptions,
+) -> Result<Vec<PathBuf>, AnyError> {
+  let mut shutdown = options.shutdown.clone();
+  let mut changes = FileWatcher::new(paths, options)?;
+  let changed = select! {
+    result = changes.next() => match result {
+      Some(result) => result?.paths,
+      None => return Err(generic_error("File watcher stopped unexpectedly")),
+    },
+    _ = wait_for_shutdown(&mut shutdown) => Vec::new(),
+  };
+  drop(changes);
+  Ok(changed)
+}
+
+/// Waits for the first debounced change to the file at `path`, which need
+/// not exist yet, and returns what happened to it, e.g. to wait for a build
+/// to write its output. Changes to anything else, including other files in
+/// its directory, are ignored. Fails if nothing changed within `timeout`.
+pub async fn wait_for_change_to(
+  path: &Path,
+  timeout: Option<Duration>,
+) -> Result<ChangeKind, AnyError> {
+  // A file that is created and written reads as created, not modified.
+  let options = WatchOptions {
+    net_changes: true,
+    ..Default::default()
+  };
+  let mut changes = FileWatcher::new(&[path.to_path_buf()], options)?;
+  let change = async {
+    match changes.next().await {
+      Some(result) => Ok(result?.kind),
+      None => Err(generic_error("File watcher stopped unexpectedly")),
+    }
+  };
+  match timeout {
+    Some(timeout) => {
+      tokio::time::timeout(timeout, change).await.map_err(|_| {
+        generic_error(format!(
+          "{} did not change within {}ms",
+          path.display(),
+          timeout.as_millis()
+        ))
+      })?
+    }
+    None => change.await,
+  }
+}
+
+/// Watches the paths read from `reader`, one per line, and calls `callback`
+/// with the paths of every debounced change. A blank line ends a list, which
+/// then replaces the watched paths; only the paths added or removed since
+/// the previous list are watched or unwatched. Nothing is watched until the
+/// first list arrives. Returns once `reader` is exhausted or on shutdown.
+pub async fn watch_from_path_stream<R, F>(
+  reader: R,
+  options: WatchOptions,
+  mut callback: F,
+) -> Result<(), AnyError>
+where
+  R: AsyncBufRead + Unpin,
+  F: FnMut(Vec<PathBuf>),
+{
+  let mut shutdown = options.shutdown.clone();
+  let reporter = options.reporter.clone();
+  let exit_on_watcher_error = options.exit_on_watcher_error;
+  let mut changes = FileWatcher::new(&[], options)?;
+  let mut lines = reader.lines();
+  let mut list = Vec::new();
+  loop {
+    select! {
+      line = lines.next() => match line {
+        Some(line) => {
+          let line = line?;
+          let path = line.trim();
+          if !path.is_empty() {
+            list.push(PathBuf::from(path));
+            continue;
+          }
+          let paths = std::mem::take(&mut list);
+          debug!("Path list received, watching: {}", format_paths(&paths));
+          for (path, err) in changes.set_paths(paths) {
+            info!(
+              "{} Failed to watch {}: {}",
+              paint(colors::yellow("Warning")),
+              path.display(),
+              err
+            );
+          }
+        }
+        None => return Ok(()),
+      },
+      result = changes.next() => match result {
+        Some(Ok(batch)) => callback(batch.paths),
+        Some(Err(err)) if !exit_on_watcher_error => reporter.on_error(&err),
+        Some(Err(err)) => return Err(err),
+        None => return Err(generic_error("File watcher stopped unexpectedly")),
+      },
+      _ = wait_for_shutdown(&mut shutdown) => return Ok(()),
+    }
+  }
+}
+
+/// Chainable configuration for a file watcher. Every setting defaults to the
+/// behavior of `WatchOptions::default()`.
+///
+/// ```ignore
+/// WatcherBuilder::new()
+///   .debounce(Duration::from_millis(100))
+///   .exclude("node_modules")
+///   .include("**/*.ts")
+///   .shutdown(ctrl_c_shutdown())
+///   .watch(&[PathBuf::from("src")], |changed_paths| {
+///     println!("{} paths changed", changed_paths.len());
+///     async { Ok(()) }.boxed_local()
+///   })
+///   .await?;
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct WatcherBuilder {
+  options: WatchOptions,
+}
+
+impl WatcherBuilder {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  pub fn debounce(mut self, debounce_time: Duration) -> Self {
+    self.options.debounce_time = Some(debounce_time);
+    self
+  }
+
+  pub fn debounce_strategy(mut self, strategy: DebounceStrategy) -> Self {
+    self.options.debounce_strategy = strategy;
+    self
+  }
+
+  pub fn debounce_anchor(mut self, anchor: DebounceAnchor) -> Self {
+    self.options.debounce_anchor = anchor;
+    self
+  }
+
+  pub fn debounce_scope(mut self, scope: DebounceScope) -> Self {
+    self.options.debounce_scope = scope;
+    self
+  }
+
+  pub fn max_debounce(mut self, max_debounce: Duration) -> Self {
+    self.options.max_debounce = Some(max_debounce);
+    self
+  }
+
+  pub fn confirm_quiet(mut self, confirm_quiet: Duration) -> Self {
+    self.options.confirm_quiet = Some(confirm_quiet);
+    self
+  }
+
+  /// Watches every path recursively, or none of them. Without this,
+  /// directories are watched recursively and files non-recursively.
+  pub fn recursive(mut self, recursive: bool) -> Self {
+    self.options.recursive_mode = Some(if recursive {
+      RecursiveMode::Recursive
+    } else {
+      RecursiveMode::NonRecursive
+    });
+    self
+  }
+
+  /// Watches `path` with the given mode, whatever `recursive` says. The
+  /// path still has to be passed to `watch`.
+  pub fn root_mode<P: Into<PathBuf>>(
+    mut self,
+    path: P,
+    recursive_mode: RecursiveMode,
+  ) -> Self {
+    self.options.root_modes.insert(path.into(), recursive_mode);
+    self
+  }
+
+  pub fn root_label<P: Into<PathBuf>, S: Into<String>>(
+    mut self,
+    path: P,
+    label: S,
+  ) -> Self {
+    self.options.root_labels.insert(path.into(), label.into());
+    self
+  }
+
+  pub fn max_depth(mut self, max_depth: usize) -> Self {
+    self.options.max_depth = Some(max_depth);
+    self
+  }
+
+  pub fn follow_symlinks(mut self, follow_symlinks: bool) -> Self {
+    self.options.follow_symlinks = follow_symlinks;
+    self
+  }
+
+  pub fn exclude<S: Into<String>>(mut self, glob: S) -> Self {
+    self.options.exclude.push(glob.into());
+    self
+  }
+
+  pub fn exclude_regex(mut self, regex: Regex) -> Self {
+    self.options.exclude_regex.push(regex);
+    self
+  }
+
+  pub fn include<S: Into<String>>(mut self, glob: S) -> Self {
+    self.options.include.push(glob.into());
+    self
+  }
+
+  pub fn extension<S: Into<String>>(mut self, extension: S) -> Self {
+    self.options.extensions.push(extension.into());
+    self
+  }
+
+  pub fn backend(mut self, backend: WatchBackend) -> Self {
+    self.options.backend = backend;
+    self
+  }
+
+  /// Applies declarative settings on top of the options set so far.
+  pub fn settings(mut self, settings: &WatchSettings) -> Self {
+    settings.apply(&mut self.options);
+    self
+  }
+
+  pub fn watch_config(mut self, watch_config: WatchConfig) -> Self {
+    self.options.watch_config = watch_config;
+    self
+  }
+
+  pub fn watcher(mut self, watcher: WatcherFactory) -> Self {
+    self.options.watcher = Some(watcher);
+    self
+  }
+
+  pub fn source<S: WatchSource + 'static>(mut self, source: S) -> Self {
+    self.options.watcher = Some(WatcherFactory::from_source(source));
+    self
+  }
+
+  pub fn clear_screen(mut self, clear_screen: bool) -> Self {
+    self.options.clear_screen = Some(clear_screen);
+    self
+  }
+
+  pub fn shutdown(mut self, shutdown: watch::Receiver<bool>) -> Self {
+    self.options.shutdown = Some(shutdown);
+    self
+  }
+
+  pub fn finish_on_shutdown(mut self, finish_on_shutdown: bool) -> Self {
+    self.options.finish_on_shutdown = finish_on_shutdown;
+    self
+  }
+
+  pub fn exit_on_error(mut self, exit_on_error: bool) -> Self {
+    self.options.exit_on_error = exit_on_error;
+    self
+  }
+
+  pub fn fail_fast(mut self, fail_fast: Duration) -> Self {
+    self.options.fail_fast = Some(fail_fast);
+    self
+  }
+
+  pub fn collapse_repeated_errors(mut self, collapse: bool) -> Self {
+    self.options.collapse_repeated_errors = collapse;
+    self
+  }
+
+  pub fn exit_on_watcher_error(mut self, exit_on_watcher_error: bool) -> Self {
+    self.options.exit_on_watcher_error = exit_on_watcher_error;
+    self
+  }
+
+  pub fn exit_on_roots_lost(mut self, exit_on_roots_lost: bool) -> Self {
+    self.options.exit_on_roots_lost = exit_on_roots_lost;
+    self
+  }
+
+  pub fn restart_storm(mut self, restart_storm: RestartStorm) -> Self {
+    self.options.restart_storm = Some(restart_storm);
+    self
+  }
+
+  pub fn throttle(mut self, throttle: Duration) -> Self {
+    self.options.throttle = Some(throttle);
+    self
+  }
+
+  pub fn restart_delay(mut self, restart_delay: Duration) -> Self {
+    self.options.restart_delay = Some(restart_delay);
+    self
+  }
+
+  pub fn on_abort<F>(mut self, hook: F) -> Self
+  where
+    F: Fn() -> BoxFuture<'static, ()> + Send + Sync + 'static,
+  {
+    self.options.on_abort = Some(AbortHook::new(hook));
+    self
+  }
+
+  pub fn wait_for_exit(mut self, wait_for_exit: bool) -> Self {
+    self.options.wait_for_exit = wait_for_exit;
+    self
+  }
+
+  pub fn detect_self_writes(mut self, detect_self_writes: bool) -> Self {
+    self.options.detect_self_writes = detect_self_writes;
+    self
+  }
+
+  pub fn output_path<P: Into<PathBuf>>(mut self, path: P) -> Self {
+    self.options.output_paths.push(path.into());
+    self
+  }
+
+  pub fn max_restarts(mut self, max_restarts: usize) -> Self {
+    self.options.max_restarts = Some(max_restarts);
+    self
+  }
+
+  pub fn summary_on_exit(mut self, summary_on_exit: bool) -> Self {
+    self.options.summary_on_exit = summary_on_exit;
+    self
+  }
+
+  pub fn run_on_start(mut self, run_on_start: bool) -> Self {
+    self.options.run_on_start = Some(run_on_start);
+    self
+  }
+
+  pub fn startup_grace(mut self, startup_grace: Duration) -> Self {
+    self.options.startup_grace = Some(startup_grace);
+    self
+  }
+
+  pub fn state_file<P: Into<PathBuf>>(mut self, state_file: P) -> Self {
+    self.options.state_file = Some(state_file.into());
+    self
+  }
+
+  pub fn idle_timeout(mut self, idle_timeout: Duration) -> Self {
+    self.options.idle_timeout = Some(idle_timeout);
+    self
+  }
+
+  pub fn dry_run(mut self, dry_run: bool) -> Self {
+    self.options.dry_run = dry_run;
+    self
+  }
+
+  pub fn skip_unchanged(mut self, skip_unchanged: bool) -> Self {
+    self.options.skip_unchanged = skip_unchanged;
+    self
+  }
+
+  pub fn with_content_delta(mut self, with_content_delta: bool) -> Self {
+    self.options.with_content_delta = with_content_delta;
+    self
+  }
+
+  pub fn relevant(mut self, relevant: Arc<Mutex<HashSet<PathBuf>>>) -> Self {
+    self.options.relevant = Some(relevant);
+    self
+  }
+
+  pub fn trigger_on<I>(mut self, kinds: I) -> Self
+  where
+    I: IntoIterator<Item = ChangeKind>,
+  {
+    self.options.trigger_on = Some(kinds.into_iter().collect());
+    self
+  }
+
+  pub fn should_restart<F>(mut self, predicate: F) -> Self
+  where
+    F: Fn(&[PathBuf], &BTreeSet<ChangeKind>) -> bool + Send + Sync + 'static,
+  {
+    self.options.should_restart = Some(RestartPredicate::new(predicate));
+    self
+  }
+
+  pub fn max_file_size(mut self, max_file_size: u64) -> Self {
+    self.options.max_file_size = Some(max_file_size);
+    self
+  }
+
+  pub fn json_events(mut self, writer: EventWriter) -> Self {
+    self.options.json_events = Some(writer);
+    self
+  }
+
+  pub fn on_before_restart<F>(mut self, hook: F) -> Self
+  where
+    F: Fn(&[PathBuf]) + Send + Sync + 'static,
+  {
+    self.options.on_before_restart = Some(RestartHook::new(hook));
+    self
+  }
+
+  pub fn on_restart_command(mut self, command: Vec<String>) -> Self {
+    self.options.on_restart_command = Some(command);
+    self
+  }
+
+  pub fn on_shutdown_flush<F>(mut self, flush: F) -> Self
+  where
+    F: Fn(Vec<PathBuf>) + Send + Sync + 'static,
+  {
+    self.options.on_shutdown_flush = Some(ShutdownFlush::new(flush));
+    self
+  }
+
+  pub fn verbose(mut self, verbose: bool) -> Self {
+    self.options.verbose = verbose;
+    self
+  }
+
+  pub fn clock(mut self, clock: SharedClock) -> Self {
+    self.options.clock = clock;
+    self
+  }
+
+  pub fn watch_hidden(mut self, watch_hidden: bool) -> Self {
+    self.options.watch_hidden = watch_hidden;
+    self
+  }
+
+  pub fn respect_denoignore(mut self, respect_denoignore: bool) -> Self {
+    self.options.respect_denoignore = Some(respect_denoignore);
+    self
+  }
+
+  pub fn watch_access(mut self, watch_access: bool) -> Self {
+    self.options.watch_access = watch_access;
+    self
+  }
+
+  pub fn watch_metadata(mut self, watch_metadata: bool) -> Self {
+    self.options.watch_metadata = watch_metadata;
+    self
+  }
+
+  pub fn net_changes(mut self, net_changes: bool) -> Self {
+    self.options.net_changes = net_changes;
+    self
+  }
+
+  pub fn watch_dir_events(mut self, watch_dir_events: bool) -> Self {
+    self.options.watch_dir_events = watch_dir_events;
+    self
+  }
+
+  pub fn restart_signal(mut self, signal: i32) -> Self {
+    self.options.restart_signal = Some(signal);
+    self
+  }
+
+  pub fn channel_capacity(mut self, capacity: usize) -> Self {
+    self.options.channel_capacity = Some(capacity);
+    self
+  }
+
+  pub fn noisy_path_limit(mut self, events_per_second: u32) -> Self {
+    self.options.noisy_path_limit = Some(events_per_second);
+    self
+  }
+
+  pub fn noisy_path_cooldown(mut self, cooldown: Duration) -> Self {
+    self.options.noisy_path_cooldown = Some(cooldown);
+    self
+  }
+
+  pub fn pause(mut self, pause: PauseFlag) -> Self {
+    self.options.pause = Some(pause);
+    self
+  }
+
+  pub fn suppressions(mut self, suppressions: Suppressions) -> Self {
+    self.options.suppressions = Some(suppressions);
+    self
+  }
+
+  pub fn recent_events(mut self, capacity: usize) -> Self {
+    self.options.recent_events = Some(capacity);
+    self
+  }
+
+  pub fn metrics(mut self, metrics: Arc<Mutex<WatchMetrics>>) -> Self {
+    self.options.metrics = Some(metrics);
+    self
+  }
+
+  pub fn quiet(mut self, quiet: bool) -> Self {
+    self.options.quiet = quiet;
+    self
+  }
+
+  pub fn reporter<R: WatchReporter + 'static>(mut self, reporter: R) -> Self {
+    self.options.reporter = Reporter::new(reporter);
+    self
+  }
+
+  pub fn config<P, F>(mut self, path: P, resolve: F) -> Self
+  where
+    P: Into<PathBuf>,
+    F: Fn(&Path) -> Vec<PathBuf> + Send + Sync + 'static,
+  {
+    self.options.config = Some(ConfigFile::new(path, resolve));
+    self
+  }
+
+  pub fn imports<P, F>(mut self, entrypoint: P, resolve: F) -> Self
+  where
+    P: Into<PathBuf>,
+    F: Fn(&Path) -> Vec<PathBuf> + Send + Sync + 'static,
+  {
+    self.options.imports = Some(ImportGraph::new(entrypoint, resolve));
+    self
+  }
+
+  pub fn watch_lockfile<P: Into<PathBuf>>(mut self, path: P) -> Self {
+    self.options.watch_lockfile = Some(path.into());
+    self
+  }
+
+  pub fn options(&self) -> &WatchOptions {
+    &self.options
+  }
+
+  /// Like `watch_paths`.
+  pub fn stream(
+    self,
+    paths: &[PathBuf],
+  ) -> Result<
+    impl Stream<Item = Result<Vec<PathBuf>, AnyError>> + Unpin,
+    AnyError,
+  > {
+    watch_paths(paths, self.options)
+  }
+
+  /// Like `watch_batches`.
+  pub fn batches(
+    self,
+    paths: &[PathBuf],
+  ) -> Result<impl Stream<Item = WatchBatch> + Unpin, AnyError> {
+    watch_batches(paths, self.options)
+  }
+
+  /// Calls `callback` with the changed paths of every debounced batch, until
+  /// shut down or the watcher fails. Nothing is restarted, so the restart
+  /// related options have no effect.
+  pub async fn notify<F>(
+    self,
+    paths: &[PathBuf],
+    mut callback: F,
+  ) -> Result<(), WatchError>
+  where
+    F: FnMut(Vec<PathBuf>),
+  {
+    let mut shutdown = self.options.shutdown.clone();
+    let mut changes =
+      watch_paths(paths, self.options.clone()).map_err(WatchError::Watcher)?;
+    loop {
+      select! {
+        result = changes.next() => match result {
+          Some(result) => callback(result.map_err(WatchError::Watcher)?),
+          None => break,
+        },
+        _ = wait_for_shutdown(&mut shutdown) => break,
+      };
+    }
+    Ok(())
+  }
+
+  /// Runs `closure`, restarting it whenever one of `paths` changes, until
+  /// shut down or a `WatchError` occurs. The closure may keep state across
+  /// runs.
+  pub async fn watch<F>(
+    self,
+    paths: &[PathBuf],
+    closure: F,
+  ) -> Result<(), WatchError>
+  where
+    F: FnMut(&[PathBuf]) -> WatchFuture,
+  {
+    self.watch_merged(paths, stream::pending(), closure).await
+  }
+
+  /// Like `watch`, but also restarts on every batch from `batches`, e.g.
+  /// `WatchBatch::forced` ones from a timer or a request. Watching still
+  /// ends when the file watcher does, whether or not `batches` has ended.
+  pub async fn watch_merged<S, F>(
+    self,
+    paths: &[PathBuf],
+    batches: S,
+    mut closure: F,
+  ) -> Result<(), WatchError>
+  where
+    S: Stream<Item = WatchBatch> + Unpin,
+    F: FnMut(&[PathBuf]) -> WatchFuture,
+  {
+    let mut options = self.options;
+    let session_started = Instant::now();
+    if options.summary_on_exit && options.metrics.is_none() {
+      options.metrics = Some(Default::default());
+    }
+    if let Some(diagnostics) = options.diagnostics.clone() {
+      options.reporter = Reporter::new(DiagnosticsReporter {
+        reporter: options.reporter.clone(),
+        diagnostics,
+      });
+    }
+    let state_file = options.state_file.clone();
+    let file_watcher =
+      FileWatcher::new(paths, options.clone()).map_err(WatchError::Watcher)?;
+    file_watcher.handler.start_grace(options.startup_grace());
+    if !options.quiet {
+      options.reporter.on_start(file_watcher.summary());
+    }
+    let file_changes = report_watcher_errors(file_watcher, &options)
+      .map(Some)
+      .chain(stream::once(future::ready(None)));
+    let batches = batches.map(|batch| Some(Ok(ChangeBatch::from(batch))));
+    let mut changes = stream::select(file_changes, batches)
+      .take_while(|result| future::ready(result.is_some()))
+      .map(Option::unwrap);
+    let mut shutdown = options.shutdown.clone();
+    let clear_screen = !options.quiet && options.clear_screen();
+    let mut restart_tracker =
+      RestartTracker::new(options.restart_storm.clone().unwrap_or_default());
+    let mut restart_signal = RestartSignal::new(&options);
+    let mut metrics = MetricsRecorder::new(options.metrics.clone());
+    let last_error = if options.collapse_repeated_errors {
+      Some(LastError::default())
+    } else {
+      None
+    };
+    let mut self_writes = if options.detect_self_writes {
+      Some(SelfWrites::new(paths, &options).map_err(WatchError::Watcher)?)
+    } else {
+      None
+    };
+    let mut changed = ChangeBatch::new(Vec::new(), ChangeKind::Modified);
+    let mut is_restart = false;
+    let mut restarts = 0;
+    // Runs in a row that ended within `fail_fast`.
+    let mut fast_runs = 0;
+
+    // When a file last changed, for `idle_timeout`.
+    let clock = options.clock.clone();
+    let mut idle_since = clock.now();
+
+    if options.dry_run {
+      loop {
+        select! {
+          result = changes.next() => match result {
+            Some(result) => {
+              let batch = result.map_err(WatchError::Watcher)?;
+              log_dry_run(&batch, options.quiet, options.json_events.as_ref());
+              idle_since = clock.now();
+            }
+            None => break,
+          },
+          _ = wait_for_idle(&options, idle_since) => break,
+          _ = wait_for_shutdown(&mut shutdown) => break,
+        };
+      }
+      return Ok(());
+    }
+
+    if let Some(state_file) = &state_file {
+      if let Some(previous) = WatchState::load(state_file) {
+        let offline =
+          WatchState::scan(paths, Some(state_file)).changed_since(&previous);
+        if !offline.is_empty() {
+          debug!("Changed while not watching: {}", format_paths(&offline));
+          changed = ChangeBatch::new(offline, ChangeKind::Modified);
+        }
+      }
+    }
+
+    if !options.run_on_start() && changed.paths.is_empty() {
+      select! {
+        result = changes.next() => match result {
+          Some(result) => {
+            changed = result.map_err(WatchError::Watcher)?;
+            idle_since = clock.now();
+          }
+          None => return Ok(()),
+        },
+        _ = restart_signal.recv() => {}
+        _ = wait_for_idle(&options, idle_since) => return Ok(()),
+        _ = wait_for_shutdown(&mut shutdown) => return Ok(()),
+      };
+    }
+
+    // Whether the previous run was still going when it was dropped.
+    let mut was_aborted = false;
+
+    'watch: loop {
+      // The previous run has been dropped by now.
+      if is_restart {
+        restarts += 1;
+        if let (true, Some(hook)) = (was_aborted, &options.on_abort) {
+          let cleanup = hook.call();
+          if options.wait_for_exit {
+            let is_watching = collect_changes_during(
+              cleanup,
+              &mut changes,
+              &mut changed,
+              &mut shutdown,
+            )
+            .await?;
+            if !is_watching {
+              break;
+            }
+          } else {
+            tokio::spawn(cleanup);
+          }
+        }
+        if let Some(restart_delay) = options.restart_delay {
+          let is_watching = collect_changes_during(
+            clock.sleep(restart_delay),
+            &mut changes,
+            &mut changed,
+            &mut shutdown,
+          )
+          .await?;
+          if !is_watching {
+            break;
+          }
+        }
+        if let Some(hook) = &options.on_before_restart {
+          hook.call(&changed.paths);
+        }
+      }
+      is_restart = true;
+      if let Some(self_writes) = &mut self_writes {
+        self_writes.run_started();
+      }
+      let func = error_handler(
+        start_run(&mut closure, &changed.paths),
+        options.exit_on_error,
+        options.reporter.clone(),
+        options.metrics.clone(),
+        last_error.clone(),
+      );
+      tokio::pin!(func);
+      if let Some(state_file) = &state_file {
+        WatchState::scan(paths, Some(state_file)).save(state_file);
+      }
+      let run_started = clock.now();
+      metrics.start_run(Instant::now());
+      if options.max_restarts == Some(restarts) {
+        select! {
+          result = &mut func => {
+            result.map_err(WatchError::Closure)?;
+            metrics.end_run();
+          }
+          _ = wait_for_shutdown(&mut shutdown) => {
+            if options.finish_on_shutdown {
+              func.await.map_err(WatchError::Closure)?;
+            }
+            break;
+          }
+        };
+        if !options.quiet {
+          info!(
+            "{} Restarted {} time{}, the maximum. Exiting.",
+            paint(colors::intense_blue("Watcher")),
+            restarts,
+            if restarts == 1 { "" } else { "s" }
+          );
+        }
+        break;
+      }
+      let mut is_file_changed = false;
+      select! {
+        result = changes.next() => {
+            changed = match result {
+              Some(result) => result.map_err(WatchError::Watcher)?,
+              None => break,
+            };
+            is_file_changed = true;
+            idle_since = clock.now();
+          },
+        _ = restart_signal.recv() => {
+          changed = ChangeBatch::new(Vec::new(), ChangeKind::Modified);
+          is_file_changed = true;
+        }
+        result = &mut func => {
+          result.map_err(WatchError::Closure)?;
+          metrics.end_run();
+          if let Some(self_writes) = &mut self_writes {
+            self_writes.run_ended();
+          }
+          match options.fail_fast {
+            Some(fail_fast)
+              if clock.now().saturating_duration_since(run_started)
+                < fail_fast =>
+            {
+              fast_runs += 1;
+            }
+            _ => fast_runs = 0,
+          }
+        }
+        _ = wait_for_idle(&options, idle_since) => break,
+        _ = wait_for_shutdown(&mut shutdown) => {
+          if options.finish_on_shutdown {
+            func.await.map_err(WatchError::Closure)?;
+          }
+          break;
+        }
+      };
+      if is_file_changed {
+        fast_runs = 0;
+      } else {
+        if !options.quiet {
+          options.reporter.on_process_terminated();
+        }
+        if fast_runs >= FAIL_FAST_RUNS {
+          if fast_runs == FAIL_FAST_RUNS {
+            info!(
+              "{} The process failed fast {} times in a row, delaying \
+              restarts by {}s",
+              paint(colors::yellow("Warning")),
+              fast_runs,
+              FAIL_FAST_DELAY.as_secs()
+            );
+          }
+          select! {
+            _ = clock.sleep(FAIL_FAST_DELAY) => {},
+            _ = wait_for_shutdown(&mut shutdown) => break,
+          };
+        }
+        loop {
+          select! {
+            result = changes.next() => match result {
+              Some(result) => {
+                changed = result.map_err(WatchError::Watcher)?;
+                idle_since = clock.now();
+              }
+              None => break 'watch,
+            },
+            _ = restart_signal.recv() => {
+              changed = ChangeBatch::new(Vec::new(), ChangeKind::Modified);
+            }
+            _ = wait_for_idle(&options, idle_since) => break 'watch,
+            _ = wait_for_shutdown(&mut shutdown) => break 'watch,
+          };
+          match &self_writes {
+            Some(self_writes) if self_writes.wrote_only(&changed.paths) => {
+              debug!(
+                "Only written by the last run, skipping: {}",
+                format_paths(&changed.paths)
+              );
+            }
+            _ => break,
+          }
+        }
+      }
+
+      let mut is_running = is_file_changed;
+      if let Some(throttle) = options.throttle {
+        // Let the current run go on and collect further changes until the
+        // throttle interval has passed since it started.
+        let mut cooldown = clock.sleep_until(run_started + throttle);
+        loop {
+          select! {
+            _ = &mut cooldown => break,
+            result = changes.next() => match result {
+              Some(result) => {
+                changed.merge(result.map_err(WatchError::Watcher)?)
+              }
+              None => break 'watch,
+            },
+            result = &mut func, if is_running => {
+              result.map_err(WatchError::Closure)?;
+              metrics.end_run();
+              is_running = false;
+            }
+            _ = wait_for_shutdown(&mut shutdown) => {
+              if is_running && options.finish_on_shutdown {
+                func.await.map_err(WatchError::Closure)?;
+              }
+              break 'watch;
+            }
+          };
+        }
+      }
+      if let Some(pause) = &options.pause {
+        // Let the current run go on and collect the changes until resumed.
+        while pause.is_paused() {
+          select! {
+            _ = pause.resumed() => {},
+            result = changes.next() => match result {
+              Some(result) => {
+                changed.merge(result.map_err(WatchError::Watcher)?)
+              }
+              None => break 'watch,
+            },
+            result = &mut func, if is_running => {
+              result.map_err(WatchError::Closure)?;
+              metrics.end_run();
+              is_running = false;
+            }
+            _ = wait_for_shutdown(&mut shutdown) => {
+              if is_running && options.finish_on_shutdown {
+                func.await.map_err(WatchError::Closure)?;
+              }
+              break 'watch;
+            }
+          };
+        }
+      }
+      let timing = metrics.run_time().map(|ran| RestartTiming {
+        ran,
+        debounced: changed.debounced,
+      });
+      log_restart(
+        &changed,
+        timing.as_ref(),
+        clear_screen,
+        if options.quiet {
+          None
+        } else {
+          Some(&options.reporter)
+        },
+        options.json_events.as_ref(),
+      );
+      if let Some(command) = &options.on_restart_command {
+        run_restart_command(command, &changed.paths);
+      }
+      metrics.record_restart(&changed.paths);
+
+      if let Some(backoff) = restart_tracker.record(clock.now().into_std()) {
+        select! {
+          _ = clock.sleep(backoff) => {},
+          _ = wait_for_shutdown(&mut shutdown) => break,
+        };
+      }
+      was_aborted = is_running;
+    }
+
+    metrics.end_run();
+    // Release the OS watch handles before returning.
+    drop(changes);
+    if let (true, Some(metrics)) = (options.summary_on_exit, &options.metrics) {
+      info!(
+        "{}",
+        exit_summary(&metrics.lock().unwrap(), session_started.elapsed())
+      );
+    }
+    Ok(())
+  }
+}
+
+impl From<WatchOptions> for WatcherBuilder {
+  fn from(options: WatchOptions) -> Self {
+    Self { options }
+  }
+}
+
+pub async fn watch_func<F>(
+  paths: &[PathBuf],
+  options: WatchOptions,
+  closure: F,
+) -> Result<(), WatchError>
+where
+  F: FnMut(&[PathBuf]) -> WatchFuture,
+{
+  WatcherBuilder::from(options).watch(paths, closure).await
+}
+
+/// Runs `command`, a program followed by its arguments, whenever `paths`
+/// change, like `watch_func` runs a closure, for programs other than deno.
+/// The command's stdout and stderr are those of the watcher. A run that is
+/// still going when the next one starts is killed first, and exiting with a
+/// failure status counts as a failed run.
+pub async fn watch_exec(
+  paths: &[PathBuf],
+  command: Vec<String>,
+  options: WatchOptions,
+) -> Result<(), WatchError> {
+  if command.is_empty() {
+    return Err(WatchError::Watcher(generic_error("No command to run")));
+  }
+  let command = Arc::new(command);
+  watch_func(paths, options, move |_| {
+    let command = command.clone();
+    // Spawned once polled, so that the run it replaces was dropped, and its
+    // process killed, by then.
+    async move {
+      let status = tokio::process::Command::new(&command[0])
+        .args(&command[1..])
+        .stdin(std::process::Stdio::null())
+        .kill_on_drop(true)
+        .spawn()?
+        .await?;
+      if status.success() {
+        Ok(())
+      } else {
+        Err(generic_error(format!(
+          "{} exited with {}",
+          command[0], status
+        )))
+      }
+    }
+    .boxed_local()
+  })
+  .await
+}
+
+/// Pauses the restarts of a `watch_func` loop, e.g. while a code generator
+/// writes into the watched directories. While paused, the current run goes
+/// on and changes are collected; on resume the closure is restarted once if
+/// anything changed.
+#[derive(Clone, Default)]
+pub struct PauseFlag(Arc<PauseState>);
+
+struct PauseState {
+  is_paused: AtomicBool,
+  resumed: Notify,
+}
+
+impl Default for PauseState {
+  fn default() -> Self {
+    Self {
+      is_paused: AtomicBool::new(false),
+      resumed: Notify::new(),
+    }
+  }
+}
+
+impl PauseFlag {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  pub fn pause(&self) {
+    self.0.is_paused.store(true, Ordering::SeqCst);
+  }
+
+  pub fn resume(&self) {
+    self.0.is_paused.store(false, Ordering::SeqCst);
+    self.0.resumed.notify();
+  }
+
+  pub fn is_paused(&self) -> bool {
+    self.0.is_paused.load(Ordering::SeqCst)
+  }
+
+  /// Resolves after the next `resume`, or right away if there was one since
+  /// the last call. Callers check `is_paused` again afterwards.
+  async fn resumed(&self) {
+    self.0.resumed.notified().await
+  }
+}
+
+impl fmt::Debug for PauseFlag {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.debug_tuple("PauseFlag").field(&self.is_paused()).finish()
+  }
+}
+
+/// Paths whose changes are dropped until a deadline, e.g. while a formatter
+/// rewrites a watched file, so that the write doesn't restart the closure.
+/// Unlike `PauseFlag` this is per path, and the changes are not restarted
+/// on later. A suppressed directory covers everything below it. Expired
+/// entries are removed as paths are checked.
+#[derive(Clone, Default)]
+pub struct Suppressions(Arc<Mutex<Vec<(PathBuf, Instant)>>>);
+
+impl Suppressions {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Drops the changes to `path` for `for_duration` from now, extending an
+  /// earlier suppression of it.
+  pub fn suppress(&self, path: PathBuf, for_duration: Duration) {
+    let until = Instant::now() + for_duration;
+    let mut entries = self.0.lock().unwrap();
+    match entries
+      .iter_mut()
+      .find(|(suppressed, _)| *suppressed == path)
+    {
+      Some((_, deadline)) => *deadline = (*deadline).max(until),
+      None => entries.push((path, until)),
+    }
+  }
+
+  pub fn is_suppressed(&self, path: &Path) -> bool {
+    let now = Instant::now();
+    let mut entries = self.0.lock().unwrap();
+    entries.retain(|(_, until)| *until > now);
+    entries
+      .iter()
+      .any(|(suppressed, _)| path.starts_with(suppressed))
+  }
+}
+
+impl fmt::Debug for Suppressions {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let entries = self.0.lock().unwrap();
+    let paths: Vec<&PathBuf> = entries.iter().map(|(path, _)| path).collect();
+    f.debug_tuple("Suppressions").field(&paths).finish()
+  }
+}
+
+/// When the last batch of changes was reported, and how many paths it had,
+/// e.g. for a status display. Reading it takes no lock; the time and size
+/// are updated one after the other, so a read racing a new batch may pair
+/// the new size with the old time.
+#[derive(Clone)]
+pub struct LastBatch(Arc<LastBatchState>);
+
+struct LastBatchState {
+  /// What the stored times are relative to.
+  created: Instant,
+  /// Nanoseconds since `created`, plus one; zero until the first batch.
+  at: AtomicU64,
+  size: AtomicUsize,
+}
+
+impl LastBatch {
+  pub fn new() -> Self {
+    Self(Arc::new(LastBatchState {
+      created: Instant::now(),
+      at: AtomicU64::new(0),
+      size: AtomicUsize::new(0),
+    }))
+  }
+
+  /// The time and number of paths of the last batch, if there was one.
+  pub fn get(&self) -> Option<(Instant, usize)> {
+    match self.0.at.load(Ordering::SeqCst) {
+      0 => None,
+      at => Some((
+        self.0.created + Duration::from_nanos(at - 1),
+        self.0.size.load(Ordering::SeqCst),
+      )),
+    }
+  }
+
+  fn record(&self, batch: &ChangeBatch) {
+    let at = self.0.created.elapsed().as_nanos() as u64 + 1;
+    self.0.size.store(batch.paths.len(), Ordering::SeqCst);
+    self.0.at.store(at, Ordering::SeqCst);
+  }
+}
+
+impl Default for LastBatch {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl fmt::Debug for LastBatch {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.debug_tuple("LastBatch").field(&self.get()).finish()
+  }
+}
+
+/// What a watch session is doing, for bug reports about watching that
+/// stopped working.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WatchDiagnostics {
+  /// The paths watching was asked for.
+  pub roots: Vec<PathBuf>,
+  /// The paths with a watch of their own, not counting paths that don't
+  /// exist and are waited for through an ancestor.
+  pub live: Vec<PathBuf>,
+  /// `None` until watching has started.
+  pub backend: Option<String>,
+  /// The last error reported, from the watcher or a run of the closure.
+  pub last_error: Option<String>,
+  pub restarts: u64,
+  pub debounce_ms: u64,
+  pub max_debounce_ms: Option<u64>,
+  pub events: EventCounts,
+  /// The most recent events the OS watcher delivered, oldest first, see
+  /// `WatchOptions::recent_events`.
+  pub recent_events: Vec<RecordedEvent>,
+}
+
+/// An event as the OS watcher delivered it, before filtering.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct RecordedEvent {
+  pub time: SystemTime,
+  /// The notify event kind, e.g. `Modify(Data(Content))`.
+  pub kind: String,
+  pub paths: Vec<PathBuf>,
+  pub verdict: EventVerdict,
+}
+
+/// What became of a `RecordedEvent`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum EventVerdict {
+  /// Passed on to the debouncing.
+  Forwarded,
+  /// Dropped for the given reason.
+  Ignored(&'static str),
+  /// Dropped because the channel was full. Its paths are still reported,
+  /// along with the next batch.
+  Overflowed,
+}
+
+/// The buffer behind `WatchDiagnostics::recent_events`, dropping the oldest
+/// event once full.
+#[derive(Default)]
+struct RecentEvents(Mutex<VecDeque<RecordedEvent>>);
+
+impl RecentEvents {
+  fn push(&self, event: RecordedEvent, capacity: usize) {
+    if capacity == 0 {
+      return;
+    }
+    let mut events = self.0.lock().unwrap();
+    while events.len() >= capacity {
+      events.pop_front();
+    }
+    events.push_back(event);
+  }
+
+  fn to_vec(&self) -> Vec<RecordedEvent> {
+    self.0.lock().unwrap().iter().cloned().collect()
+  }
+}
+
+/// The events the OS watcher delivered to the handler, before debouncing.
+/// An event is either forwarded to the debouncing, filtered out, or dropped
+/// because the channel was full, see `WatchOptions::channel_capacity`. A
+/// growing gap between `received` and `forwarded` plus `filtered` means the
+/// channel overflows.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize)]
+pub struct EventCounts {
+  /// Errors of the watcher included.
+  pub received: u64,
+  pub forwarded: u64,
+  pub filtered: u64,
+}
+
+/// The counters behind `EventCounts`, bumped by the notify callback.
+#[derive(Default)]
+struct EventCounters {
+  received: AtomicU64,
+  forwarded: AtomicU64,
+  filtered: AtomicU64,
+}
+
+impl EventCounters {
+  fn counts(&self) -> EventCounts {
+    EventCounts {
+      received: self.received.load(Ordering::SeqCst),
+      forwarded: self.forwarded.load(Ordering::SeqCst),
+      filtered: self.filtered.load(Ordering::SeqCst),
+    }
+  }
+}
+
+/// Shared `WatchDiagnostics`, updated by the watcher while it runs. Reading
+/// a snapshot only takes a lock.
+#[derive(Clone, Default)]
+pub struct Diagnostics {
+  state: Arc<Mutex<WatchDiagnostics>>,
+  /// Kept apart so that counting events doesn't take the lock.
+  events: Arc<EventCounters>,
+  recent_events: Arc<RecentEvents>,
+}
+
+impl Diagnostics {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  pub fn snapshot(&self) -> WatchDiagnostics {
+    let mut diagnostics = self.state.lock().unwrap().clone();
+    diagnostics.events = self.events.counts();
+    diagnostics.recent_events = self.recent_events.to_vec();
+    diagnostics
+  }
+
+  fn update<F: FnOnce(&mut WatchDiagnostics)>(&self, update: F) {
+    update(&mut self.state.lock().unwrap());
+  }
+}
+
+impl fmt::Debug for Diagnostics {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.debug_tuple("Diagnostics")
+      .field(&self.snapshot())
+      .finish()
+  }
+}
+
+/// Records the errors it reports in the diagnostics.
+struct DiagnosticsReporter {
+  reporter: Reporter,
+  diagnostics: Diagnostics,
+}
+
+impl WatchReporter for DiagnosticsReporter {
+  fn on_start(&self, summary: &WatchSummary) {
+    self.reporter.on_start(summary);
+  }
+
+  fn on_restart(&self, changed_paths: &[PathBuf]) {
+    self.reporter.on_restart(changed_paths);
+  }
+
+  fn on_labeled_restart(&self, source: &str, changed_paths: &[PathBuf]) {
+    self.reporter.on_labeled_restart(source, changed_paths);
+  }
+
+  fn on_timed_restart(
+    &self,
+    source: Option<&str>,
+    changed_paths: &[PathBuf],
+    timing: &RestartTiming,
+  ) {
+    self
+      .reporter
+      .on_timed_restart(source, changed_paths, timing);
+  }
+
+  fn on_process_terminated(&self) {
+    self.reporter.on_process_terminated();
+  }
+
+  fn on_error(&self, err: &AnyError) {
+    let message = err.to_string();
+    self
+      .diagnostics
+      .update(|diagnostics| diagnostics.last_error = Some(message));
+    self.reporter.on_error(err);
+  }
+
+  fn on_repeated_error(&self) {
+    self.reporter.on_repeated_error();
+  }
+}
+
+/// A `watch_func` loop running in the background, see `spawn_watch`.
+pub struct WatchHandle {
+  stop: Arc<watch::Sender<bool>>,
+  pause: PauseFlag,
+  suppressions: Suppressions,
+  last_batch: LastBatch,
+  diagnostics: Diagnostics,
+  metrics: Arc<Mutex<WatchMetrics>>,
+  task: JoinHandle<Result<(), WatchError>>,
+}
+
+impl WatchHandle {
+  /// Shuts the loop down, like the `shutdown` channel of its options.
+  pub fn stop(&self) {
+    let _ = self.stop.broadcast(true);
+  }
+
+  /// Holds back restarts until `resume`; see `PauseFlag`.
+  pub fn pause(&self) {
+    self.pause.pause();
+  }
+
+  pub fn resume(&self) {
+    self.pause.resume();
+  }
+
+  /// Ignores changes to `path` for `for_duration`; see `Suppressions`.
+  pub fn suppress(&self, path: PathBuf, for_duration: Duration) {
+    self.suppressions.suppress(path, for_duration);
+  }
+
+  /// See `LastBatch::get`.
+  pub fn last_batch(&self) -> Option<(Instant, usize)> {
+    self.last_batch.get()
+  }
+
+  /// Whether the last completed run of the closure succeeded, `None` until
+  /// a run completes.
+  pub fn last_run_ok(&self) -> Option<bool> {
+    self.metrics.lock().unwrap().last_run_ok
+  }
+
+  /// A snapshot of what the loop is watching, e.g. to dump on request.
+  pub fn diagnostics(&self) -> WatchDiagnostics {
+    let mut diagnostics = self.diagnostics.snapshot();
+    diagnostics.restarts = self.metrics.lock().unwrap().restarts;
+    diagnostics
+  }
+
+  /// Waits for the loop to end and returns its result.
+  pub async fn join(self) -> Result<(), WatchError> {
+    match self.task.await {
+      Ok(result) => result,
+      Err(err) => Err(WatchError::Watcher(AnyError::from(err))),
+    }
+  }
+}
+
+/// Runs `watch_func` in the background and returns a handle to stop or
+/// pause it.
+///
+/// Runs of the closure aren't `Send`, so the loop gets a thread and runtime
+/// of its own, taken from the blocking pool of the current runtime. The
+/// `shutdown` channel of `options` keeps working alongside the handle.
+pub fn spawn_watch<F>(
+  paths: Vec<PathBuf>,
+  mut options: WatchOptions,
+  closure: F,
+) -> WatchHandle
+where
+  F: FnMut(&[PathBuf]) -> WatchFuture + Send + 'static,
+{
+  let (sender, receiver) = watch::channel(false);
+  let stop = Arc::new(sender);
+  let mut shutdown = std::mem::replace(&mut options.shutdown, Some(receiver));
+  let forward_stop = stop.clone();
+  let pause = options.pause.get_or_insert_with(PauseFlag::new).clone();
+  let suppressions = options
+    .suppressions
+    .get_or_insert_with(Suppressions::new)
+    .clone();
+  let last_batch = options
+    .last_batch
+    .get_or_insert_with(LastBatch::new)
+    .clone();
+  let diagnostics = options
+    .diagnostics
+    .get_or_insert_with(Diagnostics::new)
+    .clone();
+  let metrics = options.metrics.get_or_insert_with(Default::default).clone();
+  let task = tokio::task::spawn_blocking(move || {
+    tokio_util::run_basic(async move {
+      if shutdown.is_some() {
+        tokio::spawn(async move {
+          wait_for_shutdown(&mut shutdown).await;
+          let _ = forward_stop.broadcast(true);
+        });
+      }
+      watch_func(&paths, options, closure).await
+    })
+  });
+  WatchHandle {
+    stop,
+    pause,
+    suppressions,
+    last_batch,
+    diagnostics,
+    metrics,
+    task,
+  }
+}
+
+/// Paths watched with a closure and options of their own, see
+/// `watch_groups`.
+pub struct WatchGroup {
+  paths: Vec<PathBuf>,
+  options: WatchOptions,
+  closure: Box<dyn FnMut(&[PathBuf]) -> WatchFuture + Send>,
+}
+
+impl WatchGroup {
+  pub fn new<F>(paths: Vec<PathBuf>, options: WatchOptions, closure: F) -> Self
+  where
+    F: FnMut(&[PathBuf]) -> WatchFuture + Send + 'static,
+  {
+    Self {
+      paths,
+      options,
+      closure: Box::new(closure),
+    }
+  }
+}
+
+impl fmt::Debug for WatchGroup {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.debug_struct("WatchGroup")
+      .field("paths", &self.paths)
+      .finish()
+  }
+}
+
+/// Watches every group at once, each restarting its own closure on its own
+/// batches of changes, e.g. to rebuild a frontend and restart a backend from
+/// one process. Each group runs as by `spawn_watch`. All of them stop once
+/// `shutdown` is signaled, which replaces their own `shutdown` channels, or
+/// once one of them fails, whose error is returned.
+pub async fn watch_groups(
+  groups: Vec<WatchGroup>,
+  shutdown: Option<watch::Receiver<bool>>,
+) -> Result<(), WatchError> {
+  let handles: Vec<WatchHandle> = groups
+    .into_iter()
+    .map(|group| {
+      let mut options = group.options;
+      options.shutdown = shutdown.clone();
+      spawn_watch(group.paths, options, group.closure)
+    })
+    .collect();
+  let stops: Vec<Arc<watch::Sender<bool>>> =
+    handles.iter().map(|handle| handle.stop.clone()).collect();
+  let mut joins: FuturesUnordered<_> =
+    handles.into_iter().map(WatchHandle::join).collect();
+  let mut result = Ok(());
+  while let Some(group_result) = joins.next().await {
+    if let Err(err) = group_result {
+      if result.is_ok() {
+        for stop in &stops {
+          let _ = stop.broadcast(true);
+        }
+        result = Err(err);
+      }
+    }
+  }
+  result
+}
+
+/// Calls `callback` once per debounced batch of changes instead of
+/// restarting a long-running closure.
+pub async fn watch_notify<F>(
+  paths: &[PathBuf],
+  options: WatchOptions,
+  callback: F,
+) -> Result<(), WatchError>
+where
+  F: FnMut(Vec<PathBuf>),
+{
+  WatcherBuilder::from(options).notify(paths, callback).await
+}
+
+/// Hands errors of the OS watcher to the reporter and drops them from the
+/// stream, unless they're fatal.
+fn report_watcher_errors<S>(
+  changes: S,
+  options: &WatchOptions,
+) -> impl Stream<Item = Result<ChangeBatch, AnyError>> + Unpin
+where
+  S: Stream<Item = Result<ChangeBatch, AnyError>> + Unpin,
+{
+  let reporter = options.reporter.clone();
+  let exit_on_watcher_error = options.exit_on_watcher_error;
+  changes.filter_map(move |result| {
+    future::ready(match result {
+      Err(err) if !exit_on_watcher_error => {
+        reporter.on_error(&err);
+        None
+      }
+      result => Some(result),
+    })
+  })
+}
+
+/// Waits for `waiting` while merging the changes that arrive meanwhile into
+/// `changed`, so that the run about to start gets their paths instead of
+/// being restarted for them right away. Returns `false` if watching has to
+/// end, because of a shutdown or because the changes ended.
+async fn collect_changes_during<W, S>(
+  waiting: W,
+  changes: &mut S,
+  changed: &mut ChangeBatch,
+  shutdown: &mut Option<watch::Receiver<bool>>,
+) -> Result<bool, WatchError>
+where
+  W: Future<Output = ()>,
+  S: Stream<Item = Result<ChangeBatch, AnyError>> + Unpin,
+{
+  tokio::pin!(waiting);
+  loop {
+    select! {
+      _ = &mut waiting => return Ok(true),
+      result = changes.next() => match result {
+        Some(result) => changed.merge(result.map_err(WatchError::Watcher)?),
+        None => return Ok(false),
+      },
+      _ = wait_for_shutdown(shutdown) => return Ok(false),
+    };
+  }
+}
+
+/// Resolves once `true` is broadcast on the shutdown channel. Never resolves
+/// if no channel is configured or its sender is gone.
+async fn wait_for_shutdown(shutdown: &mut Option<watch::Receiver<bool>>) {
+  if let Some(receiver) = shutdown {
+    while let Some(is_shutdown) = receiver.recv().await {
+      if is_shutdown {
+        return;
+      }
+    }
+  }
+  future::pending::<()>().await
+}
+
+/// Resolves, logging that watching ends, once `idle_timeout` has passed
+/// since `since` on the options' clock. Never resolves without a timeout.
+async fn wait_for_idle(options: &WatchOptions, since: tokio::time::Instant) {
+  let idle_timeout = match options.idle_timeout {
+    Some(idle_timeout) => idle_timeout,
+    None => return future::pending().await,
+  };
+  options.clock.sleep_until(since + idle_timeout).await;
+  info!(
+    "{} No file changed for {:?}, exiting",
+    paint(colors::intense_blue("Watcher")),
+    idle_timeout
+  );
+}
+
+/// Returns a shutdown channel for `WatchOptions::shutdown` that fires on
+/// Ctrl-C.
+pub fn ctrl_c_shutdown() -> watch::Receiver<bool> {
+  let (sender, receiver) = watch::channel(false);
+  tokio::spawn(async move {
+    if tokio::signal::ctrl_c().await.is_ok() {
+      let _ = sender.broadcast(true);
+    }
+  });
+  receiver
+}
+
+/// Listens for `WatchOptions::restart_signal`. If the signal can't be
+/// registered a warning is printed and `recv` never resolves.
+struct RestartSignal {
+  #[cfg(unix)]
+  signal: Option<tokio::signal::unix::Signal>,
+}
+
+impl RestartSignal {
+  #[cfg(unix)]
+  fn new(options: &WatchOptions) -> Self {
+    use tokio::signal::unix::signal;
+    use tokio::signal::unix::SignalKind;
+    let signum = options.restart_signal.unwrap_or(libc::SIGUSR1);
+    let signal = match signal(SignalKind::from_raw(signum)) {
+      Ok(signal) => Some(signal),
+      Err(err) => {
+        info!(
+          "{} Failed to listen for restart signal {}: {}",
+          paint(colors::yellow("Warning")),
+          signum,
+          err
+        );
+        None
+      }
+    };
+    Self { signal }
+  }
+
+  #[cfg(not(unix))]
+  fn new(_options: &WatchOptions) -> Self {
+    Self {}
+  }
+
+  #[cfg(unix)]
+  async fn recv(&mut self) {
+    if let Some(signal) = &mut self.signal {
+      if signal.recv().await.is_some() {
+        return;
+      }
+    }
+    future::pending::<()>().await
+  }
+
+  #[cfg(not(unix))]
+  async fn recv(&mut self) {
+    future::pending::<()>().await
+  }
+}
+
+fn log_restart(
+  batch: &ChangeBatch,
+  timing: Option<&RestartTiming>,
+  clear_screen: bool,
+  reporter: Option<&Reporter>,
+  json_events: Option<&EventWriter>,
+) {
+  if clear_screen {
+    eprint!("{}", CLEAR_SCREEN);
+  }
+  if let Some(reporter) = reporter {
+    let source = batch.source.as_deref();
+    match (source, timing) {
+      (source, Some(timing)) => {
+        reporter.on_timed_restart(source, &batch.paths, timing)
+      }
+      (Some(source), None) => reporter.on_labeled_restart(source, &batch.paths),
+      (None, None) => reporter.on_restart(&batch.paths),
+    }
+  }
+  if let Some(writer) = json_events {
+    writer.write_event(&restart_event(batch));
+  }
+}
+
+/// Starts `WatchOptions::on_restart_command` without waiting for it.
+fn run_restart_command(command: &[String], paths: &[PathBuf]) {
+  let (program, args) = match command.split_first() {
+    Some(split) => split,
+    None => return,
+  };
+  let child = tokio::process::Command::new(program)
+    .args(args)
+    .args(paths)
+    .stdin(std::process::Stdio::null())
+    .spawn();
+  match child {
+    Ok(child) => {
+      let program = program.clone();
+      tokio::spawn(async move {
+        match child.await {
+          Ok(status) if !status.success() => {
+            debug!("Restart command {} failed: {}", program, status)
+          }
+          Ok(_) => {}
+          Err(err) => debug!("Restart command {} failed: {}", program, err),
+        }
+      });
+    }
+    Err(err) => info!(
+      "{} Failed to run the restart command {}: {}",
+      paint(colors::yellow("Warning")),
+      program,
+      err
+    ),
+  }
+}
+
+/// Like `log_restart`, for a batch that would have restarted the closure in
+/// a dry run.
+fn log_dry_run(
+  batch: &ChangeBatch,
+  quiet: bool,
+  json_events: Option<&EventWriter>,
+) {
+  if !quiet {
+    info!(
+      "{} would restart due to: {}",
+      paint(colors::intense_blue("Watcher")),
+      summarize_paths(&batch.paths, MAX_REPORTED_PATHS)
+    );
+  }
+  if let Some(writer) = json_events {
+    let mut event = restart_event(batch);
+    event["event"] = json!("dryRun");
+    writer.write_event(&event);
+  }
+}
+
+fn restart_event(batch: &ChangeBatch) -> Value {
+  let paths: Vec<String> = batch
+    .paths
+    .iter()
+    .map(|path| path.to_string_lossy().into_owned())
+    .collect();
+  let mut event = json!({
+    "event": "restart",
+    "paths": paths,
+    "kind": batch.kind.as_str(),
+  });
+  if let Some(source) = &batch.source {
+    event["source"] = json!(source);
+  }
+  event
+}
+
+/// The label of the watched path every one of `paths` is below, taking the
+/// innermost labeled path for each. `None` if a path is below no labeled
+/// path, or the paths are below differently labeled ones.
+fn source_label(
+  labels: &HashMap<PathBuf, String>,
+  paths: &[PathBuf],
+) -> Option<String> {
+  let mut source = None;
+  for path in paths {
+    let label = labels
+      .iter()
+      .filter(|(root, _)| path.starts_with(root))
+      .max_by_key(|(root, _)| root.components().count())
+      .map(|(_, label)| label)?;
+    match source {
+      Some(source) if source != label => return None,
+      _ => source = Some(label),
+    }
+  }
+  source.cloned()
+}
+
+/// Lists `paths` for humans, e.g. `src/ (12 files), mod.ts ... and 3 more`.
+/// Up to `max` paths are listed as is. Past that, paths that share their
+/// directory with other changed paths are collapsed into the directory,
+/// and only the first `max` entries are shown.
+pub fn summarize_paths(paths: &[PathBuf], max: usize) -> String {
+  if paths.len() <= max {
+    return format_paths(paths);
+  }
+  let mut per_dir: HashMap<&Path, usize> = HashMap::new();
+  for path in paths {
+    if let Some(dir) = path.parent() {
+      *per_dir.entry(dir).or_insert(0) += 1;
+    }
+  }
+  // Each entry with the number of paths it stands for.
+  let mut entries: Vec<(String, usize)> = Vec::new();
+  let mut collapsed = HashSet::new();
+  for path in paths {
+    match path.parent() {
+      Some(dir) if per_dir[dir] > 1 => {
+        if collapsed.insert(dir) {
+          let dir_name = if dir.as_os_str().is_empty() {
+            ".".to_string()
+          } else {
+            dir.display().to_string()
+          };
+          let count = format!("({} files)", per_dir[dir]);
+          entries.push((
+            format!(
+              "{}/ {}",
+              paint(colors::bold(&dir_name)),
+              paint(colors::gray(&count))
+            ),
+            per_dir[dir],
+          ));
+        }
+      }
+      _ => entries.push((path.display().to_string(), 1)),
+    }
+  }
+  let shown = entries.len().min(max);
+  let hidden: usize = entries[shown..].iter().map(|(_, count)| count).sum();
+  let mut summary = entries[..shown]
+    .iter()
+    .map(|(entry, _)| entry.as_str())
+    .collect::<Vec<_>>()
+    .join(", ");
+  if hidden > 0 {
+    if !summary.is_empty() {
+      summary.push(' ');
+    }
+    let more = format!("... and {} more", hidden);
+    summary.push_str(&paint(colors::gray(&more)));
+  }
+  summary
+}
+
+/// The session recap of `WatchOptions::summary_on_exit`.
+fn exit_summary(metrics: &WatchMetrics, elapsed: Duration) -> String {
+  let mut summary = format!(
+    "{} Restarted {} time{} in {:.1}s, running {:.1}s. Runs: {} succeeded, \
+    {} failed.",
+    paint(colors::intense_blue("Watcher")),
+    metrics.restarts,
+    if metrics.restarts == 1 { "" } else { "s" },
+    elapsed.as_secs_f64(),
+    metrics.run_time.as_secs_f64(),
+    paint(colors::green(&metrics.successful_runs.to_string())),
+    if metrics.failed_runs == 0 {
+      paint(colors::green("0"))
+    } else {
+      paint(colors::red(&metrics.failed_runs.to_string()))
+    },
+  );
+  let mut most_changed: Vec<(&PathBuf, &u64)> =
+    metrics.path_changes.iter().collect();
+  most_changed.sort_by(|(a, a_count), (b, b_count)| {
+    b_count.cmp(a_count).then_with(|| a.cmp(b))
+  });
+  if !most_changed.is_empty() {
+    let paths: Vec<String> = most_changed
+      .iter()
+      .take(SUMMARY_TOP_PATHS)
+      .map(|(path, count)| format!("{} ({})", path.display(), count))
+      .collect();
+    summary.push_str(&format!(" Most changed: {}", paths.join(", ")));
+  }
+  summary
+}
+
+fn format_paths(paths: &[PathBuf]) -> String {
+  paths
+    .iter()
+    .map(|path| path.display().to_string())
+    .collect::<Vec<_>>()
+    .join(", ")
+}
+
+/// Forwards filtered events from the OS watcher to the debouncer. Shared
+/// between watcher instances so the backend can be swapped. What happens to
+/// each event is logged at trace level, see it with
+/// `RUST_LOG=deno::file_watcher=trace,deno::debounce=trace`.
+struct EventHandler {
+  /// `try_send` needs `&mut`, and the notify callback only has `&self`. The
+  /// lock is held for the `try_send` alone and never across an `.await`; the
+  /// receiving end is owned by the single `Debounce`, which is polled through
+  /// `&mut` without any lock. Taken once the last backend is gone, see
+  /// `BackendGuard`.
+  sender: Mutex<Option<mpsc::Sender<Result<ReceivedEvent, AnyError>>>>,
+  /// The number of live `BackendGuard`s.
+  backends: AtomicUsize,
+  filter: EventFilter,
+  /// `WatchOptions::clock`, which events are stamped with.
+  clock: SharedClock,
+  /// Set when an event was dropped because the channel was full. The next
+  /// batch is then reported no matter what, or a batch of its own once
+  /// nothing else is pending, so that no change is lost under load.
+  dirty: AtomicBool,
+  /// The paths of every dropped event since the last report. Best effort,
+  /// some events carry no paths.
+  dropped: Mutex<Vec<PathBuf>>,
+  /// Wakes the stream when an event is dropped while it waits.
+  waker: AtomicWaker,
+  /// Events are ignored until then, see `WatchOptions::startup_grace`.
+  grace_until: Mutex<Option<tokio::time::Instant>>,
+  /// Set when `WatchOptions::noisy_path_limit` is.
+  noisy_paths: Option<Mutex<NoisyPaths>>,
+  suppressions: Option<Suppressions>,
+  /// Shared with `WatchOptions::diagnostics`, if set, so that the counts
+  /// outlive a restarted watcher.
+  counters: Arc<EventCounters>,
+  /// Those of `WatchOptions::diagnostics`, if set.
+  recent_events: Option<Arc<RecentEvents>>,
+  recent_events_capacity: usize,
+  watch_access: bool,
+  watch_metadata: bool,
+  verbose: bool,
+}
+
+impl EventHandler {
+  fn handle(&self, res: Result<NotifyEvent, NotifyError>) {
+    self.counters.received.fetch_add(1, Ordering::SeqCst);
+    let event = match res {
+      Ok(event) => event,
+      Err(err) => {
+        debug!("Received watcher error: {}", err);
+        self.send(Err(AnyError::from(err)));
+        return;
+      }
+    };
+    trace!(
+      "Received {:?} event: {}",
+      event.kind,
+      format_paths(&event.paths)
+    );
+    let received = self.recent_events.as_ref().map(|_| {
+      (
+        SystemTime::now(),
+        format!("{:?}", event.kind),
+        event.paths.clone(),
+      )
+    });
+    let verdict = match self.check(event) {
+      Ok(event) => {
+        let received = self.clock.now();
+        self.send(Ok(ReceivedEvent { event, received }))
+      }
+      Err(reason) => {
+        self.counters.filtered.fetch_add(1, Ordering::SeqCst);
+        EventVerdict::Ignored(reason)
+      }
+    };
+    if let (Some(recent_events), Some((time, kind, paths))) =
+      (&self.recent_events, received)
+    {
+      let event = RecordedEvent {
+        time,
+        kind,
+        paths,
+        verdict,
+      };
+      recent_events.push(event, self.recent_events_capacity);
+    }
+  }
+
+  /// The event as it is to be forwarded, or why it is dropped.
+  fn check(&self, event: NotifyEvent) -> Result<NotifyEvent, &'static str> {
+    if self.is_in_grace() {
+      trace!("Dropped event during the startup grace period");
+      Err("startup grace period")
+    } else if is_rescan(&event) {
+      // Skip the filter, whatever changed may well be in scope.
+      info!(
+        "{} The file watcher may have missed some changes, restarting to be \
+        safe",
+        paint(colors::yellow("Warning"))
+      );
+      self.record_dropped(Vec::new());
+      Ok(event)
+    } else if is_access(&event) && !self.watch_access {
+      trace!("Dropped access event");
+      Err("access event")
+    } else if is_metadata_change(&event) && !self.watch_metadata {
+      trace!("Dropped metadata event");
+      Err("metadata change")
+    } else {
+      let event = self.filter.filter_with_reason(event).map_err(|reason| {
+        trace!("Dropped event, all of its paths were filtered");
+        reason
+      })?;
+      let event = self.unmute(event).ok_or("noisy path")?;
+      let event = self.unsuppress(event).ok_or("suppressed path")?;
+      trace!("Forwarding event: {}", format_paths(&event.paths));
+      if self.verbose {
+        info!("{}", verbose_event_line(&event, self.watch_access));
+      }
+      Ok(event)
+    }
+  }
+
+  /// Passes the event on to the debouncing.
+  fn send(&self, res: Result<ReceivedEvent, AnyError>) -> EventVerdict {
+    let mut sender = self.sender.lock().unwrap();
+    let sender = match sender.as_mut() {
+      Some(sender) => sender,
+      None => return EventVerdict::Ignored("watcher closed"),
+    };
+    match sender.try_send(res) {
+      Ok(()) => {
+        self.counters.forwarded.fetch_add(1, Ordering::SeqCst);
+        EventVerdict::Forwarded
+      }
+      // Remember the change, the events still queued will trigger a batch
+      // that it is added to.
+      Err(TrySendError::Full(res)) => {
+        debug!("Event channel full, dropping event");
+        let paths = res.map(|received| received.event.paths);
+        self.record_dropped(paths.unwrap_or_default());
+        EventVerdict::Overflowed
+      }
+      // The watcher was already closed, but not all messages have been
+      // flushed.
+      Err(TrySendError::Closed(_)) => EventVerdict::Ignored("watcher closed"),
+    }
+  }
+
+  /// Closes the event channel, so that the stream ends once the events
+  /// already sent have been debounced.
+  fn close(&self) {
+    self.sender.lock().unwrap().take();
+  }
+
+  /// Ignores events for `grace` from now on.
+  fn start_grace(&self, grace: Duration) {
+    *self.grace_until.lock().unwrap() = Some(self.clock.now() + grace);
+  }
+
+  fn is_in_grace(&self) -> bool {
+    let mut grace_until = self.grace_until.lock().unwrap();
+    match *grace_until {
+      Some(until) if self.clock.now() < until => true,
+      Some(_) => {
+        *grace_until = None;
+        false
+      }
+      None => false,
+    }
+  }
+
+  /// Drops the paths of the event that are ignored for being noisy, and the
+  /// event if none are left.
+  fn unmute(&self, mut event: NotifyEvent) -> Option<NotifyEvent> {
+    let noisy_paths = match &self.noisy_paths {
+      Some(noisy_paths) => noisy_paths,
+      None => return Some(event),
+    };
+    let mut noisy_paths = noisy_paths.lock().unwrap();
+    let now = Instant::now();
+    event.paths.retain(|path| !noisy_paths.is_muted(path, now));
+    if event.paths.is_empty() {
+      trace!("Dropped event, all of its paths are noisy");
+      None
+    } else {
+      Some(event)
+    }
+  }
+
+  /// Drops the paths of the event that are suppressed, and the event if
+  /// none are left.
+  fn unsuppress(&self, mut event: NotifyEvent) -> Option<NotifyEvent> {
+    let suppressions = match &self.suppressions {
+      Some(suppressions) => suppressions,
+      None => return Some(event),
+    };
+    event.paths.retain(|path| !suppressions.is_suppressed(path));
+    if event.paths.is_empty() {
+      trace!("Dropped event, all of its paths are suppressed");
+      None
+    } else {
+      Some(event)
+    }
+  }
+
+  /// Makes sure the next batch is reported, with the given paths added.
+  fn record_dropped(&self, paths: Vec<PathBuf>) {
+    let mut dropped = self.dropped.lock().unwrap();
+    dropped.extend(paths);
+    self.dirty.store(true, Ordering::SeqCst);
+    drop(dropped);
+    self.waker.wake();
+  }
+
+  fn is_dirty(&self) -> bool {
+    self.dirty.load(Ordering::SeqCst)
+  }
+
+  fn take_overflow(&self) -> Option<Vec<PathBuf>> {
+    let mut dropped = self.dropped.lock().unwrap();
+    if self.dirty.swap(false, Ordering::SeqCst) {
+      Some(std::mem::take(&mut *dropped))
+    } else {
+      None
+    }
+  }
+}
+
+/// The event rate of each path, for ignoring the paths that exceed
+/// `WatchOptions::noisy_path_limit` for a while.
+struct NoisyPaths {
+  limit: u32,
+  cooldown: Duration,
+  /// The start of the current one second window of each path, and the
+  /// events it has seen so far.
+  counts: HashMap<PathBuf, (Instant, u32)>,
+  /// The paths being ignored, until when.
+  muted: HashMap<PathBuf, Instant>,
+}
+
+impl NoisyPaths {
+  fn new(limit: u32, cooldown: Duration) -> Self {
+    Self {
+      limit,
+      cooldown,
+      counts: HashMap::new(),
+      muted: HashMap::new(),
+    }
+  }
+
+  /// Counts an event for `path`, returning whether it is ignored.
+  fn is_muted(&mut self, path: &Path, now: Instant) -> bool {
+    if let Some(until) = self.muted.get(path) {
+      if now < *until {
+        return true;
+      }
+      debug!("Watching {} again", path.display());
+      self.muted.remove(path);
+    }
+    let window = Duration::from_secs(1);
+    let count = self.counts.entry(path.to_path_buf()).or_insert((now, 0));
+    if now.duration_since(count.0) >= window {
+      *count = (now, 0);
+    }
+    count.1 += 1;
+    if count.1 <= self.limit {
+      return false;
+    }
+    info!(
+      "{} {} changes more than {} times a second, ignoring it for {}ms",
+      paint(colors::yellow("Warning")),
+      path.display(),
+      self.limit,
+      self.cooldown.as_millis()
+    );
+    self.counts.remove(path);
+    self.muted.insert(path.to_path_buf(), now + self.cooldown);
+    true
+  }
+}
+
+/// Whether the backend reports that it lost events, e.g. because the inotify
+/// queue overflowed, and that anything may have changed.
+fn is_rescan(event: &NotifyEvent) -> bool {
+  matches!(event.flag(), Some(Flag::Rescan))
+}
+
+fn is_access(event: &NotifyEvent) -> bool {
+  matches!(event.kind, EventKind::Access(_))
+}
+
+fn is_metadata_change(event: &NotifyEvent) -> bool {
+  matches!(event.kind, EventKind::Modify(ModifyKind::Metadata(_)))
+}
+
+/// The kind of change the event counts as, `None` for access events unless
+/// they are watched.
+fn event_change_kind(
+  event: &NotifyEvent,
+  watch_access: bool,
+) -> Option<ChangeKind> {
+  if watch_access && is_access(event) {
+    Some(ChangeKind::Accessed)
+  } else {
+    ChangeKind::from_event_kind(&event.kind)
+  }
+}
+
+/// The line `WatchOptions::verbose` prints for an event.
+fn verbose_event_line(event: &NotifyEvent, watch_access: bool) -> String {
+  let kind = event_change_kind(event, watch_access)
+    .map(ChangeKind::as_str)
+    .unwrap_or("accessed");
+  format!(
+    "{} {} {}",
+    paint(colors::intense_blue("Watcher")),
+    paint(colors::gray(kind)),
+    format_paths(&event.paths)
+  )
+}
+
+/// Either of the notify watchers selected through `WatchBackend`, or one
+/// built by a `WatcherFactory`.
+enum AnyWatcher {
+  Native(RecommendedWatcher),
+  Poll(PollWatcher),
+  Custom(Box<dyn DynWatcher>),
+}
+
+/// Keeps the event channel of an `EventHandler` open. Each backend owns one
+/// along with its callback, which notify drops when the backend goes away,
+/// e.g. because its thread panicked, so the channel closes with the last
+/// backend and the watcher is restarted.
+struct BackendGuard(Arc<EventHandler>);
+
+impl BackendGuard {
+  fn new(handler: Arc<EventHandler>) -> Self {
+    handler.backends.fetch_add(1, Ordering::SeqCst);
+    Self(handler)
+  }
+
+  fn handle(&self, res: Result<NotifyEvent, NotifyError>) {
+    self.0.handle(res)
+  }
+}
+
+impl Drop for BackendGuard {
+  fn drop(&mut self) {
+    if self.0.backends.fetch_sub(1, Ordering::SeqCst) == 1 {
+      self.0.close();
+    }
+  }
+}
+
+impl AnyWatcher {
+  fn native(
+    handler: Arc<EventHandler>,
+    config: &WatchConfig,
+  ) -> Result<Self, NotifyError> {
+    let guard = BackendGuard::new(handler);
+    let mut watcher: RecommendedWatcher =
+      Watcher::new_immediate(move |res: Result<NotifyEvent, NotifyError>| {
+        guard.handle(res)
+      })?;
+    // Backends that don't know a setting ignore it, and the watcher works
+    // without any of them.
+    match watcher.configure(Config::PreciseEvents(config.precise_events)) {
+      Ok(true) => {}
+      Ok(false) => debug!("Precise events not supported by the file watcher"),
+      Err(err) => info!(
+        "{} Failed to configure precise events of the file watcher: {}",
+        paint(colors::yellow("Warning")),
+        err
+      ),
+    }
+    Ok(AnyWatcher::Native(watcher))
+  }
+
+  fn poll(
+    handler: Arc<EventHandler>,
+    interval: Duration,
+  ) -> Result<Self, NotifyError> {
+    let guard = BackendGuard::new(handler);
+    let event_fn =
+      Arc::new(Mutex::new(move |res: Result<NotifyEvent, NotifyError>| {
+        guard.handle(res)
+      }));
+    Ok(AnyWatcher::Poll(PollWatcher::with_delay(
+      event_fn, interval,
+    )?))
+  }
+
+  fn custom(
+    handler: Arc<EventHandler>,
+    factory: &WatcherFactory,
+  ) -> Result<Self, NotifyError> {
+    let guard = BackendGuard::new(handler);
+    let watcher =
+      (factory.0)(Box::new(move |res: Result<NotifyEvent, NotifyError>| {
+        guard.handle(res)
+      }))?;
+    Ok(AnyWatcher::Custom(watcher))
+  }
+
+  fn watch(
+    &mut self,
+    path: &Path,
+    recursive_mode: RecursiveMode,
+  ) -> Result<(), NotifyError> {
+    match self {
+      AnyWatcher::Native(watcher) => watcher.watch(path, recursive_mode),
+      AnyWatcher::Poll(watcher) => watcher.watch(path, recursive_mode),
+      AnyWatcher::Custom(watcher) => watcher.watch_path(path, recursive_mode),
+    }
+  }
+
+  fn unwatch(&mut self, path: &Path) -> Result<(), NotifyError> {
+    match self {
+      AnyWatcher::Native(watcher) => watcher.unwatch(path),
+      AnyWatcher::Poll(watcher) => watcher.unwatch(path),
+      AnyWatcher::Custom(watcher) => watcher.unwatch_path(path),
+    }
+  }
+
+  /// Watches each registration, returning the requested paths that failed.
+  fn watch_all(
+    &mut self,
+    registrations: &[Registration],
+  ) -> Vec<(PathBuf, NotifyError)> {
+    registrations
+      .iter()
+      .filter_map(|r| {
+        self
+          .watch(&r.watched, r.recursive_mode)
+          .err()
+          .map(|err| (r.path.clone(), err))
+      })
+      .collect()
+  }
+}
+
+/// A requested path and what is handed to the OS watcher for it. A path that
+/// doesn't exist yet is covered by watching its nearest existing ancestor.
+/// A file is covered by watching its directory, which unlike a watch of the
+/// file itself survives the file being replaced.
+struct Registration {
+  path: PathBuf,
+  watched: PathBuf,
+  recursive_mode: RecursiveMode,
+  watches_parent: bool,
+}
+
+impl Registration {
+  fn new(path: &Path, options: &WatchOptions) -> Self {
+    let ancestor = if path.exists() {
+      None
+    } else {
+      nearest_existing_ancestor(path)
+    };
+    match ancestor {
+      Some(ancestor) => {
+        // Directories between the ancestor and the path are missing too, so
+        // their creation has to be seen as well.
+        let recursive_mode = if path.parent() == Some(ancestor.as_path()) {
+          RecursiveMode::NonRecursive
+        } else {
+          RecursiveMode::Recursive
+        };
+        Self {
+          path: path.to_path_buf(),
+          watched: ancestor,
+          recursive_mode,
+          watches_parent: false,
+        }
+      }
+      None => match path.parent() {
+        Some(parent) if path.is_file() && !parent.as_os_str().is_empty() => {
+          Self {
+            path: path.to_path_buf(),
+            watched: parent.to_path_buf(),
+            recursive_mode: RecursiveMode::NonRecursive,
+            watches_parent: true,
+          }
+        }
+        _ => Self {
+          path: path.to_path_buf(),
+          watched: path.to_path_buf(),
+          recursive_mode: options.recursive_mode(path),
+          watches_parent: false,
+        },
+      },
+    }
+  }
+
+  /// Whether an ancestor is watched because the path doesn't exist yet.
+  fn is_pending(&self) -> bool {
+    self.watched != self.path && !self.watches_parent
+  }
+
+  /// Whether events for other paths below the watched one are seen, and
+  /// have to be filtered out.
+  fn watches_ancestor(&self) -> bool {
+    self.watched != self.path
+  }
+}
+
+const SPECIAL_FILE_ERROR: &str = "not a regular file or directory";
+
+/// Whether the path is a socket, FIFO or device, which can't be watched
+/// meaningfully, and which the poll watcher could block on. Symlinks are
+/// judged by their target, and missing paths aren't special.
+fn is_special_file(path: &Path) -> bool {
+  let file_type = match std::fs::symlink_metadata(path) {
+    Ok(metadata) if metadata.file_type().is_symlink() => {
+      match std::fs::metadata(path) {
+        Ok(metadata) => metadata.file_type(),
+        Err(_) => return false,
+      }
+    }
+    Ok(metadata) => metadata.file_type(),
+    Err(_) => return false,
+  };
+  !file_type.is_file() && !file_type.is_dir()
+}
+
+fn nearest_existing_ancestor(path: &Path) -> Option<PathBuf> {
+  path
+    .ancestors()
+    .skip(1)
+    .find(|ancestor| !ancestor.as_os_str().is_empty() && ancestor.is_dir())
+    .map(Path::to_path_buf)
+}
+
+/// Moves the watch of each pending path that has since been created from its
+/// ancestor onto the path itself, or its directory for a file. An ancestor
+/// stays watched as long as it still stands in for another path.
+fn watch_created_paths(
+  watcher: &mut AnyWatcher,
+  registrations: &mut [Registration],
+  options: &WatchOptions,
+) {
+  for i in 0..registrations.len() {
+    let registration = &registrations[i];
+    if !registration.is_pending() || !registration.path.exists() {
+      continue;
+    }
+    let path = registration.path.clone();
+    let replacement = Registration::new(&path, options);
+    if replacement.watched != registration.watched {
+      if let Err(err) =
+        watcher.watch(&replacement.watched, replacement.recursive_mode)
+      {
+        debug!("Failed to watch created path {}: {}", path.display(), err);
+        continue;
+      }
+    }
+    let ancestor =
+      std::mem::replace(&mut registrations[i], replacement).watched;
+    if !registrations.iter().any(|r| r.watched == ancestor) {
+      let _ = watcher.unwatch(&ancestor);
+    }
+  }
+}
+
+/// Watches each directory below `dir` down to `levels` levels on its own,
+/// recording them in `subdirs`.
+fn watch_subdirs(
+  watcher: &mut AnyWatcher,
+  dir: &Path,
+  levels: usize,
+  subdirs: &mut BTreeSet<PathBuf>,
+) {
+  if levels == 0 {
+    return;
+  }
+  let entries = WalkDir::new(dir)
+    .min_depth(1)
+    .max_depth(levels)
+    .into_iter()
+    .filter_map(Result::ok)
+    .filter(|entry| entry.file_type().is_dir());
+  for entry in entries {
+    let path = entry.into_path();
+    if subdirs.contains(&path) {
+      continue;
+    }
+    match watcher.watch(&path, RecursiveMode::NonRecursive) {
+      Ok(()) => {
+        subdirs.insert(path);
+      }
+      Err(err) => debug!("Failed to watch {}: {}", path.display(), err),
+    }
+  }
+}
+
+/// A watched path that is a symlink. Changes to its target and its being
+/// retargeted need different watches, and the registration only covers one
+/// of them: a link to a file is covered by its directory, and a link to a
+/// directory by the target the OS watcher follows it to.
+struct LinkRoot {
+  link: PathBuf,
+  /// Canonical.
+  target: PathBuf,
+  to_dir: bool,
+  /// The watch the registration lacks: the target of a link to a file, the
+  /// directory of a link to a directory.
+  watched: PathBuf,
+}
+
+/// Adds the watches the registrations that are symlinks lack.
+fn watch_link_roots(
+  watcher: &mut AnyWatcher,
+  registrations: &[Registration],
+) -> Vec<LinkRoot> {
+  let mut links = Vec::new();
+  for registration in registrations.iter().filter(|r| !r.is_pending()) {
+    match std::fs::symlink_metadata(&registration.path) {
+      Ok(metadata) if metadata.file_type().is_symlink() => {}
+      _ => continue,
+    }
+    let target = match registration.path.canonicalize() {
+      Ok(target) => target,
+      Err(_) => continue,
+    };
+    let to_dir = !registration.watches_parent;
+    let watched = if to_dir {
+      match registration.path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+        _ => continue,
+      }
+    } else {
+      target.clone()
+    };
+    match watcher.watch(&watched, RecursiveMode::NonRecursive) {
+      Ok(()) => {
+        debug!(
+          "Watching symlink {} to {}",
+          registration.path.display(),
+          target.display()
+        );
+        links.push(LinkRoot {
+          link: registration.path.clone(),
+          target,
+          to_dir,
+          watched,
+        });
+      }
+      Err(err) => debug!("Failed to watch {}: {}", watched.display(), err),
+    }
+  }
+  links
+}
+
+/// Watches the targets of the symlinked directories below the recursively
+/// watched registrations, returning each target with the symlink's path.
+/// Targets already covered by another watch are skipped, which also guards
+/// against symlink cycles.
+fn watch_symlink_targets(
+  watcher: &mut AnyWatcher,
+  registrations: &[Registration],
+  subdirs: &mut BTreeSet<PathBuf>,
+  options: &WatchOptions,
+) -> Vec<(PathBuf, PathBuf)> {
+  let roots: Vec<&Registration> = registrations
+    .iter()
+    .filter(|r| {
+      !r.is_pending()
+        && options.requested_recursive_mode(&r.path) == RecursiveMode::Recursive
+    })
+    .collect();
+  let mut visited: Vec<PathBuf> = roots
+    .iter()
+    .filter_map(|r| r.path.canonicalize().ok())
+    .collect();
+  let mut symlinks = Vec::new();
+  for root in roots {
+    let depth_limit = options.depth_limit(&root.path);
+    // Loops are reported as errors by `WalkDir` and skipped.
+    let entries = WalkDir::new(&root.path)
+      .min_depth(1)
+      .max_depth(depth_limit.unwrap_or(std::usize::MAX))
+      .follow_links(true)
+      .into_iter()
+      .filter_map(Result::ok)
+      .filter(|entry| entry.path_is_symlink() && entry.file_type().is_dir());
+    for entry in entries {
+      let target = match entry.path().canonicalize() {
+        Ok(target) => target,
+        Err(_) => continue,
+      };
+      if visited.iter().any(|dir| target.starts_with(dir)) {
+        continue;
+      }
+      let link = entry.path().to_path_buf();
+      let result = match depth_limit {
+        Some(limit) => {
+          let levels = limit - entry.depth();
+          watcher
+            .watch(&target, RecursiveMode::NonRecursive)
+            .map(|()| watch_subdirs(watcher, &link, levels, subdirs))
+        }
+        None => watcher.watch(&target, RecursiveMode::Recursive),
+      };
+      match result {
+        Ok(()) => {
+          debug!("Following {} to {}", link.display(), target.display());
+          visited.push(target.clone());
+          symlinks.push((target, link));
+        }
+        Err(err) => debug!("Failed to watch {}: {}", target.display(), err),
+      }
+    }
+  }
+  symlinks
+}
+
+/// Extends depth limited watches to directories created within the limit.
+fn watch_created_subdirs(
+  watcher: &mut AnyWatcher,
+  registrations: &[Registration],
+  subdirs: &mut BTreeSet<PathBuf>,
+  created: &BTreeSet<PathBuf>,
+  options: &WatchOptions,
+) {
+  for path in created.iter().filter(|path| path.is_dir()) {
+    for registration in registrations.iter().filter(|r| !r.is_pending()) {
+      let limit = match options.depth_limit(&registration.path) {
+        Some(limit) => limit,
+        None => continue,
+      };
+      let depth = match path.strip_prefix(&registration.path) {
+        Ok(relative) => relative.components().count(),
+        Err(_) => continue,
+      };
+      if depth > limit {
+        continue;
+      }
+      // A recreated root is watched along with its registration.
+      if depth > 0 && !subdirs.contains(path) {
+        match watcher.watch(path, RecursiveMode::NonRecursive) {
+          Ok(()) => {
+            subdirs.insert(path.clone());
+          }
+          Err(err) => {
+            debug!("Failed to watch {}: {}", path.display(), err);
+            continue;
+          }
+        }
+      }
+      watch_subdirs(watcher, path, limit - depth, subdirs);
+    }
+  }
+}
+
+/// Whether a watch error means the native backend can't serve the path, as
+/// opposed to the path itself being invalid.
+fn is_backend_error(err: &NotifyError) -> bool {
+  match &err.kind {
+    notify::ErrorKind::PathNotFound => false,
+    notify::ErrorKind::Io(err) => err.kind() != std::io::ErrorKind::NotFound,
+    _ => true,
+  }
+}
+
+/// Whether the OS refused a watch because too many files are watched, as
+/// inotify does with `ENOSPC` once `fs.inotify.max_user_watches` is reached.
+/// Other platforms have no such limit.
+#[cfg(target_os = "linux")]
+fn is_watch_limit_error(err: &NotifyError) -> bool {
+  match &err.kind {
+    notify::ErrorKind::Io(err) => err.raw_os_error() == Some(libc::ENOSPC),
+    _ => false,
+  }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn is_watch_limit_error(_err: &NotifyError) -> bool {
+  false
+}
+
+fn warn_watch_limit() {
+  info!(
+    "{} The OS limit on watched files was reached, polling for changes \
+     instead. On Linux, raise the limit with \
+     `sysctl fs.inotify.max_user_watches=524288`.",
+    paint(colors::yellow("Warning"))
+  );
+}
+
+/// Replaces a native watcher that ran into the OS watch limit with a
+/// polling one, which has no such limit, rather than leaving paths
+/// unwatched.
+fn fall_back_on_watch_limit<F>(
+  native: AnyWatcher,
+  failed_paths: Vec<(PathBuf, NotifyError)>,
+  registrations: &[Registration],
+  poll: F,
+) -> Result<(AnyWatcher, Vec<(PathBuf, NotifyError)>), NotifyError>
+where
+  F: FnOnce() -> Result<AnyWatcher, NotifyError>,
+{
+  if !failed_paths
+    .iter()
+    .any(|(_, err)| is_watch_limit_error(err))
+  {
+    return Ok((native, failed_paths));
+  }
+  warn_watch_limit();
+  // Dropping the native watcher first would close the event channel, see
+  // `BackendGuard`.
+  let mut watcher = poll()?;
+  drop(native);
+  let failed_paths = watcher.watch_all(registrations);
+  Ok((watcher, failed_paths))
+}
+
+/// Re-establishes the watch of each directly watched path that was removed
+/// or renamed over, as editors do when saving atomically. A path that is
+/// gone for good, like a root directory deleted by `git clean`, falls back
+/// to its nearest existing ancestor until it is recreated, while the other
+/// paths keep being watched.
+fn rewatch_replaced_paths(
+  watcher: &mut AnyWatcher,
+  registrations: &mut [Registration],
+  replaced: &BTreeSet<PathBuf>,
+  options: &WatchOptions,
+) {
+  for registration in registrations.iter_mut() {
+    // The watch of a file's directory outlives the file.
+    let watched_path = if registration.watches_parent {
+      &registration.watched
+    } else {
+      &registration.path
+    };
+    if registration.is_pending() || !replaced.contains(watched_path) {
+      continue;
+    }
+    // The old watch may already be gone along with the replaced inode.
+    let _ = watcher.unwatch(&registration.watched);
+    let replacement = Registration::new(&registration.path, options);
+    if let Err(err) =
+      watcher.watch(&replacement.watched, replacement.recursive_mode)
+    {
+      info!(
+        "{} Failed to watch {} again: {}",
+        paint(colors::yellow("Warning")),
+        registration.path.display(),
+        err
+      );
+    } else if replacement.is_pending() {
+      info!(
+        "{} {} was removed, it will be watched again once it reappears",
+        paint(colors::yellow("Warning")),
+        registration.path.display()
+      );
+    }
+    *registration = replacement;
+  }
+}
+
+/// A running OS watcher and its debounced event stream, together with the
+/// paths that could not be watched.
+struct WatchSetup {
+  watcher: AnyWatcher,
+  handler: Arc<EventHandler>,
+  debounce: EventDebounce,
+  registrations: Vec<Registration>,
+  failed_paths: Vec<(PathBuf, AnyError)>,
+}
+
+/// An event on its way from the `EventHandler` to the debouncer, with when
+/// the handler got it. The debouncer measures its windows from then rather
+/// than from when it takes the event, which may be much later if events
+/// queue up while the stream isn't polled.
+struct ReceivedEvent {
+  event: NotifyEvent,
+  received: tokio::time::Instant,
+}
+
+impl ReceivedEvent {
+  fn received(&self) -> tokio::time::Instant {
+    self.received
+  }
+
+  fn is_same(a: &Self, b: &Self) -> bool {
+    is_same_event(&a.event, &b.event)
+  }
+}
+
+fn into_events(received: Vec<ReceivedEvent>) -> Vec<NotifyEvent> {
+  received
+    .into_iter()
+    .map(|received| received.event)
+    .collect()
+}
+
+/// The debouncer `WatchOptions::debounce_scope` selects, grouping events by
+/// the innermost root they are below for `PerRoot`.
+enum EventDebounce {
+  Global(Debounce<ReceivedEvent>),
+  PerRoot(GroupedDebounce<ReceivedEvent, Option<PathBuf>>),
+}
+
+impl EventDebounce {
+  fn take_pending(&mut self) -> Vec<NotifyEvent> {
+    into_events(match self {
+      EventDebounce::Global(debounce) => debounce.take_pending(),
+      EventDebounce::PerRoot(debounce) => debounce.take_pending(),
+    })
+  }
+
+  fn is_idle(&self) -> bool {
+    match self {
+      EventDebounce::Global(debounce) => debounce.is_idle(),
+      EventDebounce::PerRoot(debounce) => debounce.is_idle(),
+    }
+  }
+
+  fn time_until_yield(&self) -> Option<Duration> {
+    match self {
+      EventDebounce::Global(debounce) => debounce.time_until_yield(),
+      EventDebounce::PerRoot(debounce) => debounce.time_until_yield(),
+    }
+  }
+
+  fn last_window(&self) -> Option<Duration> {
+    match self {
+      EventDebounce::Global(debounce) => debounce.last_window(),
+      EventDebounce::PerRoot(debounce) => debounce.last_window(),
+    }
+  }
+
+  fn last_items(&self) -> Option<(Instant, Instant)> {
+    let items = match self {
+      EventDebounce::Global(debounce) => debounce.last_items(),
+      EventDebounce::PerRoot(debounce) => debounce.last_items(),
+    };
+    items.map(|(first, last)| (first.into_std(), last.into_std()))
+  }
+}
+
+impl Stream for EventDebounce {
+  type Item = Result<Vec<NotifyEvent>, AnyError>;
+
+  fn poll_next(
+    self: Pin<&mut Self>,
+    cx: &mut Context,
+  ) -> Poll<Option<Self::Item>> {
+    match self.get_mut() {
+      EventDebounce::Global(debounce) => debounce
+        .poll_next_unpin(cx)
+        .map(|item| item.map(|result| result.map(into_events))),
+      EventDebounce::PerRoot(debounce) => {
+        debounce.poll_next_unpin(cx).map(|item| {
+          item.map(|result| result.map(|(_, events)| into_events(events)))
+        })
+      }
+    }
+  }
+}
+
+/// Creates the handler events enter through and the debounced stream they
+/// come out of. `new_watcher` feeds the handler from a notify watcher; tests
+/// can call `EventHandler::handle` directly with scripted events.
+fn event_pipeline(
+  registrations: &[Registration],
+  options: &WatchOptions,
+) -> Result<(Arc<EventHandler>, EventDebounce), AnyError> {
+  let capacity = options
+    .channel_capacity
+    .unwrap_or(EVENT_CHANNEL_CAPACITY)
+    .max(1);
+  let (sender, receiver) =
+    mpsc::channel::<Result<ReceivedEvent, AnyError>>(capacity);
+  let handler = Arc::new(EventHandler {
+    sender: Mutex::new(Some(sender)),
+    backends: AtomicUsize::new(0),
+    filter: EventFilter::new(registrations, options)?,
+    clock: options.clock.clone(),
+    dirty: AtomicBool::new(false),
+    dropped: Mutex::new(Vec::new()),
+    waker: AtomicWaker::new(),
+    grace_until: Mutex::new(None),
+    noisy_paths: options.noisy_path_limit.map(|limit| {
+      let cooldown = options.noisy_path_cooldown.unwrap_or(NOISY_PATH_COOLDOWN);
+      Mutex::new(NoisyPaths::new(limit, cooldown))
+    }),
+    suppressions: options.suppressions.clone(),
+    counters: options
+      .diagnostics
+      .as_ref()
+      .map(|diagnostics| diagnostics.events.clone())
+      .unwrap_or_default(),
+    recent_events: options
+      .diagnostics
+      .as_ref()
+      .map(|diagnostics| diagnostics.recent_events.clone()),
+    recent_events_capacity: options
+      .recent_events
+      .unwrap_or(RECENT_EVENTS_CAPACITY),
+    watch_access: options.watch_access,
+    watch_metadata: options.watch_metadata,
+    verbose: options.verbose,
+  });
+  let debounce = match options.debounce_scope {
+    DebounceScope::Global => EventDebounce::Global(
+      Debounce::with_predicate(
+        receiver,
+        options.debounce_time(),
+        ReceivedEvent::is_same,
+      )
+      .arrival(ReceivedEvent::received)
+      .strategy(options.debounce_strategy)
+      .anchor(options.debounce_anchor)
+      .max_debounce(options.max_debounce)
+      .confirm_quiet(options.confirm_quiet)
+      .clock(options.clock.clone()),
+    ),
+    DebounceScope::PerRoot => {
+      let key_handler = handler.clone();
+      let key = move |received: &ReceivedEvent| {
+        received
+          .event
+          .paths
+          .first()
+          .and_then(|path| key_handler.filter.root_of(path))
+      };
+      EventDebounce::PerRoot(
+        GroupedDebounce::with_key(
+          receiver,
+          options.debounce_time(),
+          key,
+          ReceivedEvent::is_same,
+        )
+        .arrival(ReceivedEvent::received)
+        .clock(options.clock.clone()),
+      )
+    }
+  };
+  Ok((handler, debounce))
+}
+
+/// What makes two events the same to the debouncer: their kind and their
+/// paths in any order, but not attributes such as the info or source the
+/// backend attached. Renames keep their tracker, which pairs their ends.
+///
+/// This runs for every pair of an incoming event and one already in the
+/// batch, so it compares in place rather than building keys. Events carry
+/// one or two paths, for which the quadratic lookup is cheapest.
+fn is_same_event(a: &NotifyEvent, b: &NotifyEvent) -> bool {
+  if a.kind != b.kind || a.paths.len() != b.paths.len() {
+    return false;
+  }
+  if let EventKind::Modify(ModifyKind::Name(_)) = a.kind {
+    if a.tracker() != b.tracker() {
+      return false;
+    }
+  }
+  a.paths == b.paths
+    || (a.paths.iter().all(|path| b.paths.contains(path))
+      && b.paths.iter().all(|path| a.paths.contains(path)))
+}
+
+/// Resolves a relative `path` against `cwd`, the working directory the
+/// watcher was set up in, so that changing the working directory later does
+/// not move the watch. A relative path of which nothing exists below `cwd` is
+/// left as it is, as there is nothing to watch instead of it.
+fn absolute_path(path: &Path, cwd: &Path) -> PathBuf {
+  if path.is_absolute() {
+    return path.to_path_buf();
+  }
+  let absolute = cwd.join(path);
+  let reachable = absolute.exists()
+    || path.ancestors().skip(1).any(|ancestor| {
+      !ancestor.as_os_str().is_empty() && cwd.join(ancestor).is_dir()
+    });
+  if reachable {
+    absolute
+  } else {
+    path.to_path_buf()
+  }
+}
+
+/// Spells `path` relative again if it is below a root that was given as a
+/// relative path, the innermost one if there are several.
+fn given_path(path: &Path, relative_roots: &[(PathBuf, PathBuf)]) -> PathBuf {
+  relative_roots
+    .iter()
+    .filter_map(|(absolute, given)| {
+      path
+        .strip_prefix(absolute)
+        .ok()
+        .map(|suffix| (absolute.components().count(), given.join(suffix)))
+    })
+    .max_by_key(|(depth, _)| *depth)
+    .map(|(_, path)| path)
+    .unwrap_or_else(|| path.to_path_buf())
+}
+
+/// Drops paths that another path already covers: duplicates and descendants
+/// of a recursively watched directory. Paths are compared in canonical form
+/// so that differently spelled paths still overlap, but are kept as given.
+/// Paths that can't be canonicalized, e.g. because they don't exist yet, are
+/// compared as they are.
+fn dedup_paths(paths: &[PathBuf], options: &WatchOptions) -> Vec<PathBuf> {
+  let canonical: Vec<PathBuf> = paths
+    .iter()
+    .map(|path| std::fs::canonicalize(path).unwrap_or_else(|_| path.clone()))
+    .collect();
+  let mut kept = Vec::new();
+  for (i, path) in paths.iter().enumerate() {
+    let covered_by = (0..paths.len()).find(|&j| {
+      if canonical[i] == canonical[j] {
+        j < i
+      } else {
+        canonical[i].starts_with(&canonical[j])
+          && options.recursive_mode(&paths[j]) == RecursiveMode::Recursive
+      }
+    });
+    match covered_by {
+      Some(j) => debug!(
+        "Not watching {} separately, it is covered by {}",
+        path.display(),
+        paths[j].display()
+      ),
+      None => kept.push(path.clone()),
+    }
+  }
+  kept
+}
+
+/// Watches every path it can, including paths that don't exist yet. Fails
+/// only if none of the given paths could be watched, in which case the first
+/// error is returned.
+fn new_watcher(
+  paths: &[PathBuf],
+  options: &WatchOptions,
+) -> Result<WatchSetup, AnyError> {
+  let (special, paths): (Vec<PathBuf>, Vec<PathBuf>) =
+    dedup_paths(paths, options)
+      .into_iter()
+      .partition(|path| is_special_file(path));
+  let registrations: Vec<Registration> = paths
+    .iter()
+    .map(|path| Registration::new(path, options))
+    .collect();
+  let (handler, debounce) = event_pipeline(&registrations, options)?;
+  // A native watcher given up on must not close the event channel before
+  // the polling one replacing it is set up.
+  let _setup = BackendGuard::new(handler.clone());
+
+  let (watcher, failed_paths) = if let Some(factory) = &options.watcher {
+    let mut watcher = AnyWatcher::custom(handler.clone(), factory)?;
+    let failed_paths = watcher.watch_all(&registrations);
+    (watcher, failed_paths)
+  } else {
+    match options.backend {
+      WatchBackend::Native => {
+        let mut watcher =
+          AnyWatcher::native(handler.clone(), &options.watch_config)?;
+        let failed_paths = watcher.watch_all(&registrations);
+        fall_back_on_watch_limit(watcher, failed_paths, &registrations, || {
+          AnyWatcher::poll(handler.clone(), DEFAULT_POLL_INTERVAL)
+        })?
+      }
+      WatchBackend::Poll { interval } => {
+        let mut watcher = AnyWatcher::poll(handler.clone(), interval)?;
+        let failed_paths = watcher.watch_all(&registrations);
+        (watcher, failed_paths)
+      }
+      WatchBackend::Auto => {
+        let native = AnyWatcher::native(handler.clone(), &options.watch_config)
+          .and_then(|mut watcher| {
+            let failed_paths = watcher.watch_all(&registrations);
+            match failed_paths.iter().find(|(_, err)| is_backend_error(err)) {
+              Some((_, err)) => {
+                if is_watch_limit_error(err) {
+                  warn_watch_limit();
+                }
+                Err(NotifyError::generic(&err.to_string()))
+              }
+              None => Ok((watcher, failed_paths)),
+            }
+          });
+        match native {
+          Ok(native) => native,
+          Err(err) => {
+            debug!("Native file watcher unavailable, polling instead: {}", err);
+            let mut watcher =
+              AnyWatcher::poll(handler.clone(), DEFAULT_POLL_INTERVAL)?;
+            let failed_paths = watcher.watch_all(&registrations);
+            (watcher, failed_paths)
+          }
+        }
+      }
+    }
+  };
+
+  let requested = registrations.len() + special.len();
+  let mut failed_paths: Vec<(PathBuf, AnyError)> = special
+    .into_iter()
+    .map(|path| (path, generic_error(SPECIAL_FILE_ERROR)))
+    .chain(
+      failed_paths
+        .into_iter()
+        .map(|(path, err)| (path, AnyError::from(err))),
+    )
+    .collect();
+  if requested > 0 && failed_paths.len() == requested {
+    let (_, err) = failed_paths.remove(0);
+    return Err(err);
+  }
+
+  Ok(WatchSetup {
+    watcher,
+    handler,
+    debounce,
+    registrations,
+    failed_paths,
+  })
+}
+
+/// Paths the batch reports changes to directories for. A path that is
+/// gone by now only counts if its event says it was a directory.
+fn dir_paths(batch: &[NotifyEvent]) -> BTreeSet<PathBuf> {
+  batch
+    .iter()
+    .flat_map(|event| {
+      let is_dir_event = matches!(
+        event.kind,
+        EventKind::Create(CreateKind::Folder)
+          | EventKind::Remove(RemoveKind::Folder)
+      );
+      event
+        .paths
+        .iter()
+        .filter(move |path| is_dir_event || path.is_dir())
+        .cloned()
+    })
+    .collect()
+}
+
+/// Paths that were removed or renamed in the batch. A replaced file has to be
+/// watched again, since the old watch followed the file that is gone.
+fn replaced_paths(batch: &[NotifyEvent]) -> BTreeSet<PathBuf> {
+  batch
+    .iter()
+    .filter(|event| {
+      matches!(
+        ChangeKind::from_event_kind(&event.kind),
+        Some(ChangeKind::Removed) | Some(ChangeKind::Renamed)
+      )
+    })
+    .flat_map(|event| event.paths.iter().cloned())
+    .collect()
+}
+
+/// Paths that were created or renamed into place in the batch.
+fn created_paths(batch: &[NotifyEvent]) -> BTreeSet<PathBuf> {
+  batch
+    .iter()
+    .filter(|event| {
+      matches!(
+        ChangeKind::from_event_kind(&event.kind),
+        Some(ChangeKind::Created) | Some(ChangeKind::Renamed)
+      )
+    })
+    .flat_map(|event| event.paths.iter().cloned())
+    .collect()
+}
+
+/// Collects the paths touched by events that changed anything, or returns
+/// `None` if the batch only contained access events.
+fn change_batch(
+  events: Vec<NotifyEvent>,
+  watch_access: bool,
+) -> Option<ChangeBatch> {
+  let path_kinds = net_path_kinds(&events, watch_access);
+  let mut kinds = BTreeSet::new();
+  let mut changed_paths = BTreeSet::new();
+  let mut renames = Vec::new();
+  // Backends that see both ends of a rename report the old path first,
+  // with the same tracker as the new one.
+  let mut renamed_from: Vec<(Option<usize>, PathBuf)> = Vec::new();
+  for event in events {
+    match (&event.kind, event.paths.as_slice()) {
+      (EventKind::Modify(ModifyKind::Name(RenameMode::From)), [from]) => {
+        renamed_from.push((event.tracker(), from.clone()));
+      }
+      (EventKind::Modify(ModifyKind::Name(RenameMode::To)), [to]) => {
+        let tracker = event.tracker();
+        match renamed_from.iter().position(|(t, _)| *t == tracker) {
+          Some(i) => {
+            let (_, from) = renamed_from.remove(i);
+            push_rename(&mut renames, from, to.clone());
+            kinds.insert(ChangeKind::Renamed);
+          }
+          None => {
+            kinds.insert(ChangeKind::Created);
+          }
+        }
+      }
+      (EventKind::Modify(ModifyKind::Name(RenameMode::Both)), [from, to]) => {
+        push_rename(&mut renames, from.clone(), to.clone());
+        kinds.insert(ChangeKind::Renamed);
+      }
+      _ => match event_change_kind(&event, watch_access) {
+        Some(kind) => {
+          kinds.insert(kind);
+        }
+        None => continue,
+      },
+    }
+    changed_paths.extend(event.paths);
+  }
+  if !renamed_from.is_empty() {
+    // Moved somewhere the watcher doesn't see.
+    kinds.insert(ChangeKind::Removed);
+  }
+  if kinds.is_empty() {
+    return None;
+  }
+  let mut changed_paths = changed_paths.into_iter().collect();
+  collapse_paths(&mut changed_paths);
+  let mut batch = ChangeBatch::with_kinds(changed_paths, kinds);
+  batch.path_kinds = path_kinds;
+  batch.renames = renames;
+  Some(batch)
+}
+
+/// The net effect of the events on each of their paths, see
+/// `WatchOptions::net_changes`.
+fn net_path_kinds(
+  events: &[NotifyEvent],
+  watch_access: bool,
+) -> BTreeMap<PathBuf, ChangeKind> {
+  let mut per_path: BTreeMap<&Path, Vec<ChangeKind>> = BTreeMap::new();
+  for event in events {
+    let kinds = match (&event.kind, event.paths.as_slice()) {
+      (EventKind::Modify(ModifyKind::Name(RenameMode::Both)), [_, _]) => {
+        vec![ChangeKind::Removed, ChangeKind::Created]
+      }
+      (EventKind::Modify(ModifyKind::Name(RenameMode::From)), _) => {
+        vec![ChangeKind::Removed]
+      }
+      (EventKind::Modify(ModifyKind::Name(RenameMode::To)), _) => {
+        vec![ChangeKind::Created]
+      }
+      (EventKind::Modify(ModifyKind::Name(_)), [path]) if !path.exists() => {
+        vec![ChangeKind::Removed]
+      }
+      (EventKind::Modify(ModifyKind::Name(_)), _) => vec![ChangeKind::Created],
+      _ => match event_change_kind(event, watch_access) {
+        Some(kind) => vec![kind],
+        None => continue,
+      },
+    };
+    for (i, path) in event.paths.iter().enumerate() {
+      let kind = kinds[i.min(kinds.len() - 1)];
+      per_path.entry(path).or_default().push(kind);
+    }
+  }
+  per_path
+    .into_iter()
+    .map(|(path, kinds)| (path.to_path_buf(), net_change_kind(path, &kinds)))
+    .collect()
+}
+
+fn net_change_kind(path: &Path, kinds: &[ChangeKind]) -> ChangeKind {
+  match (kinds.first(), kinds.last()) {
+    (_, Some(ChangeKind::Removed)) => ChangeKind::Removed,
+    (Some(ChangeKind::Created), _) if path.exists() => ChangeKind::Created,
+    _ if kinds.iter().all(|kind| *kind == ChangeKind::Accessed) => {
+      ChangeKind::Accessed
+    }
+    _ => ChangeKind::Modified,
+  }
+}
+
+/// Records a rename, unless it already was, e.g. from both the `From` and
+/// `To` events and the `Both` event inotify reports for a single rename.
+fn push_rename(renames: &mut Vec<Rename>, from: PathBuf, to: PathBuf) {
+  let rename = Rename { from, to };
+  if !renames.contains(&rename) {
+    renames.push(rename);
+  }
+}
+
+/// A version of a file's contents.
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct FileHash {
+  hash: String,
+  len: u64,
+}
+
+/// Hashes the file's contents, or returns `None` if it can't be read. Files
+/// are read synchronously; this only runs for the few paths of a batch.
+fn hash_file(path: &Path) -> Option<FileHash> {
+  if !path.is_file() {
+    return None;
+  }
+  std::fs::read(path).ok().map(|contents| FileHash {
+    hash: checksum::gen(&[&contents]),
+    len: contents.len() as u64,
+  })
+}
+
+/// The content hashes of the watched files as watching starts, so that even
+/// the first save of a file that didn't change is recognized. Walks what
+/// each registration watches, skipping what the filter drops.
+fn initial_hashes(
+  registrations: &[Registration],
+  filter: &EventFilter,
+  options: &WatchOptions,
+) -> HashMap<PathBuf, FileHash> {
+  let mut hashes = HashMap::new();
+  for registration in registrations.iter().filter(|r| !r.is_pending()) {
+    let max_depth = match options.requested_recursive_mode(&registration.path) {
+      RecursiveMode::Recursive => {
+        options.max_depth.map_or(usize::MAX, |depth| depth + 1)
+      }
+      RecursiveMode::NonRecursive => 1,
+    };
+    let entries = WalkDir::new(&registration.path)
+      .max_depth(max_depth)
+      .into_iter()
+      .filter_entry(|entry| {
+        entry.depth() == 0
+          || !entry.file_type().is_dir()
+          || !filter.prunes(entry.path())
+      })
+      .filter_map(Result::ok)
+      .filter(|entry| entry.file_type().is_file());
+    for entry in entries {
+      if filter.rejection(entry.path()).is_some() {
+        continue;
+      }
+      if let Some(hash) = hash_file(entry.path()) {
+        hashes.insert(entry.into_path(), hash);
+      }
+    }
+  }
+  hashes
+}
+
+/// A stream of the debounced changes to a set of paths, which `watch_paths`
+/// and `watch_func` are built on. Owns the OS watcher and keeps its watches
+/// pointed at the requested paths as they are created, removed or replaced.
+/// Paths can be added and removed while watching. Dropping it unwatches
+/// every path, closing the event channel.
+pub struct FileWatcher {
+  watcher: AnyWatcher,
+  /// Every requested path, including ones another path covers.
+  requested: Vec<PathBuf>,
+  handler: Arc<EventHandler>,
+  debounce: EventDebounce,
+  registrations: Vec<Registration>,
+  /// Directories watched on their own because of `max_depth`.
+  subdirs: BTreeSet<PathBuf>,
+  /// Targets watched because of `follow_symlinks`.
+  symlink_targets: Vec<PathBuf>,
+  /// The requested paths that are symlinks, as of when watching started.
+  links: Vec<LinkRoot>,
+  options: WatchOptions,
+  summary: WatchSummary,
+  last_batch: LastBatch,
+  /// Content hashes of changed files, kept when `skip_unchanged` is set.
+  hashes: HashMap<PathBuf, FileHash>,
+  /// Set while restarting a watcher that stopped.
+  restart: Option<WatcherRestart>,
+  /// Whether restarting a stopped watcher failed for good.
+  stopped: bool,
+  /// Set while every watched path is gone, until the next attempt at
+  /// watching them again.
+  lost_roots_retry: Option<Delay>,
+  /// The paths last resolved from `imports`.
+  imported: Vec<PathBuf>,
+  /// Those of them that are requested only because they are imported, and
+  /// stop being watched once they no longer are.
+  imported_only: BTreeSet<PathBuf>,
+  /// Set while `imports` are resolved again, off the task polling the
+  /// watcher.
+  resolving_imports: Option<JoinHandle<Vec<PathBuf>>>,
+  /// The working directory relative paths are resolved against.
+  cwd: PathBuf,
+  /// The absolute form of each root given as a relative path, and the path
+  /// as given, which changes are reported relative to.
+  relative_roots: Vec<(PathBuf, PathBuf)>,
+}
+
+/// The next attempt at restarting a watcher that stopped.
+struct WatcherRestart {
+  attempt: u32,
+  delay: Delay,
+}
+
+impl WatcherRestart {
+  fn new(attempt: u32) -> Self {
+    Self {
+      attempt,
+      delay: delay_for(WATCHER_RESTART_BACKOFF * 2u32.pow(attempt)),
+    }
+  }
+}
+
+/// Watches what the registrations need besides themselves: the directories
+/// below them when `max_depth` is set, the symlink targets when
+/// `follow_symlinks` is, and what registrations that are symlinks need to
+/// tell retargeting from content changes.
+fn watch_extras(
+  watcher: &mut AnyWatcher,
+  registrations: &[Registration],
+  handler: &EventHandler,
+  options: &WatchOptions,
+) -> (BTreeSet<PathBuf>, Vec<PathBuf>, Vec<LinkRoot>) {
+  let mut subdirs = BTreeSet::new();
+  for registration in registrations.iter().filter(|r| !r.is_pending()) {
+    if let Some(limit) = options.depth_limit(&registration.path) {
+      watch_subdirs(watcher, &registration.path, limit, &mut subdirs);
+    }
+  }
+  let mut symlink_targets = Vec::new();
+  if options.follow_symlinks {
+    let symlinks =
+      watch_symlink_targets(watcher, registrations, &mut subdirs, options);
+    symlink_targets = symlinks.iter().map(|(t, _)| t.clone()).collect();
+    handler.filter.set_symlinks(symlinks);
+  }
+  let links = watch_link_roots(watcher, registrations);
+  handler.filter.set_links(&links);
+  (subdirs, symlink_targets, links)
+}
+
+impl FileWatcher {
+  pub fn new(
+    paths: &[PathBuf],
+    mut options: WatchOptions,
+  ) -> Result<Self, AnyError> {
+    let cwd = std::env::current_dir()?;
+    let mut relative_roots = Vec::new();
+    let mut paths: Vec<PathBuf> = paths
+      .iter()
+      .map(|path| {
+        let absolute = absolute_path(path, &cwd);
+        if absolute != *path {
+          relative_roots.push((absolute.clone(), path.clone()));
+        }
+        absolute
+      })
+      .collect();
+    options.root_modes = std::mem::take(&mut options.root_modes)
+      .into_iter()
+      .map(|(path, mode)| (absolute_path(&path, &cwd), mode))
+      .collect();
+    options.root_labels = std::mem::take(&mut options.root_labels)
+      .into_iter()
+      .map(|(path, label)| (absolute_path(&path, &cwd), label))
+      .collect();
+    if let Some(config) = &options.config {
+      paths.push(config.path.clone());
+    }
+    if let Some(state_file) = &mut options.state_file {
+      *state_file = absolute_path(state_file, &cwd);
+    }
+    if let Some(lockfile) = &mut options.watch_lockfile {
+      *lockfile = absolute_path(lockfile, &cwd);
+      if !paths.contains(lockfile) {
+        paths.push(lockfile.clone());
+      }
+    }
+    let imported = match &options.imports {
+      Some(imports) => imports.paths(),
+      None => Vec::new(),
+    };
+    let imported_only: BTreeSet<PathBuf> = imported
+      .iter()
+      .filter(|path| !paths.contains(path))
+      .cloned()
+      .collect();
+    paths.extend(imported_only.iter().cloned());
+    let WatchSetup {
+      mut watcher,
+      handler,
+      debounce,
+      registrations,
+      failed_paths,
+    } = new_watcher(&paths, &options)?;
+    for (path, err) in &failed_paths {
+      info!(
+        "{} Failed to watch {}: {}",
+        paint(colors::yellow("Warning")),
+        path.display(),
+        err
+      );
+    }
+    let (subdirs, symlink_targets, links) =
+      watch_extras(&mut watcher, &registrations, &handler, &options);
+    let summary = WatchSummary {
+      watched: registrations
+        .iter()
+        .filter(|r| !failed_paths.iter().any(|(path, _)| *path == r.path))
+        .count(),
+      skipped: failed_paths.len(),
+      debounce_time: options.debounce_time(),
+      backend: match (&watcher, &options.backend) {
+        // What a custom watcher does is unknown, so it's assumed native.
+        (AnyWatcher::Native(_), _) | (AnyWatcher::Custom(_), _) => {
+          WatchBackend::Native
+        }
+        (AnyWatcher::Poll(_), WatchBackend::Poll { interval }) => {
+          WatchBackend::Poll {
+            interval: *interval,
+          }
+        }
+        (AnyWatcher::Poll(_), _) => WatchBackend::Poll {
+          interval: DEFAULT_POLL_INTERVAL,
+        },
+      },
+    };
+    let hashes = if options.compares_contents() {
+      initial_hashes(&registrations, &handler.filter, &options)
+    } else {
+      HashMap::new()
+    };
+    let file_watcher = Self {
+      watcher,
+      requested: paths,
+      handler,
+      debounce,
+      registrations,
+      subdirs,
+      symlink_targets,
+      links,
+      last_batch: options.last_batch.clone().unwrap_or_default(),
+      options,
+      summary,
+      hashes,
+      restart: None,
+      stopped: false,
+      lost_roots_retry: None,
+      imported,
+      imported_only,
+      resolving_imports: None,
+      cwd,
+      relative_roots,
+    };
+    file_watcher.update_diagnostics();
+    Ok(file_watcher)
+  }
+
+  fn update_diagnostics(&self) {
+    let diagnostics = match &self.options.diagnostics {
+      Some(diagnostics) => diagnostics,
+      None => return,
+    };
+    let backend = match &self.summary.backend {
+      WatchBackend::Poll { interval } => {
+        format!("polling every {}ms", interval.as_millis())
+      }
+      _ => "native".to_string(),
+    };
+    diagnostics.update(|diagnostics| {
+      diagnostics.roots = self.requested.clone();
+      diagnostics.live = self
+        .registrations
+        .iter()
+        .filter(|r| !r.is_pending())
+        .map(|r| r.path.clone())
+        .collect();
+      diagnostics.backend = Some(backend);
+      diagnostics.debounce_ms = self.options.debounce_time().as_millis() as u64;
+      diagnostics.max_debounce_ms = self
+        .options
+        .max_debounce
+        .map(|max_debounce| max_debounce.as_millis() as u64);
+    });
+  }
+
+  /// Replaces the OS watcher and the event pipeline with new ones watching
+  /// the same paths. The old ones are kept if that fails.
+  fn restart_watcher(&mut self) -> Result<(), AnyError> {
+    let WatchSetup {
+      mut watcher,
+      handler,
+      debounce,
+      registrations,
+      failed_paths,
+    } = new_watcher(&self.requested, &self.options)?;
+    for (path, err) in &failed_paths {
+      info!(
+        "{} Failed to watch {}: {}",
+        paint(colors::yellow("Warning")),
+        path.display(),
+        err
+      );
+    }
+    let (subdirs, symlink_targets, links) =
+      watch_extras(&mut watcher, &registrations, &handler, &self.options);
+    self.watcher = watcher;
+    self.handler = handler;
+    self.debounce = debounce;
+    self.registrations = registrations;
+    self.subdirs = subdirs;
+    self.symlink_targets = symlink_targets;
+    self.links = links;
+    self.update_diagnostics();
+    Ok(())
+  }
+
+  /// Like `new`, but with the recursive mode of each root given along with
+  /// it; see `WatchOptions::root_modes`.
+  pub fn with_modes(
+    roots: &[(PathBuf, RecursiveMode)],
+    mut options: WatchOptions,
+  ) -> Result<Self, AnyError> {
+    let paths: Vec<PathBuf> =
+      roots.iter().map(|(path, _)| path.clone()).collect();
+    options.root_modes.extend(roots.iter().cloned());
+    Self::new(&paths, options)
+  }
+
+  /// See `LastBatch::get`.
+  pub fn last_batch(&self) -> Option<(Instant, usize)> {
+    self.last_batch.get()
+  }
+
+  /// How long until the changes being debounced are reported, see
+  /// `Debounce::time_until_yield`.
+  pub fn time_until_yield(&self) -> Option<Duration> {
+    self.debounce.time_until_yield()
+  }
+
+  /// What was watched when watching started.
+  pub fn summary(&self) -> &WatchSummary {
+    &self.summary
+  }
+
+  /// Whether the batch has a change of a kind in `trigger_on`.
+  fn is_triggering(&self, batch: &ChangeBatch) -> bool {
+    match &self.options.trigger_on {
+      Some(trigger_on) => {
+        batch.kinds.iter().any(|kind| trigger_on.contains(kind))
+      }
+      None => true,
+    }
+  }
+
+  /// Removes the files larger than `max_file_size` from the batch.
+  fn drop_large_files(&self, batch: &mut ChangeBatch) {
+    let max_file_size = match self.options.max_file_size {
+      Some(max_file_size) => max_file_size,
+      None => return,
+    };
+    batch.paths.retain(|path| match std::fs::metadata(path) {
+      Ok(metadata) if metadata.is_file() && metadata.len() > max_file_size => {
+        debug!("File too large, skipping: {}", path.display());
+        false
+      }
+      _ => true,
+    });
+  }
+
+  /// Whether any of the paths is in the `relevant` set, or the config file
+  /// or the lockfile, which always are.
+  fn is_relevant(&self, paths: &[PathBuf]) -> bool {
+    let relevant = match &self.options.relevant {
+      Some(relevant) => relevant.lock().unwrap(),
+      None => return true,
+    };
+    let config = self.options.config.as_ref().map(|config| &config.path);
+    let lockfile = self.options.watch_lockfile.as_ref();
+    paths.iter().any(|path| {
+      relevant.contains(path) || Some(path) == config || Some(path) == lockfile
+    })
+  }
+
+  /// `BatchTag::LockfileChanged` if the lockfile is among the paths.
+  fn batch_tag(&self, paths: &[PathBuf]) -> Option<BatchTag> {
+    match &self.options.watch_lockfile {
+      Some(lockfile) if paths.contains(lockfile) => {
+        Some(BatchTag::LockfileChanged)
+      }
+      _ => None,
+    }
+  }
+
+  /// Records the current content hash of each path, returning how each
+  /// compares with the last one seen. Directories and paths without a
+  /// previous hash always count as changed.
+  fn compare_contents(&mut self, paths: &[PathBuf]) -> Vec<ContentDelta> {
+    paths
+      .iter()
+      .map(|path| {
+        if path.is_dir() {
+          return ContentDelta {
+            path: path.clone(),
+            old_len: None,
+            new_len: None,
+            changed: true,
+          };
+        }
+        let hash = hash_file(path);
+        let previous = match &hash {
+          Some(hash) => self.hashes.insert(path.clone(), hash.clone()),
+          None => self.hashes.remove(path),
+        };
+        ContentDelta {
+          path: path.clone(),
+          old_len: previous.as_ref().map(|previous| previous.len),
+          new_len: hash.as_ref().map(|hash| hash.len),
+          changed: previous.is_none() || previous != hash,
+        }
+      })
+      .collect()
+  }
+
+  /// Starts watching `path` as well. Adding a path that is already watched,
+  /// directly or through a recursively watched directory, does nothing.
+  pub fn add_path(&mut self, path: PathBuf) -> Result<(), AnyError> {
+    let absolute = absolute_path(&path, &self.cwd);
+    if absolute != path
+      && !self.relative_roots.iter().any(|(a, _)| *a == absolute)
+    {
+      self.relative_roots.push((absolute.clone(), path));
+    }
+    let path = absolute;
+    // Requested for its own sake from now on.
+    self.imported_only.remove(&path);
+    if self.requested.contains(&path) {
+      return Ok(());
+    }
+    let mut requested = self.requested.clone();
+    requested.push(path.clone());
+    match self.set_paths(requested).into_iter().next() {
+      Some((_, err)) => {
+        self.requested.retain(|requested| *requested != path);
+        Err(err.into())
+      }
+      None => Ok(()),
+    }
+  }
+
+  /// Stops watching `path`. Paths below it that were added on their own
+  /// are watched again directly.
+  pub fn remove_path(&mut self, path: &Path) -> Result<(), AnyError> {
+    let path = &absolute_path(path, &self.cwd);
+    if !self.requested.iter().any(|requested| requested == path) {
+      return Err(generic_error(format!("{} is not watched", path.display())));
+    }
+    let requested = self
+      .requested
+      .iter()
+      .filter(|requested| *requested != path)
+      .cloned()
+      .collect();
+    for (path, err) in self.set_paths(requested) {
+      info!(
+        "{} Failed to watch {}: {}",
+        paint(colors::yellow("Warning")),
+        path.display(),
+        err
+      );
+    }
+    Ok(())
+  }
+
+  /// Whether every requested path is gone, with the nearest existing
+  /// ancestor watched in its place.
+  fn all_roots_lost(&self) -> bool {
+    !self.registrations.is_empty()
+      && self.registrations.iter().all(Registration::is_pending)
+  }
+
+  /// Reports that every watched path was removed, returning whether to stop
+  /// watching because of it.
+  fn on_roots_lost(&mut self) -> bool {
+    if self.options.exit_on_roots_lost {
+      info!(
+        "{}: every watched path was removed, exiting",
+        paint(colors::red_bold("error"))
+      );
+      return true;
+    }
+    info!(
+      "{}: every watched path was removed, waiting for one to reappear",
+      paint(colors::red_bold("error"))
+    );
+    self.lost_roots_retry = Some(delay_for(LOST_ROOTS_RETRY_INTERVAL));
+    false
+  }
+
+  fn on_roots_found(&mut self) {
+    if self.lost_roots_retry.take().is_some() {
+      info!(
+        "{} A watched path reappeared, watching again",
+        paint(colors::intense_blue("Watcher"))
+      );
+    }
+  }
+
+  /// Moves the watch of each removed path whose stand-in ancestor was
+  /// removed as well to the nearest ancestor that still exists, and onto
+  /// the paths that are back.
+  fn retry_lost_roots(&mut self) {
+    for registration in self.registrations.iter_mut() {
+      if !registration.is_pending() || registration.watched.exists() {
+        continue;
+      }
+      if nearest_existing_ancestor(&registration.path).is_none() {
+        continue;
+      }
+      let replacement = Registration::new(&registration.path, &self.options);
+      let _ = self.watcher.unwatch(&registration.watched);
+      match self
+        .watcher
+        .watch(&replacement.watched, replacement.recursive_mode)
+      {
+        Ok(()) => *registration = replacement,
+        Err(err) => debug!(
+          "Failed to watch {} again: {}",
+          registration.path.display(),
+          err
+        ),
+      }
+    }
+    watch_created_paths(
+      &mut self.watcher,
+      &mut self.registrations,
+      &self.options,
+    );
+    self.handler.filter.update(&self.registrations);
+    if self.all_roots_lost() {
+      self.lost_roots_retry = Some(delay_for(LOST_ROOTS_RETRY_INTERVAL));
+    } else {
+      self.on_roots_found();
+    }
+    self.update_diagnostics();
+  }
+
+  /// Tells whether the changed symlinks were retargeted or only their
+  /// targets changed, moving the watch of the target of a retargeted link
+  /// to a file along. A link to a directory was already watched again as a
+  /// replaced path.
+  fn check_links(&mut self, paths: &[PathBuf]) -> Option<LinkChange> {
+    let mut change = None;
+    let mut retargeted = false;
+    for link in self.links.iter_mut() {
+      if !paths.contains(&link.link) {
+        continue;
+      }
+      match link.link.canonicalize() {
+        Ok(target) if target != link.target => {
+          debug!("{} now points to {}", link.link.display(), target.display());
+          if !link.to_dir {
+            let _ = self.watcher.unwatch(&link.watched);
+            if let Err(err) =
+              self.watcher.watch(&target, RecursiveMode::NonRecursive)
+            {
+              info!(
+                "{} Failed to watch {}: {}",
+                paint(colors::yellow("Warning")),
+                target.display(),
+                err
+              );
+            }
+            link.watched = target.clone();
+          }
+          link.target = target;
+          retargeted = true;
+          change = Some(LinkChange::Retargeted);
+        }
+        Ok(_) if change.is_none() => change = Some(LinkChange::Content),
+        // Removed, or dangling, which the kind of the change tells.
+        _ => {}
+      }
+    }
+    if retargeted {
+      self.handler.filter.set_links(&self.links);
+    }
+    change
+  }
+
+  /// Spells the changes below roots that were given as relative paths
+  /// relative again, as the user would expect to see them.
+  fn report_relatively(&self, batch: &mut ChangeBatch) {
+    if self.relative_roots.is_empty() {
+      return;
+    }
+    let roots = &self.relative_roots;
+    for path in batch.paths.iter_mut() {
+      *path = given_path(path, roots);
+    }
+    collapse_paths(&mut batch.paths);
+    for rename in batch.renames.iter_mut() {
+      rename.from = given_path(&rename.from, roots);
+      rename.to = given_path(&rename.to, roots);
+    }
+    for delta in batch.content_deltas.iter_mut() {
+      delta.path = given_path(&delta.path, roots);
+    }
+    batch.path_kinds = std::mem::take(&mut batch.path_kinds)
+      .into_iter()
+      .map(|(path, kind)| (given_path(&path, roots), kind))
+      .collect();
+  }
+
+  /// Replaces the requested paths, only touching the watches of paths that
+  /// were added or removed. Returns the added paths that failed to be
+  /// watched.
+  fn set_paths(
+    &mut self,
+    requested: Vec<PathBuf>,
+  ) -> Vec<(PathBuf, NotifyError)> {
+    let paths = dedup_paths(&requested, &self.options);
+    self.requested = requested;
+    let mut failed_paths = Vec::new();
+    let (kept, removed): (Vec<Registration>, Vec<Registration>) =
+      std::mem::take(&mut self.registrations)
+        .into_iter()
+        .partition(|r| paths.contains(&r.path));
+    self.registrations = kept;
+    for path in &paths {
+      if self.registrations.iter().any(|r| r.path == *path) {
+        continue;
+      }
+      if is_special_file(path) {
+        failed_paths
+          .push((path.clone(), NotifyError::generic(SPECIAL_FILE_ERROR)));
+        continue;
+      }
+      let registration = Registration::new(path, &self.options);
+      if let Err(err) = self
+        .watcher
+        .watch(&registration.watched, registration.recursive_mode)
+      {
+        failed_paths.push((path.clone(), err));
+        continue;
+      }
+      if !registration.is_pending() {
+        if let Some(limit) = self.options.depth_limit(path) {
+          watch_subdirs(&mut self.watcher, path, limit, &mut self.subdirs);
+        }
+      }
+      self.registrations.push(registration);
+    }
+
+    for registration in removed {
+      if !self
+        .registrations
+        .iter()
+        .any(|r| r.watched == registration.watched)
+      {
+        let _ = self.watcher.unwatch(&registration.watched);
+      }
+    }
+    let registrations = &self.registrations;
+    let (kept, removed): (BTreeSet<PathBuf>, BTreeSet<PathBuf>) =
+      std::mem::take(&mut self.subdirs)
+        .into_iter()
+        .partition(|dir| {
+          registrations.iter().any(|r| dir.starts_with(&r.path))
+        });
+    self.subdirs = kept;
+    for dir in removed {
+      let _ = self.watcher.unwatch(&dir);
+    }
+    self.handler.filter.set_paths(&self.registrations);
+    self.update_diagnostics();
+    failed_paths
+  }
+
+  /// Reloads the watched paths if the config file changed.
+  fn reload_config(&mut self, changed_paths: &[PathBuf]) {
+    let config = match &self.options.config {
+      Some(config) if changed_paths.contains(&config.path) => config.clone(),
+      _ => return,
+    };
+    let mut paths = config.paths();
+    if let Some(lockfile) = &self.options.watch_lockfile {
+      paths.push(lockfile.clone());
+    }
+    debug!(
+      "Config {} changed, watching: {}",
+      config.path.display(),
+      format_paths(&paths)
+    );
+    for (path, err) in self.set_paths(paths) {
+      info!(
+        "{} Failed to watch {}: {}",
+        paint(colors::yellow("Warning")),
+        path.display(),
+        err
+      );
+    }
+  }
+
+  /// Resolves the imports again, off the task polling the watcher since the
+  /// resolver may read every module. A resolution still running is
+  /// superseded, as it may have read the files before they changed.
+  fn resolve_imports(&mut self) {
+    if let Some(imports) = &self.options.imports {
+      let imports = imports.clone();
+      self.resolving_imports =
+        Some(tokio::task::spawn_blocking(move || imports.paths()));
+    }
+  }
+
+  /// Watches the imports that are new and no longer watches the ones that
+  /// are gone, unless they are requested for their own sake.
+  fn set_imports(&mut self, imported: Vec<PathBuf>) {
+    if imported == self.imported {
+      return;
+    }
+    debug!("Imports changed, watching: {}", format_paths(&imported));
+    let gone: Vec<PathBuf> = self
+      .imported_only
+      .iter()
+      .filter(|path| !imported.contains(path))
+      .cloned()
+      .collect();
+    for path in &gone {
+      self.imported_only.remove(path);
+    }
+    let mut requested: Vec<PathBuf> = self
+      .requested
+      .iter()
+      .filter(|path| !gone.contains(path))
+      .cloned()
+      .collect();
+    for path in &imported {
+      if !requested.contains(path) {
+        self.imported_only.insert(path.clone());
+        requested.push(path.clone());
+      }
+    }
+    self.imported = imported;
+    for (path, err) in self.set_paths(requested) {
+      info!(
+        "{} Failed to watch {}: {}",
+        paint(colors::yellow("Warning")),
+        path.display(),
+        err
+      );
+    }
+  }
+
+  fn refresh_watches(
+    &mut self,
+    replaced: &BTreeSet<PathBuf>,
+    created: &BTreeSet<PathBuf>,
+  ) {
+    rewatch_replaced_paths(
+      &mut self.watcher,
+      &mut self.registrations,
+      replaced,
+      &self.options,
+    );
+    watch_created_paths(
+      &mut self.watcher,
+      &mut self.registrations,
+      &self.options,
+    );
+    if self.options.max_depth.is_some() {
+      // Watches of removed directories are gone along with them.
+      self.subdirs = self
+        .subdirs
+        .iter()
+        .filter(|dir| !replaced.iter().any(|path| dir.starts_with(path)))
+        .cloned()
+        .collect();
+      watch_created_subdirs(
+        &mut self.watcher,
+        &self.registrations,
+        &mut self.subdirs,
+        created,
+        &self.options,
+      );
+    }
+    self.handler.filter.update(&self.registrations);
+    self.update_diagnostics();
+  }
+}
+
+impl Drop for FileWatcher {
+  fn drop(&mut self) {
+    if let Some(flush) = self.options.on_shutdown_flush.clone() {
+      let pending = self.debounce.take_pending();
+      if let Some(batch) = change_batch(pending, self.options.watch_access) {
+        if !batch.paths.is_empty() {
+          flush.call(batch.paths);
+        }
+      }
+    }
+    for registration in &self.registrations {
+      // Paths that were removed have already lost their watch.
+      let _ = self.watcher.unwatch(&registration.watched);
+    }
+    for dir in self.subdirs.iter().chain(&self.symlink_targets) {
+      let _ = self.watcher.unwatch(dir);
+    }
+    for link in &self.links {
+      let _ = self.watcher.unwatch(&link.watched);
+    }
+    debug!("File watcher stopped");
+  }
+}
+
+impl Stream for FileWatcher {
+  type Item = Result<ChangeBatch, AnyError>;
+
+  fn poll_next(
+    self: Pin<&mut Self>,
+    cx: &mut Context,
+  ) -> Poll<Option<Self::Item>> {
+    let inner = self.get_mut();
+    loop {
+      if let Some(mut restart) = inner.restart.take() {
+        if restart.delay.poll_unpin(cx).is_pending() {
+          inner.restart = Some(restart);
+          return Poll::Pending;
+        }
+        match inner.restart_watcher() {
+          Ok(()) => {
+            info!(
+              "{} Watcher restarted",
+              paint(colors::intense_blue("Watcher"))
+            );
+          }
+          Err(err) if restart.attempt + 1 >= WATCHER_RESTART_ATTEMPTS => {
+            inner.stopped = true;
+            return Poll::Ready(Some(Err(generic_error(format!(
+              "File watcher stopped and could not be restarted: {}",
+              err
+            )))));
+          }
+          Err(err) => {
+            debug!("Restarting the file watcher failed: {}", err);
+            inner.restart = Some(WatcherRestart::new(restart.attempt + 1));
+          }
+        }
+        continue;
+      }
+      if let Some(retry) = &mut inner.lost_roots_retry {
+        if retry.poll_unpin(cx).is_ready() {
+          inner.retry_lost_roots();
+          continue;
+        }
+      }
+      if let Some(resolving) = &mut inner.resolving_imports {
+        if let Poll::Ready(result) = resolving.poll_unpin(cx) {
+          inner.resolving_imports = None;
+          match result {
+            Ok(imported) => inner.set_imports(imported),
+            Err(err) => info!(
+              "{} Failed to resolve the imports: {}",
+              paint(colors::yellow("Warning")),
+              err
+            ),
+          }
+          continue;
+        }
+      }
+      match inner.debounce.poll_next_unpin(cx) {
+        Poll::Ready(Some(Ok(batch))) => {
+          let replaced = replaced_paths(&batch);
+          let created = created_paths(&batch);
+          let dirs = if inner.options.watch_dir_events {
+            BTreeSet::new()
+          } else {
+            dir_paths(&batch)
+          };
+          let dropped = inner.handler.take_overflow();
+          let overflowed = dropped.is_some();
+          let mut batch = change_batch(batch, inner.options.watch_access);
+          if inner.options.net_changes {
+            if let Some(batch) = &mut batch {
+              let kinds = batch.path_kinds.values().copied().collect();
+              batch.set_kinds(kinds);
+            }
+          }
+          if let Some(dropped) = dropped {
+            // What the dropped events were is unknown, so this batch is
+            // reported even if it seems to change nothing.
+            let dropped = ChangeBatch::new(dropped, ChangeKind::Modified);
+            match &mut batch {
+              Some(batch) => batch.merge(dropped),
+              None => batch = Some(dropped),
+            }
+          }
+          if let Some(mut batch) = batch {
+            let was_lost = inner.all_roots_lost();
+            inner.refresh_watches(&replaced, &created);
+            if inner.all_roots_lost() {
+              if !was_lost && inner.on_roots_lost() {
+                return Poll::Ready(None);
+              }
+            } else {
+              inner.on_roots_found();
+            }
+            if !dirs.is_empty() {
+              let registrations = &inner.registrations;
+              batch.paths.retain(|path| {
+                !dirs.contains(path)
+                  || registrations.iter().any(|r| r.path == *path)
+              });
+            }
+            batch.link_change = inner.check_links(&batch.paths);
+            batch.tag = inner.batch_tag(&batch.paths);
+            batch.debounced = inner.debounce.last_window();
+            if let Some((first, last)) = inner.debounce.last_items() {
+              batch.first_event_at = Some(first);
+              batch.last_event_at = Some(last);
+            }
+            inner.reload_config(&batch.paths);
+            inner.drop_large_files(&mut batch);
+            batch.source =
+              source_label(&inner.options.root_labels, &batch.paths);
+            if batch.paths.is_empty() && !overflowed {
+              continue;
+            }
+            if !overflowed && inner.options.compares_contents() {
+              let deltas = inner.compare_contents(&batch.paths);
+              if !deltas.iter().any(|delta| delta.changed) {
+                debug!(
+                  "Contents unchanged, skipping: {}",
+                  format_paths(&batch.paths)
+                );
+                continue;
+              }
+              if inner.options.with_content_delta {
+                batch.content_deltas = deltas;
+              }
+            }
+            if !overflowed && !inner.is_relevant(&batch.paths) {
+              debug!(
+                "Not in the module graph, skipping: {}",
+                format_paths(&batch.paths)
+              );
+              continue;
+            }
+            if !overflowed && !inner.is_triggering(&batch) {
+              debug!(
+                "No triggering change, skipping: {}",
+                format_paths(&batch.paths)
+              );
+              continue;
+            }
+            if let Some(predicate) = &inner.options.should_restart {
+              if !predicate.call(&batch) {
+                debug!(
+                  "Restart declined, skipping: {}",
+                  format_paths(&batch.paths)
+                );
+                continue;
+              }
+            }
+            inner.resolve_imports();
+            inner.report_relatively(&mut batch);
+            inner.last_batch.record(&batch);
+            return Poll::Ready(Some(Ok(batch)));
+          }
+        }
+        Poll::Ready(Some(Err(err))) => return Poll::Ready(Some(Err(err))),
+        Poll::Ready(None) if inner.stopped => return Poll::Ready(None),
+        Poll::Ready(None) => {
+          // The event channel closes once the backend went away, e.g.
+          // because its thread panicked, see `BackendGuard`.
+          info!(
+            "{} The file watcher stopped unexpectedly, restarting it",
+            paint(colors::yellow("Warning"))
+          );
+          inner.restart = Some(WatcherRestart::new(0));
+        }
+        Poll::Pending => {
+          // The events that were queued when the last event was dropped
+          // may already have been reported, leaving nothing in flight that
+          // takes the dropped changes along.
+          inner.handler.waker.register(cx.waker());
+          if inner.debounce.is_idle() && inner.handler.is_dirty() {
+            if let Some(mut dropped) = inner.handler.take_overflow() {
+              collapse_paths(&mut dropped);
+              let mut batch = ChangeBatch::new(dropped, ChangeKind::Modified);
+              batch.source =
+                source_label(&inner.options.root_labels, &batch.paths);
+              batch.tag = inner.batch_tag(&batch.paths);
+              inner.report_relatively(&mut batch);
+              inner.last_batch.record(&batch);
+              return Poll::Ready(Some(Ok(batch)));
+            }
+          }
+          return Poll::Pending;
+        }
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::clock::MockClock;
+  use notify::event::AccessKind;
+  use notify::event::CreateKind;
+  use notify::event::RemoveKind;
+  use std::cell::Cell;
+  use std::cell::RefCell;
+  use std::rc::Rc;
+  use tempfile::TempDir;
+
+  fn modify_event(path: &str) -> NotifyEvent {
+    NotifyEvent::new(EventKind::Modify(ModifyKind::Any))
+      .add_path(PathBuf::from(path))
+  }
+
+  fn default_debounce_time() -> Duration {
+    Duration::from_millis(DEBOUNCE_TIME_MS)
+  }
+
+  /// Resolves with the paths of the next debounced batch that contains a
+  /// change.
+  async fn wait_for_file_change(
+    debounce: &mut (impl Stream<Item = Result<Vec<NotifyEvent>, AnyError>> + Unpin),
+  ) -> Result<Vec<PathBuf>, AnyError> {
+    while let Some(result) = debounce.next().await {
+      if let Some(batch) = change_batch(result?, false) {
+        return Ok(batch.paths);
+      }
+    }
+    Ok(Vec::new())
+  }
+
+  #[tokio::test]
+  async fn wait_for_file_change_collects_burst() {
+    let (mut sender, receiver) = mpsc::channel(16);
+    let mut debounce = Debounce::new(receiver, default_debounce_time());
+    sender.send(Ok(modify_event("b.ts"))).await.unwrap();
+    sender.send(Ok(modify_event("a.ts"))).await.unwrap();
+
+    let paths = wait_for_file_change(&mut debounce).await.unwrap();
+    assert_eq!(paths, vec![PathBuf::from("a.ts"), PathBuf::from("b.ts")]);
+  }
+
+  #[tokio::test]
+  async fn wait_for_file_change_coalesces_kinds() {
+    let (mut sender, receiver) = mpsc::channel(16);
+    let mut debounce = Debounce::new(receiver, default_debounce_time());
+    let path = PathBuf::from("mod.ts");
+    let kinds = vec![
+      EventKind::Create(CreateKind::File),
+      EventKind::Modify(ModifyKind::Any),
+      EventKind::Remove(RemoveKind::File),
+    ];
+    for kind in kinds {
+      let event = NotifyEvent::new(kind).add_path(path.clone());
+      sender.send(Ok(event)).await.unwrap();
+    }
+
+    let paths = wait_for_file_change(&mut debounce).await.unwrap();
+    assert_eq!(paths, vec![path]);
+  }
+
+  #[tokio::test]
+  async fn wait_for_file_change_skips_batches_without_changes() {
+    let (mut sender, receiver) = mpsc::channel(16);
+    let mut debounce =
+      Debounce::new(receiver, Duration::from_millis(DEBOUNCE_TIME_MS / 4));
+    let access = NotifyEvent::new(EventKind::Access(AccessKind::Any))
+      .add_path(PathBuf::from("mod.ts"));
+    sender.send(Ok(access)).await.unwrap();
+
+    let result = tokio::time::timeout(
+      Duration::from_millis(DEBOUNCE_TIME_MS),
+      wait_for_file_change(&mut debounce),
+    )
+    .await;
+    assert!(result.is_err());
+  }
+
+  fn change_kind(kind: EventKind) -> Option<ChangeKind> {
+    ChangeKind::from_event_kind(&kind)
+  }
+
+  #[test]
+  fn change_kinds_are_normalized() {
+    assert_eq!(change_kind(EventKind::Access(AccessKind::Any)), None);
+    assert_eq!(change_kind(EventKind::Any), Some(ChangeKind::Modified));
+    assert_eq!(change_kind(EventKind::Other), Some(ChangeKind::Modified));
+    assert_eq!(
+      change_kind(EventKind::Modify(ModifyKind::Any)),
+      Some(ChangeKind::Modified)
+    );
+  }
+
+  #[cfg(target_os = "linux")]
+  #[test]
+  fn inotify_change_kinds() {
+    use notify::event::AccessMode;
+    use notify::event::DataChange;
+    use notify::event::MetadataKind;
+
+    let close_write = AccessKind::Close(AccessMode::Write);
+    assert_eq!(change_kind(EventKind::Access(close_write)), None);
+    assert_eq!(
+      change_kind(EventKind::Create(CreateKind::File)),
+      Some(ChangeKind::Created)
+    );
+    assert_eq!(
+      change_kind(EventKind::Modify(ModifyKind::Data(DataChange::Any))),
+      Some(ChangeKind::Modified)
+    );
+    assert_eq!(
+      change_kind(EventKind::Modify(ModifyKind::Metadata(MetadataKind::Any))),
+      Some(ChangeKind::Modified)
+    );
+    assert_eq!(
+      change_kind(EventKind::Modify(ModifyKind::Name(RenameMode::From))),
+      Some(ChangeKind::Renamed)
+    );
+    assert_eq!(
+      change_kind(EventKind::Remove(RemoveKind::Folder)),
+      Some(ChangeKind::Removed)
+    );
+  }
+
+  #[cfg(target_os = "macos")]
+  #[test]
+  fn fsevents_change_kinds() {
+    use notify::event::DataChange;
+    use notify::event::MetadataKind;
+
+    assert_eq!(
+      change_kind(EventKind::Create(CreateKind::File)),
+      Some(ChangeKind::Created)
+    );
+    assert_eq!(
+      change_kind(EventKind::Modify(ModifyKind::Data(DataChange::Content))),
+      Some(ChangeKind::Modified)
+    );
+    assert_eq!(
+      change_kind(EventKind::Modify(ModifyKind::Metadata(
+        MetadataKind::Ownership
+      ))),
+      Some(ChangeKind::Modified)
+    );
+    assert_eq!(
+      change_kind(EventKind::Modify(ModifyKind::Name(RenameMode::Any))),
+      Some(ChangeKind::Renamed)
+    );
+    assert_eq!(
+      change_kind(EventKind::Remove(RemoveKind::File)),
+      Some(ChangeKind::Removed)
+    );
+    // Coalesced flags FSEvents can't break down further.
+    assert_eq!(change_kind(EventKind::Any), Some(ChangeKind::Modified));
+  }
+
+  #[cfg(windows)]
+  #[test]
+  fn read_directory_changes_kinds() {
+    assert_eq!(
+      change_kind(EventKind::Create(CreateKind::Any)),
+      Some(ChangeKind::Created)
+    );
+    assert_eq!(
+      change_kind(EventKind::Modify(ModifyKind::Any)),
+      Some(ChangeKind::Modified)
+    );
+    assert_eq!(
+      change_kind(EventKind::Modify(ModifyKind::Name(RenameMode::To))),
+      Some(ChangeKind::Renamed)
+    );
+    assert_eq!(
+      change_kind(EventKind::Remove(RemoveKind::Any)),
+      Some(ChangeKind::Removed)
+    );
+  }
+
+  #[tokio::test]
+  async fn unclassified_events_trigger_changes() {
+    let (mut sender, receiver) = mpsc::channel(16);
+    let mut debounce = Debounce::new(receiver, default_debounce_time());
+    let event =
+      NotifyEvent::new(EventKind::Any).add_path(PathBuf::from("a.ts"));
+    sender.send(Ok(event)).await.unwrap();
+
+    let paths = wait_for_file_change(&mut debounce).await.unwrap();
+    assert_eq!(paths, vec![PathBuf::from("a.ts")]);
+  }
+
+  /// An event pipeline for `/proj` without an OS watcher, driven through
+  /// the returned handler.
+  fn scripted_pipeline(
+    debounce_time: Duration,
+  ) -> (Arc<EventHandler>, EventDebounce) {
+    let options = WatchOptions {
+      debounce_time: Some(debounce_time),
+      exclude: vec!["node_modules".to_string()],
+      ..Default::default()
+    };
+    let registration = Registration::new(Path::new("/proj"), &options);
+    event_pipeline(&[registration], &options).unwrap()
+  }
+
+  #[test]
+  fn same_events_ignore_attributes_and_path_order() {
+    let rename = |tracker: usize, paths: &[&str]| {
+      paths.iter().fold(
+        NotifyEvent::new(EventKind::Modify(ModifyKind::Name(RenameMode::Both)))
+          .set_tracker(tracker),
+        |event, path| event.add_path(PathBuf::from(path)),
+      )
+    };
+    assert!(is_same_event(
+      &modify_event("/proj/a.ts").set_tracker(1),
+      &modify_event("/proj/a.ts").set_tracker(2),
+    ));
+    assert!(!is_same_event(
+      &modify_event("/proj/a.ts"),
+      &modify_event("/proj/b.ts"),
+    ));
+    assert!(is_same_event(
+      &rename(1, &["/proj/a.ts", "/proj/b.ts"]),
+      &rename(1, &["/proj/b.ts", "/proj/a.ts"]),
+    ));
+    assert!(!is_same_event(
+      &rename(1, &["/proj/a.ts", "/proj/b.ts"]),
+      &rename(2, &["/proj/a.ts", "/proj/b.ts"]),
+    ));
+    assert!(!is_same_event(
+      &rename(1, &["/proj/a.ts", "/proj/a.ts"]),
+      &rename(1, &["/proj/a.ts", "/proj/b.ts"]),
+    ));
+  }
+
+  #[tokio::test]
+  async fn events_differing_in_attributes_coalesce() {
+    let (handler, mut debounce) = scripted_pipeline(default_debounce_time());
+    handler.handle(Ok(modify_event("/proj/mod.ts").set_tracker(1)));
+    handler.handle(Ok(modify_event("/proj/mod.ts").set_tracker(2)));
+    let batch = debounce.next().await.unwrap().unwrap();
+    assert_eq!(batch.len(), 1);
+  }
+
+  #[tokio::test]
+  async fn metadata_changes_are_dropped() {
+    use notify::event::DataChange;
+    use notify::event::MetadataKind;
+
+    let (handler, mut debounce) = scripted_pipeline(default_debounce_time());
+    let metadata = NotifyEvent::new(EventKind::Modify(ModifyKind::Metadata(
+      MetadataKind::WriteTime,
+    )))
+    .add_path(PathBuf::from("/proj/mod.ts"));
+    handler.handle(Ok(metadata));
+    let result =
+      tokio::time::timeout(default_debounce_time() * 2, debounce.next()).await;
+    assert!(result.is_err());
+
+    let data = NotifyEvent::new(EventKind::Modify(ModifyKind::Data(
+      DataChange::Content,
+    )))
+    .add_path(PathBuf::from("/proj/mod.ts"));
+    handler.handle(Ok(data));
+    let batch = debounce.next().await.unwrap().unwrap();
+    assert_eq!(batch.len(), 1);
+  }
+
+  #[cfg(target_os = "linux")]
+  #[tokio::test]
+  async fn chmod_does_not_trigger() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let file_path = temp_dir.path().join("mod.ts");
+    std::fs::write(&file_path, "").unwrap();
+    let mut changes =
+      FileWatcher::new(&[temp_dir.path().to_path_buf()], Default::default())
+        .unwrap();
+
+    let permissions = std::fs::Permissions::from_mode(0o600);
+    std::fs::set_permissions(&file_path, permissions).unwrap();
+    let result =
+      tokio::time::timeout(Duration::from_millis(500), changes.next()).await;
+    assert!(result.is_err(), "metadata change triggered");
+
+    std::fs::write(&file_path, "changed").unwrap();
+    let batch = tokio::time::timeout(Duration::from_secs(5), changes.next())
+      .await
+      .expect("data change not detected")
+      .unwrap()
+      .unwrap();
+    assert_eq!(batch.paths, vec![file_path]);
+  }
+
+  #[tokio::test]
+  async fn access_events_are_dropped() {
+    let (handler, mut debounce) = scripted_pipeline(default_debounce_time());
+    let access = |path: &str| {
+      NotifyEvent::new(EventKind::Access(AccessKind::Any))
+        .add_path(PathBuf::from(path))
+    };
+    handler.handle(Ok(access("/proj/mod.ts")));
+    let result =
+      tokio::time::timeout(default_debounce_time() * 2, debounce.next()).await;
+    assert!(result.is_err());
+
+    // Access events arriving after a change don't extend its window.
+    let start = std::time::Instant::now();
+    handler.handle(Ok(modify_event("/proj/mod.ts")));
+    for _ in 0..5 {
+      tokio::time::delay_for(default_debounce_time() / 2).await;
+      handler.handle(Ok(access("/proj/mod.ts")));
+    }
+    let batch = debounce.next().await.unwrap().unwrap();
+    assert_eq!(batch.len(), 1);
+    assert!(start.elapsed() >= default_debounce_time());
+    assert!(debounce.next().now_or_never().is_none());
+  }
+
+  #[tokio::test]
+  async fn access_events_are_reported_when_requested() {
+    let options = WatchOptions {
+      watch_access: true,
+      ..Default::default()
+    };
+    let registration = Registration::new(Path::new("/proj"), &options);
+    let (handler, mut debounce) =
+      event_pipeline(&[registration], &options).unwrap();
+    let access = NotifyEvent::new(EventKind::Access(AccessKind::Any))
+      .add_path(PathBuf::from("/proj/mod.ts"));
+    handler.handle(Ok(access));
+
+    let events = debounce.next().await.unwrap().unwrap();
+    let batch = change_batch(events, true).unwrap();
+    assert_eq!(batch.paths, vec![PathBuf::from("/proj/mod.ts")]);
+    assert!(batch.is_only(ChangeKind::Accessed));
+  }
+
+  #[tokio::test]
+  async fn hidden_paths_are_dropped() {
+    let (handler, mut debounce) = scripted_pipeline(default_debounce_time());
+    handler.handle(Ok(modify_event("/proj/.mod.ts.swp")));
+    handler.handle(Ok(modify_event("/proj/.git/HEAD")));
+    handler.handle(Ok(modify_event("/proj/src/.DS_Store")));
+    let result =
+      tokio::time::timeout(default_debounce_time() * 2, debounce.next()).await;
+    assert!(result.is_err());
+
+    let options = WatchOptions {
+      watch_hidden: true,
+      ..Default::default()
+    };
+    let registration = Registration::new(Path::new("/proj"), &options);
+    let (handler, mut debounce) =
+      event_pipeline(&[registration], &options).unwrap();
+    handler.handle(Ok(modify_event("/proj/.git/HEAD")));
+    let batch = debounce.next().await.unwrap().unwrap();
+    assert_eq!(batch[0].paths, vec![PathBuf::from("/proj/.git/HEAD")]);
+  }
+
+  #[test]
+  fn verbose_event_lines() {
+    let line = verbose_event_line(&modify_event("/proj/mod.ts"), false);
+    assert_eq!(
+      colors::strip_ansi_codes(&line),
+      "Watcher modified /proj/mod.ts"
+    );
+    let rename =
+      NotifyEvent::new(EventKind::Modify(ModifyKind::Name(RenameMode::Both)))
+        .add_path(PathBuf::from("/proj/a.ts"))
+        .add_path(PathBuf::from("/proj/b.ts"));
+    let line = verbose_event_line(&rename, false);
+    assert_eq!(
+      colors::strip_ansi_codes(&line),
+      "Watcher renamed /proj/a.ts, /proj/b.ts"
+    );
+  }
+
+  #[tokio::test]
+  async fn scripted_single_event() {
+    let (handler, mut debounce) = scripted_pipeline(default_debounce_time());
+    handler.handle(Ok(modify_event("/proj/mod.ts")));
+
+    let paths = wait_for_file_change(&mut debounce).await.unwrap();
+    assert_eq!(paths, vec![PathBuf::from("/proj/mod.ts")]);
+    assert!(debounce.next().now_or_never().is_none());
+  }
+
+  #[tokio::test]
+  async fn scripted_burst_collapses() {
+    let (handler, mut debounce) = scripted_pipeline(default_debounce_time());
+    let start = std::time::Instant::now();
+    let feed = async {
+      for path in &["/proj/a.ts", "/proj/node_modules/x.js", "/proj/b.ts"] {
+        handler.handle(Ok(modify_event(path)));
+        delay_for(Duration::from_millis(50)).await;
+      }
+    };
+
+    let (_, paths) = tokio::join!(feed, wait_for_file_change(&mut debounce));
+    assert_eq!(
+      paths.unwrap(),
+      vec![PathBuf::from("/proj/a.ts"), PathBuf::from("/proj/b.ts")]
+    );
+    assert!(start.elapsed() >= default_debounce_time());
+    assert!(debounce.next().now_or_never().is_none());
+  }
+
+  #[tokio::test]
+  async fn events_are_accepted_while_consumer_waits() {
+    let (handler, mut debounce) = scripted_pipeline(default_debounce_time());
+    // The consumer is parked in `next().await` while another thread, like
+    // notify's, delivers the event. Neither side may block on the other.
+    let producer = std::thread::spawn(move || {
+      std::thread::sleep(Duration::from_millis(50));
+      handler.handle(Ok(modify_event("/proj/mod.ts")));
+    });
+
+    let paths = tokio::time::timeout(
+      Duration::from_secs(5),
+      wait_for_file_change(&mut debounce),
+    )
+    .await
+    .expect("event was not delivered")
+    .unwrap();
+    assert_eq!(paths, vec![PathBuf::from("/proj/mod.ts")]);
+    producer.join().unwrap();
+  }
+
+  #[tokio::test]
+  async fn scripted_events_split_across_windows() {
+    let window = Duration::from_millis(100);
+    let (handler, mut debounce) = scripted_pipeline(window);
+    let feed = async {
+      handler.handle(Ok(modify_event("/proj/a.ts")));
+      delay_for(window * 3).await;
+      handler.handle(Ok(modify_event("/proj/b.ts")));
+    };
+    let collect = async {
+      let first = wait_for_file_change(&mut debounce).await.unwrap();
+      let second = wait_for_file_change(&mut debounce).await.unwrap();
+      (first, second)
+    };
+
+    let (_, (first, second)) = tokio::join!(feed, collect);
+    assert_eq!(first, vec![PathBuf::from("/proj/a.ts")]);
+    assert_eq!(second, vec![PathBuf::from("/proj/b.ts")]);
+  }
+
+  #[test]
+  fn change_batch_kind_is_shared_or_modified() {
+    let create = |path: &str| {
+      NotifyEvent::new(EventKind::Create(CreateKind::File))
+        .add_path(PathBuf::from(path))
+    };
+    let batch =
+      change_batch(vec![create("a.ts"), create("b.ts")], false).unwrap();
+    assert_eq!(batch.kind, ChangeKind::Created);
+
+    let mut batch =
+      change_batch(vec![create("a.ts"), modify_event("a.ts")], false).unwrap();
+    assert_eq!(batch.paths, vec![PathBuf::from("a.ts")]);
+    assert_eq!(batch.kind, ChangeKind::Modified);
+
+    let access = NotifyEvent::new(EventKind::Access(AccessKind::Any));
+    assert!(change_batch(vec![access], false).is_none());
+
+    batch.merge(change_batch(vec![create("b.ts")], false).unwrap());
+    assert_eq!(
+      batch.paths,
+      vec![PathBuf::from("a.ts"), PathBuf::from("b.ts")]
+    );
+    assert_eq!(batch.kind, ChangeKind::Modified);
+  }
+
+  #[test]
+  fn change_batches_know_the_kind_of_each_path() {
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let dir = temp_dir.path();
+    let path = |name: &str| dir.join(name);
+    for name in &["created.ts", "edited.ts", "new.ts"] {
+      std::fs::write(path(name), "").unwrap();
+    }
+    let events = vec![
+      NotifyEvent::new(EventKind::Create(CreateKind::File))
+        .add_path(path("created.ts")),
+      NotifyEvent::new(EventKind::Modify(ModifyKind::Any))
+        .add_path(path("edited.ts")),
+      NotifyEvent::new(EventKind::Remove(RemoveKind::File))
+        .add_path(path("removed.ts")),
+      NotifyEvent::new(EventKind::Modify(ModifyKind::Name(RenameMode::Both)))
+        .add_path(path("old.ts"))
+        .add_path(path("new.ts")),
+    ];
+    let mut batch = change_batch(events, false).unwrap();
+    assert_eq!(batch.kind_of(&path("created.ts")), ChangeKind::Created);
+    assert_eq!(batch.kind_of(&path("edited.ts")), ChangeKind::Modified);
+    assert_eq!(batch.kind_of(&path("removed.ts")), ChangeKind::Removed);
+    assert_eq!(batch.kind_of(&path("old.ts")), ChangeKind::Removed);
+    assert_eq!(batch.kind_of(&path("new.ts")), ChangeKind::Created);
+    assert_eq!(batch.kind_of(&path("unknown.ts")), ChangeKind::Modified);
+
+    let edit = NotifyEvent::new(EventKind::Modify(ModifyKind::Any))
+      .add_path(path("created.ts"));
+    batch.merge(change_batch(vec![edit], false).unwrap());
+    assert_eq!(batch.kind_of(&path("created.ts")), ChangeKind::Created);
+  }
+
+  #[test]
+  fn change_batch_collects_kinds() {
+    let remove = |path: &str| {
+      NotifyEvent::new(EventKind::Remove(RemoveKind::File))
+        .add_path(PathBuf::from(path))
+    };
+    let batch =
+      change_batch(vec![remove("a.ts"), remove("b.ts")], false).unwrap();
+    assert!(batch.is_only(ChangeKind::Removed));
+
+    let create = NotifyEvent::new(EventKind::Create(CreateKind::File))
+      .add_path(PathBuf::from("c.ts"));
+    let mut batch = change_batch(
+      vec![remove("a.ts"), modify_event("b.ts"), create, remove("a.ts")],
+      false,
+    )
+    .unwrap();
+    let kinds: Vec<ChangeKind> = batch.kinds.iter().cloned().collect();
+    assert_eq!(
+      kinds,
+      vec![
+        ChangeKind::Created,
+        ChangeKind::Modified,
+        ChangeKind::Removed
+      ]
+    );
+    assert!(!batch.is_only(ChangeKind::Removed));
+
+    batch.merge(ChangeBatch::new(vec![], ChangeKind::Renamed));
+    assert_eq!(batch.kinds.len(), 4);
+    assert_eq!(batch.kind, ChangeKind::Modified);
+  }
+
+  #[test]
+  fn renames_are_normalized() {
+    let rename = |mode: RenameMode, paths: &[&str], tracker: usize| {
+      paths
+        .iter()
+        .fold(
+          NotifyEvent::new(EventKind::Modify(ModifyKind::Name(mode))),
+          |event, path| event.add_path(PathBuf::from(path)),
+        )
+        .set_tracker(tracker)
+    };
+
+    // Moved out of the watched directory, only the old path is seen.
+    let batch =
+      change_batch(vec![rename(RenameMode::From, &["/proj/a.ts"], 1)], false)
+        .unwrap();
+    assert!(batch.is_only(ChangeKind::Removed));
+    assert_eq!(batch.paths, vec![PathBuf::from("/proj/a.ts")]);
+    assert!(batch.renames.is_empty());
+
+    // Moved in, only the new path is seen.
+    let batch =
+      change_batch(vec![rename(RenameMode::To, &["/proj/b.ts"], 2)], false)
+        .unwrap();
+    assert!(batch.is_only(ChangeKind::Created));
+    assert!(batch.renames.is_empty());
+
+    // Moved within, as inotify reports it.
+    let batch = change_batch(
+      vec![
+        rename(RenameMode::From, &["/proj/a.ts"], 3),
+        rename(RenameMode::From, &["/proj/c.ts"], 4),
+        rename(RenameMode::To, &["/proj/b.ts"], 3),
+        rename(RenameMode::Both, &["/proj/a.ts", "/proj/b.ts"], 3),
+      ],
+      false,
+    )
+    .unwrap();
+    let kinds: Vec<ChangeKind> = batch.kinds.iter().cloned().collect();
+    assert_eq!(kinds, vec![ChangeKind::Removed, ChangeKind::Renamed]);
+    assert_eq!(
+      batch.renames,
+      vec![Rename {
+        from: PathBuf::from("/proj/a.ts"),
+        to: PathBuf::from("/proj/b.ts"),
+      }]
+    );
+    assert_eq!(
+      batch.paths,
+      vec![
+        PathBuf::from("/proj/a.ts"),
+        PathBuf::from("/proj/b.ts"),
+        PathBuf::from("/proj/c.ts")
+      ]
+    );
+  }
+
+  #[test]
+  fn paths_differing_in_case() {
+    let batch = change_batch(
+      vec![modify_event("src/Main.ts"), modify_event("src/main.ts")],
+      false,
+    )
+    .unwrap();
+    if cfg!(any(windows, target_os = "macos")) {
+      assert_eq!(batch.paths, vec![PathBuf::from("src/Main.ts")]);
+    } else {
+      assert_eq!(
+        batch.paths,
+        vec![PathBuf::from("src/Main.ts"), PathBuf::from("src/main.ts")]
+      );
+    }
+  }
+
+  #[derive(Clone, Default)]
+  struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+  impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+      self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+      Ok(())
+    }
+  }
+
+  #[test]
+  fn error_lines_show_every_cause() {
+    let err = generic_error("connection refused")
+      .context("fetching https://example.com/dep.ts")
+      .context("loading module file:///proj/main.ts");
+    assert_eq!(
+      ConsoleReporter::error_line(&err, false),
+      "error: loading module file:///proj/main.ts\n    \
+       Caused by: fetching https://example.com/dep.ts\n    \
+       Caused by: connection refused"
+    );
+    assert_eq!(
+      ConsoleReporter::error_line(&generic_error("boom"), false),
+      "error: boom"
+    );
+  }
+
+  #[test]
+  fn uncolored_lines_have_no_escape_sequences() {
+    let paths = vec![PathBuf::from("src/a.ts"), PathBuf::from("src/b.ts")];
+    let lines = vec![
+      ConsoleReporter::restart_line(&paths, None, false),
+      ConsoleReporter::restart_line(&[], None, false),
+      ConsoleReporter::terminated_line(false),
+      ConsoleReporter::error_line(&generic_error("boom"), false),
+      ConsoleReporter::repeated_error_line(false),
+    ];
+    for line in lines {
+      assert!(!line.contains('\x1b'), "{:?}", line);
+    }
+    assert_eq!(
+      ConsoleReporter::restart_line(&paths, None, false),
+      "Watcher File change detected: src/a.ts, src/b.ts. Restarting!"
+    );
+  }
+
+  /// Reads a duration as `RestartTiming` formats it.
+  fn parse_human_duration(duration: &str) -> Duration {
+    if let Some(millis) = duration.strip_suffix("ms") {
+      Duration::from_millis(millis.parse().unwrap())
+    } else {
+      let secs: f64 = duration.strip_suffix('s').unwrap().parse().unwrap();
+      Duration::from_secs_f64(secs)
+    }
+  }
+
+  #[test]
+  fn restart_lines_show_the_timing() {
+    let paths = vec![PathBuf::from("src/a.ts")];
+    let timing = RestartTiming {
+      ran: Duration::from_millis(3240),
+      debounced: Some(Duration::from_millis(200)),
+    };
+    assert_eq!(
+      ConsoleReporter::restart_line(&paths, Some(&timing), false),
+      "Watcher File change detected: src/a.ts. Restarting (ran 3.2s, \
+      debounced 200ms)!"
+    );
+    let timing = RestartTiming {
+      ran: Duration::from_millis(15),
+      debounced: None,
+    };
+    assert_eq!(
+      ConsoleReporter::restart_line(&[], Some(&timing), false),
+      "Watcher Restart requested. Restarting (ran 15ms)!"
+    );
+  }
+
+  #[tokio::test]
+  async fn restart_timing_is_measured() {
+    struct TimingReporter(Arc<Mutex<Vec<String>>>);
+
+    impl WatchReporter for TimingReporter {
+      fn on_timed_restart(
+        &self,
+        _source: Option<&str>,
+        changed_paths: &[PathBuf],
+        timing: &RestartTiming,
+      ) {
+        let line =
+          ConsoleReporter::restart_line(changed_paths, Some(timing), false);
+        self.0.lock().unwrap().push(line);
+      }
+      fn on_restart(&self, _changed_paths: &[PathBuf]) {}
+      fn on_process_terminated(&self) {}
+      fn on_error(&self, _err: &AnyError) {}
+    }
+
+    let debounce_time = Duration::from_millis(50);
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let file_path = temp_dir.path().join("mod.ts");
+    let (sender, receiver) = watch::channel(false);
+    let lines = Arc::new(Mutex::new(Vec::new()));
+    let options = WatchOptions {
+      debounce_time: Some(debounce_time),
+      shutdown: Some(receiver),
+      reporter: Reporter::new(TimingReporter(lines.clone())),
+      ..Default::default()
+    };
+
+    let run_time = Duration::from_millis(300);
+    let (result, _) = tokio::join!(
+      watch_func(&[temp_dir.path().to_path_buf()], options, |_| {
+        future::pending().boxed_local()
+      }),
+      async {
+        delay_for(run_time).await;
+        std::fs::write(&file_path, "").unwrap();
+        shutdown_after(sender, Duration::from_millis(300)).await;
+      },
+    );
+    assert!(result.is_ok());
+    let lines = lines.lock().unwrap();
+    assert_eq!(lines.len(), 1);
+    let timing = &lines[0][lines[0].find("(ran ").unwrap() + 5..];
+    let timing = timing.trim_end_matches(")!");
+    let mut parts = timing.split(", debounced ");
+    let ran = parse_human_duration(parts.next().unwrap());
+    let debounced = parse_human_duration(parts.next().unwrap());
+    assert!(ran >= run_time, "{}", lines[0]);
+    assert!(debounced >= debounce_time, "{}", lines[0]);
+    assert!(debounced < ran, "{}", lines[0]);
+  }
+
+  #[test]
+  fn restarts_are_written_as_json() {
+    let buffer = SharedBuffer::default();
+    let writer = EventWriter::new(buffer.clone());
+    let batch = ChangeBatch::new(
+      vec![PathBuf::from("src/a.ts"), PathBuf::from("src/b.ts")],
+      ChangeKind::Modified,
+    );
+    let reporter = Reporter::default();
+    log_restart(&batch, None, false, Some(&reporter), Some(&writer));
+    log_restart(
+      &ChangeBatch::new(vec![], ChangeKind::Removed),
+      None,
+      false,
+      Some(&reporter),
+      Some(&writer),
+    );
+
+    let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+    let lines: Vec<&str> = output.lines().collect();
+    assert_eq!(lines.len(), 2);
+    let event: Value = deno_core::serde_json::from_str(lines[0]).unwrap();
+    assert_eq!(
+      event,
+      json!({
+        "event": "restart",
+        "paths": ["src/a.ts", "src/b.ts"],
+        "kind": "modified",
+      })
+    );
+    let event: Value = deno_core::serde_json::from_str(lines[1]).unwrap();
+    assert_eq!(event["kind"], "removed");
+  }
+
+  #[test]
+  fn parse_debounce_env_value() {
+    assert_eq!(parse_debounce_ms("350"), Some(Duration::from_millis(350)));
+    assert_eq!(parse_debounce_ms(" 0 "), Some(Duration::from_millis(0)));
+    assert_eq!(parse_debounce_ms("fast"), None);
+  }
+
+  #[test]
+  fn explicit_debounce_time_wins() {
+    let options = WatchOptions {
+      debounce_time: Some(Duration::from_millis(5)),
+      ..Default::default()
+    };
+    assert_eq!(options.debounce_time(), Duration::from_millis(5));
+  }
+
+  #[tokio::test]
+  async fn wait_for_file_change_dedups_paths() {
+    let (mut sender, receiver) = mpsc::channel(16);
+    let mut debounce = Debounce::new(receiver, default_debounce_time());
+    let event = modify_event("b.ts")
+      .add_path(PathBuf::from("a.ts"))
+      .add_path(PathBuf::from("b.ts"));
+    sender.send(Ok(event)).await.unwrap();
+
+    let paths = wait_for_file_change(&mut debounce).await.unwrap();
+    assert_eq!(paths, vec![PathBuf::from("a.ts"), PathBuf::from("b.ts")]);
+    assert_eq!(format_paths(&paths), "a.ts, b.ts");
+  }
+
+  fn summary(paths: &[&str], max: usize) -> String {
+    let paths: Vec<PathBuf> = paths.iter().map(PathBuf::from).collect();
+    colors::strip_ansi_codes(&summarize_paths(&paths, max)).into_owned()
+  }
+
+  #[test]
+  fn summarize_few_paths() {
+    assert_eq!(summary(&["src/a.ts", "src/b.ts"], 3), "src/a.ts, src/b.ts");
+    assert_eq!(summary(&[], 3), "");
+  }
+
+  #[test]
+  fn summarize_many_paths() {
+    let paths = ["a.ts", "lib/b.ts", "src/c.ts", "test/d.ts", "x/e.ts"];
+    assert_eq!(
+      summary(&paths, 3),
+      "a.ts, lib/b.ts, src/c.ts ... and 2 more"
+    );
+  }
+
+  #[test]
+  fn summarize_common_dir() {
+    let paths = [
+      "mod.ts",
+      "src/a.ts",
+      "src/b.ts",
+      "src/c.ts",
+      "src/d/e.ts",
+      "test/f.ts",
+      "test/g.ts",
+    ];
+    assert_eq!(
+      summary(&paths, 3),
+      "mod.ts, src/ (3 files), src/d/e.ts ... and 2 more"
+    );
+    assert_eq!(summary(&["a.ts", "b.ts"], 1), "./ (2 files)");
+  }
+
+  #[test]
+  fn config_exclude_filters_events() {
+    let config = json!({
+      "compilerOptions": {},
+      "exclude": ["node_modules", "./build", "**/*.test.ts", 42],
+    });
+    let exclude = ConfigExclude::from_config(Path::new("/proj"), &config);
+    assert_eq!(exclude.patterns.len(), 3);
+    let exclude = exclude_globs_from_config(&exclude);
+    assert_eq!(
+      exclude,
+      vec!["/proj/node_modules", "/proj/build", "/proj/**/*.test.ts"]
+    );
+
+    let options = WatchOptions {
+      exclude,
+      ..Default::default()
+    };
+    let filter = proj_filter(&options);
+    assert!(filter
+      .filter(modify_event("/proj/node_modules/pkg/mod.js"))
+      .is_none());
+    assert!(filter.filter(modify_event("/proj/build/out.js")).is_none());
+    assert!(filter
+      .filter(modify_event("/proj/src/deep/mod.test.ts"))
+      .is_none());
+    assert!(filter
+      .filter(modify_event("/proj/src/node_modules/mod.js"))
+      .is_some());
+    assert!(filter.filter(modify_event("/proj/src/mod.ts")).is_some());
+  }
+
+  #[test]
+  fn watch_globs_are_split_into_roots() {
+    let patterns = vec![
+      "src/**/*.ts".to_string(),
+      "./src/lib/*.js".to_string(),
+      "*.json".to_string(),
+      "deps.ts".to_string(),
+      "/abs/**/*.ts".to_string(),
+    ];
+    let (roots, globs) =
+      expand_watch_globs_in(&patterns, Path::new("/proj")).unwrap();
+    assert_eq!(
+      roots,
+      vec![
+        PathBuf::from("/abs"),
+        PathBuf::from("/proj"),
+        PathBuf::from("/proj/deps.ts"),
+        PathBuf::from("/proj/src"),
+        PathBuf::from("/proj/src/lib"),
+      ]
+    );
+    assert_eq!(
+      globs.patterns(),
+      &[
+        "/proj/src/**/*.ts",
+        "/proj/src/lib/*.js",
+        "/proj/*.json",
+        "/proj/deps.ts",
+        "/abs/**/*.ts",
+      ]
+    );
+  }
+
+  #[test]
+  fn watch_lists_are_expanded() {
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let dir = temp_dir.path();
+    std::fs::create_dir_all(dir.join("src/nested")).unwrap();
+    for file in &["src/a.ts", "src/nested/b.ts", "src/c.js", "deps.ts"] {
+      std::fs::write(dir.join(file), "").unwrap();
+    }
+    let list = dir.join("watch.txt");
+    let contents = format!(
+      "# sources\nsrc/**/*.ts\n\n  deps.ts  \n{}\nsrc/{{a,b.ts\n",
+      dir.join("config.json").display()
+    );
+    std::fs::write(&list, contents).unwrap();
+
+    let paths = read_watch_list(&list).unwrap();
+    assert_eq!(
+      paths,
+      vec![
+        dir.join("config.json"),
+        dir.join("deps.ts"),
+        dir.join("src/a.ts"),
+        dir.join("src/nested/b.ts"),
+      ]
+    );
+    assert!(read_watch_list(&dir.join("missing.txt")).is_err());
+  }
+
+  #[test]
+  fn watch_args_expand_their_lists() {
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let dir = temp_dir.path();
+    let list = dir.join("watch.txt");
+    std::fs::write(&list, "# assets\nlogo.svg\n\nstyle.css\n").unwrap();
+
+    let args = vec!["mod.ts".to_string(), format!("@{}", list.display())];
+    let paths = expand_watch_args(&args).unwrap();
+    assert_eq!(
+      paths,
+      vec![
+        PathBuf::from("mod.ts"),
+        dir.join("logo.svg"),
+        dir.join("style.css"),
+      ]
+    );
+    let missing = format!("@{}", dir.join("missing.txt").display());
+    assert!(expand_watch_args(&[missing]).is_err());
+  }
+
+  #[tokio::test]
+  async fn watch_globs_match_new_files() {
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let pattern = format!("{}/**/*.ts", temp_dir.path().display());
+    let (roots, globs) = expand_watch_globs(&[pattern]).unwrap();
+    assert_eq!(roots, vec![temp_dir.path().to_path_buf()]);
+    let options = WatchOptions {
+      include: globs.patterns().to_vec(),
+      ..Default::default()
+    };
+    let mut changes = FileWatcher::new(&roots, options).unwrap();
+
+    std::fs::create_dir(temp_dir.path().join("src")).unwrap();
+    let module = temp_dir.path().join("src/mod.ts");
+    std::fs::write(temp_dir.path().join("src/mod.js"), "").unwrap();
+    std::fs::write(&module, "").unwrap();
+    let batch = tokio::time::timeout(Duration::from_secs(5), changes.next())
+      .await
+      .expect("new file not detected")
+      .unwrap()
+      .unwrap();
+    assert_eq!(batch.paths, vec![module]);
+  }
+
+  #[tokio::test]
+  async fn relative_watch_globs_match_new_files() {
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let cwd = temp_dir.path().canonicalize().unwrap();
+    std::fs::create_dir(cwd.join("src")).unwrap();
+    let (roots, globs) =
+      expand_watch_globs_in(&["src/**/*.ts".to_string()], &cwd).unwrap();
+    assert_eq!(roots, vec![cwd.join("src")]);
+    let options = WatchOptions {
+      include: globs.patterns().to_vec(),
+      ..Default::default()
+    };
+    let mut changes = FileWatcher::new(&roots, options).unwrap();
+
+    let module = cwd.join("src/mod.ts");
+    std::fs::write(cwd.join("src/mod.js"), "").unwrap();
+    std::fs::write(&module, "").unwrap();
+    let batch = tokio::time::timeout(Duration::from_secs(5), changes.next())
+      .await
+      .expect("new file not detected")
+      .unwrap()
+      .unwrap();
+    assert_eq!(batch.paths, vec![module]);
+  }
+
+  fn proj_filter(options: &WatchOptions) -> EventFilter {
+    let registration = Registration::new(Path::new("/proj"), options);
+    EventFilter::new(&[registration], options).unwrap()
+  }
+
+  #[test]
+  fn excluded_paths_are_filtered() {
+    let options = WatchOptions {
+      exclude: vec!["node_modules".to_string(), "/target".to_string()],
+      ..Default::default()
+    };
+    let filter = proj_filter(&options);
+
+    assert!(filter
+      .filter(modify_event("/proj/node_modules/pkg/mod.js"))
+      .is_none());
+    assert!(filter
+      .filter(modify_event("/proj/target/debug/deno"))
+      .is_none());
+    assert!(filter.filter(modify_event("/proj/src/target.ts")).is_some());
+
+    let mixed = modify_event("/proj/node_modules/a.js")
+      .add_path(PathBuf::from("/proj/main.ts"));
+    let event = filter.filter(mixed).unwrap();
+    assert_eq!(event.paths, vec![PathBuf::from("/proj/main.ts")]);
+  }
+
+  #[test]
+  fn the_state_file_is_filtered() {
+    let options = WatchOptions {
+      state_file: Some(PathBuf::from("/proj/[state]*.json")),
+      ..Default::default()
+    };
+    let filter = proj_filter(&options);
+
+    assert!(filter.filter(modify_event("/proj/[state]*.json")).is_none());
+    assert!(filter.filter(modify_event("/proj/s.json")).is_some());
+    assert!(filter
+      .filter(modify_event("/proj/sub/[state]*.json"))
+      .is_some());
+  }
+
+  #[test]
+  fn settings_are_read_from_json() {
+    let json = r#"{
+      "debounceMs": 150,
+      "recursive": false,
+      "exclude": ["node_modules"],
+      "extensions": ["ts", "tsx"],
+      "backend": "poll",
+      "pollIntervalMs": 1000,
+      "clearScreen": false,
+      "throttleMs": 2000,
+      "maxRestarts": 3
+    }"#;
+    let settings: WatchSettings =
+      deno_core::serde_json::from_str(json).unwrap();
+    assert_eq!(settings.debounce_ms, Some(150));
+    assert_eq!(settings.backend, Some(BackendSetting::Poll));
+    assert_eq!(settings.extensions, vec!["ts", "tsx"]);
+    assert_eq!(settings.run_on_start, None);
+    let typo = deno_core::serde_json::from_str::<WatchSettings>(
+      r#"{ "debounce": 150 }"#,
+    );
+    assert!(typo.is_err());
+
+    let options = WatcherBuilder::new()
+      .exclude("target")
+      .settings(&settings)
+      .options;
+    assert_eq!(options.debounce_time, Some(Duration::from_millis(150)));
+    assert_eq!(options.recursive_mode, Some(RecursiveMode::NonRecursive));
+    assert_eq!(options.exclude, vec!["target", "node_modules"]);
+    assert_eq!(options.extensions, vec!["ts", "tsx"]);
+    assert_eq!(
+      options.backend,
+      WatchBackend::Poll {
+        interval: Duration::from_secs(1)
+      }
+    );
+    assert_eq!(options.clear_screen, Some(false));
+    assert_eq!(options.throttle, Some(Duration::from_secs(2)));
+    assert_eq!(options.max_restarts, Some(3));
+    assert_eq!(options.run_on_start, None);
+  }
+
+  #[test]
+  fn only_listed_extensions_are_forwarded() {
+    let options = WatchOptions {
+      extensions: vec!["ts".to_string(), ".tsx".to_string()],
+      ..Default::default()
+    };
+    let filter = proj_filter(&options);
+
+    assert!(filter.filter(modify_event("/proj/mod.js")).is_none());
+    assert!(filter.filter(modify_event("/proj/Makefile")).is_none());
+    assert!(filter.filter(modify_event("/proj/MOD.TS")).is_some());
+    assert!(filter.filter(modify_event("/proj/src/app.tsx")).is_some());
+  }
+
+  // macOS file systems reject names that aren't valid UTF-8.
+  #[cfg(target_os = "linux")]
+  #[tokio::test]
+  async fn non_utf8_paths_are_filtered_leniently() {
+    use std::os::unix::ffi::OsStrExt;
+
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let root = temp_dir.path().to_path_buf();
+    let file_path = root.join(OsStr::from_bytes(b"inv\xffalid.ts"));
+    std::fs::write(&file_path, "").unwrap();
+    let options = WatchOptions {
+      debounce_time: Some(Duration::from_millis(50)),
+      exclude: vec!["*.log".to_string()],
+      exclude_regex: vec![Regex::new(r"\.generated\.ts$").unwrap()],
+      include: vec!["*.ts".to_string()],
+      extensions: vec!["ts".to_string()],
+      ..Default::default()
+    };
+    let mut changes = FileWatcher::new(&[root.clone()], options).unwrap();
+
+    std::fs::write(&file_path, "changed").unwrap();
+    let batch = tokio::time::timeout(Duration::from_secs(5), changes.next())
+      .await
+      .expect("change to a non UTF-8 path not detected")
+      .unwrap()
+      .unwrap();
+    assert_eq!(batch.paths, vec![file_path.clone()]);
+
+    let state_file = root.join("state.json");
+    let state = WatchState::scan(&[root], Some(&state_file));
+    assert!(state.mtimes.is_empty());
+    state.save(&state_file);
+    assert_eq!(WatchState::load(&state_file), Some(state));
+  }
+
+  #[tokio::test]
+  async fn excluded_regexes_are_filtered() {
+    let options = WatchOptions {
+      exclude: vec!["node_modules".to_string()],
+      exclude_regex: vec![Regex::new(r".*\.generated\.ts$").unwrap()],
+      ..Default::default()
+    };
+    let filter = proj_filter(&options);
+    assert!(filter
+      .filter(modify_event("/proj/src/api.generated.ts"))
+      .is_none());
+    assert!(filter
+      .filter(modify_event("/proj/node_modules/pkg/mod.js"))
+      .is_none());
+    assert!(filter.filter(modify_event("/proj/src/api.ts")).is_some());
+
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let root = temp_dir.path().canonicalize().unwrap();
+    let mut changes = FileWatcher::new(&[root.clone()], options).unwrap();
+    std::fs::write(root.join("api.generated.ts"), "").unwrap();
+    let result =
+      tokio::time::timeout(Duration::from_millis(500), changes.next()).await;
+    assert!(result.is_err());
+  }
+
+  #[test]
+  fn include_globs_restrict_paths() {
+    let options = WatchOptions {
+      include: vec!["**/*.ts".to_string(), "**/*.tsx".to_string()],
+      ..Default::default()
+    };
+    let filter = proj_filter(&options);
+
+    assert!(filter
+      .filter(modify_event("/proj/assets/logo.png"))
+      .is_none());
+    assert!(filter.filter(modify_event("/proj/src/mod.ts")).is_some());
+    assert!(filter.filter(modify_event("/proj/src/app.tsx")).is_some());
+
+    let everything = proj_filter(&Default::default());
+    assert!(everything
+      .filter(modify_event("/proj/assets/logo.png"))
+      .is_some());
+  }
+
+  #[test]
+  fn recursive_mode_defaults_by_path_type() {
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let file_path = temp_dir.path().join("mod.ts");
+    std::fs::write(&file_path, "").unwrap();
+
+    let options = WatchOptions::default();
+    assert_eq!(
+      options.recursive_mode(temp_dir.path()),
+      RecursiveMode::Recursive
+    );
+    assert_eq!(
+      options.recursive_mode(&file_path),
+      RecursiveMode::NonRecursive
+    );
+
+    let options = WatchOptions {
+      recursive_mode: Some(RecursiveMode::NonRecursive),
+      ..Default::default()
+    };
+    assert_eq!(
+      options.recursive_mode(temp_dir.path()),
+      RecursiveMode::NonRecursive
+    );
+  }
+
+  #[tokio::test]
+  async fn recursive_watch_sees_nested_changes() {
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let nested_dir = temp_dir.path().join("sub");
+    std::fs::create_dir(&nested_dir).unwrap();
+    let nested_file = nested_dir.join("nested.ts");
+    std::fs::write(&nested_file, "").unwrap();
+
+    let WatchSetup { mut debounce, .. } =
+      new_watcher(&[temp_dir.path().to_path_buf()], &Default::default())
+        .unwrap();
+    std::fs::write(&nested_file, "console.log('changed');").unwrap();
+
+    let paths = tokio::time::timeout(
+      Duration::from_secs(5),
+      wait_for_file_change(&mut debounce),
+    )
+    .await
+    .expect("no change detected")
+    .unwrap();
+    assert!(paths.iter().any(|path| path.ends_with("sub/nested.ts")));
+  }
+
+  async fn shutdown_after(sender: watch::Sender<bool>, delay: Duration) {
+    delay_for(delay).await;
+    sender.broadcast(true).unwrap();
+  }
+
+  #[tokio::test]
+  async fn watch_func_returns_on_shutdown() {
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let (sender, receiver) = watch::channel(false);
+    let options = WatchOptions {
+      shutdown: Some(receiver),
+      ..Default::default()
+    };
+
+    let (result, _) = tokio::join!(
+      watch_func(&[temp_dir.path().to_path_buf()], options, |_| {
+        future::pending().boxed_local()
+      }),
+      shutdown_after(sender, Duration::from_millis(100)),
+    );
+    assert!(result.is_ok());
+  }
+
+  #[tokio::test]
+  async fn events_during_startup_grace_are_ignored() {
+    let (handler, mut debounce) = scripted_pipeline(default_debounce_time());
+    handler.start_grace(Duration::from_millis(300));
+    handler.handle(Ok(modify_event("/proj/a.ts")));
+    let result =
+      tokio::time::timeout(default_debounce_time() * 2, debounce.next()).await;
+    assert!(result.is_err(), "event in the grace period was reported");
+
+    delay_for(Duration::from_millis(100)).await;
+    handler.handle(Ok(modify_event("/proj/b.ts")));
+    let paths = wait_for_file_change(&mut debounce).await.unwrap();
+    assert_eq!(paths, vec![PathBuf::from("/proj/b.ts")]);
+  }
+
+  #[tokio::test]
+  async fn startup_noise_does_not_restart_the_first_run() {
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let file_path = temp_dir.path().join("mod.ts");
+    let (sender, receiver) = watch::channel(false);
+    let options = WatchOptions {
+      debounce_time: Some(Duration::from_millis(50)),
+      startup_grace: Some(Duration::from_millis(300)),
+      shutdown: Some(receiver),
+      ..Default::default()
+    };
+    let runs = Cell::new(0);
+
+    let (result, _) = tokio::join!(
+      watch_func(&[temp_dir.path().to_path_buf()], options, |_| {
+        runs.set(runs.get() + 1);
+        future::pending().boxed_local()
+      }),
+      async {
+        // The watcher is established by now, the grace period just started.
+        std::fs::write(&file_path, "a").unwrap();
+        shutdown_after(sender, Duration::from_millis(600)).await;
+      },
+    );
+    assert!(result.is_ok());
+    assert_eq!(runs.get(), 1);
+  }
+
+  #[tokio::test]
+  async fn watch_func_finishes_closure_on_shutdown() {
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let (sender, receiver) = watch::channel(false);
+    let options = WatchOptions {
+      shutdown: Some(receiver),
+      finish_on_shutdown: true,
+      ..Default::default()
+    };
+    let finished = Rc::new(Cell::new(false));
+
+    let finished_ = finished.clone();
+    let (result, _) = tokio::join!(
+      watch_func(&[temp_dir.path().to_path_buf()], options, move |_| {
+        let finished = finished_.clone();
+        async move {
+          delay_for(Duration::from_millis(300)).await;
+          finished.set(true);
+          Ok(())
+        }
+        .boxed_local()
+      }),
+      shutdown_after(sender, Duration::from_millis(50)),
+    );
+    assert!(result.is_ok());
+    assert!(finished.get());
+  }
+
+  #[tokio::test]
+  async fn closure_errors_are_printed_by_default() {
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let (sender, receiver) = watch::channel(false);
+    let options = WatchOptions {
+      shutdown: Some(receiver),
+      ..Default::default()
+    };
+
+    let (result, _) = tokio::join!(
+      watch_func(&[temp_dir.path().to_path_buf()], options, |_| {
+        async { Err(generic_error("boom")) }.boxed_local()
+      }),
+      shutdown_after(sender, Duration::from_millis(100)),
+    );
+    assert!(result.is_ok());
+  }
+
+  struct RecordingReporter(Arc<Mutex<Vec<String>>>);
+
+  impl WatchReporter for RecordingReporter {
+    fn on_restart(&self, changed_paths: &[PathBuf]) {
+      let names: Vec<String> = changed_paths
+        .iter()
+        .filter_map(|path| path.file_name())
+        .map(|name| name.to_string_lossy().into_owned())
+        .collect();
+      self
+        .0
+        .lock()
+        .unwrap()
+        .push(format!("restart {}", names.join(",")));
+    }
+
+    fn on_process_terminated(&self) {
+      self.0.lock().unwrap().push("terminated".to_string());
+    }
+
+    fn on_error(&self, err: &AnyError) {
+      self.0.lock().unwrap().push(format!("error {}", err));
+    }
+
+    fn on_repeated_error(&self) {
+      self.0.lock().unwrap().push("repeated error".to_string());
+    }
+  }
+
+  #[tokio::test]
+  async fn repeated_errors_are_collapsed() {
+    let messages = Arc::new(Mutex::new(Vec::new()));
+    let reporter = Reporter::new(RecordingReporter(messages.clone()));
+    let last_error = LastError::default();
+    let run = |result: Result<(), AnyError>| {
+      error_handler(
+        async move { result }.boxed_local(),
+        false,
+        reporter.clone(),
+        None,
+        Some(last_error.clone()),
+      )
+    };
+
+    run(Err(generic_error("boom"))).await.unwrap();
+    run(Err(generic_error("boom"))).await.unwrap();
+    run(Err(generic_error("bang"))).await.unwrap();
+    run(Ok(())).await.unwrap();
+    run(Err(generic_error("bang"))).await.unwrap();
+    assert_eq!(
+      *messages.lock().unwrap(),
+      vec!["error boom", "repeated error", "error bang", "error bang"]
+    );
+  }
+
+  #[test]
+  fn repeated_errors_compare_their_causes() {
+    let last_error = LastError::default();
+    let err = generic_error("io").context("build failed");
+    assert!(!last_error.record(Some(&err)));
+    let other = generic_error("parse").context("build failed");
+    assert!(!last_error.record(Some(&other)));
+    assert!(last_error.record(Some(&other)));
+  }
+
+  #[tokio::test]
+  async fn startup_summary_is_reported_before_first_run() {
+    struct SummaryReporter(Arc<Mutex<Vec<String>>>);
+
+    impl WatchReporter for SummaryReporter {
+      fn on_start(&self, summary: &WatchSummary) {
+        let line = colors::strip_ansi_codes(&summary.to_string()).into_owned();
+        self.0.lock().unwrap().push(line);
+      }
+      fn on_restart(&self, _changed_paths: &[PathBuf]) {}
+      fn on_process_terminated(&self) {}
+      fn on_error(&self, _err: &AnyError) {}
+    }
+
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    // Relative, and without any existing ancestor to watch instead.
+    let unreachable = PathBuf::from("missing_dir/missing.ts");
+    let (sender, receiver) = watch::channel(false);
+    let messages = Arc::new(Mutex::new(Vec::new()));
+    let options = WatchOptions {
+      shutdown: Some(receiver),
+      debounce_time: Some(Duration::from_millis(50)),
+      backend: WatchBackend::Native,
+      reporter: Reporter::new(SummaryReporter(messages.clone())),
+      ..Default::default()
+    };
+
+    let messages_ = messages.clone();
+    let result = watch_func(
+      &[temp_dir.path().to_path_buf(), unreachable],
+      options,
+      |_| {
+        messages_.lock().unwrap().push("run".to_string());
+        let _ = sender.broadcast(true);
+        future::ready(Ok(())).boxed_local()
+      },
+    )
+    .await;
+    assert!(result.is_ok());
+    assert_eq!(
+      *messages.lock().unwrap(),
+      vec![
+        "Watcher Watching 1 path (1 skipped), debounce: 50ms, native \
+        notifications"
+          .to_string(),
+        "run".to_string()
+      ]
+    );
+
+    let summary = WatchSummary {
+      watched: 3,
+      skipped: 0,
+      debounce_time: Duration::from_millis(200),
+      backend: WatchBackend::Poll {
+        interval: DEFAULT_POLL_INTERVAL,
+      },
+    };
+    assert_eq!(
+      colors::strip_ansi_codes(&summary.to_string()),
+      "Watcher Watching 3 paths, debounce: 200ms, polling every 500ms"
+    );
+  }
+
+  #[tokio::test]
+  async fn running_closure_is_aborted_on_change() {
+    struct Aborted(Rc<Cell<u32>>);
+
+    impl Drop for Aborted {
+      fn drop(&mut self) {
+        self.0.set(self.0.get() + 1);
+      }
+    }
+
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let file_path = temp_dir.path().join("mod.ts");
+    let (sender, receiver) = watch::channel(false);
+    let options = WatchOptions {
+      shutdown: Some(receiver),
+      ..Default::default()
+    };
+    let runs = Cell::new(0);
+    let aborted = Rc::new(Cell::new(0));
+
+    let (result, _) = tokio::join!(
+      watch_func(&[temp_dir.path().to_path_buf()], options, |_| {
+        runs.set(runs.get() + 1);
+        let guard = Aborted(aborted.clone());
+        async move {
+          // A server that never exits on its own.
+          future::pending::<()>().await;
+          drop(guard);
+          Ok(())
+        }
+        .boxed_local()
+      }),
+      async {
+        delay_for(Duration::from_millis(100)).await;
+        std::fs::write(&file_path, "").unwrap();
+        delay_for(Duration::from_secs(1)).await;
+        assert_eq!(runs.get(), 2);
+        assert_eq!(aborted.get(), 1);
+        shutdown_after(sender, Duration::from_millis(0)).await;
+      },
+    );
+    assert!(result.is_ok());
+    assert_eq!(aborted.get(), 2);
+  }
+
+  #[tokio::test]
+  async fn next_run_waits_for_the_cleanup_of_the_aborted_one() {
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let file_path = temp_dir.path().join("mod.ts");
+    let (sender, receiver) = watch::channel(false);
+    let cleaned_up = Arc::new(AtomicBool::new(false));
+    let cleaned_up_ = cleaned_up.clone();
+    let options = WatchOptions {
+      shutdown: Some(receiver),
+      wait_for_exit: true,
+      on_abort: Some(AbortHook::new(move || {
+        let cleaned_up = cleaned_up_.clone();
+        async move {
+          delay_for(Duration::from_millis(300)).await;
+          cleaned_up.store(true, Ordering::SeqCst);
+        }
+        .boxed()
+      })),
+      ..Default::default()
+    };
+    let started_after_cleanup = RefCell::new(Vec::new());
+
+    let (result, _) = tokio::join!(
+      watch_func(&[temp_dir.path().to_path_buf()], options, |_| {
+        started_after_cleanup
+          .borrow_mut()
+          .push(cleaned_up.load(Ordering::SeqCst));
+        future::pending().boxed_local()
+      }),
+      async {
+        delay_for(Duration::from_millis(100)).await;
+        std::fs::write(&file_path, "").unwrap();
+        shutdown_after(sender, Duration::from_secs(1)).await;
+      },
+    );
+    assert!(result.is_ok());
+    assert_eq!(*started_after_cleanup.borrow(), vec![false, true]);
+  }
+
+  #[tokio::test]
+  async fn reporter_receives_messages_in_order() {
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let file_path = temp_dir.path().join("mod.ts");
+    let (sender, receiver) = watch::channel(false);
+    let messages = Arc::new(Mutex::new(Vec::new()));
+    let options = WatchOptions {
+      shutdown: Some(receiver),
+      reporter: Reporter::new(RecordingReporter(messages.clone())),
+      ..Default::default()
+    };
+    let runs = Cell::new(0);
+
+    let (result, _) = tokio::join!(
+      watch_func(&[temp_dir.path().to_path_buf()], options, |_| {
+        runs.set(runs.get() + 1);
+        if runs.get() == 1 {
+          async { Err(generic_error("boom")) }.boxed_local()
+        } else {
+          future::pending().boxed_local()
+        }
+      }),
+      async {
+        delay_for(Duration::from_millis(100)).await;
+        std::fs::write(&file_path, "").unwrap();
+        shutdown_after(sender, Duration::from_secs(1)).await;
+      },
+    );
+    assert!(result.is_ok());
+    assert_eq!(
+      *messages.lock().unwrap(),
+      vec!["error boom", "terminated", "restart mod.ts"]
+    );
+  }
+
+  #[tokio::test]
+  async fn quiet_reports_only_errors() {
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let file_path = temp_dir.path().join("mod.ts");
+    let (sender, receiver) = watch::channel(false);
+    let messages = Arc::new(Mutex::new(Vec::new()));
+    let options = WatchOptions {
+      shutdown: Some(receiver),
+      reporter: Reporter::new(RecordingReporter(messages.clone())),
+      quiet: true,
+      ..Default::default()
+    };
+    let runs = Cell::new(0);
+
+    let (result, _) = tokio::join!(
+      watch_func(&[temp_dir.path().to_path_buf()], options, |_| {
+        runs.set(runs.get() + 1);
+        if runs.get() == 1 {
+          async { Err(generic_error("boom")) }.boxed_local()
+        } else {
+          future::pending().boxed_local()
+        }
+      }),
+      async {
+        delay_for(Duration::from_millis(100)).await;
+        std::fs::write(&file_path, "").unwrap();
+        shutdown_after(sender, Duration::from_secs(1)).await;
+      },
+    );
+    assert!(result.is_ok());
+    assert_eq!(runs.get(), 2);
+    assert_eq!(*messages.lock().unwrap(), vec!["error boom"]);
+  }
+
+  #[tokio::test]
+  async fn idle_watch_exits() {
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let options = WatchOptions {
+      idle_timeout: Some(Duration::from_millis(200)),
+      ..Default::default()
+    };
+    let runs = Cell::new(0);
+    let started = Instant::now();
+
+    let result = tokio::time::timeout(
+      Duration::from_secs(5),
+      watch_func(&[temp_dir.path().to_path_buf()], options, |_| {
+        runs.set(runs.get() + 1);
+        future::pending().boxed_local()
+      }),
+    )
+    .await
+    .expect("idle watch did not exit");
+    assert!(result.is_ok());
+    assert_eq!(runs.get(), 1);
+    assert!(started.elapsed() >= Duration::from_millis(200));
+  }
+
+  #[tokio::test]
+  async fn idle_timeouts_run_on_the_clock() {
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let clock = MockClock::new();
+    let options = WatchOptions {
+      idle_timeout: Some(Duration::from_secs(3600)),
+      clock: SharedClock::new(clock.clone()),
+      ..Default::default()
+    };
+
+    let (result, ()) = tokio::time::timeout(
+      Duration::from_secs(5),
+      future::join(
+        watch_func(&[temp_dir.path().to_path_buf()], options, |_| {
+          future::pending().boxed_local()
+        }),
+        async {
+          delay_for(Duration::from_millis(100)).await;
+          clock.advance(Duration::from_secs(3600));
+        },
+      ),
+    )
+    .await
+    .expect("idle timeout did not follow the clock");
+    assert!(result.is_ok());
+  }
+
+  #[tokio::test]
+  async fn dry_run_never_runs_the_closure() {
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let file_path = temp_dir.path().join("mod.ts");
+    let (sender, receiver) = watch::channel(false);
+    let buffer = SharedBuffer::default();
+    let options = WatchOptions {
+      shutdown: Some(receiver),
+      dry_run: true,
+      json_events: Some(EventWriter::new(buffer.clone())),
+      ..Default::default()
+    };
+    let runs = Cell::new(0);
+
+    let (result, _) = tokio::join!(
+      watch_func(&[temp_dir.path().to_path_buf()], options, |_| {
+        runs.set(runs.get() + 1);
+        future::pending().boxed_local()
+      }),
+      async {
+        delay_for(Duration::from_millis(100)).await;
+        std::fs::write(&file_path, "").unwrap();
+        shutdown_after(sender, Duration::from_secs(1)).await;
+      },
+    );
+    assert!(result.is_ok());
+    assert_eq!(runs.get(), 0);
+    let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+    let event: Value =
+      deno_core::serde_json::from_str(output.lines().next().unwrap()).unwrap();
+    assert_eq!(event["event"], "dryRun");
+    assert_eq!(event["paths"], json!([file_path.to_string_lossy()]));
+  }
+
+  #[tokio::test]
+  async fn pending_changes_are_flushed_on_shutdown() {
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let file_path = temp_dir.path().join("mod.ts");
+    let (sender, receiver) = watch::channel(false);
+    let flushed = Arc::new(Mutex::new(Vec::new()));
+    let flushed_ = flushed.clone();
+    let options = WatchOptions {
+      shutdown: Some(receiver),
+      debounce_time: Some(Duration::from_secs(60)),
+      on_shutdown_flush: Some(ShutdownFlush::new(move |paths| {
+        flushed_.lock().unwrap().push(paths);
+      })),
+      ..Default::default()
+    };
+    let runs = Cell::new(0);
+
+    let (result, _) = tokio::join!(
+      watch_func(&[temp_dir.path().to_path_buf()], options, |_| {
+        runs.set(runs.get() + 1);
+        future::pending().boxed_local()
+      }),
+      async {
+        delay_for(Duration::from_millis(100)).await;
+        std::fs::write(&file_path, "").unwrap();
+        shutdown_after(sender, Duration::from_millis(500)).await;
+      },
+    );
+    assert!(result.is_ok());
+    assert_eq!(runs.get(), 1);
+    assert_eq!(*flushed.lock().unwrap(), vec![vec![file_path]]);
+  }
+
+  #[tokio::test]
+  async fn offline_changes_trigger_the_first_run() {
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let state_dir = TempDir::new().expect("tempdir fail");
+    let state_file = state_dir.path().join("watch_state.json");
+    let file_path = temp_dir.path().join("mod.ts");
+    let unchanged = temp_dir.path().join("deps.ts");
+    std::fs::write(&file_path, "").unwrap();
+    std::fs::write(&unchanged, "").unwrap();
+    let session = |run_on_start| {
+      let (sender, receiver) = watch::channel(false);
+      let options = WatchOptions {
+        shutdown: Some(receiver),
+        run_on_start: Some(run_on_start),
+        state_file: Some(state_file.clone()),
+        ..Default::default()
+      };
+      let runs = RefCell::new(Vec::new());
+      let paths = vec![temp_dir.path().to_path_buf()];
+      async move {
+        let (result, _) = tokio::join!(
+          watch_func(&paths, options, |changed| {
+            runs.borrow_mut().push(changed.to_vec());
+            future::pending().boxed_local()
+          }),
+          shutdown_after(sender, Duration::from_millis(300)),
+        );
+        assert!(result.is_ok());
+        runs.into_inner()
+      }
+    };
+
+    assert_eq!(session(true).await, vec![Vec::<PathBuf>::new()]);
+    assert!(state_file.exists());
+    delay_for(Duration::from_millis(100)).await;
+    std::fs::write(&file_path, "changed").unwrap();
+    assert_eq!(session(false).await, vec![vec![file_path]]);
+    // Nothing changed since the last run.
+    assert!(session(false).await.is_empty());
+  }
+
+  #[tokio::test]
+  async fn first_run_waits_for_a_change() {
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let file_path = temp_dir.path().join("mod.ts");
+    let (sender, receiver) = watch::channel(false);
+    let options = WatchOptions {
+      shutdown: Some(receiver),
+      run_on_start: Some(false),
+      ..Default::default()
+    };
+    let runs = Cell::new(0);
+    let first_run = RefCell::new(Vec::new());
+
+    let (result, _) = tokio::join!(
+      watch_func(&[temp_dir.path().to_path_buf()], options, |changed| {
+        runs.set(runs.get() + 1);
+        first_run.borrow_mut().extend_from_slice(changed);
+        future::pending().boxed_local()
+      }),
+      async {
+        delay_for(Duration::from_millis(500)).await;
+        assert_eq!(runs.get(), 0);
+        std::fs::write(&file_path, "").unwrap();
+        shutdown_after(sender, Duration::from_secs(1)).await;
+      },
+    );
+    assert!(result.is_ok());
+    assert_eq!(runs.get(), 1);
+    assert_eq!(*first_run.borrow(), vec![file_path]);
+  }
+
+  #[tokio::test]
+  async fn watcher_errors_are_reported() {
+    let batch =
+      ChangeBatch::new(vec![PathBuf::from("a.ts")], ChangeKind::Created);
+    let results = || {
+      deno_core::futures::stream::iter(vec![
+        Err(generic_error("inotify watch limit reached")),
+        Ok(batch.clone()),
+      ])
+    };
+
+    let messages = Arc::new(Mutex::new(Vec::new()));
+    let options = WatchOptions {
+      reporter: Reporter::new(RecordingReporter(messages.clone())),
+      ..Default::default()
+    };
+    let changes: Vec<_> =
+      report_watcher_errors(results(), &options).collect().await;
+    assert_eq!(changes.len(), 1);
+    assert_eq!(changes[0].as_ref().unwrap(), &batch);
+    assert_eq!(
+      *messages.lock().unwrap(),
+      vec!["error inotify watch limit reached"]
+    );
+
+    let options = WatchOptions {
+      exit_on_watcher_error: true,
+      ..options
+    };
+    let mut changes = report_watcher_errors(results(), &options);
+    assert!(changes.next().await.unwrap().is_err());
+    assert_eq!(messages.lock().unwrap().len(), 1);
+  }
+
+  #[tokio::test]
+  async fn closure_errors_can_be_fatal() {
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let options = WatchOptions {
+      exit_on_error: true,
+      ..Default::default()
+    };
+
+    let result = watch_func(&[temp_dir.path().to_path_buf()], options, |_| {
+      async { Err(generic_error("boom")) }.boxed_local()
+    })
+    .await;
+    match result {
+      Err(WatchError::Closure(err)) => assert_eq!(err.to_string(), "boom"),
+      other => panic!("unexpected result: {:?}", other),
+    }
+  }
+
+  #[tokio::test]
+  async fn watcher_errors_are_distinguished() {
+    let unreachable = PathBuf::from("missing_dir/missing.ts");
+    let result = watch_func(&[unreachable], Default::default(), |_| {
+      future::pending().boxed_local()
+    })
+    .await;
+    assert!(matches!(result, Err(WatchError::Watcher(_))));
+  }
+
+  #[tokio::test]
+  async fn restart_hook_runs_and_survives_panics() {
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let file_path = temp_dir.path().join("mod.ts");
+    let (sender, receiver) = watch::channel(false);
+    let hook_paths = Arc::new(Mutex::new(Vec::new()));
+    let hook_paths_ = hook_paths.clone();
+    let options = WatchOptions {
+      shutdown: Some(receiver),
+      on_before_restart: Some(RestartHook::new(move |paths| {
+        hook_paths_.lock().unwrap().extend_from_slice(paths);
+        panic!("hook failed");
+      })),
+      ..Default::default()
+    };
+    let runs = Rc::new(Cell::new(0));
+
+    let runs_ = runs.clone();
+    let file_path_ = file_path.clone();
+    let (result, _) = tokio::join!(
+      watch_func(&[temp_dir.path().to_path_buf()], options, move |_| {
+        runs_.set(runs_.get() + 1);
+        future::pending().boxed_local()
+      }),
+      async {
+        delay_for(Duration::from_millis(100)).await;
+        std::fs::write(&file_path_, "").unwrap();
+        for _ in 0..100 {
+          if runs.get() >= 2 {
+            break;
+          }
+          delay_for(Duration::from_millis(50)).await;
+        }
+        sender.broadcast(true).unwrap();
+      },
+    );
+    assert!(result.is_ok());
+    assert!(runs.get() >= 2);
+    assert!(hook_paths
+      .lock()
+      .unwrap()
+      .iter()
+      .any(|path| path.ends_with("mod.ts")));
+  }
+
+  #[tokio::test]
+  async fn watch_notify_reports_changed_paths() {
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let root = temp_dir.path().canonicalize().unwrap();
+    let expected: BTreeSet<PathBuf> = ["a.ts", "b.ts", "c.ts"]
+      .iter()
+      .map(|name| root.join(name))
+      .collect();
+    let (sender, receiver) = watch::channel(false);
+    let options = WatchOptions {
+      shutdown: Some(receiver),
+      ..Default::default()
+    };
+    let seen = Rc::new(RefCell::new(BTreeSet::new()));
+
+    let seen_ = seen.clone();
+    let (result, _) = tokio::join!(
+      watch_notify(&[root.clone()], options, move |paths| {
+        seen_.borrow_mut().extend(paths);
+      }),
+      async {
+        delay_for(Duration::from_millis(100)).await;
+        for path in &expected {
+          std::fs::write(path, "").unwrap();
+        }
+        for _ in 0..100 {
+          if *seen.borrow() == expected {
+            break;
+          }
+          delay_for(Duration::from_millis(50)).await;
+        }
+        sender.broadcast(true).unwrap();
+      },
+    );
+    assert!(result.is_ok());
+    assert_eq!(*seen.borrow(), expected);
+  }
+
+  #[tokio::test]
+  async fn closure_panics_are_survived() {
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let file_path = temp_dir.path().join("mod.ts");
+    let (sender, receiver) = watch::channel(false);
+    let options = WatchOptions {
+      shutdown: Some(receiver),
+      ..Default::default()
+    };
+    let runs = Rc::new(Cell::new(0));
+
+    let runs_ = runs.clone();
+    let file_path_ = file_path.clone();
+    let (result, _) = tokio::join!(
+      watch_func(&[temp_dir.path().to_path_buf()], options, move |_| {
+        runs_.set(runs_.get() + 1);
+        let should_panic = runs_.get() == 1;
+        async move {
+          if should_panic {
+            panic!("closure failed");
+          }
+          future::pending::<()>().await;
+          Ok(())
+        }
+        .boxed_local()
+      }),
+      async {
+        delay_for(Duration::from_millis(100)).await;
+        std::fs::write(&file_path_, "").unwrap();
+        for _ in 0..100 {
+          if runs.get() >= 2 {
+            break;
+          }
+          delay_for(Duration::from_millis(50)).await;
+        }
+        sender.broadcast(true).unwrap();
+      },
+    );
+    assert!(result.is_ok());
+    assert!(runs.get() >= 2);
+  }
+
+  #[test]
+  fn closure_panics_fail_the_run() {
+    let future = start_run(
+      &mut |_: &[PathBuf]| -> WatchFuture { panic!("sync failure") },
+      &[],
+    );
+    let err = future.now_or_never().unwrap().unwrap_err();
+    assert_eq!(err.to_string(), "Watched closure panicked: sync failure");
+  }
+
+  #[tokio::test]
+  async fn closure_can_keep_state_across_runs() {
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let file_path = temp_dir.path().join("mod.ts");
+    let (sender, receiver) = watch::channel(false);
+    let options = WatchOptions {
+      shutdown: Some(receiver),
+      ..Default::default()
+    };
+    let mut runs = 0usize;
+
+    let runs_ref = &mut runs;
+    let (result, _) = tokio::join!(
+      watch_func(&[temp_dir.path().to_path_buf()], options, |_| {
+        *runs_ref += 1;
+        future::pending().boxed_local()
+      }),
+      async {
+        delay_for(Duration::from_millis(100)).await;
+        std::fs::write(&file_path, "").unwrap();
+        delay_for(Duration::from_secs(1)).await;
+        sender.broadcast(true).unwrap();
+      },
+    );
+    assert!(result.is_ok());
+    assert!(runs >= 2);
+  }
+
+  #[cfg(unix)]
+  #[tokio::test]
+  async fn restart_signal_restarts_without_changes() {
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let (sender, receiver) = watch::channel(false);
+    // Not the default signal, so other watchers in this test process don't
+    // restart too.
+    let options = WatchOptions {
+      shutdown: Some(receiver),
+      restart_signal: Some(libc::SIGUSR2),
+      ..Default::default()
+    };
+    let calls = Rc::new(RefCell::new(Vec::new()));
+
+    let calls_ = calls.clone();
+    let (result, _) = tokio::join!(
+      watch_func(&[temp_dir.path().to_path_buf()], options, move |paths| {
+        calls_.borrow_mut().push(paths.to_vec());
+        future::pending().boxed_local()
+      }),
+      async {
+        delay_for(Duration::from_millis(100)).await;
+        crate::signal::kill(std::process::id() as i32, libc::SIGUSR2).unwrap();
+        shutdown_after(sender, Duration::from_millis(500)).await;
+      },
+    );
+    assert!(result.is_ok());
+    assert_eq!(*calls.borrow(), vec![Vec::<PathBuf>::new(), Vec::new()]);
+  }
+
+  #[cfg(unix)]
+  #[tokio::test]
+  async fn restart_command_gets_the_changed_paths() {
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let watched = temp_dir.path().join("src");
+    std::fs::create_dir(&watched).unwrap();
+    let file_path = watched.join("mod.ts");
+    let output = temp_dir.path().join("paths.txt");
+    let (sender, receiver) = watch::channel(false);
+    let script = format!("printf '%s\\n' \"$@\" > '{}'", output.display());
+    let options = WatchOptions {
+      debounce_time: Some(Duration::from_millis(50)),
+      shutdown: Some(receiver),
+      on_restart_command: Some(vec![
+        "sh".to_string(),
+        "-c".to_string(),
+        script,
+        "sh".to_string(),
+      ]),
+      ..Default::default()
+    };
+
+    let (result, _) = tokio::join!(
+      watch_func(&[watched], options, |_| future::pending().boxed_local()),
+      async {
+        delay_for(Duration::from_millis(100)).await;
+        std::fs::write(&file_path, "").unwrap();
+        shutdown_after(sender, Duration::from_millis(1000)).await;
+      },
+    );
+    assert!(result.is_ok());
+    let paths = std::fs::read_to_string(&output).unwrap();
+    assert_eq!(paths, format!("{}\n", file_path.display()));
+  }
+
+  #[cfg(unix)]
+  #[tokio::test]
+  async fn exec_reruns_the_command_and_kills_the_last_run() {
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let watched = temp_dir.path().join("src");
+    std::fs::create_dir(&watched).unwrap();
+    let file_path = watched.join("mod.ts");
+    let output = temp_dir.path().join("runs.txt");
+    let (sender, receiver) = watch::channel(false);
+    let script = format!(
+      "echo start >> '{0}'; sleep 10; echo end >> '{0}'",
+      output.display()
+    );
+    let options = WatchOptions {
+      debounce_time: Some(Duration::from_millis(50)),
+      shutdown: Some(receiver),
+      quiet: true,
+      ..Default::default()
+    };
+    let command = vec!["sh".to_string(), "-c".to_string(), script];
+
+    let (result, _) =
+      tokio::join!(watch_exec(&[watched], command, options), async {
+        delay_for(Duration::from_millis(200)).await;
+        std::fs::write(&file_path, "").unwrap();
+        shutdown_after(sender, Duration::from_millis(1000)).await;
+      });
+    assert!(result.is_ok());
+    let runs = std::fs::read_to_string(&output).unwrap();
+    assert_eq!(runs, "start\nstart\n");
+  }
+
+  #[tokio::test]
+  async fn files_written_by_the_run_dont_restart_it() {
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let root = temp_dir.path().to_path_buf();
+    let output = root.join("out.js");
+    let (sender, receiver) = watch::channel(false);
+    let options = WatchOptions {
+      debounce_time: Some(Duration::from_millis(50)),
+      startup_grace: Some(Duration::from_secs(0)),
+      detect_self_writes: true,
+      shutdown: Some(receiver),
+      quiet: true,
+      ..Default::default()
+    };
+    let runs = Cell::new(0);
+
+    let (result, _) = tokio::join!(
+      watch_func(&[root.clone()], options, |_| {
+        runs.set(runs.get() + 1);
+        std::fs::write(&output, format!("// run {}", runs.get())).unwrap();
+        future::ready(Ok(())).boxed_local()
+      }),
+      async {
+        delay_for(Duration::from_millis(500)).await;
+        std::fs::write(root.join("mod.ts"), "").unwrap();
+        shutdown_after(sender, Duration::from_millis(500)).await;
+      },
+    );
+    assert!(result.is_ok());
+    // Started, then restarted for `mod.ts` only.
+    assert_eq!(runs.get(), 2);
+  }
+
+  #[tokio::test]
+  async fn files_saved_during_a_short_run_still_restart_it() {
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let root = temp_dir.path().to_path_buf();
+    let dist = root.join("dist");
+    std::fs::create_dir(&dist).unwrap();
+    let output = dist.join("out.js");
+    let (sender, receiver) = watch::channel(false);
+    let options = WatchOptions {
+      debounce_time: Some(Duration::from_millis(300)),
+      startup_grace: Some(Duration::from_secs(0)),
+      detect_self_writes: true,
+      output_paths: vec![dist.clone()],
+      shutdown: Some(receiver),
+      quiet: true,
+      ..Default::default()
+    };
+    let runs = Cell::new(0);
+
+    let (result, _) = tokio::join!(
+      watch_func(&[root.clone()], options, |_| {
+        runs.set(runs.get() + 1);
+        let output = output.clone();
+        async move {
+          delay_for(Duration::from_millis(100)).await;
+          std::fs::write(&output, "").unwrap();
+          Ok(())
+        }
+        .boxed_local()
+      }),
+      async {
+        // Saved while the first run goes on, and still being debounced when
+        // it ends.
+        delay_for(Duration::from_millis(50)).await;
+        std::fs::write(root.join("mod.ts"), "").unwrap();
+        shutdown_after(sender, Duration::from_millis(1200)).await;
+      },
+    );
+    assert!(result.is_ok());
+    // Restarted for `mod.ts`, but not for what the second run wrote.
+    assert_eq!(runs.get(), 2);
+  }
+
+  #[test]
+  fn self_write_scans_skip_what_isnt_watched() {
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let root = temp_dir.path().to_path_buf();
+    for dir in &["src/deep/deeper", "node_modules/pkg", "dist"] {
+      std::fs::create_dir_all(root.join(dir)).unwrap();
+    }
+    for file in &[
+      "src/mod.ts",
+      "src/deep/a.ts",
+      "src/deep/deeper/b.ts",
+      "node_modules/pkg/mod.js",
+      "dist/out.js",
+    ] {
+      std::fs::write(root.join(file), "").unwrap();
+    }
+    let scanned = |options: WatchOptions| -> Vec<PathBuf> {
+      let self_writes = SelfWrites::new(&[root.clone()], &options).unwrap();
+      let state = self_writes.scan();
+      state
+        .mtimes
+        .keys()
+        .map(|path| path.strip_prefix(&root).unwrap().to_path_buf())
+        .collect()
+    };
+
+    let options = WatchOptions {
+      exclude: vec!["node_modules".to_string()],
+      max_depth: Some(1),
+      ..Default::default()
+    };
+    assert_eq!(
+      scanned(options),
+      vec![PathBuf::from("dist/out.js"), PathBuf::from("src/mod.ts")]
+    );
+    let options = WatchOptions {
+      output_paths: vec![root.join("dist")],
+      ..Default::default()
+    };
+    assert_eq!(scanned(options), vec![PathBuf::from("dist/out.js")]);
+  }
+
+  #[tokio::test]
+  async fn fast_failing_runs_slow_down_restarts() {
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let file_path = temp_dir.path().join("mod.ts");
+    let (sender, receiver) = watch::channel(false);
+    let options = WatchOptions {
+      debounce_time: Some(Duration::from_millis(20)),
+      fail_fast: Some(Duration::from_millis(100)),
+      shutdown: Some(receiver),
+      quiet: true,
+      ..Default::default()
+    };
+    let runs = Cell::new(0);
+
+    let (result, _) = tokio::join!(
+      watch_func(&[temp_dir.path().to_path_buf()], options, |_| {
+        runs.set(runs.get() + 1);
+        future::ready(Err(generic_error("boom"))).boxed_local()
+      }),
+      async {
+        // Without the delay, every write would restart the closure.
+        for i in 0..15 {
+          delay_for(Duration::from_millis(80)).await;
+          std::fs::write(&file_path, i.to_string()).unwrap();
+        }
+        shutdown_after(sender, Duration::from_millis(100)).await;
+      },
+    );
+    assert!(result.is_ok());
+    assert!(runs.get() >= FAIL_FAST_RUNS);
+    assert!(runs.get() <= FAIL_FAST_RUNS + 2, "ran {} times", runs.get());
+  }
+
+  #[tokio::test]
+  async fn watch_func_exits_after_max_restarts() {
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let file_path = temp_dir.path().join("mod.ts");
+    let options = WatchOptions {
+      debounce_time: Some(Duration::from_millis(50)),
+      max_restarts: Some(2),
+      ..Default::default()
+    };
+    let runs = Cell::new(0);
+
+    let (result, _) = tokio::join!(
+      tokio::time::timeout(
+        Duration::from_secs(5),
+        watch_func(&[temp_dir.path().to_path_buf()], options, |_| {
+          runs.set(runs.get() + 1);
+          future::ready(Ok(())).boxed_local()
+        }),
+      ),
+      async {
+        for contents in &["a", "b", "c"] {
+          delay_for(Duration::from_millis(300)).await;
+          std::fs::write(&file_path, contents).unwrap();
+        }
+      },
+    );
+    assert!(result.expect("watch_func did not exit").is_ok());
+    assert_eq!(runs.get(), 3);
+  }
+
+  #[tokio::test]
+  async fn merged_batches_restart_like_file_changes() {
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let file_path = temp_dir.path().join("mod.ts");
+    let (sender, receiver) = watch::channel(false);
+    let options = WatchOptions {
+      shutdown: Some(receiver),
+      ..Default::default()
+    };
+    let forced = stream::once(
+      delay_for(Duration::from_millis(200))
+        .map(|_| WatchBatch::forced("timer")),
+    );
+    let calls = Rc::new(RefCell::new(Vec::new()));
+
+    let calls_ = calls.clone();
+    let (result, _) = tokio::join!(
+      WatcherBuilder::from(options).watch_merged(
+        &[temp_dir.path().to_path_buf()],
+        forced,
+        move |paths| {
+          calls_.borrow_mut().push(paths.to_vec());
+          future::pending().boxed_local()
+        }
+      ),
+      async {
+        delay_for(Duration::from_millis(600)).await;
+        std::fs::write(&file_path, "").unwrap();
+        shutdown_after(sender, Duration::from_millis(1000)).await;
+      },
+    );
+    assert!(result.is_ok());
+    let calls = calls.borrow();
+    assert_eq!(calls.len(), 3);
+    assert!(calls[1].is_empty());
+    assert!(calls[2].iter().any(|path| path.ends_with("mod.ts")));
+  }
+
+  #[tokio::test]
+  async fn metrics_count_restarts() {
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let file_path = temp_dir.path().join("mod.ts");
+    let (sender, receiver) = watch::channel(false);
+    let metrics = Arc::new(Mutex::new(WatchMetrics::default()));
+    let options = WatchOptions {
+      debounce_time: Some(Duration::from_millis(50)),
+      shutdown: Some(receiver),
+      metrics: Some(metrics.clone()),
+      ..Default::default()
+    };
+    let runs = Cell::new(0u64);
+
+    let started = Instant::now();
+    let (result, _) = tokio::join!(
+      watch_func(&[temp_dir.path().to_path_buf()], options, |_| {
+        runs.set(runs.get() + 1);
+        future::pending().boxed_local()
+      }),
+      async {
+        for contents in &["a", "b"] {
+          delay_for(Duration::from_millis(300)).await;
+          std::fs::write(&file_path, contents).unwrap();
+        }
+        shutdown_after(sender, Duration::from_millis(300)).await;
+      },
+    );
+    assert!(result.is_ok());
+    let metrics = metrics.lock().unwrap();
+    assert!(metrics.restarts >= 2);
+    assert_eq!(metrics.restarts, runs.get() - 1);
+    assert!(metrics.last_restart.is_some());
+    assert!(metrics.run_time > Duration::from_millis(0));
+    assert!(metrics.run_time <= started.elapsed());
+  }
+
+  #[tokio::test]
+  async fn exit_summary_recaps_the_session() {
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let file_path = temp_dir.path().join("mod.ts");
+    std::fs::write(&file_path, "").unwrap();
+    let metrics = Arc::new(Mutex::new(WatchMetrics::default()));
+    let options = WatchOptions {
+      debounce_time: Some(Duration::from_millis(50)),
+      max_restarts: Some(2),
+      summary_on_exit: true,
+      metrics: Some(metrics.clone()),
+      ..Default::default()
+    };
+
+    let (result, _) = tokio::join!(
+      tokio::time::timeout(
+        Duration::from_secs(10),
+        watch_func(&[file_path.clone()], options, |_| {
+          future::ready(Ok(())).boxed_local()
+        })
+      ),
+      async {
+        for contents in &["a", "b"] {
+          delay_for(Duration::from_millis(300)).await;
+          std::fs::write(&file_path, contents).unwrap();
+        }
+      },
+    );
+    assert!(result.expect("watching did not end").is_ok());
+    let metrics = metrics.lock().unwrap();
+    assert_eq!(metrics.restarts, 2);
+    assert_eq!(metrics.successful_runs, 3);
+    assert_eq!(metrics.path_changes.get(&file_path), Some(&2));
+    let summary = exit_summary(&metrics, Duration::from_secs(1));
+    assert!(summary.contains("Restarted 2 times in 1.0s"));
+    assert!(summary.contains(&format!("{} (2)", file_path.display())));
+  }
+
+  #[tokio::test]
+  async fn spawned_watch_can_be_stopped() {
+    fn assert_send<T: Send>(_: &T) {}
+
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let file_path = temp_dir.path().join("mod.ts");
+    let runs = Arc::new(Mutex::new(0));
+
+    let runs_ = runs.clone();
+    let handle = spawn_watch(
+      vec![temp_dir.path().to_path_buf()],
+      Default::default(),
+      move |_| {
+        *runs_.lock().unwrap() += 1;
+        future::pending().boxed_local()
+      },
+    );
+    assert_send(&handle);
+
+    delay_for(Duration::from_millis(200)).await;
+    std::fs::write(&file_path, "").unwrap();
+    delay_for(Duration::from_secs(1)).await;
+    handle.stop();
+    let result = tokio::time::timeout(Duration::from_secs(5), handle.join())
+      .await
+      .expect("watcher did not stop");
+    assert!(result.is_ok());
+    assert!(*runs.lock().unwrap() >= 2);
+  }
+
+  #[tokio::test]
+  async fn last_run_status_follows_restarts() {
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let file_path = temp_dir.path().join("mod.ts");
+    let options = WatchOptions {
+      debounce_time: Some(Duration::from_millis(20)),
+      quiet: true,
+      reporter: Reporter::new(RecordingReporter(Default::default())),
+      ..Default::default()
+    };
+    let runs = Arc::new(AtomicUsize::new(0));
+
+    let runs_ = runs.clone();
+    let handle =
+      spawn_watch(vec![temp_dir.path().to_path_buf()], options, move |_| {
+        let run = runs_.fetch_add(1, Ordering::SeqCst) + 1;
+        async move {
+          if run == 1 {
+            Err(generic_error("boom"))
+          } else {
+            Ok(())
+          }
+        }
+        .boxed_local()
+      });
+    async fn wait_for_status(handle: &WatchHandle, ok: bool) {
+      for _ in 0..100 {
+        if handle.last_run_ok() == Some(ok) {
+          return;
+        }
+        delay_for(Duration::from_millis(50)).await;
+      }
+      panic!("last run status did not become {}", ok);
+    }
+
+    wait_for_status(&handle, false).await;
+    std::fs::write(&file_path, "").unwrap();
+    wait_for_status(&handle, true).await;
+    assert!(runs.load(Ordering::SeqCst) >= 2);
+
+    handle.stop();
+    handle.join().await.unwrap();
+  }
+
+  #[tokio::test]
+  async fn diagnostics_reflect_the_session() {
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let root = temp_dir.path().to_path_buf();
+    let options = WatchOptions {
+      backend: WatchBackend::Poll {
+        interval: Duration::from_millis(100),
+      },
+      debounce_time: Some(Duration::from_millis(20)),
+      quiet: true,
+      reporter: Reporter::new(RecordingReporter(Default::default())),
+      ..Default::default()
+    };
+    let handle = spawn_watch(vec![root.clone()], options, |_| {
+      async { Err(generic_error("boom")) }.boxed_local()
+    });
+
+    let mut diagnostics = handle.diagnostics();
+    for _ in 0..50 {
+      if diagnostics.last_error.is_some() {
+        break;
+      }
+      delay_for(Duration::from_millis(50)).await;
+      diagnostics = handle.diagnostics();
+    }
+    assert_eq!(diagnostics.roots, vec![root.clone()]);
+    assert_eq!(diagnostics.live, vec![root]);
+    assert_eq!(diagnostics.backend.as_deref(), Some("polling every 100ms"));
+    assert_eq!(diagnostics.last_error.as_deref(), Some("boom"));
+    assert_eq!(diagnostics.restarts, 0);
+    assert_eq!(diagnostics.debounce_ms, 20);
+    assert_eq!(diagnostics.max_debounce_ms, None);
+
+    handle.stop();
+    handle.join().await.unwrap();
+  }
+
+  #[tokio::test]
+  async fn groups_restart_independently() {
+    let frontend = TempDir::new().expect("tempdir fail");
+    let backend = TempDir::new().expect("tempdir fail");
+    let (sender, receiver) = watch::channel(false);
+    let options = WatchOptions {
+      debounce_time: Some(Duration::from_millis(20)),
+      quiet: true,
+      ..Default::default()
+    };
+    let frontend_runs = Arc::new(AtomicUsize::new(0));
+    let backend_runs = Arc::new(AtomicUsize::new(0));
+    let group = |dir: &TempDir, runs: &Arc<AtomicUsize>| {
+      let runs = runs.clone();
+      let paths = vec![dir.path().to_path_buf()];
+      WatchGroup::new(paths, options.clone(), move |_| {
+        runs.fetch_add(1, Ordering::SeqCst);
+        future::pending().boxed_local()
+      })
+    };
+    let groups = vec![
+      group(&frontend, &frontend_runs),
+      group(&backend, &backend_runs),
+    ];
+
+    let (result, _) =
+      tokio::join!(watch_groups(groups, Some(receiver)), async {
+        delay_for(Duration::from_millis(300)).await;
+        std::fs::write(frontend.path().join("app.tsx"), "").unwrap();
+        shutdown_after(sender, Duration::from_secs(1)).await;
+      });
+    assert!(result.is_ok());
+    assert_eq!(frontend_runs.load(Ordering::SeqCst), 2);
+    assert_eq!(backend_runs.load(Ordering::SeqCst), 1);
+  }
+
+  #[tokio::test]
+  async fn paused_changes_restart_once_on_resume() {
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let file_path = temp_dir.path().join("mod.ts");
+    let options = WatchOptions {
+      debounce_time: Some(Duration::from_millis(20)),
+      ..Default::default()
+    };
+    let runs = Arc::new(Mutex::new(Vec::new()));
+
+    let runs_ = runs.clone();
+    let handle =
+      spawn_watch(vec![temp_dir.path().to_path_buf()], options, move |paths| {
+        runs_.lock().unwrap().push(paths.to_vec());
+        future::pending().boxed_local()
+      });
+    delay_for(Duration::from_millis(200)).await;
+    handle.pause();
+    for i in 0..3 {
+      std::fs::write(temp_dir.path().join(format!("{}.ts", i)), "").unwrap();
+      delay_for(Duration::from_millis(200)).await;
+    }
+    assert_eq!(runs.lock().unwrap().len(), 1);
+
+    handle.resume();
+    delay_for(Duration::from_millis(500)).await;
+    {
+      let runs = runs.lock().unwrap();
+      assert_eq!(runs.len(), 2);
+      assert_eq!(runs[1].len(), 3);
+    }
+
+    // Unpaused, changes restart right away again.
+    std::fs::write(&file_path, "").unwrap();
+    delay_for(Duration::from_millis(500)).await;
+    assert_eq!(runs.lock().unwrap().len(), 3);
+    handle.stop();
+    let result = tokio::time::timeout(Duration::from_secs(5), handle.join())
+      .await
+      .expect("watcher did not stop");
+    assert!(result.is_ok());
+  }
+
+  #[tokio::test]
+  async fn suppressed_paths_do_not_restart() {
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let file_path = temp_dir.path().join("mod.ts");
+    std::fs::write(&file_path, "").unwrap();
+    let options = WatchOptions {
+      debounce_time: Some(Duration::from_millis(20)),
+      ..Default::default()
+    };
+    let runs = Arc::new(Mutex::new(0));
+
+    let runs_ = runs.clone();
+    let handle =
+      spawn_watch(vec![temp_dir.path().to_path_buf()], options, move |_| {
+        *runs_.lock().unwrap() += 1;
+        future::pending().boxed_local()
+      });
+    delay_for(Duration::from_millis(200)).await;
+    handle.suppress(file_path.clone(), Duration::from_millis(500));
+    std::fs::write(&file_path, "formatted").unwrap();
+    delay_for(Duration::from_millis(300)).await;
+    assert_eq!(*runs.lock().unwrap(), 1);
+
+    delay_for(Duration::from_millis(300)).await;
+    std::fs::write(&file_path, "edited").unwrap();
+    delay_for(Duration::from_millis(500)).await;
+    assert_eq!(*runs.lock().unwrap(), 2);
+    handle.stop();
+    let result = tokio::time::timeout(Duration::from_secs(5), handle.join())
+      .await
+      .expect("watcher did not stop");
+    assert!(result.is_ok());
+  }
+
+  #[test]
+  fn suppressions_expire() {
+    let suppressions = Suppressions::new();
+    suppressions.suppress(PathBuf::from("/proj/gen"), Duration::from_secs(60));
+    suppressions
+      .suppress(PathBuf::from("/proj/mod.ts"), Duration::from_secs(0));
+    assert!(suppressions.is_suppressed(Path::new("/proj/gen/out.ts")));
+    assert!(!suppressions.is_suppressed(Path::new("/proj/mod.ts")));
+    assert!(!suppressions.is_suppressed(Path::new("/proj/other.ts")));
+    assert_eq!(suppressions.0.lock().unwrap().len(), 1);
+  }
+
+  #[tokio::test]
+  async fn last_batch_is_recorded() {
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let root = temp_dir.path().canonicalize().unwrap();
+    let handle = spawn_watch(
+      vec![root.clone()],
+      WatchOptions {
+        debounce_time: Some(Duration::from_millis(50)),
+        ..Default::default()
+      },
+      |_| future::pending().boxed_local(),
+    );
+    delay_for(Duration::from_millis(200)).await;
+    assert!(handle.last_batch().is_none());
+
+    let before = Instant::now();
+    std::fs::write(root.join("a.ts"), "").unwrap();
+    std::fs::write(root.join("b.ts"), "").unwrap();
+    delay_for(Duration::from_millis(500)).await;
+    let (at, size) = handle.last_batch().expect("no batch recorded");
+    assert_eq!(size, 2);
+    assert!(at >= before && at <= Instant::now());
+    handle.stop();
+    assert!(handle.join().await.is_ok());
+  }
+
+  #[tokio::test]
+  async fn restart_delay_is_awaited() {
+    let restart_delay = Duration::from_millis(500);
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let file_path = temp_dir.path().join("mod.ts");
+    let (sender, receiver) = watch::channel(false);
+    let options = WatchOptions {
+      debounce_time: Some(Duration::from_millis(20)),
+      shutdown: Some(receiver),
+      restart_delay: Some(restart_delay),
+      ..Default::default()
+    };
+    let starts = RefCell::new(Vec::new());
+    let written = Cell::new(None);
+
+    let (result, _) = tokio::join!(
+      watch_func(&[temp_dir.path().to_path_buf()], options, |_| {
+        starts.borrow_mut().push(Instant::now());
+        async { Ok(()) }.boxed_local()
+      }),
+      async {
+        delay_for(Duration::from_millis(100)).await;
+        written.set(Some(Instant::now()));
+        std::fs::write(&file_path, "").unwrap();
+        shutdown_after(sender, Duration::from_secs(1)).await;
+      },
+    );
+    assert!(result.is_ok());
+    let starts = starts.borrow();
+    assert_eq!(starts.len(), 2);
+    assert!(starts[1] - written.get().unwrap() >= restart_delay);
+  }
+
+  #[tokio::test]
+  async fn changes_during_the_cleanup_join_the_restart() {
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let a = temp_dir.path().join("a.ts");
+    let b = temp_dir.path().join("b.ts");
+    let (sender, receiver) = watch::channel(false);
+    let options = WatchOptions {
+      debounce_time: Some(Duration::from_millis(20)),
+      shutdown: Some(receiver),
+      wait_for_exit: true,
+      on_abort: Some(AbortHook::new(|| {
+        delay_for(Duration::from_millis(500)).boxed()
+      })),
+      ..Default::default()
+    };
+    let calls = RefCell::new(Vec::new());
+
+    let (result, _) = tokio::join!(
+      watch_func(&[temp_dir.path().to_path_buf()], options, |paths| {
+        calls.borrow_mut().push(paths.to_vec());
+        future::pending().boxed_local()
+      }),
+      async {
+        delay_for(Duration::from_millis(100)).await;
+        std::fs::write(&a, "").unwrap();
+        // Well into the cleanup of the aborted run.
+        delay_for(Duration::from_millis(250)).await;
+        std::fs::write(&b, "").unwrap();
+        shutdown_after(sender, Duration::from_secs(1)).await;
+      },
+    );
+    assert!(result.is_ok());
+    let calls = calls.borrow();
+    assert_eq!(calls.len(), 2);
+    assert!(calls[1].contains(&a));
+    assert!(calls[1].contains(&b));
+  }
+
+  #[tokio::test]
+  async fn changes_during_the_restart_delay_join_the_restart() {
+    let restart_delay = Duration::from_millis(500);
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let a = temp_dir.path().join("a.ts");
+    let b = temp_dir.path().join("b.ts");
+    let (sender, receiver) = watch::channel(false);
+    let options = WatchOptions {
+      debounce_time: Some(Duration::from_millis(20)),
+      shutdown: Some(receiver),
+      restart_delay: Some(restart_delay),
+      ..Default::default()
+    };
+    let calls = RefCell::new(Vec::new());
+
+    let (result, _) = tokio::join!(
+      watch_func(&[temp_dir.path().to_path_buf()], options, |paths| {
+        calls.borrow_mut().push(paths.to_vec());
+        async { Ok(()) }.boxed_local()
+      }),
+      async {
+        delay_for(Duration::from_millis(100)).await;
+        std::fs::write(&a, "").unwrap();
+        // Well into the restart delay.
+        delay_for(Duration::from_millis(250)).await;
+        std::fs::write(&b, "").unwrap();
+        shutdown_after(sender, Duration::from_secs(1)).await;
+      },
+    );
+    assert!(result.is_ok());
+    let calls = calls.borrow();
+    assert_eq!(calls.len(), 2);
+    assert!(calls[1].contains(&a));
+    assert!(calls[1].contains(&b));
+  }
+
+  #[tokio::test]
+  async fn throttle_spaces_out_restarts() {
+    let throttle = Duration::from_millis(500);
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let file_path = temp_dir.path().join("mod.ts");
+    let (sender, receiver) = watch::channel(false);
+    let options = WatchOptions {
+      debounce_time: Some(Duration::from_millis(20)),
+      shutdown: Some(receiver),
+      throttle: Some(throttle),
+      ..Default::default()
+    };
+    let starts = Rc::new(RefCell::new(Vec::new()));
+
+    let starts_ = starts.clone();
+    let file_path_ = file_path.clone();
+    let (result, _) = tokio::join!(
+      watch_func(&[temp_dir.path().to_path_buf()], options, move |_| {
+        starts_.borrow_mut().push(Instant::now());
+        future::pending().boxed_local()
+      }),
+      async {
+        for i in 0..20 {
+          delay_for(Duration::from_millis(100)).await;
+          std::fs::write(&file_path_, i.to_string()).unwrap();
+        }
+        delay_for(throttle).await;
+        sender.broadcast(true).unwrap();
+      },
+    );
+    assert!(result.is_ok());
+    let starts = starts.borrow();
+    assert!(starts.len() >= 2);
+    // Two seconds of changes every 100ms, at most one restart per 500ms.
+    assert!(starts.len() <= 6);
+    for pair in starts.windows(2) {
+      assert!(pair[1] - pair[0] >= throttle);
+    }
+  }
+
+  #[tokio::test]
+  async fn new_watcher_reports_failed_paths() {
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let valid = temp_dir.path().to_path_buf();
+    // Relative, and without any existing ancestor to watch instead.
+    let unreachable = PathBuf::from("missing_dir/missing.ts");
+
+    let setup =
+      new_watcher(&[valid, unreachable.clone()], &Default::default()).unwrap();
+    assert_eq!(setup.failed_paths.len(), 1);
+    assert_eq!(setup.failed_paths[0].0, unreachable);
+
+    assert!(new_watcher(&[unreachable], &Default::default()).is_err());
+  }
+
+  #[test]
+  fn missing_paths_register_nearest_ancestor() {
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let options = WatchOptions::default();
+
+    let sibling = temp_dir.path().join("bundle.js");
+    let registration = Registration::new(&sibling, &options);
+    assert!(registration.is_pending());
+    assert_eq!(registration.watched, temp_dir.path());
+    assert_eq!(registration.recursive_mode, RecursiveMode::NonRecursive);
+
+    let nested = temp_dir.path().join("dist").join("bundle.js");
+    let registration = Registration::new(&nested, &options);
+    assert_eq!(registration.watched, temp_dir.path());
+    assert_eq!(registration.recursive_mode, RecursiveMode::Recursive);
+
+    let existing = Registration::new(temp_dir.path(), &options);
+    assert!(!existing.is_pending());
+  }
+
+  #[tokio::test]
+  async fn missing_path_triggers_once_created() {
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let root = temp_dir.path().canonicalize().unwrap();
+    let target = root.join("bundle.js");
+    let options = WatchOptions::default();
+
+    let WatchSetup {
+      mut watcher,
+      mut debounce,
+      mut registrations,
+      failed_paths,
+      ..
+    } = new_watcher(&[target.clone()], &options).unwrap();
+    assert!(failed_paths.is_empty());
+
+    std::fs::write(root.join("unrelated.js"), "").unwrap();
+    std::fs::write(&target, "").unwrap();
+    let paths = tokio::time::timeout(
+      Duration::from_secs(5),
+      wait_for_file_change(&mut debounce),
+    )
+    .await
+    .expect("no change detected")
+    .unwrap();
+    assert_eq!(paths, vec![target.clone()]);
+
+    watch_created_paths(&mut watcher, &mut registrations, &options);
+    assert!(!registrations[0].is_pending());
+    assert!(registrations[0].watches_parent);
+    assert_eq!(registrations[0].watched, root);
+  }
+
+  #[tokio::test]
+  async fn watch_paths_yields_changes() {
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let mut changes =
+      watch_paths(&[temp_dir.path().to_path_buf()], Default::default())
+        .unwrap();
+
+    std::fs::write(temp_dir.path().join("mod.ts"), "").unwrap();
+    let paths = tokio::time::timeout(Duration::from_secs(5), changes.next())
+      .await
+      .expect("no change detected")
+      .unwrap()
+      .unwrap();
+    assert!(paths.iter().any(|path| path.ends_with("mod.ts")));
+  }
+
+  #[tokio::test]
+  async fn watch_until_change_returns_first_change() {
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let file_path = temp_dir.path().join("mod.ts");
+
+    let (result, _) = tokio::join!(
+      tokio::time::timeout(
+        Duration::from_secs(5),
+        watch_until_change(
+          &[temp_dir.path().to_path_buf()],
+          Default::default()
+        ),
+      ),
+      async {
+        delay_for(Duration::from_millis(100)).await;
+        std::fs::write(&file_path, "").unwrap();
+      },
+    );
+    let paths = result.expect("no change detected").unwrap();
+    assert!(paths.iter().any(|path| path.ends_with("mod.ts")));
+  }
+
+  #[tokio::test]
+  async fn wait_for_change_to_reports_the_kind() {
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let output = temp_dir.path().join("output.js");
+    let other = temp_dir.path().join("other.js");
+
+    let (result, _) = tokio::join!(
+      wait_for_change_to(&output, Some(Duration::from_secs(5))),
+      async {
+        delay_for(Duration::from_millis(100)).await;
+        std::fs::write(&other, "").unwrap();
+        delay_for(Duration::from_millis(300)).await;
+        std::fs::write(&output, "").unwrap();
+      },
+    );
+    assert_eq!(result.unwrap(), ChangeKind::Created);
+
+    let result =
+      wait_for_change_to(&output, Some(Duration::from_millis(300))).await;
+    assert!(result.is_err());
+  }
+
+  #[tokio::test]
+  async fn single_file_survives_replacement() {
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let root = temp_dir.path().canonicalize().unwrap();
+    let file = root.join("mod.ts");
+    let sibling = root.join("other.ts");
+    std::fs::write(&file, "0").unwrap();
+    let mut changes =
+      FileWatcher::new(&[file.clone()], Default::default()).unwrap();
+    assert!(changes.registrations[0].watches_parent);
+    assert_eq!(changes.registrations[0].watched, root);
+
+    for i in 1..=4 {
+      if i % 2 == 0 {
+        let temp_file = root.join(format!("mod.ts.{}.tmp", i));
+        std::fs::write(&temp_file, i.to_string()).unwrap();
+        std::fs::rename(&temp_file, &file).unwrap();
+      } else {
+        std::fs::write(&file, i.to_string()).unwrap();
+      }
+      std::fs::write(&sibling, i.to_string()).unwrap();
+      let batch = tokio::time::timeout(Duration::from_secs(5), changes.next())
+        .await
+        .expect("edit not detected")
+        .unwrap()
+        .unwrap();
+      assert_eq!(batch.paths, vec![file.clone()]);
+    }
+  }
+
+  #[tokio::test]
+  async fn atomic_save_restarts_once_and_keeps_watching() {
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let root = temp_dir.path().canonicalize().unwrap();
+    let file = root.join("mod.ts");
+    std::fs::write(&file, "old").unwrap();
+    let mut changes = watch_paths(&[file.clone()], Default::default()).unwrap();
+
+    // Write to a temporary file and rename it over the original.
+    let temp_file = root.join(".mod.ts.swp");
+    std::fs::write(&temp_file, "new").unwrap();
+    std::fs::rename(&temp_file, &file).unwrap();
+    let paths = tokio::time::timeout(Duration::from_secs(5), changes.next())
+      .await
+      .expect("no change detected")
+      .unwrap()
+      .unwrap();
+    assert_eq!(paths, vec![file.clone()]);
+    let second =
+      tokio::time::timeout(Duration::from_millis(500), changes.next()).await;
+    assert!(second.is_err());
+
+    std::fs::write(&file, "newer").unwrap();
+    let paths = tokio::time::timeout(Duration::from_secs(5), changes.next())
+      .await
+      .expect("replaced file is no longer watched")
+      .unwrap()
+      .unwrap();
+    assert_eq!(paths, vec![file]);
+  }
+
+  #[test]
+  fn builder_sets_options() {
+    let builder = WatcherBuilder::new()
+      .debounce(Duration::from_millis(50))
+      .recursive(false)
+      .exclude("node_modules")
+      .include("**/*.ts")
+      .backend(WatchBackend::Native)
+      .clear_screen(false);
+    let options = builder.options();
+    assert_eq!(options.debounce_time, Some(Duration::from_millis(50)));
+    assert_eq!(options.recursive_mode, Some(RecursiveMode::NonRecursive));
+    assert_eq!(options.exclude, vec!["node_modules".to_string()]);
+    assert_eq!(options.include, vec!["**/*.ts".to_string()]);
+    assert_eq!(options.backend, WatchBackend::Native);
+    assert_eq!(options.clear_screen, Some(false));
+
+    let defaults = WatcherBuilder::new();
+    assert!(defaults.options().debounce_time.is_none());
+    assert_eq!(defaults.options().backend, WatchBackend::Auto);
+  }
+
+  #[cfg(unix)]
+  #[test]
+  fn canonical_event_paths_use_given_spelling() {
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let real = temp_dir.path().canonicalize().unwrap().join("real");
+    let link = temp_dir.path().join("link");
+    std::fs::create_dir(&real).unwrap();
+    std::os::unix::fs::symlink(&real, &link).unwrap();
+
+    let options = WatchOptions::default();
+    let registration = Registration::new(&link, &options);
+    let filter = EventFilter::new(&[registration], &options).unwrap();
+    let event = modify_event(real.join("mod.ts").to_str().unwrap());
+    assert_eq!(
+      filter.filter(event).unwrap().paths,
+      vec![link.join("mod.ts")]
+    );
+    let event = modify_event(real.to_str().unwrap());
+    assert_eq!(filter.filter(event).unwrap().paths, vec![link]);
+  }
+
+  #[cfg(target_os = "macos")]
+  #[tokio::test]
+  async fn symlinked_root_reports_given_paths() {
+    // The temporary directory itself lives behind the /var symlink.
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let real = temp_dir.path().join("real");
+    let link = temp_dir.path().join("link");
+    std::fs::create_dir(&real).unwrap();
+    std::os::unix::fs::symlink(&real, &link).unwrap();
+    let mut changes = watch_paths(&[link.clone()], Default::default()).unwrap();
+
+    std::fs::write(link.join("mod.ts"), "").unwrap();
+    let paths = tokio::time::timeout(Duration::from_secs(5), changes.next())
+      .await
+      .expect("no change detected")
+      .unwrap()
+      .unwrap();
+    assert_eq!(paths, vec![link.join("mod.ts")]);
+  }
+
+  #[test]
+  fn rescan_events_are_detected() {
+    let rescan = NotifyEvent::new(EventKind::Other).set_flag(Flag::Rescan);
+    assert!(is_rescan(&rescan));
+    assert!(!is_rescan(&NotifyEvent::new(EventKind::Other)));
+    assert!(!is_rescan(&modify_event("/proj/mod.ts")));
+  }
+
+  #[tokio::test]
+  async fn rescan_forces_a_change() {
+    let (handler, mut debounce) = scripted_pipeline(default_debounce_time());
+    // Rescan events usually carry no path, or one outside the filter.
+    let rescan = NotifyEvent::new(EventKind::Other)
+      .set_flag(Flag::Rescan)
+      .add_path(PathBuf::from("/proj/node_modules"));
+    handler.handle(Ok(rescan));
+
+    let paths = wait_for_file_change(&mut debounce).await.unwrap();
+    assert_eq!(paths, vec![PathBuf::from("/proj/node_modules")]);
+    assert_eq!(handler.take_overflow(), Some(Vec::new()));
+  }
+
+  #[tokio::test]
+  async fn flooded_events_are_all_reported() {
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let root = temp_dir.path().to_path_buf();
+    let options = WatchOptions {
+      debounce_time: Some(Duration::from_millis(20)),
+      channel_capacity: Some(4),
+      ..Default::default()
+    };
+    let mut changes = FileWatcher::new(&[root.clone()], options).unwrap();
+
+    let handler = changes.handler.clone();
+    let flood_root = root.clone();
+    let flood = std::thread::spawn(move || {
+      for i in 0..10_000 {
+        let path = flood_root.join(format!("{}.ts", i % 500));
+        handler.handle(Ok(modify_event(path.to_str().unwrap())));
+      }
+    });
+    let expected: BTreeSet<PathBuf> =
+      (0..500).map(|i| root.join(format!("{}.ts", i))).collect();
+    let mut seen = BTreeSet::new();
+    let mut restarts = 0;
+    while seen != expected {
+      let batch = tokio::time::timeout(Duration::from_secs(5), changes.next())
+        .await
+        .expect("flooded changes were lost")
+        .unwrap()
+        .unwrap();
+      restarts += 1;
+      seen.extend(batch.paths);
+    }
+    flood.join().unwrap();
+    assert!(restarts >= 1);
+  }
+
+  #[tokio::test]
+  async fn overflowed_events_still_trigger_a_change() {
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let root = temp_dir.path().to_path_buf();
+    let options = WatchOptions {
+      channel_capacity: Some(1),
+      ..Default::default()
+    };
+    let mut changes = FileWatcher::new(&[root.clone()], options).unwrap();
+
+    // Flood the channel without polling, only the first event fits.
+    let expected: Vec<PathBuf> =
+      (0..10).map(|i| root.join(format!("a{}.ts", i))).collect();
+    for path in &expected {
+      changes
+        .handler
+        .handle(Ok(modify_event(path.to_str().unwrap())));
+    }
+    let batch = tokio::time::timeout(Duration::from_secs(5), changes.next())
+      .await
+      .expect("no change detected")
+      .unwrap()
+      .unwrap();
+    assert_eq!(batch.paths, expected);
+  }
+
+  #[tokio::test]
+  async fn batches_span_their_first_and_last_events() {
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let root = temp_dir.path().to_path_buf();
+    let options = WatchOptions {
+      debounce_time: Some(Duration::from_millis(300)),
+      ..Default::default()
+    };
+    let mut changes = FileWatcher::new(&[root.clone()], options).unwrap();
+
+    // Events are timed as they are received, not as the stream takes them,
+    // so they keep their spacing even though it isn't polled meanwhile.
+    let handler = changes.handler.clone();
+    let sent_at = Instant::now();
+    handler.handle(Ok(modify_event(root.join("a.ts").to_str().unwrap())));
+    delay_for(Duration::from_millis(100)).await;
+    handler.handle(Ok(modify_event(root.join("b.ts").to_str().unwrap())));
+    let batch =
+      tokio::time::timeout(Duration::from_secs(5), changes.next()).await;
+    let batch =
+      WatchBatch::from(batch.expect("no change detected").unwrap().unwrap());
+    let burst = batch.last_event_at - batch.first_event_at;
+    assert!(batch.first_event_at >= sent_at);
+    assert!(burst >= Duration::from_millis(90), "{:?}", burst);
+    assert!(burst < Duration::from_millis(250), "{:?}", burst);
+    assert!(batch.last_event_at <= Instant::now());
+  }
+
+  #[tokio::test]
+  async fn event_counts_show_overflow() {
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let root = temp_dir.path().to_path_buf();
+    let diagnostics = Diagnostics::new();
+    let options = WatchOptions {
+      channel_capacity: Some(1),
+      exclude: vec!["*.log".to_string()],
+      diagnostics: Some(diagnostics.clone()),
+      ..Default::default()
+    };
+    let changes = FileWatcher::new(&[root.clone()], options).unwrap();
+
+    // Nothing is polled, so only the first event fits in the channel.
+    for i in 0..10 {
+      let path = root.join(format!("a{}.ts", i));
+      changes
+        .handler
+        .handle(Ok(modify_event(path.to_str().unwrap())));
+    }
+    let log = root.join("debug.log");
+    changes
+      .handler
+      .handle(Ok(modify_event(log.to_str().unwrap())));
+    let events = diagnostics.snapshot().events;
+    assert_eq!(
+      events,
+      EventCounts {
+        received: 11,
+        forwarded: 1,
+        filtered: 1,
+      }
+    );
+    assert!(events.received > events.forwarded + events.filtered);
+  }
+
+  #[test]
+  fn recent_events_keep_the_latest() {
+    let diagnostics = Diagnostics::new();
+    let options = WatchOptions {
+      exclude: vec!["*.log".to_string()],
+      diagnostics: Some(diagnostics.clone()),
+      recent_events: Some(3),
+      ..Default::default()
+    };
+    let registration = Registration::new(Path::new("/proj"), &options);
+    let (handler, _debounce) =
+      event_pipeline(&[registration], &options).unwrap();
+
+    let paths: Vec<PathBuf> = (0..5)
+      .map(|i| PathBuf::from(format!("/proj/a{}.ts", i)))
+      .chain(std::iter::once(PathBuf::from("/proj/debug.log")))
+      .collect();
+    for path in &paths {
+      handler.handle(Ok(modify_event(path.to_str().unwrap())));
+    }
+    let recent_events = diagnostics.snapshot().recent_events;
+    let recorded: Vec<(Vec<PathBuf>, EventVerdict)> = recent_events
+      .into_iter()
+      .map(|event| (event.paths, event.verdict))
+      .collect();
+    assert_eq!(
+      recorded,
+      vec![
+        (vec![paths[3].clone()], EventVerdict::Forwarded),
+        (vec![paths[4].clone()], EventVerdict::Forwarded),
+        (
+          vec![paths[5].clone()],
+          EventVerdict::Ignored("ignored by glob rules")
+        ),
+      ]
+    );
+  }
+
+  /// Creates a directory below a recursively watched root, then a file in
+  /// it, and returns the paths of the change that follows.
+  async fn change_in_created_dir(backend: WatchBackend) -> Vec<PathBuf> {
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let root = temp_dir.path().to_path_buf();
+    let options = WatchOptions {
+      backend,
+      debounce_time: Some(Duration::from_millis(50)),
+      watch_dir_events: true,
+      ..Default::default()
+    };
+    let mut changes = FileWatcher::new(&[root.clone()], options).unwrap();
+
+    std::fs::create_dir(root.join("new")).unwrap();
+    tokio::time::timeout(Duration::from_secs(5), changes.next())
+      .await
+      .expect("directory creation not detected")
+      .unwrap()
+      .unwrap();
+    std::fs::write(root.join("new").join("mod.ts"), "").unwrap();
+    let batch = tokio::time::timeout(Duration::from_secs(5), changes.next())
+      .await
+      .expect("change in created directory not detected")
+      .unwrap()
+      .unwrap();
+    batch
+      .paths
+      .iter()
+      .map(|path| path.strip_prefix(&root).unwrap().to_path_buf())
+      .collect()
+  }
+
+  // Both backends extend recursive watches to new directories themselves:
+  // inotify adds a watch on every directory creation, and polling rescans
+  // the whole tree.
+  #[tokio::test]
+  async fn native_recursive_watch_covers_created_dirs() {
+    let paths = change_in_created_dir(WatchBackend::Native).await;
+    assert!(paths.contains(&Path::new("new").join("mod.ts")));
+  }
+
+  #[tokio::test]
+  async fn poll_recursive_watch_covers_created_dirs() {
+    let backend = WatchBackend::Poll {
+      interval: Duration::from_millis(100),
+    };
+    let paths = change_in_created_dir(backend).await;
+    assert!(paths.contains(&Path::new("new").join("mod.ts")));
+  }
+
+  #[tokio::test]
+  async fn max_depth_limits_recursion() {
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let root = temp_dir.path().to_path_buf();
+    let level3 = root.join("l1").join("l2").join("l3");
+    std::fs::create_dir_all(&level3).unwrap();
+    let options = WatchOptions {
+      max_depth: Some(1),
+      ..Default::default()
+    };
+    let mut changes = FileWatcher::new(&[root.clone()], options).unwrap();
+    assert_eq!(
+      changes.subdirs.iter().collect::<Vec<_>>(),
+      vec![&root.join("l1")]
+    );
+
+    std::fs::write(root.join("l1").join("l2").join("deep.ts"), "").unwrap();
+    std::fs::write(level3.join("deeper.ts"), "").unwrap();
+    let deep =
+      tokio::time::timeout(Duration::from_millis(500), changes.next()).await;
+    assert!(deep.is_err());
+
+    std::fs::write(root.join("l1").join("mod.ts"), "").unwrap();
+    let batch = tokio::time::timeout(Duration::from_secs(5), changes.next())
+      .await
+      .expect("no change detected")
+      .unwrap()
+      .unwrap();
+    assert_eq!(batch.paths, vec![root.join("l1").join("mod.ts")]);
+  }
+
+  #[tokio::test]
+  async fn max_depth_watches_created_dirs_within_limit() {
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let root = temp_dir.path().to_path_buf();
+    let options = WatchOptions {
+      max_depth: Some(1),
+      watch_dir_events: true,
+      ..Default::default()
+    };
+    let mut changes = FileWatcher::new(&[root.clone()], options).unwrap();
+
+    std::fs::create_dir(root.join("new")).unwrap();
+    tokio::time::timeout(Duration::from_secs(5), changes.next())
+      .await
+      .expect("no change detected")
+      .unwrap()
+      .unwrap();
+    assert!(changes.subdirs.contains(&root.join("new")));
+
+    std::fs::write(root.join("new").join("mod.ts"), "").unwrap();
+    let batch = tokio::time::timeout(Duration::from_secs(5), changes.next())
+      .await
+      .expect("no change detected")
+      .unwrap()
+      .unwrap();
+    assert_eq!(batch.paths, vec![root.join("new").join("mod.ts")]);
+  }
+
+  #[cfg(unix)]
+  #[tokio::test]
+  async fn symlinked_dirs_are_followed() {
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let shared_dir = TempDir::new().expect("tempdir fail");
+    let root = temp_dir.path().to_path_buf();
+    let shared = shared_dir.path().canonicalize().unwrap();
+    let link = root.join("shared");
+    std::os::unix::fs::symlink(&shared, &link).unwrap();
+    let options = WatchOptions {
+      follow_symlinks: true,
+      ..Default::default()
+    };
+    let mut changes = FileWatcher::new(&[root.clone()], options).unwrap();
+    assert_eq!(changes.symlink_targets, vec![shared.clone()]);
+
+    std::fs::write(shared.join("mod.ts"), "").unwrap();
+    let batch = tokio::time::timeout(Duration::from_secs(5), changes.next())
+      .await
+      .expect("no change detected")
+      .unwrap()
+      .unwrap();
+    assert_eq!(batch.paths, vec![link.join("mod.ts")]);
+  }
+
+  #[cfg(unix)]
+  #[test]
+  fn symlink_cycles_are_not_followed() {
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let root = temp_dir.path().to_path_buf();
+    std::fs::create_dir(root.join("sub")).unwrap();
+    std::os::unix::fs::symlink(&root, root.join("sub").join("loop")).unwrap();
+    let options = WatchOptions {
+      follow_symlinks: true,
+      ..Default::default()
+    };
+    let changes = FileWatcher::new(&[root], options).unwrap();
+    assert!(changes.symlink_targets.is_empty());
+  }
+
+  #[tokio::test]
+  async fn roots_have_their_own_recursive_mode() {
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let src = temp_dir.path().join("src");
+    let data = temp_dir.path().join("data");
+    std::fs::create_dir_all(src.join("nested")).unwrap();
+    std::fs::create_dir_all(data.join("nested")).unwrap();
+    let roots = [
+      (src.clone(), RecursiveMode::Recursive),
+      (data.clone(), RecursiveMode::NonRecursive),
+    ];
+    let mut changes =
+      FileWatcher::with_modes(&roots, Default::default()).unwrap();
+
+    std::fs::write(data.join("nested").join("b.ts"), "").unwrap();
+    let nested_src = src.join("nested").join("a.ts");
+    std::fs::write(&nested_src, "").unwrap();
+    let batch = tokio::time::timeout(Duration::from_secs(5), changes.next())
+      .await
+      .expect("nested change under the recursive root not detected")
+      .unwrap()
+      .unwrap();
+    assert_eq!(batch.paths, vec![nested_src]);
+
+    // The top level of the non-recursive root is still watched.
+    let top_level = data.join("c.ts");
+    std::fs::write(&top_level, "").unwrap();
+    let batch = tokio::time::timeout(Duration::from_secs(5), changes.next())
+      .await
+      .expect("change at the top of the non-recursive root not detected")
+      .unwrap()
+      .unwrap();
+    assert_eq!(batch.paths, vec![top_level]);
+  }
+
+  #[tokio::test]
+  async fn paths_can_be_added_and_removed() {
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let root = temp_dir.path().to_path_buf();
+    let a = root.join("a.ts");
+    let b = root.join("b.ts");
+    std::fs::write(&a, "").unwrap();
+    std::fs::write(&b, "").unwrap();
+    let mut changes =
+      FileWatcher::new(&[a.clone()], Default::default()).unwrap();
+
+    changes.add_path(b.clone()).unwrap();
+    changes.add_path(b.clone()).unwrap();
+    assert_eq!(changes.registrations.len(), 2);
+    std::fs::write(&b, "changed").unwrap();
+    let batch = tokio::time::timeout(Duration::from_secs(5), changes.next())
+      .await
+      .expect("change to the added path not detected")
+      .unwrap()
+      .unwrap();
+    assert_eq!(batch.paths, vec![b.clone()]);
+
+    changes.remove_path(&b).unwrap();
+    assert!(changes.remove_path(&b).is_err());
+    std::fs::write(&b, "changed again").unwrap();
+    let removed =
+      tokio::time::timeout(Duration::from_millis(500), changes.next()).await;
+    assert!(removed.is_err());
+
+    // Paths covered by a watched directory are watched through it, and
+    // directly again once the directory is removed.
+    changes.add_path(root.clone()).unwrap();
+    changes.add_path(b.clone()).unwrap();
+    assert_eq!(changes.registrations.len(), 1);
+    changes.remove_path(&root).unwrap();
+    let mut watched: Vec<&PathBuf> =
+      changes.registrations.iter().map(|r| &r.path).collect();
+    watched.sort();
+    assert_eq!(watched, vec![&a, &b]);
+  }
+
+  #[tokio::test]
+  async fn imprecise_native_watcher_is_set_up() {
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let root = temp_dir.path().to_path_buf();
+    let file = root.join("mod.ts");
+    std::fs::write(&file, "").unwrap();
+    let options = WatchOptions {
+      backend: WatchBackend::Native,
+      watch_config: WatchConfig {
+        precise_events: false,
+        ..Default::default()
+      },
+      ..Default::default()
+    };
+    let mut changes = FileWatcher::new(&[root], options).unwrap();
+    assert!(matches!(changes.watcher, AnyWatcher::Native(_)));
+
+    std::fs::write(&file, "changed").unwrap();
+    let batch = tokio::time::timeout(Duration::from_secs(5), changes.next())
+      .await
+      .expect("change not detected")
+      .unwrap()
+      .unwrap();
+    assert_eq!(batch.paths, vec![file]);
+  }
+
+  /// Records the paths it's asked to watch and never delivers an event.
+  struct MockWatcher(Arc<Mutex<Vec<PathBuf>>>);
+
+  impl Watcher for MockWatcher {
+    fn new_immediate<F: notify::EventFn>(
+      _event_fn: F,
+    ) -> Result<Self, NotifyError> {
+      Ok(Self(Default::default()))
+    }
+
+    fn watch<P: AsRef<Path>>(
+      &mut self,
+      path: P,
+      _recursive_mode: RecursiveMode,
+    ) -> Result<(), NotifyError> {
+      self.0.lock().unwrap().push(path.as_ref().to_path_buf());
+      Ok(())
+    }
+
+    fn unwatch<P: AsRef<Path>>(&mut self, _path: P) -> Result<(), NotifyError> {
+      Ok(())
+    }
+
+    fn configure(&mut self, _option: Config) -> Result<bool, NotifyError> {
+      Ok(false)
+    }
+  }
+
+  #[tokio::test]
+  async fn custom_watcher_watches_each_path_once() {
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let root = temp_dir.path().join("root");
+    let other = temp_dir.path().join("other");
+    std::fs::create_dir(&root).unwrap();
+    std::fs::create_dir(&other).unwrap();
+    std::fs::write(root.join("a.ts"), "").unwrap();
+    let watched = Arc::new(Mutex::new(Vec::new()));
+    let mock_watched = watched.clone();
+    let options = WatchOptions {
+      watcher: Some(WatcherFactory::new(move |_events| {
+        Ok(MockWatcher(mock_watched.clone()))
+      })),
+      ..Default::default()
+    };
+    let paths = [root.clone(), root.join("a.ts"), root.clone(), other.clone()];
+    let changes = FileWatcher::new(&paths, options).unwrap();
+    assert!(matches!(changes.watcher, AnyWatcher::Custom(_)));
+    assert_eq!(*watched.lock().unwrap(), vec![root, other]);
+  }
+
+  /// Emits each event after its delay, without any file system, then stays
+  /// open like a live watcher.
+  struct ScriptedSource(Vec<(Duration, NotifyEvent)>);
+
+  impl WatchSource for ScriptedSource {
+    fn events(&self) -> BoxStream<'static, Result<NotifyEvent, NotifyError>> {
+      stream::iter(self.0.clone())
+        .then(|(delay, event)| async move {
+          delay_for(delay).await;
+          Ok(event)
+        })
+        .chain(stream::pending())
+        .boxed()
+    }
+  }
+
+  #[tokio::test]
+  async fn watch_sources_drive_the_whole_pipeline() {
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let root = temp_dir.path().canonicalize().unwrap();
+    let a = root.join("a.ts");
+    let b = root.join("b.ts");
+    let event = |path: &Path| {
+      NotifyEvent::new(EventKind::Modify(ModifyKind::Any))
+        .add_path(path.to_path_buf())
+    };
+    let source = ScriptedSource(vec![
+      (Duration::from_millis(200), event(&a)),
+      (Duration::from_millis(10), event(&b)),
+      // Not below the watched path, so dropped.
+      (
+        Duration::from_millis(10),
+        event(Path::new("/elsewhere/c.ts")),
+      ),
+    ]);
+    let (sender, receiver) = watch::channel(false);
+    let options = WatchOptions {
+      debounce_time: Some(Duration::from_millis(50)),
+      shutdown: Some(receiver),
+      watcher: Some(WatcherFactory::from_source(source)),
+      ..Default::default()
+    };
+    let batches = Rc::new(RefCell::new(Vec::new()));
+
+    let batches_ = batches.clone();
+    let (result, _) = tokio::join!(
+      watch_notify(&[root.clone()], options, move |paths| {
+        batches_.borrow_mut().push(paths);
+      }),
+      async {
+        for _ in 0..100 {
+          if !batches.borrow().is_empty() {
+            break;
+          }
+          delay_for(Duration::from_millis(50)).await;
+        }
+        shutdown_after(sender, Duration::from_millis(200)).await;
+      },
+    );
+    assert!(result.is_ok());
+    // No file was written; everything came from the source.
+    assert_eq!(*batches.borrow(), vec![vec![a, b]]);
+  }
+
+  /// A source whose first stream ends right away, like a backend whose
+  /// thread died, and whose later ones report a change to the path and stay
+  /// open.
+  struct DyingSource {
+    path: PathBuf,
+    calls: AtomicUsize,
+  }
+
+  impl WatchSource for DyingSource {
+    fn events(&self) -> BoxStream<'static, Result<NotifyEvent, NotifyError>> {
+      if self.calls.fetch_add(1, Ordering::SeqCst) == 0 {
+        return stream::empty().boxed();
+      }
+      let event = NotifyEvent::new(EventKind::Modify(ModifyKind::Any))
+        .add_path(self.path.clone());
+      stream::once(async move {
+        delay_for(Duration::from_millis(100)).await;
+        Ok(event)
+      })
+      .chain(stream::pending())
+      .boxed()
+    }
+  }
+
+  #[tokio::test]
+  async fn stopped_watcher_is_restarted() {
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let root = temp_dir.path().canonicalize().unwrap();
+    let file = root.join("mod.ts");
+    std::fs::write(&file, "").unwrap();
+    let source = DyingSource {
+      path: file.clone(),
+      calls: AtomicUsize::new(0),
+    };
+    let options = WatchOptions {
+      debounce_time: Some(Duration::from_millis(50)),
+      watcher: Some(WatcherFactory::from_source(source)),
+      ..Default::default()
+    };
+    let mut changes = FileWatcher::new(&[root], options).unwrap();
+    let stopped = changes.handler.clone();
+
+    let batch = tokio::time::timeout(Duration::from_secs(5), changes.next())
+      .await
+      .expect("change after the restart not detected")
+      .unwrap()
+      .unwrap();
+    assert_eq!(batch.paths, vec![file]);
+    assert!(!Arc::ptr_eq(&stopped, &changes.handler));
+  }
+
+  #[tokio::test]
+  async fn batches_are_yielded_with_their_kinds() {
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let root = temp_dir.path().to_path_buf();
+    let a = root.join("a.ts");
+    let b = root.join("b.ts");
+    let options = WatchOptions {
+      debounce_time: Some(Duration::from_millis(50)),
+      ..Default::default()
+    };
+    let watcher = FileWatcher::new(&[root], options).unwrap();
+    let handler = watcher.handler.clone();
+    let mut batches = into_batches(watcher);
+
+    handler.handle(Ok(modify_event(a.to_str().unwrap())));
+    handler.handle(Ok(
+      NotifyEvent::new(EventKind::Create(CreateKind::File)).add_path(b.clone()),
+    ));
+    let batch = batches.next().await.unwrap();
+    assert_eq!(batch.paths, vec![a.clone(), b.clone()]);
+    let kinds: HashSet<ChangeKind> =
+      vec![ChangeKind::Modified, ChangeKind::Created]
+        .into_iter()
+        .collect();
+    assert_eq!(batch.kinds, kinds);
+
+    handler.handle(Ok(
+      NotifyEvent::new(EventKind::Remove(RemoveKind::File)).add_path(a.clone()),
+    ));
+    let batch = batches.next().await.unwrap();
+    assert_eq!(batch.paths, vec![a]);
+    assert_eq!(batch.kinds, vec![ChangeKind::Removed].into_iter().collect());
+  }
+
+  #[tokio::test]
+  async fn dir_events_only_count_when_watched() {
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let root = temp_dir.path().to_path_buf();
+    let dir = root.join("new");
+    let create_dir = NotifyEvent::new(EventKind::Create(CreateKind::Folder))
+      .add_path(dir.clone());
+    let session = |watch_dir_events| {
+      let options = WatchOptions {
+        debounce_time: Some(Duration::from_millis(50)),
+        watch_dir_events,
+        ..Default::default()
+      };
+      FileWatcher::new(&[root.clone()], options).unwrap()
+    };
+
+    let mut changes = session(false);
+    changes.handler.handle(Ok(create_dir.clone()));
+    let result =
+      tokio::time::timeout(Duration::from_millis(300), changes.next()).await;
+    assert!(result.is_err(), "directory event triggered");
+
+    let mut changes = session(true);
+    changes.handler.handle(Ok(create_dir));
+    let batch = tokio::time::timeout(Duration::from_secs(5), changes.next())
+      .await
+      .expect("directory event not reported")
+      .unwrap()
+      .unwrap();
+    assert_eq!(batch.paths, vec![dir]);
+  }
+
+  #[tokio::test]
+  async fn net_changes_report_the_final_state() {
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let root = temp_dir.path().to_path_buf();
+    let file = root.join("mod.ts");
+    let options = WatchOptions {
+      debounce_time: Some(Duration::from_millis(50)),
+      net_changes: true,
+      ..Default::default()
+    };
+    let watcher = FileWatcher::new(&[root], options).unwrap();
+    let handler = watcher.handler.clone();
+    let mut batches = into_batches(watcher);
+
+    handler.handle(Ok(
+      NotifyEvent::new(EventKind::Create(CreateKind::File))
+        .add_path(file.clone()),
+    ));
+    handler.handle(Ok(modify_event(file.to_str().unwrap())));
+    handler.handle(Ok(
+      NotifyEvent::new(EventKind::Remove(RemoveKind::File))
+        .add_path(file.clone()),
+    ));
+    let batch = batches.next().await.unwrap();
+    assert_eq!(batch.paths, vec![file.clone()]);
+    assert_eq!(batch.kinds, vec![ChangeKind::Removed].into_iter().collect());
+
+    std::fs::write(&file, "").unwrap();
+    handler.handle(Ok(
+      NotifyEvent::new(EventKind::Create(CreateKind::File))
+        .add_path(file.clone()),
+    ));
+    handler.handle(Ok(modify_event(file.to_str().unwrap())));
+    let batch = batches.next().await.unwrap();
+    assert_eq!(batch.kinds, vec![ChangeKind::Created].into_iter().collect());
+  }
+
+  #[tokio::test]
+  async fn batches_carry_the_label_of_their_root() {
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let frontend = temp_dir.path().join("frontend");
+    let backend = temp_dir.path().join("backend");
+    std::fs::create_dir(&frontend).unwrap();
+    std::fs::create_dir(&backend).unwrap();
+    let mut options = WatchOptions {
+      debounce_time: Some(Duration::from_millis(50)),
+      ..Default::default()
+    };
+    options
+      .root_labels
+      .insert(frontend.clone(), "frontend".to_string());
+    options
+      .root_labels
+      .insert(backend.clone(), "backend".to_string());
+    let mut batches =
+      watch_batches(&[frontend.clone(), backend.clone()], options).unwrap();
+
+    std::fs::write(backend.join("server.ts"), "").unwrap();
+    let batch = tokio::time::timeout(Duration::from_secs(5), batches.next())
+      .await
+      .expect("no change detected")
+      .unwrap();
+    assert_eq!(batch.paths, vec![backend.join("server.ts")]);
+    assert_eq!(batch.source, Some("backend".to_string()));
+  }
+
+  #[test]
+  fn source_label_needs_a_single_root() {
+    let mut labels = HashMap::new();
+    labels.insert(PathBuf::from("/proj"), "proj".to_string());
+    labels.insert(PathBuf::from("/proj/api"), "api".to_string());
+    let label = |paths: &[&str]| {
+      let paths: Vec<PathBuf> = paths.iter().map(PathBuf::from).collect();
+      source_label(&labels, &paths)
+    };
+    assert_eq!(label(&["/proj/api/mod.ts"]), Some("api".to_string()));
+    assert_eq!(label(&["/proj/mod.ts"]), Some("proj".to_string()));
+    assert_eq!(label(&["/proj/mod.ts", "/proj/api/mod.ts"]), None);
+    assert_eq!(label(&["/other/mod.ts"]), None);
+  }
+
+  #[cfg(target_os = "linux")]
+  #[tokio::test]
+  async fn watch_limit_falls_back_to_polling() {
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let root = temp_dir.path().to_path_buf();
+    let options = WatchOptions::default();
+    let registrations = vec![Registration::new(&root, &options)];
+    let (handler, _debounce) =
+      event_pipeline(&registrations, &options).unwrap();
+    let native =
+      AnyWatcher::native(handler.clone(), &options.watch_config).unwrap();
+    let limit = NotifyError::io(io::Error::from_raw_os_error(libc::ENOSPC));
+    assert!(is_watch_limit_error(&limit));
+
+    let (watcher, failed_paths) = fall_back_on_watch_limit(
+      native,
+      vec![(root.clone(), limit)],
+      &registrations,
+      || AnyWatcher::poll(handler.clone(), DEFAULT_POLL_INTERVAL),
+    )
+    .unwrap();
+    assert!(matches!(watcher, AnyWatcher::Poll(_)));
+    assert!(failed_paths.is_empty());
+
+    // Other errors keep the native watcher.
+    let native =
+      AnyWatcher::native(handler.clone(), &options.watch_config).unwrap();
+    let not_found = NotifyError::path_not_found();
+    assert!(!is_watch_limit_error(&not_found));
+    let (watcher, failed_paths) = fall_back_on_watch_limit(
+      native,
+      vec![(root, not_found)],
+      &registrations,
+      || panic!("fell back without reaching the watch limit"),
+    )
+    .unwrap();
+    assert!(matches!(watcher, AnyWatcher::Native(_)));
+    assert_eq!(failed_paths.len(), 1);
+  }
+
+  #[tokio::test]
+  async fn path_lists_update_watched_paths() {
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let root = temp_dir.path().to_path_buf();
+    let a = root.join("a.ts");
+    let b = root.join("b.ts");
+    std::fs::write(&a, "").unwrap();
+    std::fs::write(&b, "").unwrap();
+    let (lines, receiver) =
+      mpsc::unbounded_channel::<io::Result<bytes::Bytes>>();
+    let reader = tokio::io::stream_reader(receiver);
+    let (changed, mut changes) = mpsc::unbounded_channel();
+    let watching =
+      watch_from_path_stream(reader, Default::default(), |paths| {
+        changed.send(paths).unwrap()
+      });
+    let send_list = move |paths: &[&PathBuf]| {
+      let mut list = String::new();
+      for path in paths {
+        list.push_str(&format!("{}\n", path.display()));
+      }
+      list.push('\n');
+      lines.send(Ok(list.into())).unwrap();
+    };
+    let driver = async move {
+      send_list(&[&a]);
+      tokio::time::delay_for(Duration::from_millis(200)).await;
+      std::fs::write(&a, "changed").unwrap();
+      let paths = tokio::time::timeout(Duration::from_secs(5), changes.recv())
+        .await
+        .expect("change to the listed path not detected")
+        .unwrap();
+      assert_eq!(paths, vec![a.clone()]);
+
+      send_list(&[&b]);
+      tokio::time::delay_for(Duration::from_millis(200)).await;
+      std::fs::write(&a, "changed again").unwrap();
+      std::fs::write(&b, "changed").unwrap();
+      let paths = tokio::time::timeout(Duration::from_secs(5), changes.recv())
+        .await
+        .expect("change to the newly listed path not detected")
+        .unwrap();
+      assert_eq!(paths, vec![b]);
+      // Ending the input stops watching.
+      drop(send_list);
+    };
+    let (result, _) = future::join(watching, driver).await;
+    result.unwrap();
+  }
+
+  #[tokio::test]
+  async fn config_change_updates_watched_paths() {
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let root = temp_dir.path().to_path_buf();
+    let config_path = root.join("entrypoints.txt");
+    let a = root.join("a.ts");
+    let b = root.join("b.ts");
+    for path in &[&config_path, &a, &b] {
+      std::fs::write(path, "").unwrap();
+    }
+    let resolve_root = root.clone();
+    let options = WatchOptions {
+      config: Some(ConfigFile::new(&config_path, move |path: &Path| {
+        std::fs::read_to_string(path)
+          .unwrap_or_default()
+          .lines()
+          .map(|line| resolve_root.join(line))
+          .collect()
+      })),
+      ..Default::default()
+    };
+    let mut changes = FileWatcher::new(&[a.clone()], options).unwrap();
+
+    std::fs::write(&config_path, "b.ts").unwrap();
+    let batch = tokio::time::timeout(Duration::from_secs(5), changes.next())
+      .await
+      .expect("config change not detected")
+      .unwrap()
+      .unwrap();
+    assert_eq!(batch.paths, vec![config_path.clone()]);
+    let mut watched: Vec<&PathBuf> =
+      changes.registrations.iter().map(|r| &r.path).collect();
+    watched.sort();
+    assert_eq!(watched, vec![&b, &config_path]);
+
+    std::fs::write(&b, "changed").unwrap();
+    let batch = tokio::time::timeout(Duration::from_secs(5), changes.next())
+      .await
+      .expect("change to the new entrypoint not detected")
+      .unwrap()
+      .unwrap();
+    assert_eq!(batch.paths, vec![b]);
+  }
+
+  #[tokio::test]
+  async fn new_imports_become_watched() {
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let root = temp_dir.path().to_path_buf();
+    let main = root.join("main.ts");
+    let dep = root.join("dep.ts");
+    std::fs::write(&main, "").unwrap();
+    let resolve_root = root.clone();
+    let options = WatchOptions {
+      imports: Some(ImportGraph::new(&main, move |path: &Path| {
+        std::fs::read_to_string(path)
+          .unwrap_or_default()
+          .lines()
+          .map(|line| resolve_root.join(line))
+          .collect()
+      })),
+      ..Default::default()
+    };
+    let mut changes = FileWatcher::new(&[], options).unwrap();
+    assert_eq!(changes.requested, vec![main.clone()]);
+
+    std::fs::write(&dep, "").unwrap();
+    std::fs::write(&main, "dep.ts").unwrap();
+    let batch = tokio::time::timeout(Duration::from_secs(5), changes.next())
+      .await
+      .expect("change to the entrypoint not detected")
+      .unwrap()
+      .unwrap();
+    assert_eq!(batch.paths, vec![main.clone()]);
+    // The imports are resolved while the stream is polled for the next batch.
+    let result =
+      tokio::time::timeout(Duration::from_millis(200), changes.next()).await;
+    assert!(result.is_err());
+    assert_eq!(changes.requested, vec![main, dep.clone()]);
+
+    std::fs::write(&dep, "changed").unwrap();
+    let batch = tokio::time::timeout(Duration::from_secs(5), changes.next())
+      .await
+      .expect("change to the new import not detected")
+      .unwrap()
+      .unwrap();
+    assert_eq!(batch.paths, vec![dep]);
+  }
+
+  #[tokio::test]
+  async fn requested_paths_stay_watched_when_no_longer_imported() {
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let root = temp_dir.path().to_path_buf();
+    let main = root.join("main.ts");
+    let dep = root.join("dep.ts");
+    std::fs::write(&main, "dep.ts").unwrap();
+    std::fs::write(&dep, "").unwrap();
+    let resolve_root = root.clone();
+    let options = WatchOptions {
+      imports: Some(ImportGraph::new(&main, move |path: &Path| {
+        std::fs::read_to_string(path)
+          .unwrap_or_default()
+          .lines()
+          .map(|line| resolve_root.join(line))
+          .collect()
+      })),
+      ..Default::default()
+    };
+    let mut changes = FileWatcher::new(&[dep.clone()], options).unwrap();
+    assert_eq!(changes.requested, vec![dep.clone(), main.clone()]);
+
+    std::fs::write(&main, "").unwrap();
+    let batch = tokio::time::timeout(Duration::from_secs(5), changes.next())
+      .await
+      .expect("change to the entrypoint not detected")
+      .unwrap()
+      .unwrap();
+    assert_eq!(batch.paths, vec![main.clone()]);
+    let result =
+      tokio::time::timeout(Duration::from_millis(200), changes.next()).await;
+    assert!(result.is_err());
+    assert_eq!(changes.requested, vec![dep, main]);
+  }
+
+  #[cfg(unix)]
+  #[test]
+  fn fifos_are_skipped() {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let fifo = temp_dir.path().join("fifo");
+    let file_path = temp_dir.path().join("mod.ts");
+    std::fs::write(&file_path, "").unwrap();
+    let c_path = CString::new(fifo.as_os_str().as_bytes()).unwrap();
+    assert_eq!(unsafe { libc::mkfifo(c_path.as_ptr(), 0o644) }, 0);
+    assert!(is_special_file(&fifo));
+    assert!(!is_special_file(&file_path));
+
+    let watcher =
+      FileWatcher::new(&[fifo.clone(), file_path.clone()], Default::default())
+        .unwrap();
+    assert_eq!(watcher.summary().watched, 1);
+    assert_eq!(watcher.summary().skipped, 1);
+    let watched: Vec<&PathBuf> =
+      watcher.registrations.iter().map(|r| &r.path).collect();
+    assert_eq!(watched, vec![&file_path]);
+
+    match FileWatcher::new(&[fifo], Default::default()) {
+      Ok(_) => panic!("watching only a FIFO succeeded"),
+      Err(err) => assert_eq!(err.to_string(), SPECIAL_FILE_ERROR),
+    }
+  }
+
+  #[tokio::test]
+  async fn overlapping_paths_are_watched_once() {
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let root = temp_dir.path().to_path_buf();
+    let main = root.join("main.ts");
+    std::fs::write(&main, "").unwrap();
+
+    let setup =
+      new_watcher(&[root.clone(), main], &Default::default()).unwrap();
+    assert_eq!(setup.registrations.len(), 1);
+    assert_eq!(setup.registrations[0].path, root);
+  }
+
+  #[test]
+  fn dedup_paths_keeps_distinct_paths() {
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let a = temp_dir.path().join("a");
+    let b = temp_dir.path().join("b");
+    std::fs::create_dir(&a).unwrap();
+    std::fs::create_dir(&b).unwrap();
+    let options = WatchOptions::default();
+
+    let spelled_differently = a.join("..").join("a");
+    let paths = vec![a.clone(), b.clone(), spelled_differently, a.clone()];
+    assert_eq!(dedup_paths(&paths, &options), vec![a.clone(), b]);
+
+    // A non-recursive watch doesn't cover nested paths.
+    let nested = a.join("nested.ts");
+    let options = WatchOptions {
+      recursive_mode: Some(RecursiveMode::NonRecursive),
+      ..Default::default()
+    };
+    assert_eq!(
+      dedup_paths(&[a.clone(), nested.clone()], &options),
+      vec![a, nested]
+    );
+  }
+
+  #[tokio::test]
+  async fn dropping_file_watcher_closes_channel() {
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let watcher =
+      FileWatcher::new(&[temp_dir.path().to_path_buf()], Default::default())
+        .unwrap();
+    let handler = watcher.handler.clone();
+    drop(watcher);
+
+    let event = ReceivedEvent {
+      event: modify_event("mod.ts"),
+      received: tokio::time::Instant::now(),
+    };
+    let verdict = handler.send(Ok(event));
+    assert!(matches!(verdict, EventVerdict::Ignored("watcher closed")));
+  }
+
+  #[tokio::test]
+  async fn removed_root_is_watched_again_once_recreated() {
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let root = temp_dir.path().canonicalize().unwrap();
+    let src = root.join("src");
+    let lib = root.join("lib");
+    std::fs::create_dir(&src).unwrap();
+    std::fs::create_dir(&lib).unwrap();
+    let mut watcher =
+      FileWatcher::new(&[src.clone(), lib.clone()], Default::default())
+        .unwrap();
+
+    async fn next_change(watcher: &mut FileWatcher) -> Vec<PathBuf> {
+      tokio::time::timeout(Duration::from_secs(5), watcher.next())
+        .await
+        .expect("no change detected")
+        .unwrap()
+        .unwrap()
+        .paths
+    }
+
+    std::fs::remove_dir_all(&src).unwrap();
+    let paths = next_change(&mut watcher).await;
+    assert!(paths.contains(&src));
+    assert!(watcher.registrations[0].is_pending());
+
+    // The remaining root is unaffected.
+    std::fs::write(lib.join("mod.ts"), "").unwrap();
+    let paths = next_change(&mut watcher).await;
+    assert_eq!(paths, vec![lib.join("mod.ts")]);
+
+    std::fs::create_dir(&src).unwrap();
+    let paths = next_change(&mut watcher).await;
+    assert_eq!(paths, vec![src.clone()]);
+    assert!(!watcher.registrations[0].is_pending());
+
+    std::fs::write(src.join("mod.ts"), "").unwrap();
+    let paths = next_change(&mut watcher).await;
+    assert_eq!(paths, vec![src.join("mod.ts")]);
+  }
+
+  #[tokio::test]
+  async fn only_discovered_hidden_files_are_ignored() {
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let root = temp_dir.path().canonicalize().unwrap();
+    let swap_file = root.join(".mod.ts.swp");
+    let env_file = root.join(".env");
+    std::fs::write(&env_file, "").unwrap();
+    let mut changes =
+      FileWatcher::new(&[root.clone()], Default::default()).unwrap();
+
+    std::fs::write(&swap_file, "").unwrap();
+    let result =
+      tokio::time::timeout(Duration::from_millis(500), changes.next()).await;
+    assert!(result.is_err());
+    drop(changes);
+
+    let mut changes =
+      FileWatcher::new(&[env_file.clone()], Default::default()).unwrap();
+    std::fs::write(&env_file, "PORT=8000").unwrap();
+    let batch = tokio::time::timeout(Duration::from_secs(5), changes.next())
+      .await
+      .expect("change to the explicit dotfile not detected")
+      .unwrap()
+      .unwrap();
+    assert_eq!(batch.paths, vec![env_file]);
+  }
+
+  #[tokio::test]
+  async fn only_triggering_kinds_are_reported() {
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let root = temp_dir.path().canonicalize().unwrap();
+    let temp_file = root.join("cache.tmp");
+    let source = root.join("mod.ts");
+    std::fs::write(&temp_file, "").unwrap();
+    std::fs::write(&source, "").unwrap();
+    let mut trigger_on = HashSet::new();
+    trigger_on.insert(ChangeKind::Modified);
+    let options = WatchOptions {
+      trigger_on: Some(trigger_on),
+      ..Default::default()
+    };
+    let mut changes = FileWatcher::new(&[root], options).unwrap();
+
+    std::fs::remove_file(&temp_file).unwrap();
+    let result =
+      tokio::time::timeout(Duration::from_millis(500), changes.next()).await;
+    assert!(result.is_err());
+
+    std::fs::write(&source, "changed").unwrap();
+    let batch = tokio::time::timeout(Duration::from_secs(5), changes.next())
+      .await
+      .expect("no change detected")
+      .unwrap()
+      .unwrap();
+    assert_eq!(batch.paths, vec![source]);
+    assert!(batch.kinds.contains(&ChangeKind::Modified));
+  }
+
+  #[tokio::test]
+  async fn restart_predicate_has_final_say() {
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let root = temp_dir.path().canonicalize().unwrap();
+    let trigger = root.join("main.ts");
+    let options = WatcherBuilder::new()
+      .should_restart(|paths, kinds| {
+        assert!(!kinds.is_empty());
+        paths.iter().any(|path| path.ends_with("main.ts"))
+      })
+      .options()
+      .clone();
+    let mut changes = FileWatcher::new(&[root.clone()], options).unwrap();
+
+    std::fs::write(root.join("util.ts"), "").unwrap();
+    let result =
+      tokio::time::timeout(Duration::from_millis(500), changes.next()).await;
+    assert!(result.is_err());
+
+    std::fs::write(&trigger, "").unwrap();
+    let batch = tokio::time::timeout(Duration::from_secs(5), changes.next())
+      .await
+      .expect("no change detected")
+      .unwrap()
+      .unwrap();
+    assert_eq!(batch.paths, vec![trigger]);
+  }
+
+  #[tokio::test]
+  async fn large_files_are_skipped() {
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let root = temp_dir.path().canonicalize().unwrap();
+    let artifact = root.join("app.bin");
+    let source = root.join("mod.ts");
+    let options = WatchOptions {
+      max_file_size: Some(1024),
+      ..Default::default()
+    };
+    let mut changes = FileWatcher::new(&[root], options).unwrap();
+
+    std::fs::write(&artifact, vec![0u8; 4096]).unwrap();
+    let result =
+      tokio::time::timeout(Duration::from_millis(500), changes.next()).await;
+    assert!(result.is_err());
+
+    std::fs::write(&source, "small").unwrap();
+    let batch = tokio::time::timeout(Duration::from_secs(5), changes.next())
+      .await
+      .expect("no change detected")
+      .unwrap()
+      .unwrap();
+    assert_eq!(batch.paths, vec![source]);
+
+    std::fs::remove_file(&artifact).unwrap();
+    let batch = tokio::time::timeout(Duration::from_secs(5), changes.next())
+      .await
+      .expect("removal not detected")
+      .unwrap()
+      .unwrap();
+    assert_eq!(batch.paths, vec![artifact]);
+  }
+
+  #[tokio::test]
+  async fn irrelevant_changes_are_skipped() {
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let root = temp_dir.path().canonicalize().unwrap();
+    let imported = root.join("mod.ts");
+    let unrelated = root.join("notes.txt");
+    std::fs::write(&imported, "").unwrap();
+    std::fs::write(&unrelated, "").unwrap();
+    let relevant = Arc::new(Mutex::new(HashSet::new()));
+    relevant.lock().unwrap().insert(imported.clone());
+    let options = WatchOptions {
+      relevant: Some(relevant.clone()),
+      ..Default::default()
+    };
+    let mut changes = watch_paths(&[root], options).unwrap();
+
+    std::fs::write(&unrelated, "changed").unwrap();
+    let result =
+      tokio::time::timeout(Duration::from_millis(500), changes.next()).await;
+    assert!(result.is_err());
+
+    std::fs::write(&imported, "changed").unwrap();
+    let paths = tokio::time::timeout(Duration::from_secs(5), changes.next())
+      .await
+      .expect("no change detected")
+      .unwrap()
+      .unwrap();
+    assert_eq!(paths, vec![imported]);
+
+    relevant.lock().unwrap().insert(unrelated.clone());
+    std::fs::write(&unrelated, "changed again").unwrap();
+    let paths = tokio::time::timeout(Duration::from_secs(5), changes.next())
+      .await
+      .expect("no change detected")
+      .unwrap()
+      .unwrap();
+    assert_eq!(paths, vec![unrelated]);
+  }
+
+  #[tokio::test]
+  async fn identical_saves_are_skipped() {
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let root = temp_dir.path().canonicalize().unwrap();
+    let file_path = root.join("mod.ts");
+    std::fs::write(&file_path, "old").unwrap();
+    let options = WatchOptions {
+      skip_unchanged: true,
+      ..Default::default()
+    };
+    let mut changes = watch_paths(&[root], options).unwrap();
+
+    std::fs::write(&file_path, "new").unwrap();
+    let paths = tokio::time::timeout(Duration::from_secs(5), changes.next())
+      .await
+      .expect("no change detected")
+      .unwrap()
+      .unwrap();
+    assert_eq!(paths, vec![file_path.clone()]);
+
+    std::fs::write(&file_path, "new").unwrap();
+    let result =
+      tokio::time::timeout(Duration::from_millis(500), changes.next()).await;
+    assert!(result.is_err());
+
+    std::fs::write(&file_path, "newer").unwrap();
+    let paths = tokio::time::timeout(Duration::from_secs(5), changes.next())
+      .await
+      .expect("no change detected")
+      .unwrap()
+      .unwrap();
+    assert_eq!(paths, vec![file_path]);
+  }
+
+  #[tokio::test]
+  async fn identical_first_saves_are_skipped() {
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let root = temp_dir.path().canonicalize().unwrap();
+    std::fs::create_dir(root.join("src")).unwrap();
+    let file_path = root.join("src/mod.ts");
+    std::fs::write(&file_path, "old").unwrap();
+    let options = WatchOptions {
+      skip_unchanged: true,
+      ..Default::default()
+    };
+    let mut changes = watch_paths(&[root], options).unwrap();
+
+    std::fs::write(&file_path, "old").unwrap();
+    let result =
+      tokio::time::timeout(Duration::from_millis(500), changes.next()).await;
+    assert!(result.is_err());
+
+    std::fs::write(&file_path, "new").unwrap();
+    let paths = tokio::time::timeout(Duration::from_secs(5), changes.next())
+      .await
+      .expect("no change detected")
+      .unwrap()
+      .unwrap();
+    assert_eq!(paths, vec![file_path]);
+  }
+
+  #[tokio::test]
+  async fn content_deltas_tell_how_files_changed() {
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let file_path = temp_dir.path().canonicalize().unwrap().join("mod.ts");
+    std::fs::write(&file_path, "old").unwrap();
+    let options = WatchOptions {
+      skip_unchanged: true,
+      with_content_delta: true,
+      ..Default::default()
+    };
+    let mut batches = watch_batches(&[file_path.clone()], options).unwrap();
+
+    std::fs::write(&file_path, "much longer").unwrap();
+    let batch = tokio::time::timeout(Duration::from_secs(5), batches.next())
+      .await
+      .expect("no change detected")
+      .unwrap();
+    assert_eq!(
+      batch.content_deltas,
+      vec![ContentDelta {
+        path: file_path,
+        old_len: Some(3),
+        new_len: Some(11),
+        changed: true,
+      }]
+    );
+  }
+
+  #[test]
+  fn explicit_clear_screen_wins() {
+    let options = WatchOptions {
+      clear_screen: Some(true),
+      ..Default::default()
+    };
+    assert!(options.clear_screen());
+    let options = WatchOptions {
+      clear_screen: Some(false),
+      ..Default::default()
+    };
+    assert!(!options.clear_screen());
+  }
+
+  #[test]
+  fn restart_tracker_backs_off_during_storm() {
+    let storm = RestartStorm {
+      max_restarts: 2,
+      window: Duration::from_secs(3),
+      initial_backoff: Duration::from_millis(100),
+      max_backoff: Duration::from_millis(300),
+    };
+    let mut tracker = RestartTracker::new(storm);
+    let start = Instant::now();
+    let at = |ms| start + Duration::from_millis(ms);
+
+    assert_eq!(tracker.record(at(0)), None);
+    assert_eq!(tracker.record(at(10)), None);
+    assert_eq!(tracker.record(at(20)), Some(Duration::from_millis(100)));
+    assert_eq!(tracker.record(at(30)), Some(Duration::from_millis(200)));
+    assert_eq!(tracker.record(at(40)), Some(Duration::from_millis(300)));
+    assert_eq!(tracker.record(at(50)), Some(Duration::from_millis(300)));
+    // Quiet for longer than the window, the storm is over.
+    assert_eq!(tracker.record(at(5000)), None);
+  }
+
+  #[test]
+  fn backend_errors_are_distinguished_from_bad_paths() {
+    assert!(!is_backend_error(&NotifyError::path_not_found()));
+    let not_found = std::io::Error::from(std::io::ErrorKind::NotFound);
+    assert!(!is_backend_error(&NotifyError::io(not_found)));
+    let denied = std::io::Error::from(std::io::ErrorKind::Other);
+    assert!(is_backend_error(&NotifyError::io(denied)));
+  }
+
+  #[tokio::test]
+  async fn poll_backend_detects_new_files() {
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let options = WatchOptions {
+      backend: WatchBackend::Poll {
+        interval: Duration::from_millis(50),
+      },
+      ..Default::default()
+    };
+    let WatchSetup {
+      watcher,
+      mut debounce,
+      ..
+    } = new_watcher(&[temp_dir.path().to_path_buf()], &options).unwrap();
+    assert!(matches!(watcher, AnyWatcher::Poll(_)));
+
+    std::fs::write(temp_dir.path().join("new.ts"), "").unwrap();
+    let paths = tokio::time::timeout(
+      Duration::from_secs(5),
+      wait_for_file_change(&mut debounce),
+    )
+    .await
+    .expect("no change detected")
+    .unwrap();
+    assert!(paths.iter().any(|path| path.ends_with("new.ts")));
+  }
+
+  #[tokio::test]
+  async fn relative_roots_survive_a_working_directory_change() {
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let src = temp_dir.path().join("src");
+    std::fs::create_dir(&src).unwrap();
+    let cwd = std::env::current_dir().unwrap();
+    std::env::set_current_dir(temp_dir.path()).unwrap();
+    let options = WatchOptions {
+      debounce_time: Some(Duration::from_millis(50)),
+      ..Default::default()
+    };
+    let batches = watch_batches(&[PathBuf::from("src")], options);
+    // The working directory is shared by every test, so it's restored
+    // before anything can fail.
+    std::env::set_current_dir(&cwd).unwrap();
+    let mut batches = batches.unwrap();
+
+    std::fs::write(src.join("mod.ts"), "").unwrap();
+    let batch = tokio::time::timeout(Duration::from_secs(5), batches.next())
+      .await
+      .expect("no change detected")
+      .unwrap();
+    assert_eq!(batch.paths, vec![PathBuf::from("src/mod.ts")]);
+  }
+
+  #[test]
+  fn relative_paths_are_resolved_once() {
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    std::fs::create_dir(temp_dir.path().join("src")).unwrap();
+    let cwd = temp_dir.path();
+    assert_eq!(
+      absolute_path(Path::new("src/new.ts"), cwd),
+      cwd.join("src/new.ts")
+    );
+    assert_eq!(
+      absolute_path(Path::new("missing/mod.ts"), cwd),
+      PathBuf::from("missing/mod.ts")
+    );
+    let roots = vec![
+      (cwd.join("src"), PathBuf::from("src")),
+      (cwd.join("src/lib"), PathBuf::from("lib")),
+    ];
+    assert_eq!(
+      given_path(&cwd.join("src/lib/mod.ts"), &roots),
+      PathBuf::from("lib/mod.ts")
+    );
+    assert_eq!(
+      given_path(&cwd.join("src/mod.ts"), &roots),
+      PathBuf::from("src/mod.ts")
+    );
+    assert_eq!(
+      given_path(Path::new("/other.ts"), &roots),
+      PathBuf::from("/other.ts")
+    );
+  }
+
+  #[tokio::test]
+  async fn noisy_paths_are_ignored_for_a_while() {
+    let options = WatchOptions {
+      debounce_time: Some(Duration::from_millis(50)),
+      channel_capacity: Some(64),
+      noisy_path_limit: Some(5),
+      ..Default::default()
+    };
+    let registration = Registration::new(Path::new("/proj"), &options);
+    let (handler, mut debounce) =
+      event_pipeline(&[registration], &options).unwrap();
+    for _ in 0..20 {
+      handler.handle(Ok(modify_event("/proj/app.log")));
+    }
+    // The events below the limit still go through.
+    let batch = debounce.next().await.unwrap().unwrap();
+    assert_eq!(batch[0].paths, vec![PathBuf::from("/proj/app.log")]);
+
+    handler.handle(Ok(modify_event("/proj/app.log")));
+    handler.handle(Ok(modify_event("/proj/mod.ts")));
+    let batch = debounce.next().await.unwrap().unwrap();
+    let paths: Vec<&PathBuf> =
+      batch.iter().flat_map(|event| event.paths.iter()).collect();
+    assert_eq!(paths, vec![&PathBuf::from("/proj/mod.ts")]);
+  }
+
+  #[test]
+  fn noisy_paths_are_watched_again_after_the_cooldown() {
+    let mut noisy_paths = NoisyPaths::new(2, Duration::from_secs(10));
+    let start = Instant::now();
+    let path = Path::new("/proj/app.log");
+    assert!(!noisy_paths.is_muted(path, start));
+    assert!(!noisy_paths.is_muted(path, start));
+    assert!(noisy_paths.is_muted(path, start));
+    assert!(!noisy_paths.is_muted(Path::new("/proj/mod.ts"), start));
+    assert!(noisy_paths.is_muted(path, start + Duration::from_secs(9)));
+    assert!(!noisy_paths.is_muted(path, start + Duration::from_secs(10)));
+    // A new window, not the one the path was muted in.
+    assert!(!noisy_paths.is_muted(path, start + Duration::from_secs(12)));
+  }
+
+  #[tokio::test]
+  async fn denoignore_patterns_are_excluded() {
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let root = temp_dir.path().canonicalize().unwrap();
+    std::fs::create_dir(root.join("build")).unwrap();
+    std::fs::write(root.join(DENOIGNORE_FILE), "# output\nbuild/\n").unwrap();
+    let options = WatchOptions {
+      debounce_time: Some(Duration::from_millis(50)),
+      ..Default::default()
+    };
+    let mut batches = watch_batches(&[root.clone()], options).unwrap();
+
+    std::fs::write(root.join("build/out.js"), "").unwrap();
+    std::fs::write(root.join("mod.ts"), "").unwrap();
+    let batch = tokio::time::timeout(Duration::from_secs(5), batches.next())
+      .await
+      .expect("no change detected")
+      .unwrap();
+    assert_eq!(batch.paths, vec![root.join("mod.ts")]);
+
+    // Changing the file takes effect without restarting the watcher.
+    std::fs::write(root.join(DENOIGNORE_FILE), "build/\n*.tmp\n").unwrap();
+    delay_for(Duration::from_millis(200)).await;
+    std::fs::write(root.join("a.tmp"), "").unwrap();
+    std::fs::write(root.join("mod.ts"), "1").unwrap();
+    let batch = tokio::time::timeout(Duration::from_secs(5), batches.next())
+      .await
+      .expect("no change detected")
+      .unwrap();
+    assert_eq!(batch.paths, vec![root.join("mod.ts")]);
+  }
+
+  #[tokio::test]
+  async fn denoignore_can_be_turned_off() {
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let root = temp_dir.path().canonicalize().unwrap();
+    std::fs::write(root.join(DENOIGNORE_FILE), "*.js\n").unwrap();
+    let options = WatchOptions {
+      debounce_time: Some(Duration::from_millis(50)),
+      respect_denoignore: Some(false),
+      ..Default::default()
+    };
+    let mut batches = watch_batches(&[root.clone()], options).unwrap();
+
+    std::fs::write(root.join("out.js"), "").unwrap();
+    let batch = tokio::time::timeout(Duration::from_secs(5), batches.next())
+      .await
+      .expect("no change detected")
+      .unwrap();
+    assert_eq!(batch.paths, vec![root.join("out.js")]);
+  }
+
+  #[cfg(unix)]
+  #[tokio::test]
+  async fn retargeted_links_are_told_from_content_changes() {
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let root = temp_dir.path().canonicalize().unwrap();
+    std::fs::create_dir(root.join("targets")).unwrap();
+    std::fs::create_dir(root.join("links")).unwrap();
+    let old_target = root.join("targets/a.json");
+    let new_target = root.join("targets/b.json");
+    std::fs::write(&old_target, "a").unwrap();
+    std::fs::write(&new_target, "b").unwrap();
+    let link = root.join("links/config.json");
+    std::os::unix::fs::symlink(&old_target, &link).unwrap();
+    let options = WatchOptions {
+      debounce_time: Some(Duration::from_millis(50)),
+      ..Default::default()
+    };
+    let mut changes = FileWatcher::new(&[link.clone()], options).unwrap();
+
+    std::fs::remove_file(&link).unwrap();
+    std::os::unix::fs::symlink(&new_target, &link).unwrap();
+    let batch = tokio::time::timeout(Duration::from_secs(5), changes.next())
+      .await
+      .expect("no change detected")
+      .unwrap()
+      .unwrap();
+    assert_eq!(batch.paths, vec![link.clone()]);
+    assert_eq!(batch.link_change, Some(LinkChange::Retargeted));
+
+    std::fs::write(&new_target, "c").unwrap();
+    let batch = tokio::time::timeout(Duration::from_secs(5), changes.next())
+      .await
+      .expect("no change detected")
+      .unwrap()
+      .unwrap();
+    assert_eq!(batch.paths, vec![link]);
+    assert_eq!(batch.link_change, Some(LinkChange::Content));
+  }
+
+  #[tokio::test]
+  async fn watch_func_exits_once_every_root_is_removed() {
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let root = temp_dir.path().join("proj");
+    std::fs::create_dir(&root).unwrap();
+    let options = WatchOptions {
+      debounce_time: Some(Duration::from_millis(50)),
+      exit_on_roots_lost: true,
+      ..Default::default()
+    };
+    let root_ = root.clone();
+    let result = tokio::time::timeout(
+      Duration::from_secs(5),
+      watch_func(&[root.clone()], options, move |_| {
+        let root = root_.clone();
+        async move {
+          delay_for(Duration::from_millis(100)).await;
+          let _ = std::fs::remove_dir_all(&root);
+          Ok(())
+        }
+        .boxed_local()
+      }),
+    )
+    .await;
+    assert!(result.expect("watch_func kept watching").is_ok());
+  }
+
+  #[tokio::test]
+  async fn removed_roots_are_watched_again_once_back() {
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let root = temp_dir.path().canonicalize().unwrap();
+    let proj = root.join("proj");
+    let src = proj.join("src");
+    std::fs::create_dir_all(&src).unwrap();
+    let options = WatchOptions {
+      debounce_time: Some(Duration::from_millis(50)),
+      ..Default::default()
+    };
+    let mut changes = FileWatcher::new(&[src.clone()], options).unwrap();
+
+    // The ancestor standing in for the root goes away as well.
+    std::fs::remove_dir_all(&proj).unwrap();
+    let _ = tokio::time::timeout(Duration::from_secs(5), changes.next())
+      .await
+      .expect("no change detected");
+    assert!(changes.all_roots_lost());
+    assert!(changes.lost_roots_retry.is_some());
+
+    std::fs::create_dir_all(&src).unwrap();
+    let file_path = src.join("mod.ts");
+    let found = tokio::time::timeout(Duration::from_secs(10), async {
+      loop {
+        std::fs::write(&file_path, "").unwrap();
+        let timeout = Duration::from_millis(500);
+        if let Ok(Some(Ok(batch))) =
+          tokio::time::timeout(timeout, changes.next()).await
+        {
+          if batch.paths.contains(&file_path) {
+            break;
+          }
+        }
+      }
+    })
+    .await;
+    assert!(found.is_ok(), "recreated root not watched");
+    assert!(changes.lost_roots_retry.is_none());
+  }
+
+  #[tokio::test]
+  async fn debounce_scope_decides_whether_roots_coalesce() {
+    let batches = |scope: DebounceScope| async move {
+      let options = WatchOptions {
+        debounce_time: Some(Duration::from_millis(50)),
+        debounce_scope: scope,
+        ..Default::default()
+      };
+      let registrations: Vec<Registration> =
+        ["/proj/frontend", "/proj/backend"]
+          .iter()
+          .map(|root| Registration::new(Path::new(root), &options))
+          .collect();
+      let (handler, mut debounce) =
+        event_pipeline(&registrations, &options).unwrap();
+      handler.handle(Ok(modify_event("/proj/frontend/app.ts")));
+      handler.handle(Ok(modify_event("/proj/backend/server.ts")));
+      handler.handle(Ok(modify_event("/proj/frontend/style.css")));
+      let mut batches = Vec::new();
+      while let Ok(Some(batch)) =
+        tokio::time::timeout(Duration::from_millis(300), debounce.next()).await
+      {
+        let mut paths: Vec<PathBuf> = batch
+          .unwrap()
+          .into_iter()
+          .flat_map(|event| event.paths)
+          .collect();
+        paths.sort();
+        batches.push(paths);
+      }
+      batches.sort();
+      batches
+    };
+
+    assert_eq!(
+      batches(DebounceScope::Global).await,
+      vec![vec![
+        PathBuf::from("/proj/backend/server.ts"),
+        PathBuf::from("/proj/frontend/app.ts"),
+        PathBuf::from("/proj/frontend/style.css"),
+      ]]
+    );
+    assert_eq!(
+      batches(DebounceScope::PerRoot).await,
+      vec![
+        vec![PathBuf::from("/proj/backend/server.ts")],
+        vec![
+          PathBuf::from("/proj/frontend/app.ts"),
+          PathBuf::from("/proj/frontend/style.css"),
+        ],
+      ]
+    );
+  }
+
+  #[tokio::test]
+  async fn lockfile_changes_are_tagged() {
+    let temp_dir = TempDir::new().expect("tempdir fail");
+    let main = temp_dir.path().join("main.ts");
+    let lockfile = temp_dir.path().join("deno.lock");
+    std::fs::write(&main, "").unwrap();
+    std::fs::write(&lockfile, "{}").unwrap();
+    let options = WatchOptions {
+      watch_lockfile: Some(lockfile.clone()),
+      ..Default::default()
+    };
+    let mut changes = FileWatcher::new(&[main.clone()], options).unwrap();
+
+    std::fs::write(&lockfile, "{\"version\": \"2\"}").unwrap();
+    let batch = tokio::time::timeout(Duration::from_secs(5), changes.next())
+      .await
+      .expect("lockfile change not detected")
+      .unwrap()
+      .unwrap();
+    assert_eq!(batch.paths, vec![lockfile]);
+    assert_eq!(batch.tag, Some(BatchTag::LockfileChanged));
+
+    std::fs::write(&main, "console.log(1);").unwrap();
+    let batch = tokio::time::timeout(Duration::from_secs(5), changes.next())
+      .await
+      .expect("source change not detected")
+      .unwrap()
+      .unwrap();
+    assert_eq!(batch.paths, vec![main]);
+    assert_eq!(batch.tag, None);
+  }
+
+  fn path_filter(include: &[&str], exclude: &[&str]) -> PathFilter {
+    PathFilter::new(&WatchOptions {
+      include: include.iter().map(|p| p.to_string()).collect(),
+      exclude: exclude.iter().map(|p| p.to_string()).collect(),
+      ..Default::default()
+    })
+    .unwrap()
+  }
+
+  fn proj_roots() -> Vec<(PathBuf, PathBuf)> {
+    vec![(PathBuf::from("/proj"), PathBuf::from("/proj"))]
+  }
+
+  #[test]
+  fn path_filter_include_only() {
+    let filter = path_filter(&["src/**"], &[]);
+    let roots = proj_roots();
+    assert!(filter.is_match(Path::new("/proj/src/mod.ts"), &roots, &[]));
+    assert!(!filter.is_match(Path::new("/proj/test/mod.ts"), &roots, &[]));
+  }
+
+  #[test]
+  fn path_filter_exclude_only() {
+    let filter = path_filter(&[], &["*.log"]);
+    let roots = proj_roots();
+    assert!(filter.is_match(Path::new("/proj/src/mod.ts"), &roots, &[]));
+    assert!(!filter.is_match(Path::new("/proj/debug.log"), &roots, &[]));
+  }
+
+  #[test]
+  fn path_filter_exclude_wins_over_include() {
+    let filter = path_filter(&["src/**"], &["src/generated"]);
+    let roots = proj_roots();
+    assert!(filter.is_match(Path::new("/proj/src/mod.ts"), &roots, &[]));
+    assert!(!filter.is_match(
+      Path::new("/proj/src/generated/types.ts"),
+      &roots,
+      &[]
+    ));
+    assert!(!filter.is_match(Path::new("/proj/lib/mod.ts"), &roots, &[]));
+    let denoignore = vec![(
+      PathBuf::from("/proj"),
+      GlobSet::from_ignore_file("src/vendor/\n").unwrap(),
+    )];
+    assert!(!filter.is_match(
+      Path::new("/proj/src/vendor/dep.ts"),
+      &roots,
+      &denoignore
+    ));
+    assert!(!filter.is_match(Path::new("/proj/src/.cache/a.ts"), &roots, &[]));
+  }
+
+  #[test]
+  fn path_filter_negation_overrides_exclude() {
+    let filter = path_filter(&["src/**"], &["*.json", "!deno.json"]);
+    let roots = proj_roots();
+    assert!(!filter.is_match(Path::new("/proj/src/data.json"), &roots, &[]));
+    assert!(filter.is_match(Path::new("/proj/src/deno.json"), &roots, &[]));
+    let filter = path_filter(&["src/**", "!src/fixtures"], &[]);
+    assert!(!filter.is_match(
+      Path::new("/proj/src/fixtures/a.ts"),
+      &roots,
+      &[]
+    ));
   }
-  Ok(())
 }
@@ -114,6 +114,7 @@ pub struct Flags {
   pub lock: Option<String>,
   pub lock_write: bool,
   pub log_level: Option<Level>,
+  pub max_restarts: Option<usize>,
   pub net_allowlist: Vec<String>,
   pub no_check: bool,
   pub no_prompts: bool,
@@ -125,6 +126,9 @@ pub struct Flags {
   pub v8_flags: Option<Vec<String>>,
   pub version: bool,
   pub watch: bool,
+  pub watch_paths: Vec<String>,
+  pub watch_poll: bool,
+  pub watch_verbose: bool,
   pub write_allowlist: Vec<PathBuf>,
 }
 
@@ -573,6 +577,14 @@ fn run_parse(flags: &mut Flags, matches: &clap::ArgMatches) {
   }
 
   flags.watch = matches.is_present("watch");
+  if let Some(watch_paths) = matches.values_of("watch-path") {
+    flags.watch_paths = watch_paths.map(String::from).collect();
+  }
+  flags.watch_poll = matches.is_present("watch-poll");
+  flags.watch_verbose = matches.is_present("watch-verbose");
+  if let Some(max_restarts) = matches.value_of("max-restarts") {
+    flags.max_restarts = Some(max_restarts.parse::<usize>().unwrap());
+  }
   flags.subcommand = DenoSubcommand::Run { script };
 }
 
@@ -1127,6 +1139,10 @@ fn permission_args<'a, 'b>(app: App<'a, 'b>) -> App<'a, 'b> {
 fn run_subcommand<'a, 'b>() -> App<'a, 'b> {
   runtime_args(SubCommand::with_name("run"), true)
     .arg(watch_arg())
+    .arg(watch_path_arg())
+    .arg(watch_poll_arg())
+    .arg(watch_verbose_arg())
+    .arg(max_restarts_arg())
     .setting(AppSettings::TrailingVarArg)
     .arg(script_arg())
     .about("Run a program given a filename or url to the module. Use '-' as a filename to read from stdin.")
@@ -1385,8 +1401,67 @@ fn watch_arg<'a, 'b>() -> Arg<'a, 'b> {
     .help("Watch for file changes and restart process automatically")
     .long_help(
       "Watch for file changes and restart process automatically.
-Only local files from entry point module graph are watched.",
+Only local files from entry point module graph are watched, and those
+given with --watch-path.
+On Unix, sending SIGUSR1 to deno restarts the process without a file change.",
+    )
+}
+
+fn watch_path_arg<'a, 'b>() -> Arg<'a, 'b> {
+  Arg::with_name("watch-path")
+    .requires("watch")
+    .long("watch-path")
+    .value_name("PATH")
+    .help("Watch this path too, or the paths listed in @FILE")
+    .long_help(
+      "Watch this path too, besides the local files of the module graph.
+Can be given more than once. @FILE watches the paths listed in FILE, one
+path or glob pattern per line, for watch sets too large for the command
+line.",
+    )
+    .takes_value(true)
+    .multiple(true)
+    .number_of_values(1)
+}
+
+fn watch_poll_arg<'a, 'b>() -> Arg<'a, 'b> {
+  Arg::with_name("watch-poll")
+    .requires("watch")
+    .long("watch-poll")
+    .help("Poll for file changes instead of using OS notifications")
+    .long_help(
+      "Poll for file changes instead of using OS notifications.
+Useful on network file systems and mounts that don't report changes.",
+    )
+}
+
+fn watch_verbose_arg<'a, 'b>() -> Arg<'a, 'b> {
+  Arg::with_name("watch-verbose")
+    .requires("watch")
+    .long("watch-verbose")
+    .help("Print every file change event the watcher receives")
+    .long_help(
+      "Print every file change event the watcher receives, before events
+in quick succession are grouped into a restart. Useful to find out why
+the process restarts, or doesn't.",
+    )
+}
+
+fn max_restarts_arg<'a, 'b>() -> Arg<'a, 'b> {
+  Arg::with_name("max-restarts")
+    .requires("watch")
+    .long("max-restarts")
+    .value_name("NUMBER")
+    .help("Exit after restarting this many times")
+    .long_help(
+      "Exit after restarting this many times, once the last run completes.
+Useful to bound watch mode in CI.",
     )
+    .takes_value(true)
+    .validator(|val: String| match val.parse::<usize>() {
+      Ok(_) => Ok(()),
+      Err(_) => Err("Max restarts should be a number".to_string()),
+    })
 }
 
 fn seed_arg<'a, 'b>() -> Arg<'a, 'b> {
@@ -1628,6 +1703,108 @@ mod tests {
     );
   }
 
+  #[test]
+  fn run_watch_path() {
+    let r = flags_from_vec_safe(svec![
+      "deno",
+      "run",
+      "--unstable",
+      "--watch",
+      "--watch-path",
+      "assets",
+      "--watch-path=@watch.txt",
+      "script.ts"
+    ]);
+    let flags = r.unwrap();
+    assert_eq!(
+      flags,
+      Flags {
+        subcommand: DenoSubcommand::Run {
+          script: "script.ts".to_string(),
+        },
+        watch: true,
+        watch_paths: svec!["assets", "@watch.txt"],
+        unstable: true,
+        ..Flags::default()
+      }
+    );
+  }
+
+  #[test]
+  fn run_watch_poll() {
+    let r = flags_from_vec_safe(svec![
+      "deno",
+      "run",
+      "--unstable",
+      "--watch",
+      "--watch-poll",
+      "script.ts"
+    ]);
+    let flags = r.unwrap();
+    assert_eq!(
+      flags,
+      Flags {
+        subcommand: DenoSubcommand::Run {
+          script: "script.ts".to_string(),
+        },
+        watch: true,
+        watch_poll: true,
+        unstable: true,
+        ..Flags::default()
+      }
+    );
+  }
+
+  #[test]
+  fn run_watch_verbose() {
+    let r = flags_from_vec_safe(svec![
+      "deno",
+      "run",
+      "--unstable",
+      "--watch",
+      "--watch-verbose",
+      "script.ts"
+    ]);
+    let flags = r.unwrap();
+    assert_eq!(
+      flags,
+      Flags {
+        subcommand: DenoSubcommand::Run {
+          script: "script.ts".to_string(),
+        },
+        watch: true,
+        watch_verbose: true,
+        unstable: true,
+        ..Flags::default()
+      }
+    );
+  }
+
+  #[test]
+  fn run_max_restarts() {
+    let r = flags_from_vec_safe(svec![
+      "deno",
+      "run",
+      "--unstable",
+      "--watch",
+      "--max-restarts=2",
+      "script.ts"
+    ]);
+    let flags = r.unwrap();
+    assert_eq!(
+      flags,
+      Flags {
+        subcommand: DenoSubcommand::Run {
+          script: "script.ts".to_string(),
+        },
+        watch: true,
+        max_restarts: Some(2),
+        unstable: true,
+        ..Flags::default()
+      }
+    );
+  }
+
   #[test]
   fn run_reload_allow_write() {
     let r = flags_from_vec_safe(svec![
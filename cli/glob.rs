@@ -0,0 +1,236 @@
+// Copyright 2018-2020 the Deno authors. All rights reserved. MIT license.
+
+//! Minimal gitignore-style glob matching, compiled down to a `RegexSet`.
+
+use deno_core::error::AnyError;
+use regex::RegexSet;
+use std::path::Path;
+
+/// A set of glob patterns that can be matched against paths in one pass.
+///
+/// Patterns follow gitignore conventions: a pattern without a `/` matches a
+/// file or directory name at any depth, a pattern containing a `/` is anchored
+/// to the start of the path, and a pattern matching a directory also matches
+/// everything below it. `*`, `?`, `**`, `[...]` and `{a,b}` are supported.
+/// A pattern starting with `!` negates an earlier one, the last matching
+/// pattern deciding whether a path matches.
+#[derive(Clone, Debug)]
+pub struct GlobSet {
+  patterns: Vec<String>,
+  /// Whether each pattern starts with `!`.
+  negated: Vec<bool>,
+  set: RegexSet,
+}
+
+impl GlobSet {
+  pub fn new<S: AsRef<str>>(patterns: &[S]) -> Result<Self, AnyError> {
+    let patterns: Vec<String> =
+      patterns.iter().map(|p| p.as_ref().to_string()).collect();
+    let negated: Vec<bool> =
+      patterns.iter().map(|p| p.starts_with('!')).collect();
+    let set = RegexSet::new(
+      patterns
+        .iter()
+        .map(|p| glob_to_regex(p.strip_prefix('!').unwrap_or(p))),
+    )?;
+    Ok(Self {
+      patterns,
+      negated,
+      set,
+    })
+  }
+
+  /// Reads the patterns of a gitignore-style file, skipping blank lines and
+  /// `#` comments.
+  pub fn from_ignore_file(contents: &str) -> Result<Self, AnyError> {
+    let patterns: Vec<&str> = contents
+      .lines()
+      .map(|line| line.trim_end())
+      .filter(|line| !line.is_empty() && !line.starts_with('#'))
+      .collect();
+    Self::new(&patterns)
+  }
+
+  /// The patterns as given.
+  pub fn patterns(&self) -> &[String] {
+    &self.patterns
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.patterns.is_empty()
+  }
+
+  pub fn is_match(&self, path: &Path) -> bool {
+    let path = path_to_glob_str(path);
+    if !self.negated.contains(&true) {
+      return self.set.is_match(&path);
+    }
+    match self.set.matches(&path).iter().last() {
+      Some(i) => !self.negated[i],
+      None => false,
+    }
+  }
+}
+
+impl Default for GlobSet {
+  fn default() -> Self {
+    Self::new::<String>(&[]).unwrap()
+  }
+}
+
+/// Render a path with `/` separators so the same patterns work on Windows.
+fn path_to_glob_str(path: &Path) -> String {
+  let path = path.to_string_lossy();
+  if cfg!(windows) {
+    path.replace('\\', "/")
+  } else {
+    path.into_owned()
+  }
+}
+
+fn glob_to_regex(glob: &str) -> String {
+  let glob = if cfg!(windows) {
+    glob.replace('\\', "/")
+  } else {
+    glob.to_string()
+  };
+  let glob = glob.trim_end_matches('/');
+  let mut regex = String::from("^");
+  if glob.starts_with('/') {
+    // Either an absolute path or a gitignore root-relative pattern.
+    regex.push_str("/?");
+  } else if !glob.contains('/') {
+    regex.push_str("(?:.*/)?");
+  }
+
+  let chars: Vec<char> = glob.trim_start_matches('/').chars().collect();
+  let mut brace_depth = 0;
+  let mut i = 0;
+  while i < chars.len() {
+    let c = chars[i];
+    match c {
+      '*' if chars.get(i + 1) == Some(&'*') => {
+        i += 1;
+        if chars.get(i + 1) == Some(&'/') {
+          i += 1;
+          regex.push_str("(?:.*/)?");
+        } else {
+          regex.push_str(".*");
+        }
+      }
+      '*' => regex.push_str("[^/]*"),
+      '?' => regex.push_str("[^/]"),
+      '[' => match chars[i + 1..].iter().position(|c| *c == ']') {
+        Some(len) => {
+          let class = &chars[i + 1..i + 1 + len];
+          regex.push('[');
+          for (j, c) in class.iter().enumerate() {
+            match c {
+              '!' if j == 0 => regex.push('^'),
+              '\\' | '[' | '&' | '~' => {
+                regex.push('\\');
+                regex.push(*c);
+              }
+              _ => regex.push(*c),
+            }
+          }
+          regex.push(']');
+          i += len + 1;
+        }
+        None => regex.push_str("\\["),
+      },
+      '{' => {
+        brace_depth += 1;
+        regex.push_str("(?:");
+      }
+      '}' if brace_depth > 0 => {
+        brace_depth -= 1;
+        regex.push(')');
+      }
+      ',' if brace_depth > 0 => regex.push('|'),
+      '\\' if i + 1 < chars.len() => {
+        i += 1;
+        regex.push_str(&regex::escape(&chars[i].to_string()));
+      }
+      _ => regex.push_str(&regex::escape(&c.to_string())),
+    }
+    i += 1;
+  }
+
+  regex.push_str("(?:/.*)?$");
+  regex
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn matches(pattern: &str, path: &str) -> bool {
+    GlobSet::new(&[pattern]).unwrap().is_match(Path::new(path))
+  }
+
+  #[test]
+  fn name_matches_at_any_depth() {
+    assert!(matches("node_modules", "node_modules"));
+    assert!(matches("node_modules", "/proj/node_modules/pkg/mod.js"));
+    assert!(matches("*.log", "/proj/logs/debug.log"));
+    assert!(!matches("node_modules", "/proj/my_node_modules/mod.js"));
+    assert!(!matches("*.log", "/proj/debug.log.ts"));
+  }
+
+  #[test]
+  fn anchored_patterns() {
+    assert!(matches("src/*.ts", "src/mod.ts"));
+    assert!(!matches("src/*.ts", "lib/src/mod.ts"));
+    assert!(!matches("src/*.ts", "src/nested/mod.ts"));
+    assert!(matches("/target", "target/debug/deno"));
+    assert!(matches("/proj/target", "/proj/target/debug/deno"));
+    assert!(matches("build/", "build/out.js"));
+  }
+
+  #[test]
+  fn double_star() {
+    assert!(matches("**/*.ts", "/proj/src/deep/mod.ts"));
+    assert!(matches("**/*.ts", "mod.ts"));
+    assert!(matches("src/**/test.ts", "src/test.ts"));
+    assert!(matches("src/**/test.ts", "src/a/b/test.ts"));
+    assert!(!matches("**/*.ts", "/proj/src/mod.js"));
+  }
+
+  #[test]
+  fn classes_and_alternatives() {
+    assert!(matches("*.{ts,tsx}", "/proj/app.tsx"));
+    assert!(!matches("*.{ts,tsx}", "/proj/app.js"));
+    assert!(matches("file[0-9].js", "file1.js"));
+    assert!(!matches("file[!0-9].js", "file1.js"));
+    assert!(matches("?.js", "a.js"));
+    assert!(!matches("?.js", "ab.js"));
+  }
+
+  #[test]
+  fn empty_set_matches_nothing() {
+    let set = GlobSet::default();
+    assert!(set.is_empty());
+    assert!(!set.is_match(Path::new("/proj/mod.ts")));
+  }
+
+  #[test]
+  fn negation_overrides_earlier_patterns() {
+    let set = GlobSet::new(&["*.log", "!keep.log"]).unwrap();
+    assert!(set.is_match(Path::new("/proj/debug.log")));
+    assert!(!set.is_match(Path::new("/proj/keep.log")));
+    let set = GlobSet::new(&["!keep.log", "*.log"]).unwrap();
+    assert!(set.is_match(Path::new("/proj/keep.log")));
+  }
+
+  #[test]
+  fn ignore_files_skip_comments_and_blank_lines() {
+    let set =
+      GlobSet::from_ignore_file("# build output\nbuild/\n\n*.tmp  \n!a.tmp\n")
+        .unwrap();
+    assert_eq!(set.patterns(), &["build/", "*.tmp", "!a.tmp"]);
+    assert!(set.is_match(Path::new("build/out.js")));
+    assert!(set.is_match(Path::new("src/b.tmp")));
+    assert!(!set.is_match(Path::new("src/a.tmp")));
+  }
+}
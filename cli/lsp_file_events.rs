@@ -0,0 +1,110 @@
+// Copyright 2018-2020 the Deno authors. All rights reserved. MIT license.
+
+//! Translates watch batches into the file events a language server reports
+//! with `workspace/didChangeWatchedFiles`, so that it can react to changes
+//! made outside the editor. The types mirror those of the Language Server
+//! Protocol, wire values included, to keep the translation into a protocol
+//! crate trivial.
+
+use crate::file_watcher::ChangeKind;
+use crate::file_watcher::WatchBatch;
+use deno_core::url::Url;
+
+/// `FileChangeType` of the Language Server Protocol.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FileChangeType {
+  Created = 1,
+  Changed = 2,
+  Deleted = 3,
+}
+
+/// `FileEvent` of the Language Server Protocol.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FileEvent {
+  pub uri: Url,
+  pub typ: FileChangeType,
+}
+
+/// One event per changed path of the batch, of the net change to the path,
+/// see `ChangeBatch::path_kinds`: both ends of a rename are reported deleted
+/// and created, and a path whose change isn't known changed. Paths that
+/// aren't absolute have no URI and are skipped.
+pub fn to_lsp_file_events(batch: &WatchBatch) -> Vec<FileEvent> {
+  batch
+    .paths
+    .iter()
+    .filter_map(|path| {
+      let uri = Url::from_file_path(path).ok()?;
+      let typ = match batch.path_kinds.get(path) {
+        Some(ChangeKind::Created) => FileChangeType::Created,
+        Some(ChangeKind::Removed) => FileChangeType::Deleted,
+        _ => FileChangeType::Changed,
+      };
+      Some(FileEvent { uri, typ })
+    })
+    .collect()
+}
+
+// Only built with the `lsp` feature, so run with
+// `cargo test --features lsp lsp_file_events`, as CI does.
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::path::PathBuf;
+  use std::time::Instant;
+  use std::time::SystemTime;
+
+  fn batch(path_kinds: &[(&str, ChangeKind)]) -> WatchBatch {
+    WatchBatch {
+      paths: path_kinds
+        .iter()
+        .map(|(path, _)| PathBuf::from(path))
+        .collect(),
+      kinds: path_kinds.iter().map(|(_, kind)| *kind).collect(),
+      path_kinds: path_kinds
+        .iter()
+        .map(|(path, kind)| (PathBuf::from(path), *kind))
+        .collect(),
+      source: None,
+      reason: None,
+      link_change: None,
+      tag: None,
+      content_deltas: Vec::new(),
+      first_event_at: Instant::now(),
+      last_event_at: Instant::now(),
+      time: SystemTime::now(),
+    }
+  }
+
+  #[test]
+  fn paths_map_by_their_own_kind() {
+    let batch = batch(&[
+      ("/proj/created.ts", ChangeKind::Created),
+      ("/proj/edited.ts", ChangeKind::Modified),
+      ("/proj/removed.ts", ChangeKind::Removed),
+      ("/proj/read.ts", ChangeKind::Accessed),
+      ("relative.ts", ChangeKind::Created),
+    ]);
+    let event = |path: &str, typ| FileEvent {
+      uri: Url::from_file_path(path).unwrap(),
+      typ,
+    };
+    assert_eq!(
+      to_lsp_file_events(&batch),
+      vec![
+        event("/proj/created.ts", FileChangeType::Created),
+        event("/proj/edited.ts", FileChangeType::Changed),
+        event("/proj/removed.ts", FileChangeType::Deleted),
+        event("/proj/read.ts", FileChangeType::Changed),
+      ]
+    );
+  }
+
+  #[test]
+  fn paths_of_unknown_kind_changed() {
+    let mut batch = batch(&[("/proj/dropped.ts", ChangeKind::Removed)]);
+    batch.path_kinds.clear();
+    let events = to_lsp_file_events(&batch);
+    assert_eq!(events[0].typ, FileChangeType::Changed);
+  }
+}
@@ -9,20 +9,23 @@ extern crate log;
 
 mod ast;
 mod checksum;
+pub mod clock;
 pub mod colors;
 mod coverage;
+pub mod debounce;
 pub mod deno_dir;
 pub mod diagnostics;
 mod diff;
 mod disk_cache;
 pub mod errors;
 mod file_fetcher;
-mod file_watcher;
+pub mod file_watcher;
 pub mod flags;
 mod flags_allow_net;
 mod fmt;
 pub mod fmt_errors;
 mod fs;
+pub mod glob;
 pub mod global_state;
 mod global_timer;
 mod graph;
@@ -35,6 +38,8 @@ pub mod installer;
 mod js;
 mod lint;
 mod lockfile;
+#[cfg(feature = "lsp")]
+pub mod lsp_file_events;
 mod media_type;
 mod metrics;
 mod module_graph;
@@ -494,25 +499,38 @@ async fn run_with_watch(flags: Flags, script: String) -> Result<(), AnyError> {
         .unwrap(),
     );
   }
+  paths_to_watch.extend(file_watcher::expand_watch_args(&flags.watch_paths)?);
 
-  // FIXME(bartlomieju): new file watcher is created on after each restart
-  file_watcher::watch_func(&paths_to_watch, move || {
-    // FIXME(bartlomieju): GlobalState must be created on each restart - otherwise file fetcher
-    // will use cached source files
-    let gs = GlobalState::new(flags.clone()).unwrap();
-    let main_module = main_module.clone();
-    async move {
-      let mut worker = MainWorker::new(&gs, main_module.clone());
-      debug!("main_module {}", main_module);
-      worker.execute_module(&main_module).await?;
-      worker.execute("window.dispatchEvent(new Event('load'))")?;
-      (&mut *worker).await?;
-      worker.execute("window.dispatchEvent(new Event('unload'))")?;
-      Ok(())
-    }
-    .boxed_local()
-  })
-  .await
+  let mut builder = file_watcher::WatcherBuilder::new()
+    .shutdown(file_watcher::ctrl_c_shutdown())
+    .verbose(flags.watch_verbose);
+  if let Some(max_restarts) = flags.max_restarts {
+    builder = builder.max_restarts(max_restarts);
+  }
+  if flags.watch_poll {
+    builder = builder.backend(file_watcher::WatchBackend::Poll {
+      interval: file_watcher::DEFAULT_POLL_INTERVAL,
+    });
+  }
+  builder
+    .watch(&paths_to_watch, move |_| {
+      // FIXME(bartlomieju): GlobalState must be created on each restart - otherwise file fetcher
+      // will use cached source files
+      let gs = GlobalState::new(flags.clone()).unwrap();
+      let main_module = main_module.clone();
+      async move {
+        let mut worker = MainWorker::new(&gs, main_module.clone());
+        debug!("main_module {}", main_module);
+        worker.execute_module(&main_module).await?;
+        worker.execute("window.dispatchEvent(new Event('load'))")?;
+        (&mut *worker).await?;
+        worker.execute("window.dispatchEvent(new Event('unload'))")?;
+        Ok(())
+      }
+      .boxed_local()
+    })
+    .await?;
+  Ok(())
 }
 
 async fn run_command(flags: Flags, script: String) -> Result<(), AnyError> {